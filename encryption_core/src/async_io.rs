@@ -0,0 +1,139 @@
+use crate::blob::{add_file_entries, FileMetadata, MetadataMap, VolumeType};
+use crate::compress::Compression;
+use crate::dedup::{cut_point, random_padding, ChunkIndex, DedupWriter, MAX_CHUNK_SIZE};
+use crate::error::Result;
+use crate::volume::UnlockedVolume;
+use std::{io::SeekFrom, path::Path};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+/// Async equivalent of `write_file_data`: splits everything `reader` yields into
+/// content-defined chunks, writes the ones the volume doesn't store yet and the file's chunk
+/// manifest, into free space where `chunks` has some, without referencing the file from the
+/// metadata yet.
+/// Memory use is bounded by the maximum chunk size. Everything is synced to disk before
+/// returning.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `key` - Context: The derived key for the unlocked volume.
+/// * `chunks` - Context: The volume's chunk index (see `ChunkIndex::open`); newly stored
+///   chunks are added.
+/// * `file_path` - The path the file is meant for, recorded in the manifest.
+/// * `reader` - Source of the file content; read until EOF.
+/// * `mime_type` - The MIME type of the file.
+/// * `compression` - How to encode new chunks before encryption.
+///
+/// # Returns
+/// The `FileMetadata` of the durable, not yet committed, file. Commit it with
+/// `add_file_entries`.
+pub async fn write_file_data_async<R: AsyncRead + Unpin>(
+    path: &Path,
+    key: &[u8; 32],
+    chunks: &mut ChunkIndex,
+    file_path: &str,
+    reader: &mut R,
+    mime_type: &str,
+    compression: Compression,
+) -> Result<FileMetadata> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+    let end = file.seek(SeekFrom::End(0)).await?;
+    let mut writer = DedupWriter::new(key, chunks, end, compression);
+
+    // Same windowing as the blocking writer, so both cut the same boundaries
+    let mut pending = Vec::with_capacity(MAX_CHUNK_SIZE);
+    loop {
+        let missing = MAX_CHUNK_SIZE - pending.len();
+        (&mut *reader)
+            .take(missing as u64)
+            .read_to_end(&mut pending)
+            .await?;
+        if pending.is_empty() {
+            break;
+        }
+        let len = cut_point(&pending);
+        if let Some((offset, sealed)) = writer.seal(&pending[..len])? {
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.write_all(&sealed).await?;
+            writer.written();
+        }
+        pending.drain(..len);
+    }
+
+    let (offset, manifest, padding, file_metadata) = writer.finish(key, file_path, mime_type)?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(&manifest).await?;
+    for piece in random_padding(padding) {
+        file.write_all(&piece).await?;
+    }
+    file.sync_data().await?;
+    Ok(file_metadata)
+}
+
+/// Async equivalent of `add_file_from_reader`. The content is streamed from `reader`; the
+/// metadata entry is committed only after the last chunk is durable on disk.
+/// The final metadata write is small and performed synchronously.
+///
+/// # Errors
+/// Returns an error on read, file I/O or crypto failures. On error the metadata map is unchanged.
+pub async fn add_file_from_async_reader<R: AsyncRead + Unpin>(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    metadata_map: &mut MetadataMap,
+    file_path: &str,
+    reader: &mut R,
+    mime_type: &str,
+) -> Result<()> {
+    let mut chunks = ChunkIndex::open(path, volume_type, key, metadata_map)?;
+    let compression = Compression::for_file(volume_type, mime_type);
+    let file_metadata = write_file_data_async(
+        path,
+        key,
+        &mut chunks,
+        file_path,
+        reader,
+        mime_type,
+        compression,
+    )
+    .await?;
+    add_file_entries(
+        path,
+        volume_type,
+        key,
+        metadata_map,
+        vec![(file_path.to_string(), file_metadata)],
+    )
+}
+
+impl UnlockedVolume {
+    /// Async equivalent of `UnlockedVolume::add_file_from_reader`. The content is streamed
+    /// from `reader` through a separate handle on the blob; the final metadata write is small
+    /// and performed synchronously.
+    ///
+    /// # Errors
+    /// Returns an error on read, file I/O or crypto failures.
+    pub async fn add_file_from_async_reader<R: AsyncRead + Unpin>(
+        &mut self,
+        file_path: &str,
+        reader: &mut R,
+        mime_type: &str,
+    ) -> Result<()> {
+        let compression = Compression::for_file(self.volume_type(), mime_type);
+        let (path, key, chunks) = self.write_context()?;
+        let (path, key) = (path.to_path_buf(), *key);
+        let file_metadata = write_file_data_async(
+            &path,
+            &key,
+            chunks,
+            file_path,
+            reader,
+            mime_type,
+            compression,
+        )
+        .await?;
+        self.add_file_entries(vec![(file_path.to_string(), file_metadata)])
+    }
+}
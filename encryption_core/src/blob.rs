@@ -1,5 +1,14 @@
-use anyhow::{anyhow, Result};
-use argon2::{Argon2, Params};
+use crate::compact::CompactionReport;
+use crate::compress::Compression;
+use crate::credential::{generate_recovery_key, Credential};
+use crate::dedup::{cut_point, random_padding, ChunkIndex, DedupWriter, MAX_CHUNK_SIZE};
+use crate::error::{BlobError, Result};
+use crate::kdf::{derive_key, KdfParams};
+use crate::keyslot::{generate_data_key, Keyslot, KEYSLOTS_PER_VOLUME, KEYSLOT_LEN};
+use crate::padding::PaddingPolicy;
+use crate::space::{referenced_extents, FreeSpace, Protected};
+use crate::stream;
+use crate::volume::UnlockedVolume;
 use chacha20poly1305::{
     aead::{Aead, AeadCore, KeyInit},
     Key, XChaCha20Poly1305, XNonce,
@@ -9,28 +18,50 @@ use rand::rngs::OsRng;
 use rand::RngCore;
 use serde::{Deserialize, Serialize}; // Make sure 'serde' features = ["derive"] is in Cargo.toml
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::{self, File, OpenOptions},
     io::{Read, Seek, SeekFrom, Write},
     path::Path,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 // --- Constants ---
 const MAGIC: &[u8] = b"ENC_BLOB";
-const VERSION: u8 = 3; // Version indicating hidden volume support
-const SALT_LEN: usize = 16;
+const VERSION: u8 = 4; // Version written by init_blob: encrypted superblocks locate the metadata
+
+// Fixed-region metadata; still readable and writable, see migrate_blob
+pub(crate) const VERSION_V3: u8 = 3;
+pub(crate) const SALT_LEN: usize = 16;
 pub const XNONCE_LEN: usize = 24; // For XChaCha20Poly1305
+const TAG_LEN: usize = 16; // Poly1305 tag appended to every AEAD ciphertext
+const SUPERBLOCK_LEN: usize = 128; // Fixed plaintext size of a v4 superblock (zero padded)
+const SUPERBLOCK_SLOTS: usize = 2; // Commits alternate between two superblock slots per volume
+const SUPERBLOCK_SLOT_LEN: u64 = (XNONCE_LEN + SUPERBLOCK_LEN + TAG_LEN) as u64; // Nonce + encrypted superblock
+
+// Prefix of the serialized metadata plaintext. Legacy (unversioned) metadata starts with the
+// bincode u64 entry count instead, which can never plausibly equal these bytes.
+const METADATA_MAGIC: &[u8] = b"KPMETA\x00\x01";
+const DIRECTORY_MIME_TYPE: &str = "inode/directory"; // MIME type of folder entries
+
+// Version of the metadata after `METADATA_MAGIC`: the file map, then the volume's free space
+const METADATA_VERSION: u32 = 1;
 
 // --- Offsets and lengths ---
 const HEADER_COMMON_LEN: usize = MAGIC.len() + 1; // Magic + Version byte
 const STANDARD_HEADER_LEN: usize = HEADER_COMMON_LEN + SALT_LEN + XNONCE_LEN + 8; // Common + Salt_S + MetaNonce_S + MetaSize_S
 const HIDDEN_HEADER_LEN: usize = SALT_LEN + XNONCE_LEN + 8; // Salt_H + MetaNonce_H + MetaSize_H
 
+// v4 volume header: Keyslots + SuperblockNonce + Encrypted superblock (same for both volumes)
+const V4_VOLUME_HEADER_LEN: usize =
+    KEYSLOTS_PER_VOLUME * KEYSLOT_LEN + XNONCE_LEN + SUPERBLOCK_LEN + TAG_LEN;
+
 const STANDARD_METADATA_OFFSET: u64 = STANDARD_HEADER_LEN as u64;
 const HIDDEN_HEADER_OFFSET: u64 = 65536; // Standard 64 KiB offset for hidden header
 const HIDDEN_METADATA_OFFSET: u64 = HIDDEN_HEADER_OFFSET + HIDDEN_HEADER_LEN as u64;
+const V4_STANDARD_METADATA_OFFSET: u64 = (HEADER_COMMON_LEN + V4_VOLUME_HEADER_LEN) as u64;
+const V4_HIDDEN_METADATA_OFFSET: u64 = HIDDEN_HEADER_OFFSET + V4_VOLUME_HEADER_LEN as u64;
 // Ensure data area starts well after potential hidden metadata block, allowing space for it
-const DATA_AREA_START_OFFSET: u64 = HIDDEN_METADATA_OFFSET + 1024 * 1024; // Start data 1MB after hidden meta starts
+pub(crate) const DATA_AREA_START_OFFSET: u64 = HIDDEN_METADATA_OFFSET + 1024 * 1024; // Start data 1MB after hidden meta starts
 
 // --- Core Public Structs & Enums ---
 
@@ -41,18 +72,167 @@ pub enum VolumeType {
     Hidden,
 }
 
+/// Describes how a file's data block is encrypted.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataFormat {
+    /// Legacy v3 layout: Nonce + one XChaCha20-Poly1305 message over the whole file.
+    Single,
+    /// STREAM layout: a random nonce prefix followed by independently authenticated chunks of
+    /// `chunk_size` plaintext bytes (the last one may be shorter). Each chunk's nonce binds its
+    /// index and whether it is the final chunk. Read but no longer written.
+    Chunked { chunk_size: u32 },
+    /// `Chunked` behind an encrypted, self-describing header of `header_len` bytes that names
+    /// the file, so the block can be salvaged without the metadata (see `salvage_blob`).
+    /// Blocks start at a multiple of 64 bytes. Read but no longer written.
+    ChunkedWithHeader { chunk_size: u32, header_len: u32 },
+    /// Content-defined chunks listed in `FileMetadata::chunks`, each stored once per volume
+    /// and shared by every file containing it. The data block is the file's sealed chunk
+    /// manifest, which names the file for `salvage_blob`. Written by this version.
+    Deduplicated,
+    /// A folder entry made by `create_folder`, not a file. It has no data block: size, offset
+    /// and length are zero and it lists no chunks. Folders without an entry of their own
+    /// still exist implicitly while any path below them does.
+    Directory,
+}
+
+impl DataFormat {
+    /// Plaintext bytes per chunk of a fixed-size chunked block, or `None` for legacy
+    /// single-message blocks and deduplicated files.
+    pub(crate) fn chunk_size(&self) -> Option<u32> {
+        match *self {
+            DataFormat::Single | DataFormat::Deduplicated | DataFormat::Directory => None,
+            DataFormat::Chunked { chunk_size }
+            | DataFormat::ChunkedWithHeader { chunk_size, .. } => Some(chunk_size),
+        }
+    }
+
+    /// Bytes in front of the encrypted content (the block header).
+    pub(crate) fn header_len(&self) -> u64 {
+        match *self {
+            DataFormat::ChunkedWithHeader { header_len, .. } => header_len as u64,
+            _ => 0,
+        }
+    }
+}
+
 /// Represents metadata for a single file stored within the blob.
 /// This is stored in the encrypted metadata block.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct FileMetadata {
     /// Original size of the file content in bytes.
     pub size: u64,
-    /// Offset within the blob file where the file's encrypted data block (Nonce + Ciphertext) begins.
+    /// Offset within the blob file where the file's encrypted data block begins. For
+    /// `DataFormat::Deduplicated` the block is the manifest, after any padding that aligned it.
     pub data_offset: u64,
-    /// Total length of the file's encrypted data block (Nonce + Ciphertext) in bytes.
+    /// Total length of the file's encrypted data block in bytes, including the random bytes
+    /// after a manifest that pad the file to its size bucket (see `PaddingPolicy`).
     pub data_length: u64,
     /// MIME type of the file (e.g., "image/jpeg", "application/pdf"). Used for HTTP responses.
     pub mime_type: String,
+    /// Encryption layout of the data block.
+    pub format: DataFormat,
+    /// The file's content chunks in order, for `DataFormat::Deduplicated`; empty otherwise.
+    pub chunks: Vec<ChunkRef>,
+    /// Compression the file was written with. Chunks shared with other files keep the
+    /// encoding they were first stored with.
+    pub compression: Compression,
+    /// When a file was first added at this path, in seconds since the Unix epoch. Kept when
+    /// the file is overwritten or renamed. `None` for files added before timestamps were
+    /// recorded, as are the other timestamps.
+    pub created_at: Option<u64>,
+    /// When the entry last changed: new content, a rename or new attributes.
+    pub modified_at: Option<u64>,
+    /// When the current content was written to the blob.
+    pub uploaded_at: Option<u64>,
+    /// SHA-256 of the plaintext content. `None` for files written before it was recorded.
+    pub sha256: Option<[u8; 32]>,
+    /// Unix permission bits of the original file, if the uploader supplied them.
+    pub original_mode: Option<u32>,
+    /// Modification time of the original file in seconds since the Unix epoch, if the
+    /// uploader supplied it.
+    pub original_mtime: Option<u64>,
+    /// Application-defined attributes.
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl FileMetadata {
+    /// A new, empty folder entry (see `DataFormat::Directory`).
+    pub fn directory() -> Self {
+        let now = Some(unix_now());
+        FileMetadata {
+            size: 0,
+            data_offset: 0,
+            data_length: 0,
+            mime_type: DIRECTORY_MIME_TYPE.to_string(),
+            format: DataFormat::Directory,
+            chunks: Vec::new(),
+            compression: Compression::None,
+            created_at: now,
+            modified_at: now,
+            uploaded_at: None,
+            sha256: None,
+            original_mode: None,
+            original_mtime: None,
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    /// True for folder entries, which have no content.
+    pub fn is_dir(&self) -> bool {
+        self.format == DataFormat::Directory
+    }
+}
+
+/// Where one chunk of a deduplicated file is stored. The same chunk may be listed by many
+/// files of the volume.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkRef {
+    /// Keyed hash of the chunk's plaintext (see `ChunkIndex`).
+    pub hash: [u8; 32],
+    /// Offset of the stored chunk (nonce, then the encrypted chunk and its tag).
+    pub offset: u64,
+    /// Plaintext bytes in the chunk.
+    pub size: u32,
+    /// Length of the zstd-compressed plaintext that was encrypted instead, or `None` if the
+    /// chunk is stored uncompressed.
+    pub compressed_size: Option<u32>,
+}
+
+impl ChunkRef {
+    /// On-disk length of the stored chunk: nonce, ciphertext and tag.
+    pub fn stored_len(&self) -> u64 {
+        (XNONCE_LEN + TAG_LEN) as u64 + self.compressed_size.unwrap_or(self.size) as u64
+    }
+}
+
+/// `FileMetadata` as written by v3 blobs, before metadata was versioned.
+#[derive(Deserialize)]
+struct LegacyFileMetadata {
+    size: u64,
+    data_offset: u64,
+    data_length: u64,
+    mime_type: String,
+}
+
+impl From<LegacyFileMetadata> for FileMetadata {
+    fn from(legacy: LegacyFileMetadata) -> Self {
+        FileMetadata {
+            size: legacy.size,
+            data_offset: legacy.data_offset,
+            data_length: legacy.data_length,
+            mime_type: legacy.mime_type,
+            format: DataFormat::Single,
+            chunks: Vec::new(),
+            compression: Compression::None,
+            created_at: None,
+            modified_at: None,
+            uploaded_at: None,
+            sha256: None,
+            original_mode: None,
+            original_mtime: None,
+            attributes: BTreeMap::new(),
+        }
+    }
 }
 
 /// The map holding all file metadata for the currently unlocked volume.
@@ -60,104 +240,146 @@ pub struct FileMetadata {
 /// This map is serialized using `bincode` and encrypted as the metadata block.
 pub type MetadataMap = HashMap<String, FileMetadata>;
 
+/// What a file is as far as can be told without unlocking it (see `blob_kind`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobKind {
+    /// A blob whose plaintext header records this format version (3 or 4).
+    Versioned(u8),
+    /// A file without the magic that is large enough to be a headerless blob. It may just as
+    /// well be any other file; only a password that opens one of its keyslots tells.
+    Headerless,
+}
+
+impl BlobKind {
+    /// The format version of the blob once a password has opened it: headerless blobs are
+    /// always v4.
+    pub fn unlocked_version(self) -> u8 {
+        match self {
+            BlobKind::Versioned(version) => version,
+            BlobKind::Headerless => VERSION,
+        }
+    }
+}
+
+/// Settings for `init_blob_with_options`. `Default` gives what `init_blob` uses.
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    /// KDF algorithm and cost, recorded in the header of both volumes.
+    pub kdf: KdfParams,
+    /// Size buckets the blocks of both volumes are padded to, recorded in their superblocks.
+    pub padding: PaddingPolicy,
+    /// Creates the blob file at this many bytes of random data, which both volumes then
+    /// write into instead of growing the file, so its length says nothing about how much is
+    /// stored. `None` creates a blob that grows as data is added. See `grow_blob`.
+    pub capacity: Option<u64>,
+    /// With `capacity`, how many bytes at the end of the blob the hidden volume gets as its
+    /// own region; the standard volume gets the rest of the data area and whatever
+    /// `grow_blob` adds later. Neither volume ever writes into the other's space. `None`
+    /// splits the data area in half.
+    pub hidden_capacity: Option<u64>,
+    /// Writes random bytes instead of the plaintext magic, version and KDF parameters, so
+    /// nothing in the file tells it apart from random data. Such a blob is only recognized
+    /// by unlocking it, and its keyslots always use the default KDF parameters, so `kdf`
+    /// must be the default.
+    pub headerless: bool,
+}
+
+/// Recovery keys generated by `init_blob`, one per volume. Each opens its volume like a
+/// password (see `Credential::RecoveryKey`). They are returned once and never stored in
+/// plaintext, so they must be written down at creation time.
+#[derive(Debug, Clone)]
+pub struct RecoveryKeys {
+    /// Recovery key of the standard (decoy) volume.
+    pub standard: String,
+    /// Recovery key of the hidden volume.
+    pub hidden: String,
+}
+
 // --- Internal Header Info Structs ---
-// Used temporarily when reading/writing headers
-struct StandardHeaderInfo {
-    salt: [u8; SALT_LEN],
+// Used temporarily when reading v3 headers
+struct V3HeaderInfo {
     nonce: [u8; XNONCE_LEN], // Metadata nonce
     size: u64,               // Metadata size
 }
-struct HiddenHeaderInfo {
-    salt: [u8; SALT_LEN],
-    nonce: [u8; XNONCE_LEN], // Metadata nonce
-    size: u64,               // Metadata size
+
+/// v4 per-volume superblock, stored encrypted so nothing about the volume is visible without
+/// its key. Points at the encrypted metadata block, which lives in the volume's inline metadata
+/// region when it fits and in an extent in the data area otherwise.
+/// Each volume has two superblock slots that commits alternate between; the valid slot with
+/// the highest `generation` is current.
+/// New fields may only be appended: older superblocks decode them from the zero padding.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Superblock {
+    meta_nonce: [u8; XNONCE_LEN],
+    meta_offset: u64,
+    meta_size: u64,
+    generation: u64,        // Zero for superblocks written before slots alternated
+    padding: PaddingPolicy, // None for superblocks written before blocks were padded
 }
 
 // --- Cryptographic Functions ---
 
-/// Derives a 32-byte key from a password and salt using Argon2id.
-/// Uses recommended parameters: 64 MiB memory, 3 iterations, 1 parallelism.
-fn derive_key(password: &str, salt: &[u8]) -> Result<[u8; 32]> {
-    let params =
-        Params::new(65536, 3, 1, None).map_err(|e| anyhow!("argon2 params error: {}", e))?;
-    let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
-    let mut key = [0u8; 32];
-    argon2
-        .hash_password_into(password.as_bytes(), salt, &mut key)
-        .map_err(|e| anyhow!("argon2 hash error: {}", e))?;
-    Ok(key)
-}
-
 /// Helper to get an AEAD cipher instance (XChaCha20-Poly1305).
-fn get_cipher(key: &[u8; 32]) -> XChaCha20Poly1305 {
+pub(crate) fn get_cipher(key: &[u8; 32]) -> XChaCha20Poly1305 {
     XChaCha20Poly1305::new(Key::from_slice(key))
 }
 
 // --- Low-Level Header I/O ---
 
-/// Reads the standard header section from the beginning of the file.
-fn read_standard_header(file: &mut File) -> Result<StandardHeaderInfo> {
-    file.seek(SeekFrom::Start(0))?;
-    // Verify Magic Bytes
-    let mut magic = [0u8; MAGIC.len()];
-    file.read_exact(&mut magic)?;
-    if magic != *MAGIC {
-        return Err(anyhow!("Invalid blob format"));
+/// Verifies the magic bytes and returns the format version of the blob. A headerless
+/// candidate (see `BlobKind::Headerless`) is taken to be v4; whether it really is a blob only
+/// shows when a password opens one of its keyslots.
+pub(crate) fn read_blob_version(file: &mut File) -> Result<u8> {
+    match read_blob_kind(file)? {
+        BlobKind::Versioned(version) => Ok(version),
+        BlobKind::Headerless => Ok(VERSION),
+    }
+}
+
+/// Tells a blob with a plaintext header from a file that can only be a headerless blob.
+fn read_blob_kind(file: &mut File) -> Result<BlobKind> {
+    if is_headerless(file)? {
+        return if file.metadata()?.len() >= DATA_AREA_START_OFFSET {
+            Ok(BlobKind::Headerless)
+        } else {
+            Err(BlobError::NotABlob)
+        };
     }
     // Verify Version
     let mut ver = [0u8; 1];
     file.read_exact(&mut ver)?;
-    if ver[0] != VERSION {
-        return Err(anyhow!("Unsupported blob version (requires v{})", VERSION));
+    match ver[0] {
+        VERSION_V3 | VERSION => Ok(BlobKind::Versioned(ver[0])),
+        other => Err(BlobError::UnsupportedVersion(other)),
     }
-    // Read Standard Header fields
-    let mut salt = [0u8; SALT_LEN];
-    file.read_exact(&mut salt)?;
-    let mut nonce = [0u8; XNONCE_LEN];
-    file.read_exact(&mut nonce)?;
-    let mut size_bytes = [0u8; 8];
-    file.read_exact(&mut size_bytes)?;
-    let size = u64::from_le_bytes(size_bytes);
-    Ok(StandardHeaderInfo { salt, nonce, size })
 }
 
-/// Reads the hidden header section from its fixed offset.
-fn read_hidden_header(file: &mut File) -> Result<HiddenHeaderInfo> {
-    file.seek(SeekFrom::Start(HIDDEN_HEADER_OFFSET))?;
-    // Read Hidden Header fields
-    let mut salt = [0u8; SALT_LEN];
-    file.read_exact(&mut salt)?;
+/// Returns true if the blob doesn't start with the plaintext magic, i.e. it is headerless
+/// (see `InitOptions::headerless`) or not a blob at all.
+fn is_headerless(file: &mut File) -> Result<bool> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut magic = [0u8; MAGIC.len()];
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic != *MAGIC),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads a v3 volume header's metadata nonce and size (stored after the volume's salt).
+fn read_v3_header(file: &mut File, volume_type: VolumeType) -> Result<V3HeaderInfo> {
+    file.seek(SeekFrom::Start(
+        volume_header_offset(volume_type) + SALT_LEN as u64,
+    ))?;
     let mut nonce = [0u8; XNONCE_LEN];
     file.read_exact(&mut nonce)?;
     let mut size_bytes = [0u8; 8];
     file.read_exact(&mut size_bytes)?;
     let size = u64::from_le_bytes(size_bytes);
-    Ok(HiddenHeaderInfo { salt, nonce, size })
-}
-
-/// Writes the *entire* standard header (Magic, Version, Salt, Nonce, Size). Used during init.
-fn write_full_standard_header(file: &mut File, header: &StandardHeaderInfo) -> Result<()> {
-    file.seek(SeekFrom::Start(0))?;
-    file.write_all(MAGIC)?;
-    file.write_all(&[VERSION])?;
-    file.write_all(&header.salt)?;
-    file.write_all(&header.nonce)?;
-    file.write_all(&header.size.to_le_bytes())?;
-    file.sync_data()?; // Ensure this header write is flushed
-    Ok(())
-}
-
-/// Writes the *entire* hidden header (Salt, Nonce, Size) at its fixed offset. Used during init.
-fn write_full_hidden_header(file: &mut File, header: &HiddenHeaderInfo) -> Result<()> {
-    file.seek(SeekFrom::Start(HIDDEN_HEADER_OFFSET))?;
-    file.write_all(&header.salt)?;
-    file.write_all(&header.nonce)?;
-    file.write_all(&header.size.to_le_bytes())?;
-    file.sync_data()?; // Ensure this header write is flushed
-    Ok(())
+    Ok(V3HeaderInfo { nonce, size })
 }
 
-/// Updates only the metadata nonce and size fields within an *existing* header block.
+/// Updates only the metadata nonce and size fields within an *existing* v3 header block.
 fn update_header_metadata(
     file: &mut File,
     volume_type: VolumeType,
@@ -174,6 +396,297 @@ fn update_header_metadata(
     Ok(())
 }
 
+/// Offset of a volume's header: right after Magic+Version for the standard volume, at the fixed
+/// 64 KiB offset for the hidden one. Both v3 and v4 headers start with the volume's salt.
+fn volume_header_offset(volume_type: VolumeType) -> u64 {
+    match volume_type {
+        VolumeType::Standard => HEADER_COMMON_LEN as u64,
+        VolumeType::Hidden => HIDDEN_HEADER_OFFSET,
+    }
+}
+
+/// Start of a volume's inline metadata region for the given format version.
+fn inline_metadata_offset(version: u8, volume_type: VolumeType) -> u64 {
+    match (version, volume_type) {
+        (VERSION_V3, VolumeType::Standard) => STANDARD_METADATA_OFFSET,
+        (VERSION_V3, VolumeType::Hidden) => HIDDEN_METADATA_OFFSET,
+        (_, VolumeType::Standard) => V4_STANDARD_METADATA_OFFSET,
+        (_, VolumeType::Hidden) => V4_HIDDEN_METADATA_OFFSET,
+    }
+}
+
+/// Reads the salt at the start of a v3 volume's header.
+fn read_volume_salt(file: &mut File, volume_type: VolumeType) -> Result<[u8; SALT_LEN]> {
+    file.seek(SeekFrom::Start(volume_header_offset(volume_type)))?;
+    let mut salt = [0u8; SALT_LEN];
+    file.read_exact(&mut salt)?;
+    Ok(salt)
+}
+
+/// Offset of keyslot `index` in a v4 volume header.
+fn keyslot_offset(volume_type: VolumeType, index: usize) -> u64 {
+    volume_header_offset(volume_type) + (index * KEYSLOT_LEN) as u64
+}
+
+/// Reads all keyslots of a v4 volume. Slots that don't decode (damaged, or random bytes left by
+/// an older header) are `None`; they can't be opened by any password. In a headerless blob
+/// every slot decodes.
+pub(crate) fn read_keyslots(
+    file: &mut File,
+    volume_type: VolumeType,
+) -> Result<Vec<Option<Keyslot>>> {
+    let headerless = is_headerless(file)?;
+    file.seek(SeekFrom::Start(keyslot_offset(volume_type, 0)))?;
+    let mut bytes = vec![0u8; KEYSLOTS_PER_VOLUME * KEYSLOT_LEN];
+    file.read_exact(&mut bytes)?;
+    Ok(bytes
+        .chunks_exact(KEYSLOT_LEN)
+        .map(|slot| Keyslot::from_bytes(slot.try_into().unwrap(), headerless).ok())
+        .collect())
+}
+
+/// Writes one keyslot of a v4 volume. Each slot is a single small write, so replacing one never
+/// touches the others.
+pub(crate) fn write_keyslot(
+    file: &mut File,
+    volume_type: VolumeType,
+    index: usize,
+    keyslot: &Keyslot,
+) -> Result<()> {
+    let headerless = is_headerless(file)?;
+    put_keyslot(file, volume_type, index, keyslot, headerless)
+}
+
+/// Writes one keyslot in the given format. A headerless slot can't record KDF parameters, so
+/// it must use the defaults.
+fn put_keyslot(
+    file: &mut File,
+    volume_type: VolumeType,
+    index: usize,
+    keyslot: &Keyslot,
+    headerless: bool,
+) -> Result<()> {
+    if headerless && *keyslot.kdf() != KdfParams::default() {
+        return Err(BlobError::invalid(
+            "Headerless blobs only support the default KDF parameters",
+        ));
+    }
+    file.seek(SeekFrom::Start(keyslot_offset(volume_type, index)))?;
+    file.write_all(&keyslot.to_bytes(headerless))?;
+    Ok(())
+}
+
+/// Writes a fresh keyslot array for a v4 volume: `keyslots` first and decoys with the same
+/// KDF parameters in the rest, so every volume header looks alike however many slots are used.
+/// The format is passed in because the magic may not have been written yet.
+fn write_initial_keyslots(
+    file: &mut File,
+    volume_type: VolumeType,
+    keyslots: &[Keyslot],
+    headerless: bool,
+) -> Result<()> {
+    let kdf = *keyslots[0].kdf();
+    for index in 0..KEYSLOTS_PER_VOLUME {
+        match keyslots.get(index) {
+            Some(keyslot) => put_keyslot(file, volume_type, index, keyslot, headerless)?,
+            None => put_keyslot(file, volume_type, index, &Keyslot::decoy(&kdf), headerless)?,
+        }
+    }
+    Ok(())
+}
+
+/// Finds the keyslot of a v4 volume that `credential` opens.
+///
+/// # Returns
+/// The slot index, the slot and the unwrapped data key, or `None` if no slot matches.
+fn open_keyslots(
+    file: &mut File,
+    volume_type: VolumeType,
+    credential: &Credential,
+) -> Result<Option<(usize, Keyslot, [u8; 32])>> {
+    for (index, keyslot) in read_keyslots(file, volume_type)?.into_iter().enumerate() {
+        if let Some(keyslot) = keyslot {
+            if let Ok(data_key) = keyslot.open(credential) {
+                return Ok(Some((index, keyslot, data_key)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Copies a v4 volume's keyslot array verbatim from one blob to another.
+fn copy_keyslots(from: &mut File, to: &mut File, volume_type: VolumeType) -> Result<()> {
+    let mut bytes = vec![0u8; KEYSLOTS_PER_VOLUME * KEYSLOT_LEN];
+    from.seek(SeekFrom::Start(keyslot_offset(volume_type, 0)))?;
+    from.read_exact(&mut bytes)?;
+    to.seek(SeekFrom::Start(keyslot_offset(volume_type, 0)))?;
+    to.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Returns true if `credential` opens any keyslot of either volume. Two slots sharing a
+/// credential would make all but the first unreachable. `key` tells which slots of the
+/// `volume_type` volume are its own, so only those are tried there; every readable slot of the
+/// other volume costs a key derivation.
+pub(crate) fn credential_in_use(
+    file: &mut File,
+    credential: &Credential,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<bool> {
+    for other in [VolumeType::Standard, VolumeType::Hidden] {
+        for keyslot in read_keyslots(file, other)?.into_iter().flatten() {
+            if other == volume_type && !keyslot.wraps(key) {
+                continue;
+            }
+            if keyslot.open(credential).is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Reads the KDF parameters of a volume. v3 headers don't record them: those volumes
+/// always used the defaults. For v4 these are the parameters of the first readable slot.
+pub(crate) fn read_volume_kdf(
+    file: &mut File,
+    version: u8,
+    volume_type: VolumeType,
+) -> Result<KdfParams> {
+    if version == VERSION_V3 {
+        return Ok(KdfParams::default());
+    }
+    read_keyslots(file, volume_type)?
+        .into_iter()
+        .flatten()
+        .map(|keyslot| *keyslot.kdf())
+        .next()
+        .ok_or_else(|| BlobError::corrupt("volume header has no readable keyslot"))
+}
+
+/// Offset of one of a v4 volume's superblock slots. Slot 0 follows the keyslots in the volume
+/// header; slot 1 takes the last bytes of the volume's inline metadata region.
+fn superblock_offset(volume_type: VolumeType, slot: usize) -> u64 {
+    match slot {
+        0 => keyslot_offset(volume_type, KEYSLOTS_PER_VOLUME),
+        _ => {
+            let region = inline_metadata_offset(VERSION, volume_type);
+            region + metadata_capacity(region) - SUPERBLOCK_SLOT_LEN
+        }
+    }
+}
+
+/// The half of a v4 volume's inline metadata region that belongs to a superblock slot, as
+/// (offset, capacity). A commit never writes into the half the current superblock uses.
+fn metadata_half(volume_type: VolumeType, slot: usize) -> (u64, u64) {
+    let region = inline_metadata_offset(VERSION, volume_type);
+    let usable = metadata_capacity(region) - SUPERBLOCK_SLOT_LEN;
+    match slot {
+        0 => (region, usable / 2),
+        _ => (region + usable / 2, usable - usable / 2),
+    }
+}
+
+/// Reads and decrypts one superblock slot of a v4 volume.
+fn read_superblock(
+    file: &mut File,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    slot: usize,
+) -> Result<Superblock> {
+    file.seek(SeekFrom::Start(superblock_offset(volume_type, slot)))?;
+    let mut nonce = [0u8; XNONCE_LEN];
+    file.read_exact(&mut nonce)?;
+    let mut ciphertext = vec![0u8; SUPERBLOCK_LEN + TAG_LEN];
+    file.read_exact(&mut ciphertext)?;
+    let plaintext = get_cipher(key)
+        .decrypt(XNonce::from_slice(&nonce), ciphertext.as_ref())
+        .map_err(|_| BlobError::corrupt("superblock decryption failed"))?;
+    bincode::deserialize(&plaintext).map_err(|e| BlobError::corrupt(format!("superblock: {}", e)))
+}
+
+/// Reads both superblock slots of a v4 volume and returns the ones that authenticate as
+/// `(slot, superblock)`, newest generation first. A slot that was torn by a crash, or never
+/// written, just doesn't authenticate.
+fn read_superblocks(
+    file: &mut File,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<Vec<(usize, Superblock)>> {
+    let mut valid = Vec::with_capacity(SUPERBLOCK_SLOTS);
+    for slot in 0..SUPERBLOCK_SLOTS {
+        match read_superblock(file, volume_type, key, slot) {
+            Ok(superblock) => valid.push((slot, superblock)),
+            Err(BlobError::Io(e)) => return Err(BlobError::Io(e)),
+            Err(e) => info!("{:?} superblock slot {} skipped: {}", volume_type, slot, e),
+        }
+    }
+    valid.sort_by_key(|(_, superblock)| std::cmp::Reverse(superblock.generation));
+    Ok(valid)
+}
+
+/// Encrypts a v4 superblock under a fresh nonce and writes it into one of the volume's
+/// superblock slots.
+fn write_superblock(
+    file: &mut File,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    slot: usize,
+    superblock: &Superblock,
+) -> Result<()> {
+    let mut plaintext =
+        bincode::serialize(superblock).map_err(|_| BlobError::Crypto("superblock encoding"))?;
+    if plaintext.len() > SUPERBLOCK_LEN {
+        return Err(BlobError::Crypto("superblock encoding"));
+    }
+    plaintext.resize(SUPERBLOCK_LEN, 0); // Fixed size, so the header layout never changes
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = get_cipher(key)
+        .encrypt(&nonce, plaintext.as_ref())
+        .map_err(|_| BlobError::Crypto("superblock encryption"))?;
+    file.seek(SeekFrom::Start(superblock_offset(volume_type, slot)))?;
+    file.write_all(nonce.as_slice())?;
+    file.write_all(&ciphertext)?;
+    Ok(())
+}
+
+// --- Metadata Serialization ---
+
+/// Serializes the metadata map and the volume's free space behind the versioned
+/// `METADATA_MAGIC` prefix.
+fn serialize_metadata(map: &MetadataMap, free: &FreeSpace) -> Result<Vec<u8>> {
+    let mut plaintext = Vec::from(METADATA_MAGIC);
+    plaintext.extend_from_slice(&METADATA_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut plaintext, &(map, free))
+        .map_err(|_| BlobError::Crypto("metadata encoding"))?;
+    Ok(plaintext)
+}
+
+/// Deserializes a decrypted metadata block, accepting both versioned and legacy v3 metadata.
+/// Legacy metadata has no free space.
+fn deserialize_metadata(plaintext: &[u8]) -> Result<(MetadataMap, FreeSpace)> {
+    let map_error = |e: bincode::Error| BlobError::corrupt(format!("metadata: {}", e));
+    let Some(rest) = plaintext.strip_prefix(METADATA_MAGIC) else {
+        // Legacy v3 metadata: a bare bincode map without the DataFormat field
+        let legacy: HashMap<String, LegacyFileMetadata> =
+            bincode::deserialize(plaintext).map_err(map_error)?;
+        let map = legacy.into_iter().map(|(k, v)| (k, v.into())).collect();
+        return Ok((map, FreeSpace::default()));
+    };
+    if rest.len() < 4 {
+        return Err(BlobError::corrupt("truncated metadata header"));
+    }
+    match u32::from_le_bytes(rest[..4].try_into().unwrap()) {
+        // Trailing bytes are padding (see `write_volume_metadata`)
+        METADATA_VERSION => bincode::deserialize(&rest[4..]).map_err(map_error),
+        version => Err(BlobError::corrupt(format!(
+            "unsupported metadata version {}",
+            version
+        ))),
+    }
+}
+
 // --- Low-Level Metadata Block I/O ---
 
 /// Reads and decrypts the metadata block for a given volume.
@@ -183,7 +696,7 @@ fn read_metadata_block(
     nonce: &[u8; XNONCE_LEN],
     size: u64,
     offset: u64,
-) -> Result<MetadataMap> {
+) -> Result<(MetadataMap, FreeSpace)> {
     // Added Logging
     info!(
         "Attempting read_metadata_block: Offset={}, Size={}, Nonce starts with: {:x?}",
@@ -195,7 +708,7 @@ fn read_metadata_block(
     // Handle case where volume might be empty (size 0) during initial load
     if size == 0 {
         info!("Metadata size is 0, returning empty map.");
-        return Ok(MetadataMap::new());
+        return Ok((MetadataMap::new(), FreeSpace::default()));
     }
     // Basic sanity check for size
     if size > 50 * 1024 * 1024 {
@@ -204,12 +717,12 @@ fn read_metadata_block(
             "Metadata block size {} at offset {} is excessively large. Aborting read.",
             size, offset
         );
-        return Err(anyhow!("metadata block size too large"));
+        return Err(BlobError::corrupt("metadata block size too large"));
     }
 
     if let Err(e) = file.seek(SeekFrom::Start(offset)) {
         error!("Failed to seek to metadata offset {}: {}", offset, e);
-        return Err(anyhow!("seek to metadata offset failed: {}", e));
+        return Err(e.into());
     }
     info!("Seeked to offset {} successfully.", offset);
 
@@ -220,7 +733,7 @@ fn read_metadata_block(
             size, offset, e
         );
         // Consider logging file size here: let file_len = file.seek(SeekFrom::End(0)).unwrap_or(0); info!("File size: {}", file_len);
-        return Err(anyhow!("read metadata failed: {}", e));
+        return Err(e.into());
     }
     info!(
         "Read {} encrypted bytes successfully for metadata.",
@@ -239,7 +752,7 @@ fn read_metadata_block(
                 plaintext.len()
             );
             // Deserialize
-            match bincode::deserialize(&plaintext) {
+            match deserialize_metadata(&plaintext) {
                 Ok(map) => {
                     info!("Bincode deserialization successful for offset {}.", offset);
                     Ok(map)
@@ -249,7 +762,7 @@ fn read_metadata_block(
                         "Bincode deserialization FAILED for offset {}: {}",
                         offset, e
                     );
-                    Err(anyhow!("metadata deserialization failed: {}", e))
+                    Err(e)
                 }
             }
         }
@@ -259,27 +772,44 @@ fn read_metadata_block(
                 "AEAD decryption FAILED for offset {}. AEAD Error: {:?}",
                 offset, aead_err
             );
-            Err(anyhow!("metadata decryption failed")) // Keep generic API error
+            Err(BlobError::WrongPassword) // Keep generic API error
         }
     }
 }
 
+/// Size of the fixed region reserved for the metadata block starting at `offset`.
+/// Standard metadata must end before the hidden header; hidden metadata before the data area.
+fn metadata_capacity(offset: u64) -> u64 {
+    if offset < HIDDEN_HEADER_OFFSET {
+        HIDDEN_HEADER_OFFSET - offset
+    } else {
+        DATA_AREA_START_OFFSET - offset
+    }
+}
+
 /// Encrypts and writes the metadata map to the specified offset. Returns the new (nonce, size).
+///
+/// # Errors
+/// `MetadataTooLarge`, without writing anything, if the encrypted block doesn't fit in the
+/// volume's metadata region. Writing it anyway would overwrite the hidden header (standard
+/// volume) or the start of the data area (hidden volume).
 fn write_metadata_block(
     file: &mut File,
     key: &[u8; 32],
     map: &MetadataMap,
+    free: &FreeSpace,
     offset: u64,
 ) -> Result<([u8; XNONCE_LEN], u64)> {
-    // Serialize the map using bincode
-    let plaintext = bincode::serialize(map)?;
-
-    // Encrypt the serialized data
-    let cipher = get_cipher(key);
-    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng); // Generate a fresh random nonce
-    let ciphertext = cipher
-        .encrypt(&nonce, plaintext.as_ref())
-        .map_err(|e| anyhow!("metadata encryption failed: {}", e))?;
+    let (nonce, ciphertext) = encrypt_metadata(key, &serialize_metadata(map, free)?)?;
+
+    // Refuse to spill into the neighbouring region
+    let capacity = metadata_capacity(offset);
+    if ciphertext.len() as u64 > capacity {
+        return Err(BlobError::MetadataTooLarge {
+            size: ciphertext.len() as u64,
+            capacity,
+        });
+    }
 
     // Write the encrypted block to the specified offset
     file.seek(SeekFrom::Start(offset))?;
@@ -289,77 +819,458 @@ fn write_metadata_block(
     file.sync_data()?; // Ensure metadata block write is flushed to disk
 
     // Return the nonce used and the size of the ciphertext written
+    Ok((nonce, ciphertext.len() as u64))
+}
+
+/// Encrypts serialized metadata under a fresh nonce. Returns (nonce, ciphertext).
+fn encrypt_metadata(key: &[u8; 32], plaintext: &[u8]) -> Result<([u8; XNONCE_LEN], Vec<u8>)> {
+    let cipher = get_cipher(key);
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng); // Generate a fresh random nonce
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| BlobError::Crypto("metadata encryption"))?;
     let mut nonce_bytes = [0u8; XNONCE_LEN];
     nonce_bytes.copy_from_slice(nonce.as_slice());
-    Ok((nonce_bytes, ciphertext.len() as u64))
+    Ok((nonce_bytes, ciphertext))
 }
 
-// --- Low-Level File Data Block I/O ---
-
-/// Encrypts content and appends it (with its nonce) to the end of the data area.
-/// Returns metadata describing the location and size of the written block.
-fn append_file_data(
+/// Writes a v4 volume's metadata and points a superblock at it, crash-safely: the new state
+/// goes to the superblock slot and metadata half the current superblock doesn't use, with a
+/// higher generation, and the superblock is only written once the metadata block is on disk.
+/// Until that last write completes the current superblock and its metadata stay untouched, so
+/// a crash at any point leaves either the old or the new state.
+///
+/// The block goes into the slot's half of the inline region when it fits; otherwise it spills
+/// into the data area, into free space if there is a large enough hole and appended
+/// otherwise (never in a preallocated blob). A spilled block the slot pointed to before is
+/// no longer needed by either generation afterwards, so it becomes free space.
+///
+/// The block is padded to the size bucket of the volume's `PaddingPolicy`, which `padding`
+/// sets and the current superblock records otherwise. An inline block is never padded past
+/// its half of the region, which is no less uniform.
+///
+/// # Returns
+/// The free space as committed: `free`, less the hole the block went into, plus the block
+/// it replaced.
+///
+/// # Errors
+/// `HiddenVolumeProtected` if the block would land on one of the `protected` extents, or
+/// `BlobFull` if it spills and the preallocated blob has no room for it.
+fn write_volume_metadata(
     file: &mut File,
+    volume_type: VolumeType,
     key: &[u8; 32],
-    content: &[u8],
-    mime_type: &str,
-) -> Result<FileMetadata> {
-    // Seek to the current end of the file
-    let mut current_offset = file.seek(SeekFrom::End(0))?;
-
-    // Pad with random data if the file end is before the designated data start area
-    // This ensures headers/metadata aren't overwritten and data starts at a known point.
-    if current_offset < DATA_AREA_START_OFFSET {
-        let padding_size = (DATA_AREA_START_OFFSET - current_offset) as usize;
-        let mut padding = vec![0u8; padding_size];
-        OsRng.fill_bytes(&mut padding); // Use cryptographically secure random padding
-        file.write_all(&padding)?;
-        current_offset = DATA_AREA_START_OFFSET; // Update offset to the actual start of data area
+    map: &MetadataMap,
+    mut free: FreeSpace,
+    protected: &Protected,
+    padding: Option<PaddingPolicy>,
+) -> Result<FreeSpace> {
+    let superblocks = read_superblocks(file, volume_type, key)?;
+    let current = superblocks.first().cloned();
+    let (slot, generation) = match &current {
+        Some((slot, superblock)) => ((slot + 1) % SUPERBLOCK_SLOTS, superblock.generation + 1),
+        None => (0, 0), // Fresh header
+    };
+    let padding = padding
+        .or(current.as_ref().map(|(_, superblock)| superblock.padding))
+        .unwrap_or_default();
+    if let Some((_, replaced)) = superblocks.iter().find(|(s, _)| *s == slot) {
+        if current
+            .as_ref()
+            .is_some_and(|(_, current)| current.meta_offset != replaced.meta_offset)
+        {
+            free.release(replaced.meta_offset, replaced.meta_size);
+        }
     }
 
-    let data_offset = current_offset; // This is where the Nonce + Ciphertext block will start
+    let (half_offset, half_capacity) = metadata_half(volume_type, slot);
+    let mut plaintext = serialize_metadata(map, &free)?;
+    let sealed_len = (plaintext.len() + TAG_LEN) as u64;
+    let mut padded_len = padding.padded_len(sealed_len);
+    if sealed_len <= half_capacity {
+        padded_len = padded_len.min(half_capacity);
+    }
+    plaintext.resize((padded_len - TAG_LEN as u64) as usize, 0);
+    let (mut meta_nonce, mut ciphertext) = encrypt_metadata(key, &plaintext)?;
+    let meta_size = ciphertext.len() as u64;
+    // Blocks written before the region was split may reach into the other half
+    let overlaps_current = current.as_ref().is_some_and(|(_, superblock)| {
+        half_offset < superblock.meta_offset + superblock.meta_size
+            && superblock.meta_offset < half_offset + meta_size
+    });
+    let mut spilled = free.clone();
+    let meta_offset = if meta_size <= half_capacity && !overlaps_current {
+        half_offset
+    } else if let Some(hole) = spilled.allocate(meta_size, 1) {
+        // Taking the front of a hole never lengthens the free list, so padding the new
+        // encoding to the old length keeps the block exactly as large as the hole taken
+        let len = plaintext.len();
+        plaintext = serialize_metadata(map, &spilled)?;
+        plaintext.resize(len, 0);
+        (meta_nonce, ciphertext) = encrypt_metadata(key, &plaintext)?;
+        free = spilled;
+        hole
+    } else if free.is_preallocated() {
+        return Err(BlobError::BlobFull);
+    } else {
+        let mut end = file.seek(SeekFrom::End(0))?;
+        if let Some(padding) = data_area_padding(end) {
+            file.write_all(&padding)?;
+            end = DATA_AREA_START_OFFSET;
+        }
+        end
+    };
+    protected.check(meta_offset, meta_size)?;
+    file.seek(SeekFrom::Start(meta_offset))?;
+    file.write_all(&ciphertext)?;
+    file.sync_data()?; // Metadata must be durable before the superblock points at it
+
+    write_superblock(
+        file,
+        volume_type,
+        key,
+        slot,
+        &Superblock {
+            meta_nonce,
+            meta_offset,
+            meta_size,
+            generation,
+            padding,
+        },
+    )?;
+    file.sync_data()?;
+    Ok(free)
+}
 
-    // Encrypt the file content
-    let cipher = get_cipher(key);
-    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng); // Fresh random nonce for this file block
-    let ciphertext = cipher
-        .encrypt(&nonce, content)
-        .map_err(|e| anyhow!("file data encryption failed: {}", e))?;
+/// A volume's metadata as found by `check_volume_metadata`.
+pub(crate) struct MetadataCheck {
+    pub(crate) metadata: MetadataMap,
+    pub(crate) free: FreeSpace,
+    /// Offset and size of the metadata block in use.
+    pub(crate) block: (u64, u64),
+    /// Damage that was worked around by falling back to the other superblock slot.
+    pub(crate) warnings: Vec<String>,
+}
 
-    // Write the Nonce first, then the Ciphertext
-    file.write_all(nonce.as_slice())?;
-    file.write_all(&ciphertext)?;
-    file.sync_data()?; // Ensure file data block write is flushed to disk
-
-    // Create metadata describing the block just written
-    Ok(FileMetadata {
-        size: content.len() as u64, // Original content size
-        data_offset,                // Starting offset of Nonce+Ciphertext
-        data_length: (XNONCE_LEN as u64) + (ciphertext.len() as u64), // Total length (Nonce + CT)
-        mime_type: mime_type.to_string(),
-    })
+/// Reads and decrypts a volume's metadata map, for either format version, noting what was
+/// damaged along the way. For v4 this is the newest superblock whose metadata authenticates,
+/// falling back to the previous one.
+pub(crate) fn check_volume_metadata(
+    file: &mut File,
+    version: u8,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<MetadataCheck> {
+    if version == VERSION_V3 {
+        let header = read_v3_header(file, volume_type)?;
+        let offset = inline_metadata_offset(version, volume_type);
+        let (metadata, free) = read_metadata_block(file, key, &header.nonce, header.size, offset)?;
+        return Ok(MetadataCheck {
+            metadata,
+            free,
+            block: (offset, header.size),
+            warnings: Vec::new(),
+        });
+    }
+    let superblocks = read_superblocks(file, volume_type, key)?;
+    let mut warnings = Vec::new();
+    // Both slots have been written once a volume has committed past its first generation
+    if superblocks.len() < SUPERBLOCK_SLOTS
+        && superblocks
+            .first()
+            .is_some_and(|(_, newest)| newest.generation > 0)
+    {
+        warnings.push("one superblock slot doesn't authenticate".to_string());
+    }
+    let mut last_error = BlobError::corrupt("no valid superblock");
+    for (slot, superblock) in superblocks {
+        match read_metadata_block(
+            file,
+            key,
+            &superblock.meta_nonce,
+            superblock.meta_size,
+            superblock.meta_offset,
+        ) {
+            Ok((metadata, free)) => {
+                return Ok(MetadataCheck {
+                    metadata,
+                    free,
+                    block: (superblock.meta_offset, superblock.meta_size),
+                    warnings,
+                })
+            }
+            Err(e) => {
+                warn!(
+                    "{:?} metadata of superblock slot {} (generation {}) unreadable: {}",
+                    volume_type, slot, superblock.generation, e
+                );
+                warnings.push(format!(
+                    "metadata generation {} is unreadable: {}",
+                    superblock.generation, e
+                ));
+                last_error = match e {
+                    // The key came from a keyslot, so it is right and the block is damaged
+                    BlobError::WrongPassword => BlobError::corrupt("metadata decryption failed"),
+                    e => e,
+                };
+            }
+        }
+    }
+    Err(last_error)
 }
 
-/// Reads and decrypts a file's data block given its metadata.
-fn read_file_data(file: &mut File, key: &[u8; 32], metadata: &FileMetadata) -> Result<Vec<u8>> {
-    // Seek to the start of the data block (where the nonce is)
-    file.seek(SeekFrom::Start(metadata.data_offset))?;
+/// Reads and decrypts a volume's metadata map, for either format version.
+fn read_volume_metadata(
+    file: &mut File,
+    version: u8,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<MetadataMap> {
+    Ok(check_volume_metadata(file, version, volume_type, key)?.metadata)
+}
 
-    // Read the Nonce (which is stored prepended to the ciphertext)
-    let mut nonce_bytes = [0u8; XNONCE_LEN];
-    file.read_exact(&mut nonce_bytes)?;
+// --- Low-Level File Data Block I/O ---
 
-    // Read the Ciphertext that follows the nonce
-    let ciphertext_len = metadata.data_length - XNONCE_LEN as u64;
-    let mut ciphertext = vec![0u8; ciphertext_len as usize];
-    file.read_exact(&mut ciphertext)?;
+/// Current time in seconds since the Unix epoch, for `FileMetadata` timestamps.
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Returns the random padding needed to extend a file ending at `current_offset` up to
+/// `DATA_AREA_START_OFFSET`, so headers/metadata aren't overwritten and data starts at a known
+/// point.
+fn data_area_padding(current_offset: u64) -> Option<Vec<u8>> {
+    if current_offset >= DATA_AREA_START_OFFSET {
+        return None;
+    }
+    let mut padding = vec![0u8; (DATA_AREA_START_OFFSET - current_offset) as usize];
+    OsRng.fill_bytes(&mut padding); // Use cryptographically secure random padding
+    Some(padding)
+}
+
+/// Splits everything `reader` yields into content-defined chunks and writes the ones the
+/// volume doesn't store yet (per `chunks`) to the data area, followed by the file's chunk
+/// manifest. Blocks fill the free space `chunks` holds before they are appended. Only
+/// `MAX_CHUNK_SIZE` bytes of plaintext are held in memory at a time.
+/// Returns metadata listing the file's chunks; everything is synced to disk before returning.
+/// New chunks are added to `chunks`, so later writes can share them before the metadata is
+/// committed. New chunks are compressed before encryption if `compression` asks for it.
+pub(crate) fn append_file_data<R: Read>(
+    file: &mut File,
+    key: &[u8; 32],
+    chunks: &mut ChunkIndex,
+    file_path: &str,
+    reader: &mut R,
+    mime_type: &str,
+    compression: Compression,
+) -> Result<FileMetadata> {
+    let end = file.seek(SeekFrom::End(0))?;
+    let mut writer = DedupWriter::new(key, chunks, end, compression);
+
+    // Keep a full window buffered so each boundary is found the same way wherever the content
+    // sits in the stream; a short buffer after topping up means the reader is exhausted
+    let mut pending = Vec::with_capacity(MAX_CHUNK_SIZE);
+    loop {
+        let missing = MAX_CHUNK_SIZE - pending.len();
+        reader.take(missing as u64).read_to_end(&mut pending)?;
+        if pending.is_empty() {
+            break;
+        }
+        let len = cut_point(&pending);
+        if let Some((offset, sealed)) = writer.seal(&pending[..len])? {
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&sealed)?;
+            writer.written();
+        }
+        pending.drain(..len);
+    }
+
+    let (offset, manifest, padding, file_metadata) = writer.finish(key, file_path, mime_type)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&manifest)?;
+    for piece in random_padding(padding) {
+        file.write_all(&piece)?;
+    }
+    file.sync_data()?; // Ensure the chunks and manifest are flushed to disk
+    Ok(file_metadata)
+}
+
+/// Reads the free space a volume last committed, less any past the end of the blob file
+/// (compaction cuts it off before committing) and plus any `grow_blob` added.
+pub(crate) fn read_free_space(
+    file: &mut File,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<FreeSpace> {
+    let version = read_blob_version(file)?;
+    let mut free = check_volume_metadata(file, version, volume_type, key)?.free;
+    free.fit_to(file.seek(SeekFrom::End(0))?);
+    Ok(free)
+}
+
+/// Reads the `PaddingPolicy` the volume's newest superblock records. v3 volumes don't pad.
+pub(crate) fn read_padding_policy(
+    file: &mut File,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<PaddingPolicy> {
+    if read_blob_version(file)? == VERSION_V3 {
+        return Ok(PaddingPolicy::None);
+    }
+    Ok(read_superblocks(file, volume_type, key)?
+        .first()
+        .map(|(_, superblock)| superblock.padding)
+        .unwrap_or_default())
+}
+
+/// Offsets and sizes of the volume's metadata blocks that spilled into the data area, for
+/// both superblock slots. v3 volumes keep their metadata inline.
+pub(crate) fn spilled_metadata_blocks(
+    file: &mut File,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<Vec<(u64, u64)>> {
+    if read_blob_version(file)? == VERSION_V3 {
+        return Ok(Vec::new());
+    }
+    Ok(read_superblocks(file, volume_type, key)?
+        .into_iter()
+        .map(|(_, superblock)| (superblock.meta_offset, superblock.meta_size))
+        .filter(|&(offset, _)| offset >= DATA_AREA_START_OFFSET)
+        .collect())
+}
+
+/// Collects every extent of the blob a volume owns: its header and inline metadata region,
+/// its spilled metadata blocks, the blocks its files reference and its free space. Space
+/// the volume wrote but never committed isn't known to be its own and isn't included.
+///
+/// # Errors
+/// Returns an error if the volume's metadata can't be read; nothing can be protected then.
+pub(crate) fn volume_extents(
+    file: &mut File,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<Protected> {
+    let version = read_blob_version(file)?;
+    let mut extents = Protected::default();
+    match volume_type {
+        VolumeType::Standard => extents.insert(0, HIDDEN_HEADER_OFFSET),
+        VolumeType::Hidden => extents.insert(
+            HIDDEN_HEADER_OFFSET,
+            DATA_AREA_START_OFFSET - HIDDEN_HEADER_OFFSET,
+        ),
+    }
+    if version != VERSION_V3 {
+        for (_, superblock) in read_superblocks(file, volume_type, key)? {
+            extents.insert(superblock.meta_offset, superblock.meta_size);
+        }
+    }
+    let check = check_volume_metadata(file, version, volume_type, key)?;
+    for (offset, len) in referenced_extents(&check.metadata)
+        .into_iter()
+        .chain(check.free.extents())
+    {
+        extents.insert(offset, len);
+    }
+    Ok(extents)
+}
+
+/// Encrypts the updated metadata map, writes it to the volume's metadata block, points the
+/// volume header at it and flushes everything to disk. Space the committed metadata
+/// referenced and `metadata_map` doesn't is recorded as free (see `FreeSpace`); if the
+/// committed metadata can't be read, nothing is and the space stays unused until compaction.
+/// A metadata block that doesn't fit inline is never written onto the `protected` extents.
+///
+/// # Returns
+/// The free space as committed.
+pub(crate) fn commit_metadata(
+    path: &Path,
+    file: &mut File,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    metadata_map: &MetadataMap,
+    protected: &Protected,
+) -> Result<FreeSpace> {
+    let version = read_blob_version(file)?;
+    let mut free = match check_volume_metadata(file, version, volume_type, key) {
+        Ok(committed) => committed.free.next(&committed.metadata, metadata_map),
+        Err(BlobError::Io(e)) => return Err(BlobError::Io(e)),
+        Err(e) => {
+            warn!("Committed {:?} metadata unreadable: {}", volume_type, e);
+            FreeSpace::default()
+        }
+    };
+    free.fit_to(file.seek(SeekFrom::End(0))?);
+    let free = if version == VERSION_V3 {
+        let metadata_offset = inline_metadata_offset(version, volume_type);
+        let (new_nonce, new_size) =
+            write_metadata_block(file, key, metadata_map, &free, metadata_offset)?;
+        update_header_metadata(file, volume_type, &new_nonce, new_size)?;
+        free
+    } else {
+        write_volume_metadata(file, volume_type, key, metadata_map, free, protected, None)?
+    };
+
+    // Ensure changes are flushed - with enhanced iOS handling
+    file.sync_data()?; // Sync after metadata and header updates
+
+    // Additional iOS-specific file handle management
+    #[cfg(target_os = "ios")]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        // Flush through the caller's handle, then reopen to ensure iOS commits changes
+        file.sync_all()?;
+
+        // Verify the file size has actually changed on disk
+        if let Ok(metadata) = std::fs::metadata(path) {
+            info!(
+                "iOS file verification: blob size on disk is {} bytes",
+                metadata.size()
+            );
+        }
+
+        // Reopen and sync one more time for iOS
+        let verify_file = OpenOptions::new().read(true).write(true).open(path)?;
+        verify_file.sync_all()?;
+    }
+
+    #[cfg(not(target_os = "ios"))]
+    {
+        // Standard platform: just ensure sync_all is called
+        let _ = path;
+        file.sync_all()?;
+    }
+
+    Ok(free)
+}
+
+/// Reads and decrypts a legacy single-message (`DataFormat::Single`) data block.
+pub(crate) fn read_file_data(
+    file: &mut File,
+    key: &[u8; 32],
+    metadata: &FileMetadata,
+) -> Result<Vec<u8>> {
+    // Seek to the start of the data block (where the nonce is)
+    file.seek(SeekFrom::Start(metadata.data_offset))?;
+
+    // Read the Nonce (which is stored prepended to the ciphertext)
+    let mut nonce_bytes = [0u8; XNONCE_LEN];
+    file.read_exact(&mut nonce_bytes)?;
+
+    // Read the Ciphertext that follows the nonce
+    let ciphertext_len = metadata.data_length - XNONCE_LEN as u64;
+    let mut ciphertext = vec![0u8; ciphertext_len as usize];
+    file.read_exact(&mut ciphertext)?;
 
     // Decrypt using the key and the nonce read from the file
     let cipher = get_cipher(key);
     let nonce = XNonce::from_slice(&nonce_bytes);
     cipher
         .decrypt(nonce, ciphertext.as_ref())
-        .map_err(|e| anyhow!("file data decryption failed: {}", e))
+        .map_err(|_| BlobError::CorruptBlock { path: None })
 }
 
 // --- Public High-Level API Functions ---
@@ -373,80 +1284,256 @@ fn read_file_data(file: &mut File, key: &[u8; 32], metadata: &FileMetadata) -> R
 /// * `password_s` - Password for the standard (decoy) volume.
 /// * `password_h` - Password for the hidden volume.
 ///
+/// # Returns
+/// A recovery key for each volume, stored in the volume's second keyslot.
+///
 /// # Errors
 /// Returns an error if passwords are the same, or if file I/O or crypto operations fail.
-pub fn init_blob(path: &Path, password_s: &str, password_h: &str) -> Result<()> {
+pub fn init_blob(path: &Path, password_s: &str, password_h: &str) -> Result<RecoveryKeys> {
+    init_blob_with_options(path, password_s, password_h, &InitOptions::default())
+}
+
+/// Initializes a new blob file like `init_blob`, with explicit options.
+///
+/// # Arguments
+/// * `path` - Path where the new blob file will be created.
+/// * `password_s` - Password for the standard (decoy) volume.
+/// * `password_h` - Password for the hidden volume.
+/// * `options` - KDF parameters (see `KdfParams::calibrate`) and other settings.
+///
+/// # Returns
+/// A recovery key for each volume, as for `init_blob`.
+///
+/// # Errors
+/// Returns an error if passwords are the same, the options are invalid (including a
+/// capacity too small for the header region), or if file I/O or crypto operations fail.
+pub fn init_blob_with_options(
+    path: &Path,
+    password_s: &str,
+    password_h: &str,
+    options: &InitOptions,
+) -> Result<RecoveryKeys> {
     if password_s == password_h {
-        return Err(anyhow!("Standard and hidden passwords must be different"));
+        return Err(BlobError::invalid(
+            "Standard and hidden passwords must be different",
+        ));
     }
-
-    // 1. Generate distinct salts
-    let mut salt_s = [0u8; SALT_LEN];
-    OsRng.fill_bytes(&mut salt_s);
-    let mut salt_h = [0u8; SALT_LEN];
-    loop {
-        OsRng.fill_bytes(&mut salt_h);
-        if salt_h != salt_s {
-            break;
+    options.kdf.validate()?;
+    if options.headerless && options.kdf != KdfParams::default() {
+        return Err(BlobError::invalid(
+            "Headerless blobs only support the default KDF parameters",
+        ));
+    }
+    if options
+        .capacity
+        .is_some_and(|capacity| capacity < DATA_AREA_START_OFFSET)
+    {
+        return Err(BlobError::invalid(format!(
+            "Capacity must be at least {} bytes",
+            DATA_AREA_START_OFFSET
+        )));
+    }
+    match (options.capacity, options.hidden_capacity) {
+        (None, Some(_)) => {
+            return Err(BlobError::invalid(
+                "A hidden capacity needs a capacity for the blob",
+            ))
         }
-    } // Ensure salts differ
-
-    // 2. Derive keys
-    let key_s = derive_key(password_s, &salt_s)?;
-    let key_h = derive_key(password_h, &salt_h)?;
-
-    // 3. Create empty metadata maps
-    let metadata_s = MetadataMap::new();
-    let metadata_h = MetadataMap::new();
-
-    // 4. Create file (overwrite if exists)
-    let mut file = File::create(path)?;
+        (Some(capacity), Some(hidden)) if hidden > capacity - DATA_AREA_START_OFFSET => {
+            return Err(BlobError::invalid(format!(
+                "The hidden volume can get at most {} bytes",
+                capacity - DATA_AREA_START_OFFSET
+            )))
+        }
+        _ => {}
+    }
 
-    // 5. Write initial empty metadata blocks to get nonces/sizes for headers
-    let (meta_nonce_s, meta_size_s) =
-        write_metadata_block(&mut file, &key_s, &metadata_s, STANDARD_METADATA_OFFSET)?;
-    let (meta_nonce_h, meta_size_h) =
-        write_metadata_block(&mut file, &key_h, &metadata_h, HIDDEN_METADATA_OFFSET)?;
+    // 1. Generate random data keys and wrap each with its volume's password and a new
+    //    recovery key
+    let key_s = generate_data_key();
+    let key_h = generate_data_key();
+    let recovery_keys = RecoveryKeys {
+        standard: generate_recovery_key(),
+        hidden: generate_recovery_key(),
+    };
+    let keyslots_s = [
+        Keyslot::seal(&Credential::Password(password_s), &key_s, &options.kdf)?,
+        Keyslot::seal(
+            &Credential::RecoveryKey(&recovery_keys.standard),
+            &key_s,
+            &options.kdf,
+        )?,
+    ];
+    let keyslots_h = [
+        Keyslot::seal(&Credential::Password(password_h), &key_h, &options.kdf)?,
+        Keyslot::seal(
+            &Credential::RecoveryKey(&recovery_keys.hidden),
+            &key_h,
+            &options.kdf,
+        )?,
+    ];
+
+    // 2. Create file (overwrite if exists) and fill the whole header region with random data,
+    //    so unused header/metadata space is indistinguishable from ciphertext
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    let mut region = vec![0u8; DATA_AREA_START_OFFSET as usize];
+    OsRng.fill_bytes(&mut region);
+    file.write_all(&region)?;
+    // A preallocated blob is random data up to its full length
+    if let Some(capacity) = options.capacity {
+        for piece in random_padding(capacity - DATA_AREA_START_OFFSET) {
+            file.write_all(&piece)?;
+        }
+    }
 
-    // 6. Write the complete headers
-    write_full_standard_header(
+    // 3. Write Magic + Version (left random in a headerless blob) and both keyslot arrays
+    if !options.headerless {
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(MAGIC)?;
+        file.write_all(&[VERSION])?;
+    }
+    write_initial_keyslots(
         &mut file,
-        &StandardHeaderInfo {
-            salt: salt_s,
-            nonce: meta_nonce_s,
-            size: meta_size_s,
-        },
+        VolumeType::Standard,
+        &keyslots_s,
+        options.headerless,
     )?;
-    write_full_hidden_header(
+    write_initial_keyslots(
         &mut file,
-        &HiddenHeaderInfo {
-            salt: salt_h,
-            nonce: meta_nonce_h,
-            size: meta_size_h,
-        },
+        VolumeType::Hidden,
+        &keyslots_h,
+        options.headerless,
     )?;
 
-    // 7. Fill padding between standard metadata end and hidden header start with random data
-    let standard_meta_end = STANDARD_METADATA_OFFSET + meta_size_s;
-    if standard_meta_end < HIDDEN_HEADER_OFFSET {
-        let padding_len = (HIDDEN_HEADER_OFFSET - standard_meta_end) as usize;
-        let mut padding = vec![0u8; padding_len];
-        OsRng.fill_bytes(&mut padding); // Use cryptographically random padding
-        file.seek(SeekFrom::Start(standard_meta_end))?;
-        file.write_all(&padding)?;
+    // 4. Write initial empty metadata blocks and the superblocks pointing at them
+    for (volume_type, key) in [(VolumeType::Standard, &key_s), (VolumeType::Hidden, &key_h)] {
+        write_volume_metadata(
+            &mut file,
+            volume_type,
+            key,
+            &MetadataMap::new(),
+            initial_free_space(volume_type, options),
+            &Protected::default(),
+            Some(options.padding),
+        )?;
     }
 
-    // 8. Ensure file size extends at least to the start of the data area
-    let current_len = file.seek(SeekFrom::End(0))?;
-    if current_len < DATA_AREA_START_OFFSET {
-        file.set_len(DATA_AREA_START_OFFSET)?; // Extend file size if needed
+    // 5. Sync all changes to disk
+    file.sync_all()?;
+    Ok(recovery_keys)
+}
+
+/// The free space a new volume starts out with: none, or in a preallocated blob (see
+/// `InitOptions::capacity`), the volume's whole region as unused space.
+fn initial_free_space(volume_type: VolumeType, options: &InitOptions) -> FreeSpace {
+    match options.capacity {
+        Some(capacity) => {
+            let hidden_len = options
+                .hidden_capacity
+                .unwrap_or((capacity - DATA_AREA_START_OFFSET) / 2);
+            FreeSpace::preallocated(
+                capacity,
+                (capacity - hidden_len, hidden_len),
+                volume_type == VolumeType::Hidden,
+            )
+        }
+        None => FreeSpace::default(),
     }
+}
 
-    // 9. Sync all changes to disk
+/// Grows a preallocated blob (see `InitOptions::capacity`) to `capacity` bytes by appending
+/// random data. No password is needed: the standard volume takes the new space as unused the
+/// next time it reads its free space or writes; the hidden volume keeps its region. A blob
+/// created without a capacity never uses the added bytes; it keeps growing by appending
+/// after them.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `capacity` - The new length of the blob file in bytes.
+///
+/// # Errors
+/// `InvalidInput` if the blob is already longer than `capacity`, or an error if the file isn't
+/// a blob or can't be written.
+pub fn grow_blob(path: &Path, capacity: u64) -> Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    read_blob_version(&mut file)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    if capacity < len {
+        return Err(BlobError::invalid(format!(
+            "The blob is already {} bytes long",
+            len
+        )));
+    }
+    for piece in random_padding(capacity - len) {
+        file.write_all(&piece)?;
+    }
     file.sync_all()?;
     Ok(())
 }
 
+/// Returns the on-disk format version of a blob (3 or 4) without unlocking it, as its
+/// header records it.
+///
+/// # Errors
+/// `NotABlob` for a file without the magic, which includes headerless blobs (see
+/// `blob_kind`), or an error if the blob uses an unsupported version.
+pub fn blob_version(path: &Path) -> Result<u8> {
+    match blob_kind(path)? {
+        BlobKind::Versioned(version) => Ok(version),
+        BlobKind::Headerless => Err(BlobError::NotABlob),
+    }
+}
+
+/// Tells, without unlocking it, whether a file is a blob with a plaintext header or could be
+/// a headerless one (see `InitOptions::headerless`).
+///
+/// # Errors
+/// `NotABlob` if the file has no magic and is too short to be a headerless blob, or an error
+/// if the blob uses an unsupported version.
+pub fn blob_kind(path: &Path) -> Result<BlobKind> {
+    let mut file = File::open(path)?;
+    read_blob_kind(&mut file)
+}
+
+/// Recovers a volume's data key with `credential`, without reading its metadata.
+/// v3 volumes have no keyslots: their key is derived from the password directly, and they
+/// can't be opened with keyfiles or recovery keys. A wrong v3 password only shows once the
+/// metadata fails to decrypt.
+pub(crate) fn open_volume_key(
+    file: &mut File,
+    version: u8,
+    volume_type: VolumeType,
+    credential: &Credential,
+) -> Result<[u8; 32]> {
+    match (version, credential) {
+        (VERSION_V3, Credential::Password(password)) => {
+            let salt = read_volume_salt(file, volume_type)?;
+            derive_key(password.as_bytes(), None, &salt, &KdfParams::default())
+        }
+        (VERSION_V3, _) => Err(BlobError::WrongPassword), // No keyslots to try
+        _ => open_keyslots(file, volume_type, credential)?
+            .map(|(_, _, data_key)| data_key)
+            .ok_or(BlobError::WrongPassword),
+    }
+}
+
+/// Recovers a volume's data key with `credential` and decrypts its metadata.
+fn open_volume(
+    file: &mut File,
+    version: u8,
+    volume_type: VolumeType,
+    credential: &Credential,
+) -> Result<([u8; 32], MetadataMap)> {
+    let key = open_volume_key(file, version, volume_type, credential)?;
+    let map = read_volume_metadata(file, version, volume_type, &key)?;
+    Ok((key, map))
+}
+
 /// Attempts to unlock an existing blob file using the provided password.
 /// Tries to decrypt the standard volume header/metadata first, then the hidden volume.
 ///
@@ -457,106 +1544,84 @@ pub fn init_blob(path: &Path, password_s: &str, password_h: &str) -> Result<()>
 /// # Returns
 /// On success: `Ok((VolumeType, derived_key, metadata_map))` indicating which volume
 ///             was unlocked, its derived key, and its metadata map.
-/// On failure: `Err` if the password doesn't match either volume, the blob is
-///             corrupted, or file I/O fails. The error is generic to avoid
+/// On failure: `BlobError::WrongPassword` if the password doesn't match either volume or
+///             the volume it matches is damaged, `NotABlob` / `UnsupportedVersion` for
+///             files this build can't read, or `Io`. The error is generic to avoid
 ///             leaking information about volume existence.
 pub fn unlock_blob(path: &Path, password: &str) -> Result<(VolumeType, [u8; 32], MetadataMap)> {
+    unlock_blob_with_credential(path, &Credential::Password(password))
+}
+
+/// Unlocks a blob like `unlock_blob`, with a password, a password plus keyfile, or a
+/// recovery key.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `credential` - The credential attempt.
+///
+/// # Returns
+/// As for `unlock_blob`.
+///
+/// # Errors
+/// As for `unlock_blob`. Keyfiles and recovery keys only open v4 volumes.
+pub fn unlock_blob_with_credential(
+    path: &Path,
+    credential: &Credential,
+) -> Result<(VolumeType, [u8; 32], MetadataMap)> {
     // Note: In a real app, init logger once at startup
     // let _ = env_logger::try_init();
 
     info!("Unlock attempt for path: {}", path.display());
-    let mut file = File::open(path)
-        .map_err(|e| anyhow!("Failed to open blob file {}: {}", path.display(), e))?;
-
-    // --- Attempt 1: Unlock Standard Volume ---
-    info!("Attempting Standard Volume unlock.");
-    match read_standard_header(&mut file) {
-        Ok(header_s) => {
-            info!(
-                "Standard Header: Nonce starts {:x?}, Size {}",
-                &header_s.nonce[..4],
-                header_s.size
-            );
-            match derive_key(password, &header_s.salt) {
-                Ok(key_s) => {
-                    info!("Derived potential standard key.");
-                    match read_metadata_block(
-                        &mut file,
-                        &key_s,
-                        &header_s.nonce,
-                        header_s.size,
-                        STANDARD_METADATA_OFFSET,
-                    ) {
-                        Ok(metadata_s) => {
-                            info!("Standard volume unlocked successfully!");
-                            return Ok((VolumeType::Standard, key_s, metadata_s));
-                        }
-                        Err(e) => {
-                            warn!("Standard metadata decryption failed: {}", e);
-                            // Log specific error from read_metadata_block
-                        }
-                    }
-                }
-                Err(e) => warn!("Failed to derive standard key: {}", e),
-            }
-        }
-        Err(e) => warn!("Failed to read standard header: {}", e),
-    }
-
-    // Reset file position potentially needed if standard read failed mid-way
-    if let Err(e) = file.seek(SeekFrom::Start(0)) {
-        error!("Failed to seek to start before hidden attempt: {}", e);
-        // Decide if this is fatal or recoverable. Let's assume fatal for now.
-        return Err(anyhow!("seek failed: {}", e));
-    }
+    let mut file = File::open(path)?;
+    unlock_file(&mut file, credential)
+}
 
-    // --- Attempt 2: Unlock Hidden Volume ---
-    info!("Attempting Hidden Volume unlock.");
-    match read_hidden_header(&mut file) {
-        Ok(header_h) => {
-            info!(
-                "Hidden Header: Salt starts {:x?}, Nonce starts {:x?}, Size {}",
-                &header_h.salt[..4],
-                &header_h.nonce[..4],
-                header_h.size
-            );
-            match derive_key(password, &header_h.salt) {
-                Ok(key_h) => {
-                    let mut key_hash = [0u8; 16]; // Example hash
-                    key_hash.copy_from_slice(&key_h[..16]);
-                    info!(
-                        "Derived potential hidden key (hash starts {:x?})",
-                        &key_hash[..4]
-                    );
-                    match read_metadata_block(
-                        &mut file,
-                        &key_h,
-                        &header_h.nonce,
-                        header_h.size,
-                        HIDDEN_METADATA_OFFSET,
-                    ) {
-                        Ok(metadata_h) => {
-                            info!("Hidden volume unlocked successfully!");
-                            return Ok((VolumeType::Hidden, key_h, metadata_h));
-                        }
-                        Err(e) => {
-                            error!("Hidden metadata decryption FAILED: {}", e); // Log specific error
-                        }
-                    }
-                }
-                Err(e) => error!("Failed to derive hidden key: {}", e),
+/// Unlocks whichever volume of an open blob `credential` belongs to. See
+/// `unlock_blob_with_credential`.
+pub(crate) fn unlock_file(
+    file: &mut File,
+    credential: &Credential,
+) -> Result<(VolumeType, [u8; 32], MetadataMap)> {
+    let version = read_blob_version(file)?;
+    info!("Blob format version: v{}", version);
+
+    // Try the standard volume first, then the hidden one
+    for volume_type in [VolumeType::Standard, VolumeType::Hidden] {
+        info!("Attempting {:?} Volume unlock.", volume_type);
+        match open_volume(file, version, volume_type, credential) {
+            Ok((key, metadata)) => {
+                info!("{:?} volume unlocked successfully!", volume_type);
+                return Ok((volume_type, key, metadata));
             }
+            // Expected for the volume the password doesn't belong to
+            Err(e) => warn!("{:?} volume unlock failed: {}", volume_type, e),
         }
-        Err(e) => error!("Failed to read hidden header: {}", e),
     }
 
     // If neither attempt succeeded
     warn!("Unlock failed for both volumes: Invalid password or corrupted blob.");
-    Err(anyhow!("Invalid password or corrupted blob"))
+    Err(BlobError::WrongPassword)
+}
+
+/// Runs `op` on an `UnlockedVolume` built from the caller's volume context, handing the
+/// (possibly updated) metadata map back afterwards. The functions below take that context
+/// explicitly on every call; `UnlockedVolume` keeps it for you.
+fn with_volume<T>(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    metadata_map: &mut MetadataMap,
+    op: impl FnOnce(&mut UnlockedVolume) -> Result<T>,
+) -> Result<T> {
+    let mut volume = UnlockedVolume::from_parts(path, volume_type, key, metadata_map)?;
+    let result = op(&mut volume);
+    *metadata_map = volume.into_metadata();
+    result
 }
 
 /// Adds or updates a file within the currently unlocked volume.
-/// Appends the encrypted file data and updates the volume's metadata block.
+/// Writes the encrypted file data, into space freed by deleted files where it fits, and updates
+/// the volume's metadata block.
 ///
 /// # Arguments
 /// * `path` - Path to the blob file.
@@ -578,140 +1643,151 @@ pub fn add_file(
     content: &[u8],
     mime_type: &str,
 ) -> Result<()> {
-    // Open file for read/write access
-    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
-
-    // 1. Append encrypted file data (Nonce + Ciphertext) to the data area
-    let file_metadata = append_file_data(&mut file, key, content, mime_type)?;
-
-    // 2. Add/Update entry in the in-memory metadata map (passed as mutable ref)
-    metadata_map.insert(file_path.to_string(), file_metadata);
-
-    // 3. Write the updated metadata map back to the correct block on disk
-    let metadata_offset = match volume_type {
-        VolumeType::Standard => STANDARD_METADATA_OFFSET,
-        VolumeType::Hidden => HIDDEN_METADATA_OFFSET,
-    };
-    let (new_nonce, new_size) =
-        write_metadata_block(&mut file, key, metadata_map, metadata_offset)?;
-
-    // 4. Update the corresponding header (Standard or Hidden) with the new metadata nonce/size
-    update_header_metadata(&mut file, volume_type, &new_nonce, new_size)?;
-
-    // 5. Ensure changes are flushed - with enhanced iOS handling
-    file.sync_data()?; // Sync after metadata and header updates
-
-    // Additional iOS-specific file handle management
-    #[cfg(target_os = "ios")]
-    {
-        use std::os::unix::fs::MetadataExt;
-
-        // Force close and reopen the file handle to ensure iOS commits changes
-        drop(file);
-
-        // Verify the file size has actually changed on disk
-        if let Ok(metadata) = std::fs::metadata(path) {
-            info!(
-                "iOS file verification: blob size on disk is {} bytes",
-                metadata.size()
-            );
-        }
-
-        // Reopen and sync one more time for iOS
-        let mut verify_file = OpenOptions::new().read(true).write(true).open(path)?;
-        verify_file.sync_all()?;
-    }
-
-    #[cfg(not(target_os = "ios"))]
-    {
-        // Standard platform: just ensure sync_all is called
-        file.sync_all()?;
-    }
-
-    Ok(())
-}
-
-/// Retrieves the decrypted content of a file from the blob.
-/// Assumes the correct volume context (key) is provided.
-///
-/// # Arguments
-/// * `path` - Path to the blob file.
-/// * `key` - Context: The derived key for the volume containing the file.
-/// * `metadata` - The `FileMetadata` entry corresponding to the file to retrieve (obtained from the unlocked `MetadataMap`).
-///
-/// # Returns
-/// `Ok(Vec<u8>)` containing the decrypted file content on success.
-/// `Err` on file I/O or decryption failure.
-pub fn get_file(path: &Path, key: &[u8; 32], metadata: &FileMetadata) -> Result<Vec<u8>> {
-    let mut file = File::open(path)?;
-    read_file_data(&mut file, key, metadata)
+    with_volume(path, volume_type, key, metadata_map, |volume| {
+        volume.add_file(file_path, content, mime_type)
+    })
 }
 
-/// Removes a file's entry from the currently unlocked volume's metadata.
-/// This makes the file inaccessible but does *not* reclaim the disk space used by its data block (orphaned data).
-/// A separate compaction process would be needed to reclaim space.
+/// Adds or updates a file within the currently unlocked volume, encrypting its content while
+/// reading it from `reader`. Memory use is bounded by the chunk size, not the file size.
+/// The metadata entry is committed only after the last chunk has been synced to disk, so an
+/// interrupted write leaves at most an orphaned (unreferenced) data block behind.
 ///
 /// # Arguments
 /// * `path` - Path to the blob file.
-/// * `volume_type` - Context: Which volume is unlocked.
+/// * `volume_type` - Context: Which volume (`Standard` or `Hidden`) is currently unlocked.
 /// * `key` - Context: The derived key for the unlocked volume.
-/// * `metadata_map` - Context: Mutable reference to the metadata map.
-/// * `file_path` - The full path of the file to remove.
+/// * `metadata_map` - Context: A mutable reference to the in-memory metadata map for the unlocked volume.
+/// * `file_path` - The full path inside the blob where the file should be stored.
+/// * `reader` - Source of the file content; read until EOF.
+/// * `mime_type` - The MIME type of the file.
 ///
-/// # Returns
-/// `Ok(true)` if the file was found and removed, `Ok(false)` if the file was not found.
-/// `Err` on file I/O or crypto failures during metadata update.
-pub fn remove_file(
+/// # Errors
+/// Returns an error on read, file I/O or crypto failures. On error the metadata map is unchanged.
+pub fn add_file_from_reader<R: Read>(
     path: &Path,
     volume_type: VolumeType,
     key: &[u8; 32],
     metadata_map: &mut MetadataMap,
     file_path: &str,
-) -> Result<bool> {
-    // 1. Try removing the entry from the in-memory map
-    if metadata_map.remove(file_path).is_none() {
-        return Ok(false); // File not found in this volume's metadata
-    }
+    reader: &mut R,
+    mime_type: &str,
+) -> Result<()> {
+    with_volume(path, volume_type, key, metadata_map, |volume| {
+        volume.add_file_from_reader(file_path, reader, mime_type)
+    })
+}
 
-    // 2. If removed, update the metadata block on disk
+/// Encrypts and writes a file's content to the data area *without* referencing it from the
+/// metadata. The returned `FileMetadata` must be committed with `add_file_entries` to make the
+/// file visible. Useful when the destination path is only known after the content (for example
+/// multipart uploads), or to commit many files with a single metadata write.
+///
+/// Content already stored in the volume isn't stored again: the file references the existing
+/// chunks instead.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `key` - Context: The derived key for the unlocked volume.
+/// * `chunks` - Context: The volume's chunk index (`ChunkIndex::open`, or `ChunkIndex::new`
+///   to only ever append). Newly stored chunks are added and the free space it hands out is
+///   taken, so keep using it for further writes to the volume.
+/// * `file_path` - The path the file is meant for, recorded in the block header so the file
+///   can be salvaged under its name. Later renames don't update it.
+/// * `reader` - Source of the file content; read until EOF.
+/// * `mime_type` - The MIME type of the file.
+/// * `compression` - How to encode new chunks before encryption; `Compression::for_file`
+///   picks what `add_file` would.
+///
+/// # Returns
+/// The `FileMetadata` of the durable, not yet committed, data block.
+pub fn write_file_data<R: Read>(
+    path: &Path,
+    key: &[u8; 32],
+    chunks: &mut ChunkIndex,
+    file_path: &str,
+    reader: &mut R,
+    mime_type: &str,
+    compression: Compression,
+) -> Result<FileMetadata> {
     let mut file = OpenOptions::new().read(true).write(true).open(path)?;
-    let metadata_offset = match volume_type {
-        VolumeType::Standard => STANDARD_METADATA_OFFSET,
-        VolumeType::Hidden => HIDDEN_METADATA_OFFSET,
-    };
-    let (new_nonce, new_size) =
-        write_metadata_block(&mut file, key, metadata_map, metadata_offset)?;
-    update_header_metadata(&mut file, volume_type, &new_nonce, new_size)?;
-
-    // Enhanced iOS file sync handling
-    file.sync_data()?; // Sync after metadata and header updates
-
-    #[cfg(target_os = "ios")]
-    {
-        use std::os::unix::fs::MetadataExt;
-
-        // Force close and reopen the file handle to ensure iOS commits changes
-        drop(file);
-
-        // Verify the file size on disk for iOS
-        if let Ok(metadata) = std::fs::metadata(path) {
-            info!(
-                "iOS file verification after remove: blob size on disk is {} bytes",
-                metadata.size()
-            );
-        }
+    append_file_data(
+        &mut file,
+        key,
+        chunks,
+        file_path,
+        reader,
+        mime_type,
+        compression,
+    )
+}
 
-        // Reopen and sync one more time for iOS
-        let mut verify_file = OpenOptions::new().read(true).write(true).open(path)?;
-        verify_file.sync_all()?;
-    }
+/// Commits previously written data blocks (see `write_file_data`) to the volume's metadata
+/// in a single metadata write. Existing entries with the same paths are replaced.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume is unlocked.
+/// * `key` - Context: The derived key for the unlocked volume.
+/// * `metadata_map` - Context: Mutable reference to the metadata map.
+/// * `entries` - `(file_path, FileMetadata)` pairs to insert.
+///
+/// # Errors
+/// Returns an error on file I/O or crypto failures. On error the metadata map is left unchanged.
+pub fn add_file_entries(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    metadata_map: &mut MetadataMap,
+    entries: Vec<(String, FileMetadata)>,
+) -> Result<()> {
+    with_volume(path, volume_type, key, metadata_map, |volume| {
+        volume.add_file_entries(entries)
+    })
+}
 
-    #[cfg(not(target_os = "ios"))]
-    {
-        file.sync_all()?;
-    }
+/// Retrieves the decrypted content of a file from the blob.
+/// Assumes the correct volume context (key) is provided.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `key` - Context: The derived key for the volume containing the file.
+/// * `metadata` - The `FileMetadata` entry corresponding to the file to retrieve (obtained from the unlocked `MetadataMap`).
+///
+/// # Returns
+/// `Ok(Vec<u8>)` containing the decrypted file content on success.
+/// `Err` on file I/O or decryption failure.
+pub fn get_file(path: &Path, key: &[u8; 32], metadata: &FileMetadata) -> Result<Vec<u8>> {
+    let mut reader = stream::open_file(path, key, metadata)?;
+    let mut content = Vec::with_capacity(metadata.size as usize);
+    reader.read_to_end(&mut content)?;
+    Ok(content)
+}
 
-    Ok(true)
+/// Removes a file's entry from the currently unlocked volume's metadata.
+/// The space of its blocks (minus chunks other files still share) is freed: after one more
+/// commit, later writes to the volume fill it before the blob grows (see `FreeSpace`).
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume is unlocked.
+/// * `key` - Context: The derived key for the unlocked volume.
+/// * `metadata_map` - Context: Mutable reference to the metadata map.
+/// * `file_path` - The full path of the file to remove.
+///
+/// # Returns
+/// `Ok(true)` if the file was found and removed, `Ok(false)` if the file was not found.
+/// `Err` on file I/O or crypto failures during metadata update; the map is left unchanged.
+pub fn remove_file(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    metadata_map: &mut MetadataMap,
+    file_path: &str,
+) -> Result<bool> {
+    with_volume(path, volume_type, key, metadata_map, |volume| {
+        volume.remove_file(file_path)
+    })
 }
 
 /// Renames a file within the currently unlocked volume's metadata.
@@ -737,58 +1813,40 @@ pub fn rename_file(
     old_path: &str,
     new_path: &str,
 ) -> Result<bool> {
-    // 1. Try removing the old entry, getting its metadata
-    if let Some(file_metadata) = metadata_map.remove(old_path) {
-        // 2. Insert the metadata back with the new path key
-        // This implicitly handles overwriting new_path if it exists, adjust if needed.
-        metadata_map.insert(new_path.to_string(), file_metadata);
-
-        // 3. Update the metadata block on disk
-        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
-        let metadata_offset = match volume_type {
-            VolumeType::Standard => STANDARD_METADATA_OFFSET,
-            VolumeType::Hidden => HIDDEN_METADATA_OFFSET,
-        };
-        let (new_nonce, new_size) =
-            write_metadata_block(&mut file, key, metadata_map, metadata_offset)?;
-        update_header_metadata(&mut file, volume_type, &new_nonce, new_size)?;
-
-        // Enhanced iOS file sync handling
-        file.sync_data()?;
-
-        #[cfg(target_os = "ios")]
-        {
-            use std::os::unix::fs::MetadataExt;
-
-            // Force close and reopen the file handle to ensure iOS commits changes
-            drop(file);
-
-            // Verify the file size on disk for iOS
-            if let Ok(metadata) = std::fs::metadata(path) {
-                info!(
-                    "iOS file verification after rename: blob size on disk is {} bytes",
-                    metadata.size()
-                );
-            }
-
-            // Reopen and sync one more time for iOS
-            let mut verify_file = OpenOptions::new().read(true).write(true).open(path)?;
-            verify_file.sync_all()?;
-        }
-
-        #[cfg(not(target_os = "ios"))]
-        {
-            file.sync_all()?;
-        }
+    with_volume(path, volume_type, key, metadata_map, |volume| {
+        volume.rename_file(old_path, new_path)
+    })
+}
 
-        return Ok(true); // Rename successful
-    }
-    Ok(false) // Old path not found
+/// Creates a folder, and any missing parent folders, in the currently unlocked volume's
+/// metadata, so it exists even while it is empty.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume is unlocked.
+/// * `key` - Context: The derived key for the unlocked volume.
+/// * `metadata_map` - Context: Mutable reference to the metadata map.
+/// * `folder_path` - The path of the folder to create (e.g., "documents/work").
+///
+/// # Returns
+/// `Ok(true)` if the folder was created, `Ok(false)` if it already had an entry.
+/// `Err` if the path is empty, the folder or one of its parents is a file, or on file I/O
+/// or crypto failures during metadata update.
+pub fn create_folder(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    metadata_map: &mut MetadataMap,
+    folder_path: &str,
+) -> Result<bool> {
+    with_volume(path, volume_type, key, metadata_map, |volume| {
+        volume.create_folder(folder_path)
+    })
 }
 
 /// Removes a folder and all files/subfolders within it from the currently unlocked volume's metadata.
 /// Uses prefix matching on the file paths stored in the metadata map.
-/// Like `remove_file`, this orphans the data blocks without reclaiming space.
+/// Like `remove_file`, this frees the space of their blocks for later writes.
 ///
 /// # Arguments
 /// * `path` - Path to the blob file.
@@ -807,151 +1865,1125 @@ pub fn remove_folder(
     metadata_map: &mut MetadataMap,
     folder_path: &str,
 ) -> Result<bool> {
-    // Ensure folder path format for prefix matching (e.g., "documents/work/")
-    let prefix = if folder_path.is_empty() {
-        // Handle removing root content if needed? Risky.
-        "".to_string() // Or return error? Current logic allows removing everything if "" passed.
-    } else if folder_path.ends_with('/') {
-        folder_path.to_string()
+    with_volume(path, volume_type, key, metadata_map, |volume| {
+        volume.remove_folder(folder_path)
+    })
+}
+
+/// Rewrites the blob without the space no file references any more: data of deleted or
+/// overwritten files, metadata spilled by earlier commits and chunks whose last referencing
+/// file is gone. Every chunk still referenced is copied once, however many files share it.
+/// A preallocated blob keeps its length; the space is freed inside it.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `password_s` - Password for the standard volume.
+/// * `password_h` - Password for the hidden volume.
+///
+/// # Errors
+/// Returns an error if either password is wrong or on file I/O failures; the blob is only
+/// replaced once the compacted copy is complete.
+pub fn compact_blob(path: &Path, password_s: &str, password_h: &str) -> Result<()> {
+    // 1. Open the existing blob and unlock both volumes
+    let mut file = File::open(path)?;
+    let version = read_blob_version(&mut file)?;
+    let (key_s, metadata_s) = open_volume(
+        &mut file,
+        version,
+        VolumeType::Standard,
+        &Credential::Password(password_s),
+    )?;
+    let (key_h, metadata_h) = open_volume(
+        &mut file,
+        version,
+        VolumeType::Hidden,
+        &Credential::Password(password_h),
+    )?;
+
+    // Drop the file handle so we can regenerate a new blob in its place
+    drop(file);
+
+    // 2. Copy every file into a temporary blob
+    let tmp_path = path.with_extension("compact_tmp");
+    rebuild_blob(
+        path,
+        &tmp_path,
+        (password_s, &key_s, &metadata_s),
+        (password_h, &key_h, &metadata_h),
+    )?;
+
+    // 3. Atomically swap the old blob out for the new compacted blob
+    //    First, rename the original to a .bak in case something goes wrong
+    let backup_path = path.with_extension("bak");
+    fs::rename(path, &backup_path)?;
+
+    //    Then, move the compacted tmp file into place
+    fs::rename(&tmp_path, path)?;
+
+    //    Finally, remove the old backup blob
+    fs::remove_file(&backup_path)?;
+
+    Ok(())
+}
+
+/// Runs one step of compacting the unlocked volume in place, without the other volume's
+/// password (see `UnlockedVolume::compact_in_place`). Call it until the report says `done`;
+/// `metadata_map` is updated after every step.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume (`Standard` or `Hidden`) is unlocked.
+/// * `key` - Context: The derived key for the unlocked volume.
+/// * `metadata_map` - Context: Mutable reference to the metadata map.
+///
+/// # Errors
+/// As for `UnlockedVolume::compact_step`.
+pub fn compact_volume_step(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    metadata_map: &mut MetadataMap,
+) -> Result<CompactionReport> {
+    with_volume(path, volume_type, key, metadata_map, |volume| {
+        volume.compact_step()
+    })
+}
+
+/// Writes a fresh blob at `new_path` holding the given files of both volumes of the blob at
+/// `path`, as `(password, data key, files)` per volume. Only the listed blocks are copied,
+/// each re-encrypted into a new, densely packed data area; a preallocated blob is rebuilt at
+/// the same length, with a hidden region of the same size at its end. Files are re-chunked
+/// into one chunk index per volume, so a chunk shared by several files is stored once, and
+/// keep the compression, timestamps and attributes they were written with.
+pub(crate) fn rebuild_blob(
+    path: &Path,
+    new_path: &Path,
+    standard: (&str, &[u8; 32], &MetadataMap),
+    hidden: (&str, &[u8; 32], &MetadataMap),
+) -> Result<()> {
+    let (password_s, key_s_old, metadata_s) = standard;
+    let (password_h, key_h_old, metadata_h) = hidden;
+    let mut file = File::open(path)?;
+    let version = read_blob_version(&mut file)?;
+    // Salvaging reads damaged blobs, so a volume whose metadata is gone doesn't count
+    let hidden_region = [
+        (VolumeType::Standard, key_s_old),
+        (VolumeType::Hidden, key_h_old),
+    ]
+    .into_iter()
+    .find_map(|(volume_type, key)| {
+        read_free_space(&mut file, volume_type, key)
+            .ok()
+            .and_then(|free| free.hidden_region())
+    });
+    let capacity = match hidden_region {
+        Some(_) => Some(file.seek(SeekFrom::End(0))?),
+        None => None,
+    };
+    let options = InitOptions {
+        kdf: read_volume_kdf(&mut file, version, VolumeType::Standard)?,
+        padding: read_padding_policy(&mut file, VolumeType::Standard, key_s_old)?,
+        capacity,
+        hidden_capacity: hidden_region.map(|(_, len)| len),
+        headerless: is_headerless(&mut file)?,
+    };
+    drop(file);
+
+    // 1. Initialize the new blob with fresh salts, the same KDF cost, padding, capacity and
+    //    format
+    init_blob_with_options(new_path, password_s, password_h, &options)?;
+
+    // 2. v4 volumes keep their keyslots, so every password (and the data keys) carry over.
+    //    v3 volumes get the fresh keys of the new blob.
+    let (key_s_new, key_h_new) = if version == VERSION_V3 {
+        let (_, key_s_new, _) = unlock_blob(new_path, password_s)?;
+        let (_, key_h_new, _) = unlock_blob(new_path, password_h)?;
+        (key_s_new, key_h_new)
     } else {
-        format!("{}/", folder_path) // Append slash for prefix matching
+        let mut from = File::open(path)?;
+        let mut to = OpenOptions::new().read(true).write(true).open(new_path)?;
+        for (volume_type, key) in [
+            (VolumeType::Standard, key_s_old),
+            (VolumeType::Hidden, key_h_old),
+        ] {
+            copy_keyslots(&mut from, &mut to, volume_type)?;
+            // The empty metadata written by init is sealed with the data key it generated
+            write_volume_metadata(
+                &mut to,
+                volume_type,
+                key,
+                &MetadataMap::new(),
+                initial_free_space(volume_type, &options),
+                &Protected::default(),
+                Some(options.padding),
+            )?;
+        }
+        to.sync_all()?;
+        (*key_s_old, *key_h_old)
     };
+    let mut map_s_new = MetadataMap::new();
+    let mut map_h_new = MetadataMap::new();
 
-    // Handle removing just the folder entry itself if it's treated as a file/entry somehow
-    let path_itself = folder_path.trim_end_matches('/');
+    // 3. Stream every file into the new blob, re-encrypting as we go, then commit each volume's
+    //    metadata once (per-file commits would leave a trail of spilled metadata extents)
+    for (volume_type, old_key, old_map, new_key, new_map) in [
+        (
+            VolumeType::Standard,
+            key_s_old,
+            metadata_s,
+            &key_s_new,
+            &mut map_s_new,
+        ),
+        (
+            VolumeType::Hidden,
+            key_h_old,
+            metadata_h,
+            &key_h_new,
+            &mut map_h_new,
+        ),
+    ] {
+        let referenced = ChunkIndex::new(old_map);
+        let mut chunks = ChunkIndex::open(new_path, volume_type, new_key, &MetadataMap::new())?;
+        let mut entries = Vec::with_capacity(old_map.len());
+        for (relative_path, meta) in old_map.iter() {
+            // Folders have no data to copy
+            if meta.is_dir() {
+                entries.push((relative_path.clone(), meta.clone()));
+                continue;
+            }
+            let mut reader =
+                stream::open_file(path, old_key, meta).map_err(|e| e.for_file(relative_path))?;
+            let new_meta = write_file_data(
+                new_path,
+                new_key,
+                &mut chunks,
+                relative_path,
+                &mut reader,
+                &meta.mime_type,
+                meta.compression,
+            )
+            .map_err(|e| e.for_file(relative_path))?;
+            // Only the location changed; legacy files gain a content hash
+            let new_meta = FileMetadata {
+                created_at: meta.created_at,
+                modified_at: meta.modified_at,
+                uploaded_at: meta.uploaded_at,
+                original_mode: meta.original_mode,
+                original_mtime: meta.original_mtime,
+                attributes: meta.attributes.clone(),
+                ..new_meta
+            };
+            entries.push((relative_path.clone(), new_meta));
+        }
+        info!(
+            "Rebuilt {:?} volume: {} files in {} chunks ({} bytes), {} chunks referenced before",
+            volume_type,
+            old_map.len(),
+            chunks.len(),
+            chunks.stored_bytes(),
+            referenced.len()
+        );
+        add_file_entries(new_path, volume_type, new_key, new_map, entries)?;
+    }
+    Ok(())
+}
 
-    // 1. Find all keys matching the exact folder path or starting with the prefix
-    let keys_to_remove: Vec<String> = metadata_map
-        .keys()
-        .filter(|k| k == &path_itself || (!prefix.is_empty() && k.starts_with(&prefix)))
-        .cloned()
-        .collect();
+/// Upgrades a v3 blob to the current format in place.
+/// File data blocks stay where they are; only the header region before the data area is
+/// rewritten, so migration is fast regardless of how much data the blob holds. Each volume's
+/// v3 key becomes its data key, wrapped with the same password, so existing sessions remain
+/// valid.
+///
+/// The original header region is saved to a `.migrate_bak` file next to the blob first. If
+/// migration is interrupted, the blob won't open until `recover_migration` (also run at the
+/// start of `migrate_blob`) puts the saved region back.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `password_s` - Password for the standard volume.
+/// * `password_h` - Password for the hidden volume.
+///
+/// # Returns
+/// `Ok(true)` if the blob was migrated, `Ok(false)` if it already uses the current format.
+///
+/// # Errors
+/// Returns an error if either password is wrong or on file I/O failures.
+pub fn migrate_blob(path: &Path, password_s: &str, password_h: &str) -> Result<bool> {
+    let backup_path = path.with_extension("migrate_bak");
 
-    if keys_to_remove.is_empty() {
-        return Ok(false); // Folder or contents not found
+    // 1. Roll back an interrupted migration before doing anything else
+    recover_migration(path)?;
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let version = read_blob_version(&mut file)?;
+    if version == VERSION {
+        return Ok(false);
     }
 
-    // 2. Remove the collected keys from the in-memory map
-    for key_to_remove in &keys_to_remove {
-        // Use reference here
-        metadata_map.remove(key_to_remove);
+    // 2. Unlock both volumes with the old layout; both are needed to rewrite their headers
+    let (key_s, metadata_s) = open_volume(
+        &mut file,
+        version,
+        VolumeType::Standard,
+        &Credential::Password(password_s),
+    )?;
+    let (key_h, metadata_h) = open_volume(
+        &mut file,
+        version,
+        VolumeType::Hidden,
+        &Credential::Password(password_h),
+    )?;
+
+    // The v3 keys become the volumes' data keys, wrapped with the same passwords.
+    // v3 volumes were always derived with the default KDF parameters.
+    let kdf = KdfParams::default();
+    let keyslot_s = Keyslot::seal(&Credential::Password(password_s), &key_s, &kdf)?;
+    let keyslot_h = Keyslot::seal(&Credential::Password(password_h), &key_h, &kdf)?;
+
+    // 3. Save the old header region durably before touching it
+    let mut region = vec![0u8; DATA_AREA_START_OFFSET as usize];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut region)?;
+    let mut backup = File::create(&backup_path)?;
+    backup.write_all(&region)?;
+    backup.sync_all()?;
+
+    // 4. Rewrite the header region: fresh random fill, keyslots, metadata and superblocks.
+    //    Magic + Version go last, so a half-written region never looks like a valid blob.
+    OsRng.fill_bytes(&mut region);
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&region)?;
+    write_initial_keyslots(&mut file, VolumeType::Standard, &[keyslot_s], false)?;
+    write_initial_keyslots(&mut file, VolumeType::Hidden, &[keyslot_h], false)?;
+    // Space freed while the blob was v3 stays unused until the next compaction
+    write_volume_metadata(
+        &mut file,
+        VolumeType::Standard,
+        &key_s,
+        &metadata_s,
+        FreeSpace::default(),
+        &Protected::default(),
+        None,
+    )?;
+    write_volume_metadata(
+        &mut file,
+        VolumeType::Hidden,
+        &key_h,
+        &metadata_h,
+        FreeSpace::default(),
+        &Protected::default(),
+        None,
+    )?;
+    file.sync_all()?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(MAGIC)?;
+    file.write_all(&[VERSION])?;
+    file.sync_all()?;
+
+    // 5. The new layout is durable; drop the backup
+    fs::remove_file(&backup_path)?;
+    info!(
+        "Migrated blob {} from v{} to v{}",
+        path.display(),
+        version,
+        VERSION
+    );
+    Ok(true)
+}
+
+/// Cleans up after a `migrate_blob` run that was interrupted. If the new header region was
+/// fully written (the version byte is only updated last) the leftover backup is removed;
+/// otherwise the original header region is restored, leaving the blob in its old format.
+/// Safe to call on any blob before unlocking it.
+///
+/// # Returns
+/// `Ok(true)` if an interrupted migration was found and cleaned up.
+pub fn recover_migration(path: &Path) -> Result<bool> {
+    let backup_path = path.with_extension("migrate_bak");
+    if !backup_path.exists() {
+        return Ok(false);
     }
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    // The random fill of a half-written region would pass for a headerless blob
+    if is_headerless(&mut file)? || !matches!(read_blob_version(&mut file), Ok(VERSION)) {
+        warn!(
+            "Restoring header region of {} from interrupted migration",
+            path.display()
+        );
+        let region = fs::read(&backup_path)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&region)?;
+        file.sync_all()?;
+    }
+    fs::remove_file(&backup_path)?;
+    Ok(true)
+}
 
-    // 3. Update the metadata block on disk
+/// Changes the password of whichever keyslot `old_password` unlocks; the volume's other
+/// keyslots are untouched. The data key is rewrapped under the new password; file data and
+/// metadata are untouched, so this takes the same time regardless of blob size, and keys held
+/// by existing sessions stay valid.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `old_password` - Current password of the volume.
+/// * `new_password` - Password to replace it with.
+///
+/// # Returns
+/// The `VolumeType` whose password was changed.
+///
+/// # Errors
+/// Returns an error if `old_password` unlocks neither volume, if the new password is the
+/// same as the old one or already opens a keyslot, if the blob still uses the v3
+/// format (see `migrate_blob`), or on file I/O failures.
+pub fn change_password(path: &Path, old_password: &str, new_password: &str) -> Result<VolumeType> {
+    if old_password == new_password {
+        return Err(BlobError::invalid(
+            "New password must differ from the current one",
+        ));
+    }
     let mut file = OpenOptions::new().read(true).write(true).open(path)?;
-    let metadata_offset = match volume_type {
-        VolumeType::Standard => STANDARD_METADATA_OFFSET,
-        VolumeType::Hidden => HIDDEN_METADATA_OFFSET,
-    };
-    let (new_nonce, new_size) =
-        write_metadata_block(&mut file, key, metadata_map, metadata_offset)?;
-    update_header_metadata(&mut file, volume_type, &new_nonce, new_size)?;
+    if read_blob_version(&mut file)? == VERSION_V3 {
+        return Err(BlobError::RequiresMigration(
+            "Passwords of v3 blobs can't be changed",
+        ));
+    }
 
-    // Enhanced iOS file sync handling
-    file.sync_data()?; // Sync after metadata and header updates
+    // 1. Find the slot the old password belongs to and recover its data key
+    let mut unlocked = None;
+    for volume_type in [VolumeType::Standard, VolumeType::Hidden] {
+        if let Some((index, keyslot, data_key)) =
+            open_keyslots(&mut file, volume_type, &Credential::Password(old_password))?
+        {
+            unlocked = Some((volume_type, index, keyslot, data_key));
+            break;
+        }
+    }
+    let (volume_type, index, keyslot, data_key) = unlocked.ok_or(BlobError::WrongPassword)?;
 
-    #[cfg(target_os = "ios")]
-    {
-        use std::os::unix::fs::MetadataExt;
+    // 2. Two slots sharing a password would make one of them unreachable
+    let new_credential = Credential::Password(new_password);
+    if credential_in_use(&mut file, &new_credential, volume_type, &data_key)? {
+        return Err(BlobError::CredentialInUse);
+    }
 
-        // Force close and reopen the file handle to ensure iOS commits changes
-        drop(file);
+    // 3. Rewrap the data key with the slot's KDF settings and replace the slot in place
+    let new_keyslot = Keyslot::seal(&new_credential, &data_key, keyslot.kdf())?;
+    write_keyslot(&mut file, volume_type, index, &new_keyslot)?;
+    file.sync_all()?;
+    info!("Changed password of {:?} volume", volume_type);
+    Ok(volume_type)
+}
 
-        // Verify the file size on disk for iOS
-        if let Ok(metadata) = std::fs::metadata(path) {
-            info!(
-                "iOS file verification after remove folder: blob size on disk is {} bytes",
-                metadata.size()
+/// Adds a password, keyfile or recovery key to the unlocked volume by wrapping its data key
+/// in a free keyslot. Every slot has its own salt; the new one gets the KDF parameters of the
+/// volume's first slot, like the decoys in unused slots, so used slots can't be told from
+/// decoys by their parameters. Existing slots are untouched.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume (`Standard` or `Hidden`) is currently unlocked.
+/// * `key` - Context: The data key of the unlocked volume.
+/// * `credential` - What the new slot will be opened with.
+///
+/// # Returns
+/// The index of the slot that was filled.
+///
+/// # Errors
+/// `KeyslotsFull` if all `KEYSLOTS_PER_VOLUME` slots are in use, `CredentialInUse` if the
+/// credential already opens a keyslot of either volume, `RequiresMigration` for v3 blobs,
+/// `WrongVolumeKey`, or `Io`.
+pub fn add_keyslot(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    credential: &Credential,
+) -> Result<usize> {
+    with_volume(path, volume_type, key, &mut MetadataMap::new(), |volume| {
+        volume.add_keyslot(credential)
+    })
+}
+
+/// Generates a new recovery key for the unlocked volume and adds it in a free keyslot, with
+/// the KDF parameters of the volume's first slot. Use this for blobs migrated from v3, or to
+/// replace a recovery key after revoking its slot.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume (`Standard` or `Hidden`) is currently unlocked.
+/// * `key` - Context: The data key of the unlocked volume.
+///
+/// # Returns
+/// The new recovery key and the index of its slot.
+///
+/// # Errors
+/// As for `add_keyslot`.
+pub fn add_recovery_key(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<(String, usize)> {
+    with_volume(path, volume_type, key, &mut MetadataMap::new(), |volume| {
+        volume.add_recovery_key()
+    })
+}
+
+/// Like `add_recovery_key`, but the new recovery key is never shown: it is split into
+/// `count` mnemonic shares, any `threshold` of which unlock the volume through
+/// `Credential::RecoveryShares`. Hand each share to a different person.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume (`Standard` or `Hidden`) is currently unlocked.
+/// * `key` - Context: The data key of the unlocked volume.
+/// * `threshold` - Number of shares needed to unlock (at least 2).
+/// * `count` - Number of shares to create (at most 255).
+///
+/// # Returns
+/// The shares and the index of their slot.
+///
+/// # Errors
+/// Returns an error if the threshold is out of range, otherwise as for `add_keyslot`.
+pub fn add_recovery_shares(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    threshold: u8,
+    count: u8,
+) -> Result<(Vec<String>, usize)> {
+    with_volume(path, volume_type, key, &mut MetadataMap::new(), |volume| {
+        volume.add_recovery_shares(threshold, count)
+    })
+}
+
+/// Lists the keyslots in use by the unlocked volume. Slots carry no names or other
+/// information, so this is only which slot indices are taken; the other volume's slots are
+/// never reported.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume (`Standard` or `Hidden`) is currently unlocked.
+/// * `key` - Context: The data key of the unlocked volume.
+///
+/// # Errors
+/// Returns an error for v3 blobs, if `key` isn't the volume's data key, or on file I/O
+/// failures.
+pub fn list_keyslots(path: &Path, volume_type: VolumeType, key: &[u8; 32]) -> Result<Vec<usize>> {
+    with_volume(path, volume_type, key, &mut MetadataMap::new(), |volume| {
+        volume.list_keyslots()
+    })
+}
+
+/// Revokes a keyslot of the unlocked volume by overwriting it with a decoy. The password it
+/// held stops working immediately; the data key, and therefore the stored data, is unchanged.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume (`Standard` or `Hidden`) is currently unlocked.
+/// * `key` - Context: The data key of the unlocked volume.
+/// * `index` - The slot to revoke, as returned by `list_keyslots`.
+///
+/// # Errors
+/// `KeyslotNotInUse` if the slot isn't in use by this volume, `LastKeyslot` if it is the
+/// volume's last slot, `RequiresMigration` for v3 blobs, `WrongVolumeKey`, or `Io`.
+pub fn revoke_keyslot(
+    path: &Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    index: usize,
+) -> Result<()> {
+    with_volume(path, volume_type, key, &mut MetadataMap::new(), |volume| {
+        volume.revoke_keyslot(index)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::DEFAULT_CHUNK_SIZE;
+
+    #[derive(Serialize)]
+    struct V3FileMetadata {
+        size: u64,
+        data_offset: u64,
+        data_length: u64,
+        mime_type: String,
+    }
+
+    /// Writes a blob the way v3 releases did: fixed-region headers, unversioned metadata and
+    /// single-message file data. Each volume gets one file, `(path, content)`.
+    fn init_v3_blob(
+        path: &Path,
+        password_s: &str,
+        password_h: &str,
+        file_s: (&str, &[u8]),
+        file_h: (&str, &[u8]),
+    ) {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+        let mut region = vec![0u8; DATA_AREA_START_OFFSET as usize];
+        OsRng.fill_bytes(&mut region);
+        file.write_all(&region).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(MAGIC).unwrap();
+        file.write_all(&[VERSION_V3]).unwrap();
+
+        for (volume_type, password, (file_path, content)) in [
+            (VolumeType::Standard, password_s, file_s),
+            (VolumeType::Hidden, password_h, file_h),
+        ] {
+            let salt = read_volume_salt(&mut file, volume_type).unwrap();
+            let key = derive_key(password.as_bytes(), None, &salt, &KdfParams::default()).unwrap();
+
+            // Legacy data block: nonce + one AEAD message over the whole file
+            let data_offset = file.seek(SeekFrom::End(0)).unwrap();
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = get_cipher(&key).encrypt(&nonce, content).unwrap();
+            file.write_all(nonce.as_slice()).unwrap();
+            file.write_all(&ciphertext).unwrap();
+
+            let mut legacy = HashMap::new();
+            legacy.insert(
+                file_path.to_string(),
+                V3FileMetadata {
+                    size: content.len() as u64,
+                    data_offset,
+                    data_length: (XNONCE_LEN + ciphertext.len()) as u64,
+                    mime_type: "text/plain".to_string(),
+                },
             );
+            let plaintext = bincode::serialize(&legacy).unwrap();
+            let meta_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let meta = get_cipher(&key)
+                .encrypt(&meta_nonce, plaintext.as_ref())
+                .unwrap();
+            file.seek(SeekFrom::Start(inline_metadata_offset(
+                VERSION_V3,
+                volume_type,
+            )))
+            .unwrap();
+            file.write_all(&meta).unwrap();
+            file.seek(SeekFrom::Start(
+                volume_header_offset(volume_type) + SALT_LEN as u64,
+            ))
+            .unwrap();
+            file.write_all(meta_nonce.as_slice()).unwrap();
+            file.write_all(&(meta.len() as u64).to_le_bytes()).unwrap();
         }
+    }
 
-        // Reopen and sync one more time for iOS
-        let mut verify_file = OpenOptions::new().read(true).write(true).open(path)?;
-        verify_file.sync_all()?;
+    /// Long entries pointing nowhere; 1000 of them need more than the 64 KiB standard region.
+    fn bulky_entries(count: usize) -> Vec<(String, FileMetadata)> {
+        (0..count)
+            .map(|i| {
+                let entry = FileMetadata {
+                    size: 0,
+                    data_offset: DATA_AREA_START_OFFSET,
+                    data_length: 35,
+                    mime_type: "text/plain".to_string(),
+                    format: DataFormat::Chunked {
+                        chunk_size: DEFAULT_CHUNK_SIZE,
+                    },
+                    chunks: Vec::new(),
+                    compression: Compression::None,
+                    created_at: None,
+                    modified_at: None,
+                    uploaded_at: None,
+                    sha256: None,
+                    original_mode: None,
+                    original_mtime: None,
+                    attributes: Default::default(),
+                };
+                (format!("{}/file-{:04}.txt", "d".repeat(64), i), entry)
+            })
+            .collect()
     }
 
-    #[cfg(not(target_os = "ios"))]
-    {
-        file.sync_all()?;
+    #[test]
+    fn test_legacy_metadata_deserializes() {
+        let mut legacy = HashMap::new();
+        legacy.insert(
+            "docs/a.txt".to_string(),
+            V3FileMetadata {
+                size: 5,
+                data_offset: DATA_AREA_START_OFFSET,
+                data_length: 45,
+                mime_type: "text/plain".to_string(),
+            },
+        );
+        let plaintext = bincode::serialize(&legacy).unwrap();
+
+        let map = deserialize_metadata(&plaintext).unwrap().0;
+        let entry = &map["docs/a.txt"];
+        assert_eq!(entry.size, 5);
+        assert_eq!(entry.data_length, 45);
+        assert_eq!(entry.format, DataFormat::Single);
     }
 
-    Ok(true)
-}
+    #[test]
+    fn test_versioned_metadata_roundtrip() {
+        let mut map = MetadataMap::new();
+        map.insert(
+            "b.bin".to_string(),
+            FileMetadata {
+                size: 1,
+                data_offset: DATA_AREA_START_OFFSET,
+                data_length: 36,
+                mime_type: "application/octet-stream".to_string(),
+                format: DataFormat::Chunked {
+                    chunk_size: DEFAULT_CHUNK_SIZE,
+                },
+                chunks: Vec::new(),
+                compression: Compression::Zstd,
+                created_at: None,
+                modified_at: None,
+                uploaded_at: None,
+                sha256: None,
+                original_mode: None,
+                original_mtime: None,
+                attributes: Default::default(),
+            },
+        );
+        let plaintext = serialize_metadata(&map, &FreeSpace::default()).unwrap();
+        assert!(plaintext.starts_with(METADATA_MAGIC));
 
-pub fn compact_blob(path: &Path, password_s: &str, password_h: &str) -> Result<()> {
-    // 1. Open the existing blob and read headers for both volumes
-    let mut file = File::open(path)?;
-    let header_s = read_standard_header(&mut file)?;
-    let header_h = read_hidden_header(&mut file)?;
+        let decoded = deserialize_metadata(&plaintext).unwrap().0;
+        assert_eq!(decoded["b.bin"].format, map["b.bin"].format);
+    }
 
-    // 2. Derive the old keys and read metadata blocks
-    let key_s_old = derive_key(password_s, &header_s.salt)?;
-    let metadata_s = read_metadata_block(
-        &mut file,
-        &key_s_old,
-        &header_s.nonce,
-        header_s.size,
-        STANDARD_METADATA_OFFSET,
-    )?;
+    #[test]
+    fn test_v3_metadata_cannot_overrun_hidden_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("full.blob");
+        init_v3_blob(
+            &path,
+            "standard_pw",
+            "hidden_pw",
+            ("a.txt", b"a"),
+            ("b.txt", b"b"),
+        );
+        let (volume, key, mut map) = unlock_blob(&path, "standard_pw").unwrap();
 
-    let key_h_old = derive_key(password_h, &header_h.salt)?;
-    let metadata_h = read_metadata_block(
-        &mut file,
-        &key_h_old,
-        &header_h.nonce,
-        header_h.size,
-        HIDDEN_METADATA_OFFSET,
-    )?;
+        let err = add_file_entries(&path, volume, &key, &mut map, bulky_entries(1000)).unwrap_err();
+        assert!(matches!(err, BlobError::MetadataTooLarge { .. }));
+        assert_eq!(map.len(), 1);
 
-    // Drop the file handle so we can regenerate a new blob in its place
-    drop(file);
+        // Both volumes are intact
+        assert_eq!(unlock_blob(&path, "standard_pw").unwrap().2.len(), 1);
+        assert_eq!(
+            unlock_blob(&path, "hidden_pw").unwrap().0,
+            VolumeType::Hidden
+        );
+    }
 
-    // 3. Initialize a temporary blob on disk with fresh salts
-    let tmp_path = path.with_extension("compact_tmp");
-    init_blob(&tmp_path, password_s, password_h)?;
+    #[test]
+    fn test_v4_metadata_spills_into_data_area() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.blob");
+        init_blob(&path, "standard_pw", "hidden_pw").unwrap();
+        let (volume, key, mut map) = unlock_blob(&path, "standard_pw").unwrap();
+
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(1000)).unwrap();
+        let mut file = File::open(&path).unwrap();
+        let (_, superblock) = read_superblocks(&mut file, VolumeType::Standard, &key)
+            .unwrap()
+            .remove(0);
+        assert!(superblock.meta_offset >= DATA_AREA_START_OFFSET);
+
+        assert_eq!(unlock_blob(&path, "standard_pw").unwrap().2.len(), 1000);
+        assert!(unlock_blob(&path, "hidden_pw").unwrap().2.is_empty());
+    }
+
+    #[test]
+    fn test_metadata_blocks_are_padded_to_buckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("padded.blob");
+        let options = InitOptions {
+            kdf: KdfParams {
+                memory_kib: 8192,
+                iterations: 1,
+                ..KdfParams::default()
+            },
+            padding: PaddingPolicy::PowerOfTwo,
+            ..InitOptions::default()
+        };
+        init_blob_with_options(&path, "standard_pw", "hidden_pw", &options).unwrap();
+        let (volume, key, mut map) = unlock_blob(&path, "standard_pw").unwrap();
+        let mut file = File::open(&path).unwrap();
+        let newest = |file: &mut File| read_superblocks(file, volume, &key).unwrap().remove(0).1;
+
+        // Inline blocks are padded, but never past their half of the region
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(10)).unwrap();
+        let inline = newest(&mut file);
+        assert_eq!(inline.padding, PaddingPolicy::PowerOfTwo);
+        assert!(inline.meta_size.is_power_of_two());
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(120)).unwrap();
+        let full = newest(&mut file);
+        assert!(full.meta_offset < DATA_AREA_START_OFFSET);
+        assert_eq!(full.meta_size, metadata_half(volume, 0).1);
+
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(1000)).unwrap();
+        let spilled = newest(&mut file);
+        assert!(spilled.meta_offset >= DATA_AREA_START_OFFSET);
+        assert!(spilled.meta_size.is_power_of_two());
+        assert_eq!(unlock_blob(&path, "standard_pw").unwrap().2.len(), 1000);
+
+        // Compaction keeps the policy of both volumes
+        compact_blob(&path, "standard_pw", "hidden_pw").unwrap();
+        let mut file = File::open(&path).unwrap();
+        for password in ["standard_pw", "hidden_pw"] {
+            let (volume_type, key, _) = unlock_blob(&path, password).unwrap();
+            let policy = read_padding_policy(&mut file, volume_type, &key).unwrap();
+            assert_eq!(policy, PaddingPolicy::PowerOfTwo);
+        }
+    }
 
-    // 4. Unlock both volumes in the new blob to get fresh keys and mutable maps
-    let (_, key_s_new, mut map_s_new) = unlock_blob(&tmp_path, password_s)?;
-    let (_, key_h_new, mut map_h_new) = unlock_blob(&tmp_path, password_h)?;
+    /// Paths in the volume's metadata, sorted.
+    fn paths(map: &MetadataMap) -> Vec<String> {
+        let mut paths: Vec<String> = map.keys().cloned().collect();
+        paths.sort();
+        paths
+    }
 
-    // 5. Iterate over every file in the standard volume, read its plaintext, and re-add it
-    for (relative_path, meta) in metadata_s.iter() {
-        let data = get_file(path, &key_s_old, meta)?;
+    #[test]
+    fn test_v4_commits_alternate_superblock_slots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slots.blob");
+        init_blob(&path, "standard_pw", "hidden_pw").unwrap();
+        let (volume, key, mut map) = unlock_blob(&path, "standard_pw").unwrap();
+        let mut file = File::open(&path).unwrap();
+        let newest = |file: &mut File| read_superblocks(file, volume, &key).unwrap().remove(0);
+        assert_eq!(newest(&mut file).0, 0);
+        assert_eq!(newest(&mut file).1.generation, 0);
+
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(1)).unwrap();
+        let (slot, first) = newest(&mut file);
+        assert_eq!((slot, first.generation), (1, 1));
+        assert_eq!(first.meta_offset, metadata_half(volume, 1).0);
+        remove_file(&path, volume, &key, &mut map, &bulky_entries(1)[0].0).unwrap();
+        let (slot, second) = newest(&mut file);
+        assert_eq!((slot, second.generation), (0, 2));
+        assert_eq!(second.meta_offset, metadata_half(volume, 0).0);
+        // Both generations are still on disk
+        assert_eq!(read_superblocks(&mut file, volume, &key).unwrap().len(), 2);
+        // Only the emptied folder is left
+        assert_eq!(
+            paths(&unlock_blob(&path, "standard_pw").unwrap().2),
+            ["d".repeat(64)]
+        );
+    }
+
+    #[test]
+    fn test_torn_commit_falls_back_to_previous_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("torn.blob");
+        init_blob(&path, "standard_pw", "hidden_pw").unwrap();
+        let (volume, key, mut map) = unlock_blob(&path, "standard_pw").unwrap();
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(2)).unwrap();
+        let committed = paths(&map);
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(5)).unwrap();
+        assert_eq!(unlock_blob(&path, "standard_pw").unwrap().2.len(), 5);
+
+        // Power cut while writing the newest superblock: its slot no longer authenticates
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let (slot, _) = read_superblocks(&mut file, volume, &key).unwrap().remove(0);
+        file.seek(SeekFrom::Start(superblock_offset(volume, slot) + 40))
+            .unwrap();
+        file.write_all(&[0u8; 32]).unwrap();
+        let (_, _, map) = unlock_blob(&path, "standard_pw").unwrap();
+        assert_eq!(paths(&map), committed);
+
+        // Commits carry on from the surviving generation without touching its slot
+        let (_, _, mut map) = unlock_blob(&path, "standard_pw").unwrap();
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(3)).unwrap();
+        assert_eq!(unlock_blob(&path, "standard_pw").unwrap().2.len(), 3);
+
+        // A newest superblock whose metadata is damaged falls back the same way
+        let (_, newest) = read_superblocks(&mut file, volume, &key).unwrap().remove(0);
+        file.seek(SeekFrom::Start(newest.meta_offset)).unwrap();
+        file.write_all(&[0u8; 16]).unwrap();
+        let (_, _, map) = unlock_blob(&path, "standard_pw").unwrap();
+        assert_eq!(paths(&map), committed);
+        assert!(unlock_blob(&path, "hidden_pw").unwrap().2.is_empty());
+    }
+
+    #[test]
+    fn test_pre_slot_metadata_is_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.blob");
+        init_blob(&path, "standard_pw", "hidden_pw").unwrap();
+        let (volume, key, _) = unlock_blob(&path, "standard_pw").unwrap();
+
+        // Lay the volume out like a v4 blob from before slots alternated: one superblock
+        // (generation 0) pointing at a block at the start of the inline region that runs
+        // past the middle of it
+        let mut old_map = MetadataMap::new();
+        old_map.extend(bulky_entries(300));
+        let (meta_nonce, ciphertext) = encrypt_metadata(
+            &key,
+            &serialize_metadata(&old_map, &FreeSpace::default()).unwrap(),
+        )
+        .unwrap();
+        let region = inline_metadata_offset(VERSION, volume);
+        assert!(ciphertext.len() as u64 > metadata_half(volume, 0).1);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        file.seek(SeekFrom::Start(region)).unwrap();
+        file.write_all(&ciphertext).unwrap();
+        let old = Superblock {
+            meta_nonce,
+            meta_offset: region,
+            meta_size: ciphertext.len() as u64,
+            generation: 0,
+            padding: PaddingPolicy::None,
+        };
+        write_superblock(&mut file, volume, &key, 0, &old).unwrap();
+        let mut stale = [0u8; SUPERBLOCK_SLOT_LEN as usize];
+        OsRng.fill_bytes(&mut stale);
+        file.seek(SeekFrom::Start(superblock_offset(volume, 1)))
+            .unwrap();
+        file.write_all(&stale).unwrap();
+
+        // The next commit can't use the second half without clobbering the old block
+        let (_, _, mut map) = unlock_blob(&path, "standard_pw").unwrap();
+        assert_eq!(map.len(), 300);
+        add_file_entries(&path, volume, &key, &mut map, bulky_entries(1)).unwrap();
+        let superblocks = read_superblocks(&mut file, volume, &key).unwrap();
+        assert_eq!(superblocks[0].1.generation, 1);
+        assert!(superblocks[0].1.meta_offset >= DATA_AREA_START_OFFSET);
+        assert_eq!(superblocks[1].1, old);
+        let mut block = vec![0u8; ciphertext.len()];
+        file.seek(SeekFrom::Start(region)).unwrap();
+        file.read_exact(&mut block).unwrap();
+        assert_eq!(block, ciphertext);
+    }
+
+    #[test]
+    fn test_kdf_params_recorded_and_kept_by_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("light.blob");
+        let options = InitOptions {
+            kdf: KdfParams {
+                memory_kib: 8192,
+                iterations: 1,
+                ..KdfParams::default()
+            },
+            ..InitOptions::default()
+        };
+        init_blob_with_options(&path, "standard_pw", "hidden_pw", &options).unwrap();
+        let (volume, key, mut map) = unlock_blob(&path, "standard_pw").unwrap();
         add_file(
-            &tmp_path,
-            VolumeType::Standard,
-            &key_s_new,
-            &mut map_s_new,
-            relative_path,
-            &data,
-            &meta.mime_type,
-        )?;
+            &path,
+            volume,
+            &key,
+            &mut map,
+            "a.txt",
+            b"light",
+            "text/plain",
+        )
+        .unwrap();
+
+        let mut file = File::open(&path).unwrap();
+        for volume_type in [VolumeType::Standard, VolumeType::Hidden] {
+            let params = read_volume_kdf(&mut file, VERSION, volume_type).unwrap();
+            assert_eq!(params, options.kdf);
+        }
+        drop(file);
+
+        compact_blob(&path, "standard_pw", "hidden_pw").unwrap();
+        let mut file = File::open(&path).unwrap();
+        let params = read_volume_kdf(&mut file, VERSION, VolumeType::Hidden).unwrap();
+        assert_eq!(params, options.kdf);
+        let (_, key, map) = unlock_blob(&path, "standard_pw").unwrap();
+        assert_eq!(get_file(&path, &key, &map["a.txt"]).unwrap(), b"light");
     }
 
-    // 6. Do the same for every file in the hidden volume
-    for (relative_path, meta) in metadata_h.iter() {
-        let data = get_file(path, &key_h_old, meta)?;
+    #[test]
+    fn test_v3_blob_opens_and_migrates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.blob");
+        init_v3_blob(
+            &path,
+            "standard_pw",
+            "hidden_pw",
+            ("decoy.txt", b"nothing to see"),
+            ("secret.txt", b"the real thing"),
+        );
+        assert_eq!(blob_version(&path).unwrap(), VERSION_V3);
+
+        // v3 blobs keep working, including writes, without being upgraded
+        let (volume, key, mut map) = unlock_blob(&path, "standard_pw").unwrap();
+        assert_eq!(
+            get_file(&path, &key, &map["decoy.txt"]).unwrap(),
+            b"nothing to see"
+        );
         add_file(
-            &tmp_path,
-            VolumeType::Hidden,
-            &key_h_new,
-            &mut map_h_new,
-            relative_path,
-            &data,
-            &meta.mime_type,
-        )?;
+            &path,
+            volume,
+            &key,
+            &mut map,
+            "new.txt",
+            b"fresh",
+            "text/plain",
+        )
+        .unwrap();
+        assert_eq!(blob_version(&path).unwrap(), VERSION_V3);
+
+        assert!(matches!(
+            migrate_blob(&path, "standard_pw", "wrong"),
+            Err(BlobError::WrongPassword)
+        ));
+        assert!(migrate_blob(&path, "standard_pw", "hidden_pw").unwrap());
+        assert_eq!(blob_version(&path).unwrap(), VERSION);
+        assert!(!path.with_extension("migrate_bak").exists());
+
+        // Same keys, same files, in both volumes
+        let (volume, key_s, map) = unlock_blob(&path, "standard_pw").unwrap();
+        assert_eq!(volume, VolumeType::Standard);
+        assert_eq!(key_s, key);
+        assert_eq!(
+            get_file(&path, &key_s, &map["decoy.txt"]).unwrap(),
+            b"nothing to see"
+        );
+        assert_eq!(get_file(&path, &key_s, &map["new.txt"]).unwrap(), b"fresh");
+        let (volume, key_h, map) = unlock_blob(&path, "hidden_pw").unwrap();
+        assert_eq!(volume, VolumeType::Hidden);
+        assert_eq!(
+            get_file(&path, &key_h, &map["secret.txt"]).unwrap(),
+            b"the real thing"
+        );
+
+        // Already current
+        assert!(!migrate_blob(&path, "standard_pw", "hidden_pw").unwrap());
     }
 
-    // 7. Atomically swap the old blob out for the new compacted blob
-    //    First, rename the original to a .bak in case something goes wrong
-    let backup_path = path.with_extension("bak");
-    fs::rename(path, &backup_path)?;
+    #[test]
+    fn test_interrupted_migration_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("old.blob");
+        init_v3_blob(
+            &path,
+            "standard_pw",
+            "hidden_pw",
+            ("a.txt", b"aaa"),
+            ("b.txt", b"bbb"),
+        );
 
-    //    Then, move the compacted tmp file into place
-    fs::rename(&tmp_path, path)?;
+        // Simulate a crash after the backup was taken and the header region half rewritten
+        let region = fs::read(&path).unwrap()[..DATA_AREA_START_OFFSET as usize].to_vec();
+        fs::write(path.with_extension("migrate_bak"), &region).unwrap();
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all(&vec![0u8; HIDDEN_HEADER_OFFSET as usize + 100])
+            .unwrap();
+        drop(file);
+        assert!(unlock_blob(&path, "standard_pw").is_err());
 
-    //    Finally, remove the old backup blob
-    fs::remove_file(&backup_path)?;
+        assert!(recover_migration(&path).unwrap());
+        assert_eq!(blob_version(&path).unwrap(), VERSION_V3);
+        assert!(!recover_migration(&path).unwrap());
 
-    Ok(())
+        assert!(migrate_blob(&path, "standard_pw", "hidden_pw").unwrap());
+        let (_, key, map) = unlock_blob(&path, "hidden_pw").unwrap();
+        assert_eq!(get_file(&path, &key, &map["b.txt"]).unwrap(), b"bbb");
+    }
+
+    #[test]
+    fn test_change_password_rewraps_only_the_keyslot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.blob");
+        init_blob(&path, "standard_pw", "hidden_pw").unwrap();
+        let (volume, key, mut map) = unlock_blob(&path, "hidden_pw").unwrap();
+        add_file(
+            &path,
+            volume,
+            &key,
+            &mut map,
+            "secret.txt",
+            b"the real thing",
+            "text/plain",
+        )
+        .unwrap();
+        let before = fs::read(&path).unwrap();
+
+        assert!(change_password(&path, "wrong", "new_hidden_pw").is_err());
+        assert!(change_password(&path, "hidden_pw", "standard_pw").is_err());
+        assert_eq!(
+            change_password(&path, "hidden_pw", "new_hidden_pw").unwrap(),
+            VolumeType::Hidden
+        );
+
+        // Only the hidden keyslot changed
+        let after = fs::read(&path).unwrap();
+        let slot = HIDDEN_HEADER_OFFSET as usize..HIDDEN_HEADER_OFFSET as usize + KEYSLOT_LEN;
+        assert_eq!(before.len(), after.len());
+        assert_ne!(before[slot.clone()], after[slot.clone()]);
+        assert_eq!(before[..slot.start], after[..slot.start]);
+        assert_eq!(before[slot.end..], after[slot.end..]);
+
+        // Same data key, new password; the standard volume is unaffected
+        assert!(unlock_blob(&path, "hidden_pw").is_err());
+        let (volume, new_key, map) = unlock_blob(&path, "new_hidden_pw").unwrap();
+        assert_eq!(volume, VolumeType::Hidden);
+        assert_eq!(new_key, key);
+        assert_eq!(
+            get_file(&path, &new_key, &map["secret.txt"]).unwrap(),
+            b"the real thing"
+        );
+        assert_eq!(
+            unlock_blob(&path, "standard_pw").unwrap().0,
+            VolumeType::Standard
+        );
+
+        // v3 blobs have no keyslots to rewrap
+        let old_path = dir.path().join("old.blob");
+        init_v3_blob(
+            &old_path,
+            "standard_pw",
+            "hidden_pw",
+            ("a.txt", b"aaa"),
+            ("b.txt", b"bbb"),
+        );
+        assert!(matches!(
+            change_password(&old_path, "standard_pw", "other_pw"),
+            Err(BlobError::RequiresMigration(_))
+        ));
+    }
+
+    #[test]
+    fn test_unused_keyslots_look_like_real_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.blob");
+        init_blob(&path, "standard_pw", "hidden_pw").unwrap();
+        let (_, key, _) = unlock_blob(&path, "standard_pw").unwrap();
+
+        // Every slot of both volumes decodes with the same KDF parameters, used or not
+        let mut file = File::open(&path).unwrap();
+        for volume_type in [VolumeType::Standard, VolumeType::Hidden] {
+            let keyslots = read_keyslots(&mut file, volume_type).unwrap();
+            assert_eq!(keyslots.len(), KEYSLOTS_PER_VOLUME);
+            for keyslot in keyslots {
+                assert_eq!(*keyslot.unwrap().kdf(), KdfParams::default());
+            }
+        }
+
+        // Only the data key tells them apart
+        let keyslots = read_keyslots(&mut file, VolumeType::Standard).unwrap();
+        let owned: Vec<bool> = keyslots
+            .iter()
+            .map(|k| k.as_ref().unwrap().wraps(&key))
+            .collect();
+        assert!(owned[0] && owned[1]); // Password and recovery key
+        assert!(!owned[2..].iter().any(|o| *o));
+    }
 }
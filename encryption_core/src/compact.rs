@@ -0,0 +1,206 @@
+use crate::blob::{DataFormat, FileMetadata, MetadataMap};
+use crate::dedup::{random_padding, ChunkIndex};
+use crate::error::Result;
+use crate::stream::{block_padding, manifest_offset, ChunkManifest, BLOCK_ALIGN};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// --- Constants ---
+/// Bytes of blocks one compaction step moves before it commits. A larger block is still
+/// moved on its own.
+pub(crate) const STEP_BYTES: u64 = 16 * 1024 * 1024;
+/// Room a spilled metadata block needs on top of its size to be worth moving down: the
+/// commit that moves it also changes the free space it records.
+pub(crate) const METADATA_SLACK: u64 = 4096;
+/// Bytes copied at a time when a block moves.
+const COPY_BUFFER_LEN: usize = 1024 * 1024;
+
+/// What `UnlockedVolume::compact_in_place`, or a single `compact_step`, did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CompactionReport {
+    /// Bytes of blocks written further down the blob, including manifests sealed again.
+    pub moved_bytes: u64,
+    /// Bytes cut off the end of the blob file.
+    pub reclaimed_bytes: u64,
+    /// Steps run, each ending in a commit.
+    pub steps: u64,
+    /// True once nothing is left to move or cut off.
+    pub done: bool,
+}
+
+impl CompactionReport {
+    /// Adds the work of another step.
+    pub fn add(&mut self, step: CompactionReport) {
+        self.moved_bytes += step.moved_bytes;
+        self.reclaimed_bytes += step.reclaimed_bytes;
+        self.steps += step.steps;
+        self.done = step.done;
+    }
+}
+
+/// Entries as they were before a change, for `commit` to restore if committing fails.
+type Previous = Vec<(String, Option<FileMetadata>)>;
+
+/// A block of the volume that compaction can move.
+enum Block {
+    /// The data block of the file at this path (for deduplicated files, its manifest).
+    Data(String),
+    /// A chunk, wherever it is shared.
+    Chunk,
+}
+
+/// Moves up to `STEP_BYTES` of the volume's blocks, furthest into the blob first, into holes
+/// of the free space of `chunks` that lie below them, and points `metadata` at the copies.
+/// Blocks don't depend on where they are stored, so they are copied as they are; only the
+/// manifests listing a moved chunk are sealed again with its new offset, below the old one
+/// if there is room and otherwise at the end of the blob. Everything is
+/// synced before returning, but nothing is committed.
+///
+/// # Returns
+/// Bytes written, and the entries as they were.
+/// Nothing is written if no block fits in a hole below it.
+pub(crate) fn move_blocks(
+    file: &mut File,
+    key: &[u8; 32],
+    metadata: &mut MetadataMap,
+    chunks: &mut ChunkIndex,
+) -> Result<(u64, Previous)> {
+    let mut blocks = Vec::new();
+    let mut seen = HashSet::new();
+    for (path, entry) in metadata.iter() {
+        if entry.data_length > 0 {
+            // A manifest moves without the padding in front of it
+            let (offset, align) = match entry.format {
+                DataFormat::Deduplicated => (manifest_offset(entry), BLOCK_ALIGN),
+                DataFormat::ChunkedWithHeader { .. } => (entry.data_offset, BLOCK_ALIGN),
+                _ => (entry.data_offset, 1),
+            };
+            let len = entry.data_offset + entry.data_length - offset;
+            blocks.push((offset, len, align, Block::Data(path.clone())));
+        }
+        for chunk in &entry.chunks {
+            if seen.insert(chunk.offset) {
+                blocks.push((chunk.offset, chunk.stored_len(), 1, Block::Chunk));
+            }
+        }
+    }
+    blocks.sort_unstable_by_key(|block| Reverse(block.0));
+
+    let mut moved = 0;
+    let mut moved_chunks = HashMap::new();
+    let mut moved_data = HashMap::new();
+    for (offset, len, align, block) in blocks {
+        if moved >= STEP_BYTES {
+            break;
+        }
+        let Some(hole) = chunks.allocate(len, align, offset)? else {
+            continue;
+        };
+        copy_block(file, offset, hole, len)?;
+        moved += len;
+        match block {
+            Block::Data(path) => {
+                moved_data.insert(path, (hole, len));
+            }
+            Block::Chunk => {
+                moved_chunks.insert(offset, hole);
+            }
+        }
+    }
+    if moved == 0 {
+        return Ok((0, Vec::new()));
+    }
+
+    let touched: BTreeSet<&String> = metadata
+        .iter()
+        .filter(|(path, entry)| {
+            moved_data.contains_key(*path)
+                || entry
+                    .chunks
+                    .iter()
+                    .any(|chunk| moved_chunks.contains_key(&chunk.offset))
+        })
+        .map(|(path, _)| path)
+        .collect();
+    let mut updated = Vec::with_capacity(touched.len());
+    for path in touched {
+        let mut entry = metadata[path].clone();
+        let mut relisted = false;
+        for chunk in &mut entry.chunks {
+            if let Some(&offset) = moved_chunks.get(&chunk.offset) {
+                chunk.offset = offset;
+                relisted = true;
+            }
+        }
+        if relisted {
+            // The manifest keeps the path it was written under, and its block the size
+            // padding after it. A copy made above is stale, so its hole is taken again for
+            // the new manifest if that still fits.
+            let below = manifest_offset(&entry);
+            let block_len = entry.data_offset + entry.data_length - below;
+            let mut manifest =
+                ChunkManifest::read(file, key, below).map_err(|e| e.for_file(path))?;
+            if let Some(&(hole, len)) = moved_data.get(path) {
+                chunks.release(hole, len);
+                moved -= len;
+            }
+            manifest.chunks = entry.chunks.clone();
+            let sealed = manifest.seal(key)?;
+            let len = block_len.max(sealed.len() as u64);
+            (entry.data_offset, entry.data_length) =
+                match chunks.allocate(len, BLOCK_ALIGN, below)? {
+                    Some(hole) => {
+                        file.seek(SeekFrom::Start(hole))?;
+                        (hole, len)
+                    }
+                    None => {
+                        let end = file.seek(SeekFrom::End(0))?;
+                        let padding = block_padding(end);
+                        chunks.check_append(end + padding.len() as u64, len)?;
+                        file.write_all(&padding)?;
+                        (end, padding.len() as u64 + len)
+                    }
+                };
+            file.write_all(&sealed)?;
+            for piece in random_padding(len - sealed.len() as u64) {
+                file.write_all(&piece)?;
+            }
+            moved += len;
+        } else if let Some(&(hole, len)) = moved_data.get(path) {
+            entry.data_offset = hole;
+            entry.data_length = len;
+        }
+        updated.push((path.clone(), entry));
+    }
+    file.sync_data()?;
+
+    for chunk in updated.iter().flat_map(|(_, entry)| &entry.chunks) {
+        chunks.relocate(&chunk.hash, chunk.offset);
+    }
+    let previous = updated
+        .into_iter()
+        .map(|(path, entry)| {
+            let before = metadata.insert(path.clone(), entry);
+            (path, before)
+        })
+        .collect();
+    Ok((moved, previous))
+}
+
+/// Copies `len` bytes of the blob at `from` to `to`. The two ranges must not overlap.
+fn copy_block(file: &mut File, from: u64, to: u64, len: u64) -> Result<()> {
+    let mut buffer = vec![0u8; COPY_BUFFER_LEN.min(len as usize)];
+    let mut copied = 0;
+    while copied < len {
+        let n = buffer.len().min((len - copied) as usize);
+        file.seek(SeekFrom::Start(from + copied))?;
+        file.read_exact(&mut buffer[..n])?;
+        file.seek(SeekFrom::Start(to + copied))?;
+        file.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+    Ok(())
+}
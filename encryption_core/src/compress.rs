@@ -0,0 +1,132 @@
+use crate::blob::VolumeType;
+use crate::error::{BlobError, Result};
+use serde::{Deserialize, Serialize};
+
+// --- Constants ---
+/// zstd level for file chunks. Low levels already get most of the gain on text and logs.
+const ZSTD_LEVEL: i32 = 3;
+/// MIME type prefixes whose formats are compressed already.
+const COMPRESSED_PREFIXES: &[&str] = &["image/", "audio/", "video/", "font/woff"];
+/// Exceptions to `COMPRESSED_PREFIXES` that are stored uncompressed.
+const UNCOMPRESSED_MEDIA: &[&str] = &[
+    "image/svg+xml",
+    "image/bmp",
+    "image/x-ms-bmp",
+    "image/tiff",
+    "audio/wav",
+    "audio/x-wav",
+];
+/// Archive and container formats that are compressed already.
+const COMPRESSED_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/x-gzip",
+    "application/zip",
+    "application/x-7z-compressed",
+    "application/vnd.rar",
+    "application/x-rar-compressed",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/zstd",
+    "application/java-archive",
+    "application/epub+zip",
+    "application/vnd.android.package-archive",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    "application/vnd.oasis.opendocument.text",
+    "application/vnd.oasis.opendocument.spreadsheet",
+];
+
+/// How a file's content is encoded before encryption, recorded in its `FileMetadata`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Chunks are encrypted as they are.
+    #[default]
+    None,
+    /// Each chunk is zstd-compressed before encryption, unless that doesn't make it smaller
+    /// (see `ChunkRef::compressed_size`).
+    Zstd,
+}
+
+impl Compression {
+    /// The compression `add_file` and friends pick: zstd, except for MIME types that are
+    /// compressed already, and never in the hidden volume. Stored chunk sizes are visible in
+    /// the blob, and in the hidden volume they would hint at what its files contain.
+    pub fn for_file(volume_type: VolumeType, mime_type: &str) -> Self {
+        let mime_type = mime_type.to_ascii_lowercase();
+        let precompressed = COMPRESSED_TYPES.contains(&mime_type.as_str())
+            || (COMPRESSED_PREFIXES
+                .iter()
+                .any(|prefix| mime_type.starts_with(prefix))
+                && !UNCOMPRESSED_MEDIA.contains(&mime_type.as_str()));
+        if volume_type == VolumeType::Hidden || precompressed {
+            Compression::None
+        } else {
+            Compression::Zstd
+        }
+    }
+}
+
+/// Compresses one chunk.
+///
+/// # Returns
+/// The compressed chunk, or `None` if compressing didn't make it smaller.
+pub(crate) fn compress_chunk(plaintext: &[u8]) -> Result<Option<Vec<u8>>> {
+    let compressed = zstd::bulk::compress(plaintext, ZSTD_LEVEL)
+        .map_err(|_| BlobError::Crypto("chunk compression"))?;
+    Ok((compressed.len() < plaintext.len()).then_some(compressed))
+}
+
+/// Reverses `compress_chunk` for a chunk of `size` plaintext bytes.
+///
+/// # Errors
+/// `CorruptBlock` if the data doesn't decompress to exactly `size` bytes.
+pub(crate) fn decompress_chunk(compressed: &[u8], size: u32) -> Result<Vec<u8>> {
+    zstd::bulk::decompress(compressed, size as usize)
+        .ok()
+        .filter(|plaintext| plaintext.len() == size as usize)
+        .ok_or(BlobError::CorruptBlock { path: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heuristic_skips_compressed_formats_and_the_hidden_volume() {
+        let standard = |mime| Compression::for_file(VolumeType::Standard, mime);
+        assert_eq!(standard("text/plain"), Compression::Zstd);
+        assert_eq!(standard("application/json"), Compression::Zstd);
+        assert_eq!(standard("image/svg+xml"), Compression::Zstd);
+        assert_eq!(standard("image/jpeg"), Compression::None);
+        assert_eq!(standard("Video/MP4"), Compression::None);
+        assert_eq!(standard("application/zip"), Compression::None);
+        assert_eq!(
+            Compression::for_file(VolumeType::Hidden, "text/plain"),
+            Compression::None
+        );
+    }
+
+    #[test]
+    fn test_chunks_roundtrip_and_incompressible_ones_are_left_alone() {
+        let text = b"2026-10-17 INFO request served\n".repeat(1000);
+        let compressed = compress_chunk(&text).unwrap().unwrap();
+        assert!(compressed.len() < text.len() / 10);
+        assert_eq!(
+            decompress_chunk(&compressed, text.len() as u32).unwrap(),
+            text
+        );
+        assert!(decompress_chunk(&compressed, text.len() as u32 - 1).is_err());
+
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert!(compress_chunk(&noise).unwrap().is_none());
+    }
+}
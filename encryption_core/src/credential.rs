@@ -0,0 +1,205 @@
+use crate::error::{BlobError, Result};
+use crate::kdf::{derive_key, KdfParams};
+use crate::shamir::{self, Share};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+// --- Constants ---
+/// Random bytes in a recovery key (160 bits).
+const RECOVERY_KEY_LEN: usize = 20;
+/// Base32 digits in a recovery key.
+const RECOVERY_KEY_DIGITS: usize = RECOVERY_KEY_LEN * 8 / 5;
+/// Characters per dash-separated group when a recovery key is displayed.
+const RECOVERY_GROUP_LEN: usize = 4;
+/// Crockford base32 alphabet: no I, L, O or U, so keys are easy to read back and type.
+const BASE32_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// Largest keyfile accepted. Keyfiles are read into memory and hashed into the KDF in full.
+pub const MAX_KEYFILE_LEN: usize = 1024 * 1024;
+
+/// Something that opens a keyslot.
+#[derive(Debug, Clone, Copy)]
+pub enum Credential<'a> {
+    /// A password on its own.
+    Password(&'a str),
+    /// A password (which may be empty) together with the contents of a keyfile. The keyfile is
+    /// mixed into the KDF as Argon2's secret input, so the slot needs both to open.
+    PasswordAndKeyfile {
+        password: &'a str,
+        keyfile: &'a [u8],
+    },
+    /// A recovery key as returned by `init_blob`. Case, spaces and dashes are ignored.
+    RecoveryKey(&'a str),
+    /// At least the threshold number of mnemonic shares of a recovery key, as returned by
+    /// `split_recovery_key` or `add_recovery_shares`. Unlocks the same slot the recovery
+    /// key would.
+    RecoveryShares(&'a [String]),
+}
+
+impl Credential<'_> {
+    /// Derives the key-wrapping key for a slot with the given salt and KDF parameters.
+    pub(crate) fn derive(&self, salt: &[u8], params: &KdfParams) -> Result<[u8; 32]> {
+        match self {
+            Credential::Password(password) => derive_key(password.as_bytes(), None, salt, params),
+            Credential::PasswordAndKeyfile { password, keyfile } => {
+                if keyfile.is_empty() {
+                    return Err(BlobError::invalid("Keyfile is empty"));
+                }
+                if keyfile.len() > MAX_KEYFILE_LEN {
+                    return Err(BlobError::invalid(format!(
+                        "Keyfile exceeds the maximum size of {} bytes",
+                        MAX_KEYFILE_LEN
+                    )));
+                }
+                derive_key(password.as_bytes(), Some(keyfile), salt, params)
+            }
+            Credential::RecoveryKey(recovery_key) => {
+                let bytes = decode_recovery_key(recovery_key)?;
+                derive_key(&bytes, None, salt, params)
+            }
+            Credential::RecoveryShares(shares) => {
+                let bytes = combine_shares(shares)?;
+                derive_key(&bytes, None, salt, params)
+            }
+        }
+    }
+}
+
+// --- Recovery Keys ---
+
+/// Generates a new random recovery key, formatted for display
+/// (e.g. `7KQ2-M9XD-...`, 8 groups of 4 characters).
+pub(crate) fn generate_recovery_key() -> String {
+    let mut bytes = [0u8; RECOVERY_KEY_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    encode_recovery_key(&bytes)
+}
+
+fn encode_recovery_key(bytes: &[u8; RECOVERY_KEY_LEN]) -> String {
+    // 160 bits are exactly 32 base32 digits
+    let mut digits = Vec::with_capacity(RECOVERY_KEY_DIGITS);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            digits.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize]);
+        }
+    }
+    digits
+        .chunks(RECOVERY_GROUP_LEN)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Parses a recovery key typed back in. Dashes, whitespace and case are ignored, and the
+/// letters Crockford base32 leaves out are read as the digits they resemble.
+fn decode_recovery_key(text: &str) -> Result<[u8; RECOVERY_KEY_LEN]> {
+    let digits: Vec<char> = text
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .collect();
+    if digits.len() != RECOVERY_KEY_DIGITS {
+        return Err(BlobError::invalid("Recovery key has the wrong length"));
+    }
+    let mut bytes = Vec::with_capacity(RECOVERY_KEY_LEN);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in digits {
+        let c = match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        };
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|d| *d as char == c)
+            .ok_or_else(|| {
+                BlobError::invalid(format!("Recovery key contains invalid character '{}'", c))
+            })?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes
+        .try_into()
+        .expect("32 base32 digits decode to 20 bytes"))
+}
+
+// --- Recovery Shares ---
+
+/// Splits a recovery key into `count` mnemonic shares, any `threshold` of which can stand in
+/// for it. Works offline; the blob is not touched.
+///
+/// # Arguments
+/// * `recovery_key` - A recovery key as returned by `init_blob` or `add_recovery_key`
+/// * `threshold` - Number of shares needed to recover the key (at least 2)
+/// * `count` - Number of shares to create (at most 255)
+///
+/// # Returns
+/// One space-separated word list per share
+///
+/// # Errors
+/// Returns an error if the recovery key doesn't parse or the threshold is out of range
+pub fn split_recovery_key(recovery_key: &str, threshold: u8, count: u8) -> Result<Vec<String>> {
+    let bytes = decode_recovery_key(recovery_key)?;
+    Ok(shamir::split(&bytes, threshold, count)?
+        .iter()
+        .map(Share::to_mnemonic)
+        .collect())
+}
+
+/// Reassembles a recovery key from at least the threshold number of its mnemonic shares.
+///
+/// # Errors
+/// Returns an error if a share is mistyped, the shares come from different splits, or too
+/// few were given
+pub fn combine_recovery_shares(shares: &[String]) -> Result<String> {
+    Ok(encode_recovery_key(&combine_shares(shares)?))
+}
+
+fn combine_shares(mnemonics: &[String]) -> Result<[u8; RECOVERY_KEY_LEN]> {
+    let shares = mnemonics
+        .iter()
+        .map(|mnemonic| Share::from_mnemonic(mnemonic))
+        .collect::<Result<Vec<_>>>()?;
+    shamir::combine(&shares)?
+        .try_into()
+        .map_err(|_| BlobError::invalid("Shares don't hold a recovery key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recovery_key_roundtrip() {
+        let bytes: [u8; RECOVERY_KEY_LEN] = core::array::from_fn(|i| (i * 37 + 11) as u8);
+        let text = encode_recovery_key(&bytes);
+        assert_eq!(text.len(), 8 * RECOVERY_GROUP_LEN + 7);
+        assert_eq!(decode_recovery_key(&text).unwrap(), bytes);
+
+        // Sloppy typing still parses
+        let sloppy = text.replace('-', " ").to_lowercase().replace('0', "o");
+        assert_eq!(decode_recovery_key(&sloppy).unwrap(), bytes);
+
+        assert!(decode_recovery_key(&text[..text.len() - 1]).is_err());
+        assert!(decode_recovery_key(&format!("{}0", text)).is_err());
+        assert!(decode_recovery_key("UUUU").is_err());
+    }
+
+    #[test]
+    fn test_recovery_shares_roundtrip() {
+        let recovery_key = generate_recovery_key();
+        let shares = split_recovery_key(&recovery_key, 2, 3).unwrap();
+        assert_eq!(shares.len(), 3);
+        let pair = [shares[2].clone(), shares[0].clone()];
+        assert_eq!(combine_recovery_shares(&pair).unwrap(), recovery_key);
+        assert!(combine_recovery_shares(&shares[..1]).is_err());
+    }
+}
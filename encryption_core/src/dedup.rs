@@ -0,0 +1,625 @@
+use crate::blob::{
+    get_cipher, read_free_space, read_padding_policy, unix_now, ChunkRef, DataFormat, FileMetadata,
+    MetadataMap, VolumeType, DATA_AREA_START_OFFSET, XNONCE_LEN,
+};
+use crate::compress::{compress_chunk, decompress_chunk, Compression};
+use crate::error::{BlobError, Result};
+use crate::padding::PaddingPolicy;
+use crate::space::{referenced_extents, FreeSpace, Protected};
+use crate::stream::{block_padding, ChunkManifest, BLOCK_ALIGN};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::path::Path;
+
+// --- Constants ---
+/// Content-defined chunks are never shorter than this, except the last chunk of a file.
+const MIN_CHUNK_SIZE: usize = 16 * 1024;
+/// Chunk size the boundary masks aim for.
+const AVG_CHUNK_SIZE: usize = 64 * 1024;
+/// A chunk is cut here if no boundary was found. Also the most plaintext a writer buffers.
+pub(crate) const MAX_CHUNK_SIZE: usize = 256 * 1024;
+/// Boundary masks for normalized chunking: harder to match before `AVG_CHUNK_SIZE` and easier
+/// after it, so chunk sizes cluster around the average. The gear hash shifts left, so its top
+/// bits depend on the most recent bytes.
+const MASK_SMALL: u64 = !0 << (64 - 18);
+const MASK_LARGE: u64 = !0 << (64 - 14);
+/// Context for deriving a volume's chunk hash key from its data key.
+const HASH_KEY_CONTEXT: &[u8] = b"kurpod chunk hash key v1";
+
+/// Random values for the gear hash, generated with splitmix64 rather than spelled out.
+/// Changing them moves every chunk boundary and defeats deduplication against stored chunks.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6b75_7270_6f64_6764; // "kurpodgd"
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+// --- Chunking ---
+
+/// Length of the content-defined chunk at the start of `data` (FastCDC-style gear hash).
+/// `data` must hold `MAX_CHUNK_SIZE` bytes unless the content ends within it; the boundary
+/// then only depends on the bytes before it, so equal content is cut the same way wherever
+/// it appears.
+pub(crate) fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < AVG_CHUNK_SIZE {
+            MASK_SMALL
+        } else {
+            MASK_LARGE
+        };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Decrypts (and decompresses) a stored chunk read from `chunk.offset`. The chunk's hash is
+/// authenticated along with it, so a chunk can't stand in for another one of the same volume.
+///
+/// # Errors
+/// `CorruptBlock` if the chunk fails authentication or doesn't decompress to its size.
+pub(crate) fn open_chunk(
+    cipher: &XChaCha20Poly1305,
+    chunk: &ChunkRef,
+    sealed: &[u8],
+) -> Result<Vec<u8>> {
+    let corrupt = || BlobError::CorruptBlock { path: None };
+    if sealed.len() as u64 != chunk.stored_len() {
+        return Err(corrupt());
+    }
+    let (nonce, ciphertext) = sealed.split_at(XNONCE_LEN);
+    let plaintext = cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &chunk.hash,
+            },
+        )
+        .map_err(|_| corrupt())?;
+    match chunk.compressed_size {
+        Some(_) => decompress_chunk(&plaintext, chunk.size),
+        None => Ok(plaintext),
+    }
+}
+
+// --- Chunk Index ---
+
+/// The chunks stored in one volume, by keyed hash, with the number of file references to
+/// each. It is built from the chunk lists in the volume's (encrypted) metadata and never
+/// stored on its own, so the hashes are only ever visible to the volume's key holder.
+/// Hashes are keyed with a key derived from the volume's data key: equal content in the other
+/// volume, or in another blob, has unrelated hashes and is never shared.
+///
+/// Writes through an index opened with `open` also place new blocks in the space the volume
+/// has freed before growing the blob, or in a preallocated blob, before taking unused space.
+#[derive(Debug, Clone, Default)]
+pub struct ChunkIndex {
+    chunks: HashMap<[u8; 32], (ChunkRef, u64)>,
+    /// Holes that new blocks go into first.
+    free: FreeSpace,
+    /// Holes taken by writes through this index that no committed metadata references yet.
+    /// They stay taken even though the committed free space still lists them.
+    taken: Vec<(u64, u64)>,
+    /// Extents of the hidden volume that writes through this index must not touch.
+    protected: Protected,
+    /// Size buckets files written through this index are padded to.
+    padding: PaddingPolicy,
+}
+
+impl ChunkIndex {
+    /// Indexes the chunks referenced by a volume's files. Writes through the index only
+    /// append; see `open`.
+    pub fn new(metadata: &MetadataMap) -> Self {
+        let mut index = Self::default();
+        for chunk in metadata.values().flat_map(|entry| &entry.chunks) {
+            index.reference(*chunk);
+        }
+        index
+    }
+
+    /// Indexes the chunks like `new` and loads the free space the volume last committed, so
+    /// writes through the index fill the holes left by deleted data first, and its
+    /// `PaddingPolicy`. `metadata` must be the volume's committed metadata.
+    ///
+    /// # Errors
+    /// Returns an error if the blob can't be read or `key` doesn't open the volume's metadata.
+    pub fn open(
+        path: &Path,
+        volume_type: VolumeType,
+        key: &[u8; 32],
+        metadata: &MetadataMap,
+    ) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut index = Self::new(metadata);
+        index.set_free_space(read_free_space(&mut file, volume_type, key)?);
+        index.set_padding(read_padding_policy(&mut file, volume_type, key)?);
+        Ok(index)
+    }
+
+    /// Bytes of free space writes through the index may still fill.
+    pub fn free_bytes(&self) -> u64 {
+        self.free.free_bytes()
+    }
+
+    /// Replaces the free space, e.g. with what a commit recorded, keeping the holes this
+    /// index already handed out. Chunks of removed files are kept for deduplication only
+    /// until their space may be reused.
+    pub(crate) fn set_free_space(&mut self, mut free: FreeSpace) {
+        for &(offset, len) in &self.taken {
+            free.reserve(offset, len);
+        }
+        free.avoid(&self.protected);
+        self.chunks
+            .retain(|_, (chunk, _)| !free.overlaps(chunk.offset, chunk.stored_len()));
+        self.free = free;
+    }
+
+    /// Takes the free space the commit of `metadata` recorded. Holes taken for blocks that
+    /// `metadata` references are its business from now on; the others stay taken.
+    pub(crate) fn committed(&mut self, metadata: &MetadataMap, free: FreeSpace) {
+        let referenced = referenced_extents(metadata);
+        self.taken.retain(|extent| !referenced.contains(extent));
+        self.set_free_space(free);
+    }
+
+    /// Pads files written through this index to the size buckets of `padding`.
+    pub(crate) fn set_padding(&mut self, padding: PaddingPolicy) {
+        self.padding = padding;
+    }
+
+    /// Refuses writes through this index onto `protected` from now on. Free holes and unused
+    /// space of a preallocated blob are only taken around it.
+    pub(crate) fn protect(&mut self, protected: Protected) {
+        self.free.avoid(&protected);
+        self.protected = protected;
+    }
+
+    /// Fits the free space to a blob file ending at `end` (see `FreeSpace::fit_to`).
+    fn fit_to(&mut self, end: u64) {
+        self.free.fit_to(end);
+        self.free.avoid(&self.protected);
+    }
+
+    /// The free space writes through the index fill.
+    pub(crate) fn free_space(&self) -> &FreeSpace {
+        &self.free
+    }
+
+    /// Takes a hole for a block of `len` bytes starting at a multiple of `align` and ending
+    /// at or before `limit`.
+    ///
+    /// # Errors
+    /// `HiddenVolumeProtected` if the hole lies on an extent the index protects. The hole is
+    /// given back.
+    pub(crate) fn allocate(&mut self, len: u64, align: u64, limit: u64) -> Result<Option<u64>> {
+        let Some(offset) = self.free.allocate_below(len, align, limit) else {
+            return Ok(None);
+        };
+        if let Err(e) = self.protected.check(offset, len) {
+            self.free.release(offset, len);
+            return Err(e);
+        }
+        self.taken.push((offset, len));
+        Ok(Some(offset))
+    }
+
+    /// Gives back a hole taken by `allocate` that ended up unused.
+    pub(crate) fn release(&mut self, offset: u64, len: u64) {
+        if let Some(index) = self
+            .taken
+            .iter()
+            .position(|&extent| extent == (offset, len))
+        {
+            self.taken.swap_remove(index);
+            self.free.release(offset, len);
+        }
+    }
+
+    /// Checks that a block of `len` bytes may be appended at `offset`.
+    ///
+    /// # Errors
+    /// `BlobFull` in a preallocated blob, which never grows by appending, and
+    /// `HiddenVolumeProtected` if the block would land on an extent the index protects.
+    pub(crate) fn check_append(&self, offset: u64, len: u64) -> Result<()> {
+        if self.free.is_preallocated() {
+            return Err(BlobError::BlobFull);
+        }
+        self.protected.check(offset, len)
+    }
+
+    /// Records that the chunk with this hash now lives at `offset`.
+    pub(crate) fn relocate(&mut self, hash: &[u8; 32], offset: u64) {
+        if let Some((chunk, _)) = self.chunks.get_mut(hash) {
+            chunk.offset = offset;
+        }
+    }
+
+    /// Number of distinct chunks.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Returns true if no chunks are indexed.
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Number of file references to the chunk with this hash (zero if it isn't indexed).
+    pub fn references(&self, hash: &[u8; 32]) -> u64 {
+        self.chunks.get(hash).map_or(0, |(_, refs)| *refs)
+    }
+
+    /// On-disk bytes of the distinct chunks, each counted once however often it is
+    /// referenced.
+    pub fn stored_bytes(&self) -> u64 {
+        self.chunks
+            .values()
+            .map(|(chunk, _)| chunk.stored_len())
+            .sum()
+    }
+
+    fn get(&self, hash: &[u8; 32]) -> Option<ChunkRef> {
+        self.chunks.get(hash).map(|(chunk, _)| *chunk)
+    }
+
+    /// Counts one more reference to `chunk`, indexing it if it is new.
+    fn reference(&mut self, chunk: ChunkRef) {
+        self.chunks.entry(chunk.hash).or_insert((chunk, 0)).1 += 1;
+    }
+}
+
+// --- Writing ---
+
+/// State of one deduplicating file write. The caller reads the content, cuts it with
+/// `cut_point`, passes each chunk to `seal` and writes whatever it returns where it says,
+/// then does the same with the output of `finish`. Chunks are only indexed once the caller
+/// reports them written. Blocks go into holes from the index's free space where they fit and
+/// are appended otherwise.
+pub(crate) struct DedupWriter<'a> {
+    cipher: XChaCha20Poly1305,
+    hash_key: [u8; 32],
+    compression: Compression,
+    index: &'a mut ChunkIndex,
+    /// Offset the next appended byte lands at.
+    end: u64,
+    /// Where the bytes returned by the last `seal` end, if they were appended.
+    appended_to: Option<u64>,
+    chunks: Vec<ChunkRef>,
+    /// Chunks written by this write, so content repeated within the file is stored once.
+    new_chunks: HashMap<[u8; 32], ChunkRef>,
+    size: u64,
+    /// SHA-256 of the whole content so far.
+    content_hash: Sha256,
+}
+
+impl<'a> DedupWriter<'a> {
+    /// Starts a write to a blob currently ending at `end`. New chunks are stored with
+    /// `compression`. Space `grow_blob` added to a preallocated blob since the index was
+    /// opened is taken into account.
+    pub(crate) fn new(
+        key: &[u8; 32],
+        index: &'a mut ChunkIndex,
+        end: u64,
+        compression: Compression,
+    ) -> Self {
+        index.fit_to(end);
+        Self {
+            cipher: get_cipher(key),
+            hash_key: keyed_hash(key, HASH_KEY_CONTEXT),
+            compression,
+            index,
+            end,
+            appended_to: None,
+            chunks: Vec::new(),
+            new_chunks: HashMap::new(),
+            size: 0,
+            content_hash: Sha256::new(),
+        }
+    }
+
+    /// Where a block of `len` bytes goes: a hole starting at a multiple of `align`, or the
+    /// end of the blob after the padding that keeps it inside the data area and aligned.
+    ///
+    /// # Returns
+    /// The offset to write at, the padding to write first and the offset the block lands at.
+    ///
+    /// # Errors
+    /// As for `ChunkIndex::check_append`.
+    fn place(&mut self, len: u64, align: u64) -> Result<(u64, Vec<u8>, u64)> {
+        if let Some(hole) = self.index.allocate(len, align, u64::MAX)? {
+            return Ok((hole, Vec::new(), hole));
+        }
+        let padding = if self.end < DATA_AREA_START_OFFSET || align > 1 {
+            block_padding(self.end)
+        } else {
+            Vec::new()
+        };
+        let start = self.end + padding.len() as u64;
+        self.index.check_append(start, len)?;
+        Ok((self.end, padding, start))
+    }
+
+    /// Adds the next chunk of the file.
+    ///
+    /// # Returns
+    /// The offset to write at and the bytes to write there, or `None` if the volume already
+    /// stores this content.
+    pub(crate) fn seal(&mut self, plaintext: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+        let size = u32::try_from(plaintext.len()).expect("chunks are at most MAX_CHUNK_SIZE");
+        let hash = keyed_hash(&self.hash_key, plaintext);
+        self.size += size as u64;
+        self.content_hash.update(plaintext);
+        let existing = self.new_chunks.get(&hash).copied();
+        if let Some(existing) = existing.or_else(|| self.index.get(&hash)) {
+            self.chunks.push(existing);
+            return Ok(None);
+        }
+        let compressed = match self.compression {
+            Compression::Zstd => compress_chunk(plaintext)?,
+            Compression::None => None,
+        };
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(
+                    &nonce,
+                    Payload {
+                        msg: compressed.as_deref().unwrap_or(plaintext),
+                        aad: &hash,
+                    },
+                )
+                .map_err(|_| BlobError::Crypto("chunk encryption"))?,
+        );
+        let (write_at, mut bytes, offset) = self.place(sealed.len() as u64, 1)?;
+        bytes.extend_from_slice(&sealed);
+        self.appended_to = (write_at == self.end).then_some(write_at + bytes.len() as u64);
+        self.chunks.push(ChunkRef {
+            hash,
+            offset,
+            size,
+            compressed_size: compressed.map(|compressed| compressed.len() as u32),
+        });
+        Ok(Some((write_at, bytes)))
+    }
+
+    /// Records that the chunk returned by the last `seal` call is written. Must be called
+    /// before the next `seal`, and only once the write succeeded.
+    pub(crate) fn written(&mut self) {
+        let chunk = *self.chunks.last().expect("a chunk was sealed");
+        self.new_chunks.insert(chunk.hash, chunk);
+        if let Some(end) = self.appended_to.take() {
+            self.end = end;
+        }
+    }
+
+    /// Seals the file's manifest, which names it for `salvage_blob` and lists its chunks.
+    ///
+    /// Everything the file adds to the blob, its new chunks and the manifest, is padded up to
+    /// the size bucket of the index's `PaddingPolicy` with random bytes after the manifest.
+    ///
+    /// # Returns
+    /// The offset to write at, the bytes to write there (any alignment padding, then the
+    /// manifest), how many random bytes to write right after them (see `random_padding`) and
+    /// the file's metadata, valid once all of it is written and synced. Its timestamps are
+    /// all the current time.
+    pub(crate) fn finish(
+        mut self,
+        key: &[u8; 32],
+        file_path: &str,
+        mime_type: &str,
+    ) -> Result<(u64, Vec<u8>, u64, FileMetadata)> {
+        let manifest = ChunkManifest {
+            path: file_path.to_string(),
+            mime_type: mime_type.to_string(),
+            size: self.size,
+            chunks: std::mem::take(&mut self.chunks),
+            compression: self.compression,
+        };
+        let sealed = manifest.seal(key)?;
+        // Counted as if the manifest is appended, where the blob then grows by a whole bucket
+        let stored: u64 = self.new_chunks.values().map(ChunkRef::stored_len).sum();
+        let alignment = self.end.next_multiple_of(BLOCK_ALIGN) - self.end;
+        let len = sealed.len() as u64;
+        let added = stored + alignment + len;
+        let padding = self.index.padding.padded_len(added) - added;
+        // Manifests start on a block boundary, where `salvage_blob` looks for them. The
+        // padding before an appended one is part of the file's block, so it is freed with it.
+        let (write_at, mut bytes, _) = self.place(len + padding, BLOCK_ALIGN)?;
+        bytes.extend_from_slice(&sealed);
+        let data_offset = write_at.max(DATA_AREA_START_OFFSET);
+        let data_length = write_at + bytes.len() as u64 + padding - data_offset;
+
+        let now = unix_now();
+        // Index the chunks only now that all of them are written
+        for chunk in &manifest.chunks {
+            self.index.reference(*chunk);
+        }
+        Ok((
+            write_at,
+            bytes,
+            padding,
+            FileMetadata {
+                size: manifest.size,
+                data_offset,
+                data_length,
+                mime_type: manifest.mime_type,
+                format: DataFormat::Deduplicated,
+                chunks: manifest.chunks,
+                compression: manifest.compression,
+                created_at: Some(now),
+                modified_at: Some(now),
+                uploaded_at: Some(now),
+                sha256: Some(self.content_hash.finalize().into()),
+                original_mode: None,
+                original_mtime: None,
+                attributes: BTreeMap::new(),
+            },
+        ))
+    }
+}
+
+/// `len` random bytes in pieces of at most `MAX_CHUNK_SIZE`, for the size padding that
+/// `DedupWriter::finish` asks for.
+pub(crate) fn random_padding(len: u64) -> impl Iterator<Item = Vec<u8>> {
+    (0..len).step_by(MAX_CHUNK_SIZE).map(move |start| {
+        let mut piece = vec![0u8; (len - start).min(MAX_CHUNK_SIZE as u64) as usize];
+        OsRng.fill_bytes(&mut piece);
+        piece
+    })
+}
+
+/// HMAC-SHA256 of `data` under `key`.
+fn keyed_hash(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random bytes (xorshift), so boundaries fall where they would in
+    /// real content.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Splits `data` the way the writers do.
+    fn chunk_lengths(data: &[u8]) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let len = cut_point(&rest[..rest.len().min(MAX_CHUNK_SIZE)]);
+            lengths.push(len);
+            rest = &rest[len..];
+        }
+        lengths
+    }
+
+    #[test]
+    fn test_chunk_sizes_stay_within_bounds() {
+        let data = noise(4 * 1024 * 1024, 7);
+        let lengths = chunk_lengths(&data);
+        let (last, rest) = lengths.split_last().unwrap();
+        assert!(*last <= MAX_CHUNK_SIZE);
+        assert!(rest
+            .iter()
+            .all(|len| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(len)));
+        let average = data.len() / lengths.len();
+        assert!(
+            (AVG_CHUNK_SIZE / 2..AVG_CHUNK_SIZE * 2).contains(&average),
+            "average chunk size {}",
+            average
+        );
+    }
+
+    #[test]
+    fn test_boundaries_resynchronize_after_an_insertion() {
+        let data = noise(2 * 1024 * 1024, 11);
+        let mut shifted = b"a few inserted bytes".to_vec();
+        shifted.extend_from_slice(&data);
+
+        // Apart from the first chunk or two, both split into the same chunks
+        let ends = |data: &[u8], skip: usize| {
+            let mut end = 0;
+            chunk_lengths(data)
+                .into_iter()
+                .map(|len| {
+                    end += len;
+                    end - skip
+                })
+                .collect::<Vec<_>>()
+        };
+        let original = ends(&data, 0);
+        let moved = ends(&shifted, 20);
+        let shared = original.iter().filter(|end| moved.contains(end)).count();
+        assert!(
+            shared + 2 >= original.len(),
+            "{} of {}",
+            shared,
+            original.len()
+        );
+    }
+
+    #[test]
+    fn test_blocks_never_land_on_protected_extents() {
+        let key = [7u8; 32];
+        let hole = DATA_AREA_START_OFFSET.next_multiple_of(BLOCK_ALIGN);
+        let mut free = FreeSpace::default();
+        free.release(hole, 1 << 20);
+        let mut index = ChunkIndex::default();
+        index.set_free_space(free);
+        let mut protected = Protected::default();
+        protected.insert(hole + 4096, 64);
+        index.protect(protected);
+
+        // A chunk fits in front of the protected extent, the next one goes past it
+        let mut writer = DedupWriter::new(&key, &mut index, hole + (2 << 20), Compression::None);
+        let (offset, _) = writer.seal(&noise(1000, 3)).unwrap().unwrap();
+        assert_eq!(offset, hole);
+        writer.written();
+        let (offset, _) = writer.seal(&noise(8192, 5)).unwrap().unwrap();
+        assert!(offset >= hole + 4096 + 64, "{}", offset);
+        writer.written();
+        drop(writer);
+
+        // Holes freed later are taken around the protected extent as well
+        let mut free = FreeSpace::default();
+        free.release(hole, 1 << 20);
+        index.set_free_space(free);
+        assert!(!index.free_space().overlaps(hole + 4096, 64));
+        let mut writer = DedupWriter::new(&key, &mut index, hole + (2 << 20), Compression::None);
+        for seed in [7, 9, 11, 13, 15] {
+            let (offset, sealed) = writer.seal(&noise(2000, seed)).unwrap().unwrap();
+            let end = offset + sealed.len() as u64;
+            assert!(end <= hole + 4096 || offset >= hole + 4096 + 64);
+            writer.written();
+        }
+
+        // Appending onto a protected extent is still refused
+        let mut protected = Protected::default();
+        protected.insert(hole + (2 << 20), 64);
+        index.protect(protected);
+        assert!(matches!(
+            index.check_append(hole + (2 << 20), 100),
+            Err(BlobError::HiddenVolumeProtected)
+        ));
+    }
+}
@@ -0,0 +1,157 @@
+use std::io;
+use thiserror::Error;
+
+/// Errors returned by the blob API.
+///
+/// Unlock failures are deliberately coarse: a wrong password and a volume that doesn't exist
+/// both surface as `WrongPassword`, so errors never reveal whether a hidden volume is present.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum BlobError {
+    /// The credential opens neither volume.
+    #[error("Invalid password or corrupted blob")]
+    WrongPassword,
+    /// The file doesn't start with the blob magic bytes.
+    #[error("Not a blob file")]
+    NotABlob,
+    /// The blob's format version is newer (or older) than this build supports.
+    #[error("Unsupported blob version v{0}")]
+    UnsupportedVersion(u8),
+    /// The operation needs the current format; the blob has to be migrated first.
+    #[error("{0} (migrate the blob to v4 first)")]
+    RequiresMigration(&'static str),
+    /// The encrypted metadata doesn't fit in the volume's metadata region.
+    #[error("Metadata too large: {size} bytes exceeds the {capacity} byte metadata region")]
+    MetadataTooLarge { size: u64, capacity: u64 },
+    /// A file's data block failed authentication or doesn't match its metadata. `path` is the
+    /// file's path inside the volume, when the caller knows it (see `for_file`).
+    #[error("Corrupt data block{}", .path.as_ref().map(|p| format!(" for '{}'", p)).unwrap_or_default())]
+    CorruptBlock { path: Option<String> },
+    /// The unlocked volume has no file at this path.
+    #[error("File not found: {0}")]
+    FileNotFound(String),
+    /// The volume opened, but its superblock or metadata is damaged.
+    #[error("Corrupt blob: {0}")]
+    Corrupt(String),
+    /// Every keyslot of the volume is in use.
+    #[error("All keyslots are in use")]
+    KeyslotsFull,
+    /// The credential already opens a keyslot of this or the other volume.
+    #[error("The credential is already in use")]
+    CredentialInUse,
+    /// The keyslot isn't in use by the unlocked volume.
+    #[error("Keyslot {0} is not in use")]
+    KeyslotNotInUse(usize),
+    /// Revoking the keyslot would leave the volume without any way to open it.
+    #[error("Can't revoke the last keyslot of a volume")]
+    LastKeyslot,
+    /// The key passed in is not the data key of the given volume.
+    #[error("Key does not belong to the {0} volume")]
+    WrongVolumeKey(&'static str),
+    /// The write would land on data of the hidden volume, which the standard volume was
+    /// unlocked to protect.
+    #[error("Write refused: it would overwrite the protected hidden volume")]
+    HiddenVolumeProtected,
+    /// The write doesn't fit in the space left in a preallocated blob, which never grows by
+    /// itself (see `grow_blob`).
+    #[error("No space left in the preallocated blob")]
+    BlobFull,
+    /// An argument was rejected (identical passwords, bad KDF parameters, a malformed
+    /// recovery key or share, an out-of-range read, ...).
+    #[error("{0}")]
+    InvalidInput(String),
+    /// Encoding or encryption failed. Only happens for inputs beyond the format's limits.
+    #[error("Encryption failed: {0}")]
+    Crypto(&'static str),
+    /// Reading or writing the blob file failed.
+    #[error("I/O error: {0}")]
+    Io(io::Error),
+}
+
+pub(crate) type Result<T, E = BlobError> = std::result::Result<T, E>;
+
+impl BlobError {
+    /// A stable, machine-readable identifier for the error kind, e.g. for API clients.
+    /// Codes never change once released; new variants get new codes.
+    pub fn code(&self) -> &'static str {
+        match self {
+            BlobError::WrongPassword => "wrong_password",
+            BlobError::NotABlob => "not_a_blob",
+            BlobError::UnsupportedVersion(_) => "unsupported_version",
+            BlobError::RequiresMigration(_) => "requires_migration",
+            BlobError::MetadataTooLarge { .. } => "metadata_too_large",
+            BlobError::CorruptBlock { .. } => "corrupt_block",
+            BlobError::FileNotFound(_) => "not_found",
+            BlobError::Corrupt(_) => "corrupt_blob",
+            BlobError::KeyslotsFull => "keyslots_full",
+            BlobError::CredentialInUse => "credential_in_use",
+            BlobError::KeyslotNotInUse(_) => "keyslot_not_in_use",
+            BlobError::LastKeyslot => "last_keyslot",
+            BlobError::WrongVolumeKey(_) => "wrong_volume_key",
+            BlobError::HiddenVolumeProtected => "hidden_volume_protected",
+            BlobError::BlobFull => "blob_full",
+            BlobError::InvalidInput(_) => "invalid_input",
+            BlobError::Crypto(_) => "crypto_failure",
+            BlobError::Io(e) if e.kind() == io::ErrorKind::NotFound => "not_found",
+            BlobError::Io(_) => "io_error",
+        }
+    }
+
+    /// Names the file a `CorruptBlock` error belongs to. Other errors are returned unchanged.
+    pub fn for_file(self, path: &str) -> Self {
+        match self {
+            BlobError::CorruptBlock { path: None } => BlobError::CorruptBlock {
+                path: Some(path.to_string()),
+            },
+            other => other,
+        }
+    }
+
+    pub(crate) fn invalid(message: impl Into<String>) -> Self {
+        BlobError::InvalidInput(message.into())
+    }
+
+    pub(crate) fn corrupt(message: impl Into<String>) -> Self {
+        BlobError::Corrupt(message.into())
+    }
+}
+
+impl From<io::Error> for BlobError {
+    /// `FileReader` reports blob errors through `io::Error`; unwrap those again so callers
+    /// reading through it see the original error.
+    fn from(e: io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<BlobError>()) {
+            return *e
+                .into_inner()
+                .and_then(|inner| inner.downcast::<BlobError>().ok())
+                .expect("checked above");
+        }
+        BlobError::Io(e)
+    }
+}
+
+impl From<BlobError> for io::Error {
+    fn from(e: BlobError) -> Self {
+        match e {
+            BlobError::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blob_errors_survive_io_roundtrip() {
+        let err: io::Error = BlobError::CorruptBlock { path: None }.into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let back = BlobError::from(err).for_file("docs/a.txt");
+        assert_eq!(back.code(), "corrupt_block");
+        assert_eq!(back.to_string(), "Corrupt data block for 'docs/a.txt'");
+
+        let missing = BlobError::from(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(missing.code(), "not_found");
+    }
+}
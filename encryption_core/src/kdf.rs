@@ -0,0 +1,189 @@
+use crate::error::{BlobError, Result};
+use argon2::{Argon2, Params};
+use std::time::{Duration, Instant};
+
+// --- Constants ---
+/// Encoded size of `KdfParams` in a volume header: algorithm id + three u32 cost fields.
+pub(crate) const KDF_PARAMS_LEN: usize = 1 + 4 + 4 + 4;
+const ARGON2ID_ID: u8 = 1;
+/// Upper bounds accepted from a blob header, so a crafted blob can't make unlocking
+/// allocate unbounded memory or spin forever.
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024; // 4 GiB
+const MAX_ITERATIONS: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+/// Lowest memory cost `calibrate` will go down to on slow devices.
+const MIN_CALIBRATED_MEMORY_KIB: u32 = 8 * 1024; // 8 MiB
+
+/// Password hashing algorithm used to derive a volume's key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfAlgorithm {
+    Argon2id,
+}
+
+/// Key derivation algorithm and cost parameters, recorded per volume in the blob header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    /// Memory cost in KiB.
+    pub memory_kib: u32,
+    /// Number of passes over the memory.
+    pub iterations: u32,
+    /// Degree of parallelism (lanes).
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// The parameters every blob used before they became configurable:
+    /// Argon2id with 64 MiB memory, 3 iterations, 1 lane.
+    fn default() -> Self {
+        KdfParams {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory_kib: 65536,
+            iterations: 3,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Picks parameters that make one key derivation take roughly `target` on this machine.
+    /// Starts from the default memory cost and scales the iteration count; on devices where a
+    /// single pass already exceeds the target, the memory cost is halved (down to 8 MiB) first.
+    ///
+    /// # Errors
+    /// Returns an error if a trial derivation fails.
+    pub fn calibrate(target: Duration) -> Result<Self> {
+        let mut params = KdfParams {
+            iterations: 1,
+            ..KdfParams::default()
+        };
+        loop {
+            let start = Instant::now();
+            derive_key(b"calibration", None, &[0u8; 16], &params)?;
+            let elapsed = start.elapsed().max(Duration::from_millis(1));
+
+            if elapsed > target && params.memory_kib / 2 >= MIN_CALIBRATED_MEMORY_KIB {
+                params.memory_kib /= 2;
+                continue;
+            }
+            let passes = target.as_secs_f64() / elapsed.as_secs_f64();
+            params.iterations = (passes as u32).clamp(1, MAX_ITERATIONS);
+            return Ok(params);
+        }
+    }
+
+    /// Checks the parameters are usable and within the bounds accepted when opening a blob.
+    pub fn validate(&self) -> Result<()> {
+        if self.memory_kib > MAX_MEMORY_KIB
+            || self.iterations > MAX_ITERATIONS
+            || self.parallelism > MAX_PARALLELISM
+        {
+            return Err(BlobError::invalid(format!(
+                "KDF parameters exceed supported limits: {:?}",
+                self
+            )));
+        }
+        self.argon2_params().map(|_| ())
+    }
+
+    fn argon2_params(&self) -> Result<Params> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, None)
+            .map_err(|e| BlobError::invalid(format!("Invalid KDF parameters: {}", e)))
+    }
+
+    /// Encodes the parameters for the volume header.
+    pub(crate) fn to_bytes(self) -> [u8; KDF_PARAMS_LEN] {
+        let mut bytes = [0u8; KDF_PARAMS_LEN];
+        bytes[0] = match self.algorithm {
+            KdfAlgorithm::Argon2id => ARGON2ID_ID,
+        };
+        bytes[1..5].copy_from_slice(&self.memory_kib.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.iterations.to_le_bytes());
+        bytes[9..13].copy_from_slice(&self.parallelism.to_le_bytes());
+        bytes
+    }
+
+    /// Decodes and validates parameters read from a volume header.
+    pub(crate) fn from_bytes(bytes: &[u8; KDF_PARAMS_LEN]) -> Result<Self> {
+        let algorithm = match bytes[0] {
+            ARGON2ID_ID => KdfAlgorithm::Argon2id,
+            other => {
+                return Err(BlobError::invalid(format!(
+                    "Unknown KDF algorithm id {}",
+                    other
+                )))
+            }
+        };
+        let field = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let params = KdfParams {
+            algorithm,
+            memory_kib: field(1),
+            iterations: field(5),
+            parallelism: field(9),
+        };
+        params.validate()?;
+        Ok(params)
+    }
+}
+
+/// Derives a 32-byte key from a password and salt with the given KDF parameters.
+pub(crate) fn derive_key(
+    password: &[u8],
+    secret: Option<&[u8]>,
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<[u8; 32]> {
+    let argon2 = match (params.algorithm, secret) {
+        (KdfAlgorithm::Argon2id, None) => Argon2::new(
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            params.argon2_params()?,
+        ),
+        (KdfAlgorithm::Argon2id, Some(secret)) => Argon2::new_with_secret(
+            secret,
+            argon2::Algorithm::Argon2id,
+            argon2::Version::V0x13,
+            params.argon2_params()?,
+        )
+        .map_err(|e| BlobError::invalid(format!("Invalid keyfile: {}", e)))?,
+    };
+    let mut key = [0u8; 32];
+    argon2
+        .hash_password_into(password, salt, &mut key)
+        .map_err(|e| BlobError::invalid(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_params_roundtrip_and_limits() {
+        let params = KdfParams {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory_kib: 19456,
+            iterations: 2,
+            parallelism: 1,
+        };
+        assert_eq!(KdfParams::from_bytes(&params.to_bytes()).unwrap(), params);
+
+        // A crafted header can't demand absurd costs or unknown algorithms
+        let greedy = KdfParams {
+            memory_kib: u32::MAX,
+            ..params
+        };
+        assert!(KdfParams::from_bytes(&greedy.to_bytes()).is_err());
+        let mut unknown = params.to_bytes();
+        unknown[0] = 0xff;
+        assert!(KdfParams::from_bytes(&unknown).is_err());
+    }
+
+    #[test]
+    fn test_calibrate_targets_duration() {
+        let params = KdfParams::calibrate(Duration::from_millis(30)).unwrap();
+        params.validate().unwrap();
+        assert!(params.memory_kib >= MIN_CALIBRATED_MEMORY_KIB);
+        assert!(params.iterations >= 1);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::blob::{get_cipher, SALT_LEN, XNONCE_LEN};
+use crate::credential::Credential;
+use crate::error::{BlobError, Result};
+use crate::kdf::{KdfParams, KDF_PARAMS_LEN};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+// --- Constants ---
+/// Number of keyslots in every volume header, used or not. Decoys can't be told apart from
+/// used slots, so opening a volume tries each slot in turn: a wrong password, or the hidden
+/// volume's, costs up to `2 * KEYSLOTS_PER_VOLUME` key derivations at the slots' KDF cost (a
+/// few seconds at the default cost). A volume can't have fewer slots without showing how many
+/// credentials it has.
+pub const KEYSLOTS_PER_VOLUME: usize = 8;
+/// A 32-byte data key plus the Poly1305 tag of its wrapping.
+const WRAPPED_KEY_LEN: usize = 32 + 16;
+/// Poly1305 tag binding a slot to the data key it wraps.
+const CHECK_LEN: usize = 16;
+/// Encoded keyslot: Salt + KdfParams + WrapNonce + WrappedKey + Check.
+pub(crate) const KEYSLOT_LEN: usize =
+    SALT_LEN + KDF_PARAMS_LEN + XNONCE_LEN + WRAPPED_KEY_LEN + CHECK_LEN;
+
+/// A volume's data key, wrapped (encrypted) with a key derived from one password (or other
+/// `Credential`).
+/// Files and metadata are encrypted with the data key, so a password can be changed by
+/// rewriting the keyslot alone, and several passwords can share a volume by each having a
+/// slot.
+///
+/// Unused slots hold decoys: random bytes with plausible KDF parameters, which can't be told
+/// apart from a real slot without a password. The `check` tag (an AEAD tag over the rest of
+/// the slot, keyed by the data key) lets the holder of the data key tell which slots are its
+/// own without unwrapping them.
+///
+/// Headerless blobs (see `InitOptions::headerless`) store random bytes in place of the KDF
+/// parameters, which are then always the defaults, so no slot has any visible structure.
+#[derive(Clone)]
+pub(crate) struct Keyslot {
+    salt: [u8; SALT_LEN],
+    kdf: KdfParams,
+    nonce: [u8; XNONCE_LEN],
+    wrapped: [u8; WRAPPED_KEY_LEN],
+    check: [u8; CHECK_LEN],
+}
+
+impl Keyslot {
+    /// Wraps `data_key` with a key derived from `credential` under a fresh salt.
+    pub(crate) fn seal(
+        credential: &Credential,
+        data_key: &[u8; 32],
+        kdf: &KdfParams,
+    ) -> Result<Self> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let wrapping_key = credential.derive(&salt, kdf)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = get_cipher(&wrapping_key)
+            .encrypt(&nonce, data_key.as_ref())
+            .map_err(|_| BlobError::Crypto("key wrapping"))?;
+
+        let mut slot = Keyslot {
+            salt,
+            kdf: *kdf,
+            nonce: [0u8; XNONCE_LEN],
+            wrapped: [0u8; WRAPPED_KEY_LEN],
+            check: [0u8; CHECK_LEN],
+        };
+        slot.nonce.copy_from_slice(nonce.as_slice());
+        slot.wrapped.copy_from_slice(&ciphertext);
+        let check = slot.check_tag(data_key)?;
+        slot.check.copy_from_slice(&check);
+        Ok(slot)
+    }
+
+    /// A random slot that no password opens, recording `kdf` like a real one would.
+    pub(crate) fn decoy(kdf: &KdfParams) -> Self {
+        let mut slot = Keyslot {
+            salt: [0u8; SALT_LEN],
+            kdf: *kdf,
+            nonce: [0u8; XNONCE_LEN],
+            wrapped: [0u8; WRAPPED_KEY_LEN],
+            check: [0u8; CHECK_LEN],
+        };
+        OsRng.fill_bytes(&mut slot.salt);
+        OsRng.fill_bytes(&mut slot.nonce);
+        OsRng.fill_bytes(&mut slot.wrapped);
+        OsRng.fill_bytes(&mut slot.check);
+        slot
+    }
+
+    /// Tag over the slot's other fields under `data_key`. The wrap nonce is reused, which is
+    /// safe because the wrapping key and the data key are independent.
+    fn check_tag(&self, data_key: &[u8; 32]) -> Result<Vec<u8>> {
+        let mut aad = Vec::with_capacity(KEYSLOT_LEN);
+        aad.extend_from_slice(&self.salt);
+        aad.extend_from_slice(&self.kdf.to_bytes());
+        aad.extend_from_slice(&self.wrapped);
+        get_cipher(data_key)
+            .encrypt(
+                XNonce::from_slice(&self.nonce),
+                Payload {
+                    msg: &[],
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| BlobError::Crypto("keyslot check"))
+    }
+
+    /// Returns true if this slot wraps `data_key` (i.e. it is in use by that volume).
+    /// Costs one AEAD operation, no key derivation.
+    pub(crate) fn wraps(&self, data_key: &[u8; 32]) -> bool {
+        matches!(self.check_tag(data_key), Ok(tag) if tag == self.check)
+    }
+
+    /// Recovers the data key if `credential` is the one this slot was sealed with.
+    pub(crate) fn open(&self, credential: &Credential) -> Result<[u8; 32]> {
+        let wrapping_key = credential.derive(&self.salt, &self.kdf)?;
+        let plaintext = get_cipher(&wrapping_key)
+            .decrypt(XNonce::from_slice(&self.nonce), self.wrapped.as_ref())
+            .map_err(|_| BlobError::WrongPassword)?;
+        let mut data_key = [0u8; 32];
+        data_key.copy_from_slice(&plaintext);
+        Ok(data_key)
+    }
+
+    pub(crate) fn kdf(&self) -> &KdfParams {
+        &self.kdf
+    }
+
+    /// Encodes the slot for the volume header. `headerless` leaves the KDF parameters out.
+    pub(crate) fn to_bytes(&self, headerless: bool) -> [u8; KEYSLOT_LEN] {
+        let mut bytes = [0u8; KEYSLOT_LEN];
+        let (salt, rest) = bytes.split_at_mut(SALT_LEN);
+        let (kdf, rest) = rest.split_at_mut(KDF_PARAMS_LEN);
+        let (nonce, rest) = rest.split_at_mut(XNONCE_LEN);
+        let (wrapped, check) = rest.split_at_mut(WRAPPED_KEY_LEN);
+        salt.copy_from_slice(&self.salt);
+        if headerless {
+            OsRng.fill_bytes(kdf);
+        } else {
+            kdf.copy_from_slice(&self.kdf.to_bytes());
+        }
+        nonce.copy_from_slice(&self.nonce);
+        wrapped.copy_from_slice(&self.wrapped);
+        check.copy_from_slice(&self.check);
+        bytes
+    }
+
+    /// Decodes a slot read from a volume header, validating its KDF parameters. A slot of a
+    /// headerless blob always decodes, with the default parameters.
+    pub(crate) fn from_bytes(bytes: &[u8; KEYSLOT_LEN], headerless: bool) -> Result<Self> {
+        let (salt, rest) = bytes.split_at(SALT_LEN);
+        let (kdf, rest) = rest.split_at(KDF_PARAMS_LEN);
+        let (nonce, rest) = rest.split_at(XNONCE_LEN);
+        let (wrapped, check) = rest.split_at(WRAPPED_KEY_LEN);
+        Ok(Keyslot {
+            salt: salt.try_into().unwrap(),
+            kdf: if headerless {
+                KdfParams::default()
+            } else {
+                KdfParams::from_bytes(kdf.try_into().unwrap())?
+            },
+            nonce: nonce.try_into().unwrap(),
+            wrapped: wrapped.try_into().unwrap(),
+            check: check.try_into().unwrap(),
+        })
+    }
+}
+
+/// Generates a fresh random data key for a new volume.
+pub(crate) fn generate_data_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
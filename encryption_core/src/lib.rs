@@ -1,6 +1,41 @@
+#[cfg(feature = "async")]
+mod async_io;
 mod blob;
+mod compact;
+mod compress;
+mod credential;
+mod dedup;
+mod error;
+mod kdf;
+mod keyslot;
+mod padding;
+mod salvage;
+mod shamir;
+mod space;
+mod stream;
+mod verify;
+mod volume;
+mod wordlist;
 
+#[cfg(feature = "async")]
+pub use async_io::{add_file_from_async_reader, write_file_data_async};
 pub use blob::{
-    add_file, compact_blob, get_file, init_blob, remove_file, remove_folder, rename_file,
-    unlock_blob, FileMetadata, MetadataMap, VolumeType, XNONCE_LEN,
+    add_file, add_file_entries, add_file_from_reader, add_keyslot, add_recovery_key,
+    add_recovery_shares, blob_kind, blob_version, change_password, compact_blob,
+    compact_volume_step, create_folder, get_file, grow_blob, init_blob, init_blob_with_options,
+    list_keyslots, migrate_blob, recover_migration, remove_file, remove_folder, rename_file,
+    revoke_keyslot, unlock_blob, unlock_blob_with_credential, write_file_data, BlobKind, ChunkRef,
+    DataFormat, FileMetadata, InitOptions, MetadataMap, RecoveryKeys, VolumeType, XNONCE_LEN,
 };
+pub use compact::CompactionReport;
+pub use compress::Compression;
+pub use credential::{combine_recovery_shares, split_recovery_key, Credential, MAX_KEYFILE_LEN};
+pub use dedup::ChunkIndex;
+pub use error::BlobError;
+pub use kdf::{KdfAlgorithm, KdfParams};
+pub use keyslot::KEYSLOTS_PER_VOLUME;
+pub use padding::PaddingPolicy;
+pub use salvage::{salvage_blob, SalvageReport, VolumeSalvage};
+pub use stream::{open_file, read_file_range, FileReader, DEFAULT_CHUNK_SIZE, MAX_RANGE_LEN};
+pub use verify::{verify_blob, VerifyIssue, VerifyReport};
+pub use volume::UnlockedVolume;
@@ -0,0 +1,303 @@
+use crate::blob::{
+    check_volume_metadata, get_cipher, open_volume_key, read_blob_version, rebuild_blob,
+    DataFormat, FileMetadata, MetadataMap, VolumeType, DATA_AREA_START_OFFSET, VERSION_V3,
+};
+use crate::compress::Compression;
+use crate::credential::Credential;
+use crate::error::{BlobError, Result};
+use crate::stream::{
+    encrypted_len, open_header_body, open_preamble, BlockHeader, ChunkManifest, HeaderKind,
+    BLOCK_ALIGN, SEALED_PREAMBLE_LEN,
+};
+use crate::verify::authenticate;
+use log::{info, warn};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Folder that salvaged files without a usable path are placed in.
+const LOST_AND_FOUND: &str = "lost+found";
+/// Bytes of the blob read at a time while scanning for block headers.
+const SCAN_BUFFER_LEN: usize = 4 * 1024 * 1024;
+
+/// What `salvage_blob` recovered from each volume.
+#[derive(Debug, Clone, Serialize)]
+pub struct SalvageReport {
+    pub standard: VolumeSalvage,
+    pub hidden: VolumeSalvage,
+}
+
+/// What `salvage_blob` recovered from one volume.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct VolumeSalvage {
+    /// True if the volume's metadata still authenticated. Its files were then taken from the
+    /// metadata; otherwise they were found by scanning the data area for block headers.
+    pub metadata_intact: bool,
+    /// Paths of the recovered files in the new blob, sorted.
+    pub recovered: Vec<String>,
+    /// The recovered files whose original path couldn't be determined and that were given a
+    /// name under `lost+found/` instead. Also listed in `recovered`.
+    pub placeholders: Vec<String>,
+    /// Files whose data block is damaged and that were left behind.
+    pub lost: Vec<String>,
+}
+
+/// Recovers what can be read from a damaged blob into a fresh blob at `output`, leaving the
+/// damaged blob untouched. Both volumes are salvaged and keep their passwords.
+///
+/// A volume whose metadata still authenticates keeps every file whose data block does.
+/// Otherwise its data area is scanned for blocks that decrypt under the volume key: blocks
+/// written by this version carry an encrypted header (or, for deduplicated files, a chunk
+/// manifest) with the file's path, size and MIME type, so they are recovered under the path
+/// they were written with (renames since are lost). Files deleted since the last compaction
+/// come back too, and older versions of an overwritten file are kept under `lost+found/`.
+/// Blocks written before headers were introduced can only be recovered through intact
+/// metadata.
+///
+/// # Arguments
+/// * `path` - Path to the damaged blob file.
+/// * `password_s` - Password of the standard volume.
+/// * `password_h` - Password of the hidden volume.
+/// * `output` - Where to write the salvaged blob. Must not be `path`.
+///
+/// # Returns
+/// What was recovered from each volume.
+///
+/// # Errors
+/// `WrongPassword` if a password doesn't open its volume (the keyslots must be intact),
+/// `InvalidInput` if `output` is `path`, or I/O errors reading the damaged blob or writing the
+/// new one.
+pub fn salvage_blob(
+    path: &Path,
+    password_s: &str,
+    password_h: &str,
+    output: &Path,
+) -> Result<SalvageReport> {
+    if output == path {
+        return Err(BlobError::invalid(
+            "Salvage must write to a new file, not over the damaged blob",
+        ));
+    }
+    let mut file = File::open(path)?;
+    let version = read_blob_version(&mut file)?;
+
+    let mut volumes = Vec::with_capacity(2);
+    for (volume_type, password) in [
+        (VolumeType::Standard, password_s),
+        (VolumeType::Hidden, password_h),
+    ] {
+        let key = open_volume_key(
+            &mut file,
+            version,
+            volume_type,
+            &Credential::Password(password),
+        )?;
+        let (metadata, salvage) = salvage_volume(&mut file, path, version, volume_type, &key)?;
+        info!(
+            "Salvaged {} files of the {:?} volume ({} lost)",
+            salvage.recovered.len(),
+            volume_type,
+            salvage.lost.len()
+        );
+        volumes.push((key, metadata, salvage));
+    }
+    drop(file);
+
+    let (key_h, metadata_h, hidden) = volumes.pop().expect("two volumes");
+    let (key_s, metadata_s, standard) = volumes.pop().expect("two volumes");
+    rebuild_blob(
+        path,
+        output,
+        (password_s, &key_s, &metadata_s),
+        (password_h, &key_h, &metadata_h),
+    )?;
+    Ok(SalvageReport { standard, hidden })
+}
+
+/// Rebuilds the metadata of one volume from whatever still authenticates.
+fn salvage_volume(
+    file: &mut File,
+    path: &Path,
+    version: u8,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+) -> Result<(MetadataMap, VolumeSalvage)> {
+    let mut salvage = VolumeSalvage::default();
+    let mut metadata = MetadataMap::new();
+    match check_volume_metadata(file, version, volume_type, key) {
+        Ok(check) => {
+            salvage.metadata_intact = true;
+            for (file_path, file_metadata) in check.metadata {
+                if file_metadata.is_dir() || authenticate(path, key, &file_metadata).is_ok() {
+                    metadata.insert(file_path, file_metadata);
+                } else {
+                    salvage.lost.push(file_path);
+                }
+            }
+        }
+        Err(BlobError::Io(e)) => return Err(BlobError::Io(e)),
+        Err(e) => {
+            warn!(
+                "{:?} metadata unreadable ({}), scanning for data blocks",
+                volume_type, e
+            );
+            for (block, block_path) in scan_blocks(file, path, key, &mut salvage.lost)? {
+                let (name, placeholder) = if block_path.is_empty() {
+                    let name = format!("{}/block-{}", LOST_AND_FOUND, block.data_offset);
+                    (name, true)
+                } else {
+                    (block_path, false)
+                };
+                // Blocks are usually appended, so a later block with the same path is the newer version
+                if let Some(older) = metadata.remove(&name) {
+                    let renamed = format!("{}/{}/{}", LOST_AND_FOUND, older.data_offset, name);
+                    salvage.placeholders.push(renamed.clone());
+                    metadata.insert(renamed, older);
+                }
+                if placeholder {
+                    salvage.placeholders.push(name.clone());
+                }
+                metadata.insert(name, block);
+            }
+            // A wrong v3 password derives a key that decrypts nothing; there is no keyslot to
+            // tell it apart from total damage
+            if version == VERSION_V3 && metadata.is_empty() && salvage.lost.is_empty() {
+                return Err(BlobError::WrongPassword);
+            }
+        }
+    }
+    salvage.recovered = metadata.keys().cloned().collect();
+    salvage.recovered.sort();
+    salvage.placeholders.sort();
+    salvage.lost.sort();
+    Ok((metadata, salvage))
+}
+
+/// Scans the data area for blocks with a header or manifest written under `key`, in blob
+/// order, returning each with the path it was written under. Headers are only tried at
+/// `BLOCK_ALIGN` boundaries. Blocks whose header decrypts but whose content doesn't
+/// authenticate are added to `lost` by the path in their header.
+fn scan_blocks(
+    file: &mut File,
+    path: &Path,
+    key: &[u8; 32],
+    lost: &mut Vec<String>,
+) -> Result<Vec<(FileMetadata, String)>> {
+    let cipher = get_cipher(key);
+    let blob_len = file.metadata()?.len();
+    let mut buffer = ScanBuffer::default();
+    let mut found = Vec::new();
+    let mut offset = DATA_AREA_START_OFFSET.next_multiple_of(BLOCK_ALIGN);
+    while offset + SEALED_PREAMBLE_LEN as u64 <= blob_len {
+        let sealed = buffer.get(file, offset, SEALED_PREAMBLE_LEN)?;
+        let Some((kind, body_nonce, body_len)) =
+            open_preamble(&cipher, sealed.try_into().expect("preamble length"))
+        else {
+            offset += BLOCK_ALIGN;
+            continue;
+        };
+        let body_offset = offset + SEALED_PREAMBLE_LEN as u64;
+        let header_len = SEALED_PREAMBLE_LEN as u64 + body_len;
+        let sealed_body = if body_offset + body_len <= blob_len {
+            Some(buffer.get(file, body_offset, body_len as usize)?)
+        } else {
+            None
+        };
+        let block = sealed_body.and_then(|sealed_body| match kind {
+            HeaderKind::Block => {
+                let header: BlockHeader = open_header_body(&cipher, &body_nonce, sealed_body)?;
+                (header.chunk_size != 0).then(|| {
+                    let block = FileMetadata {
+                        size: header.size,
+                        data_offset: offset,
+                        data_length: header_len + encrypted_len(header.size, header.chunk_size),
+                        mime_type: header.mime_type,
+                        format: DataFormat::ChunkedWithHeader {
+                            chunk_size: header.chunk_size,
+                            header_len: header_len as u32,
+                        },
+                        chunks: Vec::new(),
+                        compression: Compression::None,
+                        created_at: None,
+                        modified_at: None,
+                        uploaded_at: None,
+                        sha256: None,
+                        original_mode: None,
+                        original_mtime: None,
+                        attributes: Default::default(),
+                    };
+                    (block, header.path)
+                })
+            }
+            HeaderKind::Manifest => {
+                let manifest = ChunkManifest::open(&cipher, kind, &body_nonce, sealed_body)?;
+                let block = FileMetadata {
+                    size: manifest.size,
+                    data_offset: offset,
+                    data_length: header_len,
+                    mime_type: manifest.mime_type,
+                    format: DataFormat::Deduplicated,
+                    chunks: manifest.chunks,
+                    compression: manifest.compression,
+                    created_at: None,
+                    modified_at: None,
+                    uploaded_at: None,
+                    sha256: None,
+                    original_mode: None,
+                    original_mtime: None,
+                    attributes: Default::default(),
+                };
+                Some((block, manifest.path))
+            }
+        });
+        let Some((block, block_path)) = block else {
+            warn!("Damaged block header at offset {}", offset);
+            offset += BLOCK_ALIGN;
+            continue;
+        };
+
+        let end = offset + block.data_length;
+        if end <= blob_len && authenticate(path, key, &block).is_ok() {
+            found.push((block, block_path));
+            offset = end.next_multiple_of(BLOCK_ALIGN);
+        } else {
+            warn!(
+                "Data block of '{}' at offset {} is damaged",
+                block_path, offset
+            );
+            lost.push(block_path);
+            offset += BLOCK_ALIGN;
+        }
+    }
+    Ok(found)
+}
+
+/// Window of the blob file for the scan, so candidate offsets don't each cost a read.
+#[derive(Default)]
+struct ScanBuffer {
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl ScanBuffer {
+    /// Returns `len` bytes at `offset`, refilling the window if they aren't in it. The caller
+    /// makes sure the range lies within the file.
+    fn get(&mut self, file: &mut File, offset: u64, len: usize) -> Result<&[u8]> {
+        let end = offset + len as u64;
+        if offset < self.start || end > self.start + self.bytes.len() as u64 {
+            self.start = offset;
+            self.bytes.clear();
+            file.seek(SeekFrom::Start(offset))?;
+            file.take(SCAN_BUFFER_LEN.max(len) as u64)
+                .read_to_end(&mut self.bytes)?;
+            if self.bytes.len() < len {
+                return Err(BlobError::corrupt("blob ended during the scan"));
+            }
+        }
+        let within = (offset - self.start) as usize;
+        Ok(&self.bytes[within..within + len])
+    }
+}
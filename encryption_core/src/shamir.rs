@@ -0,0 +1,254 @@
+use crate::error::{BlobError, Result};
+use crate::wordlist::WORDS;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// --- Constants ---
+/// Random identifier shared by all shares of one split, so shares of different splits
+/// can't be mixed by accident.
+const SET_ID_LEN: usize = 2;
+/// Truncated SHA-256 over the rest of the share, to catch mistyped words.
+const CHECKSUM_LEN: usize = 2;
+/// Share layout: SetId + Threshold + Index + Value + Checksum.
+const SHARE_OVERHEAD: usize = SET_ID_LEN + 2 + CHECKSUM_LEN;
+
+/// One share of a secret split with `split`. `index` is the x coordinate (never 0) and
+/// `value` holds the y coordinate of each byte's polynomial.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Share {
+    set_id: [u8; SET_ID_LEN],
+    threshold: u8,
+    index: u8,
+    value: Vec<u8>,
+}
+
+// --- GF(256) Arithmetic ---
+// The AES field, x^8 + x^4 + x^3 + x + 1. Addition is XOR.
+
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse: a^254, since a^255 = 1 for every non-zero a.
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1u8;
+    for _ in 0..254 {
+        result = gf_mul(result, a);
+    }
+    result
+}
+
+// --- Splitting and Combining ---
+
+/// Splits `secret` into `count` shares, any `threshold` of which recover it. Fewer shares
+/// reveal nothing about the secret.
+///
+/// # Errors
+/// Returns an error unless `2 <= threshold <= count`.
+pub(crate) fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>> {
+    if threshold < 2 || threshold > count {
+        return Err(BlobError::invalid(format!(
+            "Invalid share threshold {} of {} (need 2 <= threshold <= shares)",
+            threshold, count
+        )));
+    }
+    let mut set_id = [0u8; SET_ID_LEN];
+    OsRng.fill_bytes(&mut set_id);
+    let mut shares: Vec<Share> = (1..=count)
+        .map(|index| Share {
+            set_id,
+            threshold,
+            index,
+            value: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+
+    // Each secret byte is the constant term of its own random polynomial of degree k - 1
+    let mut coefficients = vec![0u8; threshold as usize - 1];
+    for byte in secret {
+        OsRng.fill_bytes(&mut coefficients);
+        for share in shares.iter_mut() {
+            // Horner's rule, highest coefficient first
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0u8, |acc, c| gf_mul(acc, share.index) ^ c);
+            share.value.push(gf_mul(y, share.index) ^ byte);
+        }
+    }
+    Ok(shares)
+}
+
+/// Recovers a secret from at least `threshold` shares of the same split.
+///
+/// # Errors
+/// Returns an error if the shares come from different splits, repeat an index, or are
+/// fewer than the threshold.
+pub(crate) fn combine(shares: &[Share]) -> Result<Vec<u8>> {
+    let first = shares
+        .first()
+        .ok_or_else(|| BlobError::invalid("No shares given"))?;
+    for share in shares {
+        if share.set_id != first.set_id
+            || share.threshold != first.threshold
+            || share.value.len() != first.value.len()
+        {
+            return Err(BlobError::invalid("Shares belong to different sets"));
+        }
+    }
+    let mut indices: Vec<u8> = shares.iter().map(|share| share.index).collect();
+    indices.sort_unstable();
+    indices.dedup();
+    if indices.len() != shares.len() {
+        return Err(BlobError::invalid(
+            "The same share was given more than once",
+        ));
+    }
+    if shares.len() < first.threshold as usize {
+        return Err(BlobError::invalid(format!(
+            "{} of {} required shares given",
+            shares.len(),
+            first.threshold
+        )));
+    }
+
+    // Lagrange interpolation at x = 0 over the first `threshold` shares
+    let used = &shares[..first.threshold as usize];
+    let mut secret = vec![0u8; first.value.len()];
+    for (i, share) in used.iter().enumerate() {
+        let mut basis = 1u8;
+        for (j, other) in used.iter().enumerate() {
+            if i != j {
+                // x_j / (x_j - x_i); subtraction is XOR
+                basis = gf_mul(
+                    basis,
+                    gf_mul(other.index, gf_inv(other.index ^ share.index)),
+                );
+            }
+        }
+        for (byte, y) in secret.iter_mut().zip(&share.value) {
+            *byte ^= gf_mul(basis, *y);
+        }
+    }
+    Ok(secret)
+}
+
+// --- Mnemonics ---
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let digest = Sha256::digest(bytes);
+    [digest[0], digest[1]]
+}
+
+impl Share {
+    /// Encodes the share as space-separated words, one per byte.
+    pub(crate) fn to_mnemonic(&self) -> String {
+        let mut bytes = Vec::with_capacity(SHARE_OVERHEAD + self.value.len());
+        bytes.extend_from_slice(&self.set_id);
+        bytes.push(self.threshold);
+        bytes.push(self.index);
+        bytes.extend_from_slice(&self.value);
+        bytes.extend_from_slice(&checksum(&bytes));
+        bytes
+            .iter()
+            .map(|byte| WORDS[*byte as usize])
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Parses a share written with `to_mnemonic`. Case and extra whitespace are ignored.
+    pub(crate) fn from_mnemonic(mnemonic: &str) -> Result<Self> {
+        let bytes = mnemonic
+            .split_whitespace()
+            .map(|word| {
+                let word = word.to_ascii_lowercase();
+                WORDS
+                    .iter()
+                    .position(|w| *w == word)
+                    .map(|byte| byte as u8)
+                    .ok_or_else(|| BlobError::invalid(format!("Unknown word '{}' in share", word)))
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        if bytes.len() <= SHARE_OVERHEAD {
+            return Err(BlobError::invalid("Share is too short"));
+        }
+        let (body, sum) = bytes.split_at(bytes.len() - CHECKSUM_LEN);
+        if checksum(body) != sum {
+            return Err(BlobError::invalid(
+                "Share checksum mismatch (check for mistyped words)",
+            ));
+        }
+        let share = Share {
+            set_id: [body[0], body[1]],
+            threshold: body[2],
+            index: body[3],
+            value: body[SET_ID_LEN + 2..].to_vec(),
+        };
+        if share.index == 0 || share.threshold < 2 {
+            return Err(BlobError::invalid("Malformed share"));
+        }
+        Ok(share)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_inverse() {
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_any_threshold_subset_recovers() {
+        let secret: Vec<u8> = (0..20).map(|i| i * 13 + 7).collect();
+        let shares = split(&secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        for (a, b, c) in [(0, 1, 2), (4, 2, 0), (1, 3, 4)] {
+            let subset = [shares[a].clone(), shares[b].clone(), shares[c].clone()];
+            assert_eq!(combine(&subset).unwrap(), secret);
+        }
+        // More than enough shares also works; too few or repeated ones don't
+        assert_eq!(combine(&shares).unwrap(), secret);
+        assert!(combine(&shares[..2]).is_err());
+        assert!(combine(&[shares[0].clone(), shares[0].clone(), shares[1].clone()]).is_err());
+
+        // Shares of another split of the same secret can't be mixed in
+        let other = split(&secret, 3, 5).unwrap();
+        assert!(combine(&[shares[0].clone(), shares[1].clone(), other[2].clone()]).is_err());
+
+        assert!(split(&secret, 1, 5).is_err());
+        assert!(split(&secret, 4, 3).is_err());
+    }
+
+    #[test]
+    fn test_mnemonic_roundtrip_and_checksum() {
+        let shares = split(b"0123456789abcdefghij", 2, 3).unwrap();
+        let mnemonic = shares[1].to_mnemonic();
+        assert_eq!(mnemonic.split(' ').count(), SHARE_OVERHEAD + 20);
+        let parsed = Share::from_mnemonic(&format!("  {}  ", mnemonic.to_uppercase())).unwrap();
+        assert_eq!(parsed, shares[1]);
+
+        // Swapping one word is caught by the checksum
+        let mut words: Vec<&str> = mnemonic.split(' ').collect();
+        words[6] = if words[6] == "acid" { "acorn" } else { "acid" };
+        assert!(Share::from_mnemonic(&words.join(" ")).is_err());
+        assert!(Share::from_mnemonic("acid acorn unknownword").is_err());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::blob::{MetadataMap, DATA_AREA_START_OFFSET};
+use crate::error::{BlobError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Space in the data area that one volume's blocks used to occupy and no longer do, recorded
+/// in the volume's encrypted metadata so new blocks can fill it instead of growing the blob.
+///
+/// Only extents the volume's own metadata referenced ever get here. Whatever else lies in the
+/// data area (the other volume's blocks, blocks that were written but never committed) is
+/// unknown to the volume and never reused.
+///
+/// Extents a commit stops referencing are still referenced by the previous metadata
+/// generation, which is the fallback if the newest one is damaged, so they are held back as
+/// `pending` until the commit after that.
+///
+/// In a preallocated blob the volume also tracks the space it has never written, which new
+/// blocks go into once no hole fits them; the blob then never grows by appending.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct FreeSpace {
+    /// Reusable extents as offset to length, never overlapping or adjacent.
+    free: BTreeMap<u64, u64>,
+    /// Extents released by the latest commit.
+    pending: Vec<(u64, u64)>,
+    /// Space of a preallocated blob the volume hasn't written yet; `None` if the blob grows
+    /// by appending.
+    preallocated: Option<Preallocated>,
+}
+
+/// The part of a preallocated blob (see `InitOptions::capacity`) a volume has never written.
+/// The hidden volume gets a fixed region at the end of the blob, chosen when the blob is
+/// created, and the standard volume the rest of the data area, so neither ever writes into
+/// the other's space. Both volumes record the hidden region, as every preallocated blob has
+/// one whether or not its hidden volume is used.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct Preallocated {
+    /// Unwritten extents of the volume's own region as offset to length, never overlapping
+    /// or adjacent.
+    unused: BTreeMap<u64, u64>,
+    /// Length of the blob file `unused` accounts for. Whatever `grow_blob` adds past it is
+    /// unused space of the standard volume.
+    capacity: u64,
+    /// The hidden volume's region as offset and length.
+    hidden_region: (u64, u64),
+    /// True for the hidden volume, which fills its region from the end.
+    hidden: bool,
+}
+
+impl FreeSpace {
+    /// The free space of a volume in a blob preallocated at `capacity` bytes, whose hidden
+    /// volume gets `hidden_region` (offset and length): nothing is reusable yet, and the
+    /// volume's whole region is unused.
+    pub(crate) fn preallocated(
+        capacity: u64,
+        hidden_region: (u64, u64),
+        hidden: bool,
+    ) -> FreeSpace {
+        let mut unused = BTreeMap::new();
+        if hidden {
+            insert_extent(&mut unused, hidden_region.0, hidden_region.1);
+        } else {
+            let start = DATA_AREA_START_OFFSET;
+            insert_extent(&mut unused, start, hidden_region.0.saturating_sub(start));
+        }
+        FreeSpace {
+            preallocated: Some(Preallocated {
+                unused,
+                capacity,
+                hidden_region,
+                hidden,
+            }),
+            ..FreeSpace::default()
+        }
+    }
+
+    /// True if the volume lives in a preallocated blob, which new blocks must never grow.
+    pub(crate) fn is_preallocated(&self) -> bool {
+        self.preallocated.is_some()
+    }
+
+    /// The hidden volume's region of a preallocated blob as offset and length.
+    pub(crate) fn hidden_region(&self) -> Option<(u64, u64)> {
+        self.preallocated
+            .as_ref()
+            .map(|preallocated| preallocated.hidden_region)
+    }
+
+    /// Bytes new blocks can still go into: the reusable ones and, in a preallocated blob,
+    /// the unused ones.
+    pub(crate) fn free_bytes(&self) -> u64 {
+        let unused = self
+            .preallocated
+            .as_ref()
+            .map_or(0, |preallocated| preallocated.unused.values().sum());
+        self.free.values().sum::<u64>() + unused
+    }
+
+    /// The free space to commit along with `new`, when it replaces the committed `old`:
+    /// what the previous commit released becomes reusable, what `old` references and `new`
+    /// doesn't is held back, and nothing `new` references is left free (blocks may have been
+    /// written into holes since).
+    pub(crate) fn next(&self, old: &MetadataMap, new: &MetadataMap) -> FreeSpace {
+        let mut next = FreeSpace {
+            free: self.free.clone(),
+            pending: Vec::new(),
+            preallocated: self.preallocated.clone(),
+        };
+        for &(offset, len) in &self.pending {
+            next.release(offset, len);
+        }
+        let referenced = referenced_extents(new);
+        for &(offset, len) in &referenced {
+            next.reserve(offset, len);
+        }
+        next.pending = referenced_extents(old)
+            .difference(&referenced)
+            .copied()
+            .collect();
+        next.pending.sort_unstable();
+        next
+    }
+
+    /// Makes `offset..offset + len` reusable right away.
+    pub(crate) fn release(&mut self, offset: u64, len: u64) {
+        if offset >= DATA_AREA_START_OFFSET {
+            insert_extent(&mut self.free, offset, len);
+        }
+    }
+
+    /// Removes `offset..offset + len` from the reusable and the unused extents.
+    pub(crate) fn reserve(&mut self, offset: u64, len: u64) {
+        remove_extent(&mut self.free, offset, len);
+        if let Some(preallocated) = &mut self.preallocated {
+            remove_extent(&mut preallocated.unused, offset, len);
+        }
+    }
+
+    /// Takes `len` bytes from the first reusable extent they fit in, starting at a multiple
+    /// of `align`. In a preallocated blob, unused space is taken if no reusable extent is
+    /// large enough.
+    ///
+    /// # Returns
+    /// The offset of the taken space, or `None` if no extent is large enough.
+    pub(crate) fn allocate(&mut self, len: u64, align: u64) -> Option<u64> {
+        self.allocate_below(len, align, u64::MAX)
+    }
+
+    /// Like `allocate`, but only takes space that ends at or before `limit`.
+    pub(crate) fn allocate_below(&mut self, len: u64, align: u64, limit: u64) -> Option<u64> {
+        if len == 0 {
+            return None;
+        }
+        let offset = self
+            .free
+            .iter()
+            .take_while(|(&start, _)| start < limit)
+            .find_map(|(&start, &length)| {
+                let aligned = start.next_multiple_of(align);
+                (aligned + len <= (start + length).min(limit)).then_some(aligned)
+            })
+            .or_else(|| {
+                let preallocated = self.preallocated.as_ref()?;
+                let fits = |(&start, &length): (&u64, &u64)| {
+                    let end = (start + length).min(limit);
+                    let aligned = if preallocated.hidden {
+                        end.checked_sub(len)? / align * align
+                    } else {
+                        start.next_multiple_of(align)
+                    };
+                    (aligned >= start && aligned + len <= end).then_some(aligned)
+                };
+                if preallocated.hidden {
+                    preallocated.unused.iter().rev().find_map(fits)
+                } else {
+                    preallocated.unused.iter().find_map(fits)
+                }
+            })?;
+        self.reserve(offset, len);
+        Some(offset)
+    }
+
+    /// Fits the free space to a blob file of `len` bytes: forgets what lies at or past it,
+    /// and in a preallocated blob gives what `grow_blob` added to the standard volume.
+    pub(crate) fn fit_to(&mut self, len: u64) {
+        self.reserve(len, u64::MAX - len);
+        if let Some(preallocated) = &mut self.preallocated {
+            if len > preallocated.capacity && !preallocated.hidden {
+                insert_extent(
+                    &mut preallocated.unused,
+                    preallocated.capacity,
+                    len - preallocated.capacity,
+                );
+            }
+            preallocated.capacity = len;
+        }
+    }
+
+    /// The reusable extent furthest into the blob.
+    pub(crate) fn last_extent(&self) -> Option<(u64, u64)> {
+        self.free
+            .last_key_value()
+            .map(|(&offset, &len)| (offset, len))
+    }
+
+    /// True if the latest commit released extents that aren't reusable yet.
+    pub(crate) fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// True if any byte of `offset..offset + len` is reusable.
+    pub(crate) fn overlaps(&self, offset: u64, len: u64) -> bool {
+        overlaps(&self.free, offset, len)
+    }
+
+    /// Removes the `protected` extents from the free and unused space, so blocks go around
+    /// them. Only for space in memory: committed, it would record where the hidden volume is.
+    pub(crate) fn avoid(&mut self, protected: &Protected) {
+        for (&offset, &len) in &protected.extents {
+            remove_extent(&mut self.free, offset, len);
+            if let Some(preallocated) = &mut self.preallocated {
+                remove_extent(&mut preallocated.unused, offset, len);
+            }
+        }
+    }
+
+    /// Every extent the volume owns without referencing it: the reusable ones and those
+    /// held back. Unused space of a preallocated blob isn't owned until it is written.
+    pub(crate) fn extents(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.free
+            .iter()
+            .map(|(&offset, &len)| (offset, len))
+            .chain(self.pending.iter().copied())
+    }
+}
+
+/// Extents of the blob that belong to the hidden volume, loaded when the standard volume is
+/// unlocked with the hidden password as a guard (see `UnlockedVolume::unlock_protected`).
+/// Writes of the standard volume that would land on any of them are refused.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Protected {
+    /// Protected extents as offset to length, never overlapping or adjacent.
+    extents: BTreeMap<u64, u64>,
+}
+
+impl Protected {
+    /// Adds `offset..offset + len` to the protected extents.
+    pub(crate) fn insert(&mut self, offset: u64, len: u64) {
+        insert_extent(&mut self.extents, offset, len);
+    }
+
+    /// True if no extent is protected.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.extents.is_empty()
+    }
+
+    /// Checks that a write of `len` bytes at `offset` leaves the protected extents alone.
+    ///
+    /// # Errors
+    /// `HiddenVolumeProtected` if any byte of `offset..offset + len` is protected.
+    pub(crate) fn check(&self, offset: u64, len: u64) -> Result<()> {
+        if overlaps(&self.extents, offset, len) {
+            return Err(BlobError::HiddenVolumeProtected);
+        }
+        Ok(())
+    }
+}
+
+/// Adds `offset..offset + len` to `extents`, merging it with the extents it touches.
+fn insert_extent(extents: &mut BTreeMap<u64, u64>, mut offset: u64, len: u64) {
+    if len == 0 {
+        return;
+    }
+    let mut end = offset + len;
+    if let Some((&start, &length)) = extents.range(..=offset).next_back() {
+        if start + length >= offset {
+            extents.remove(&start);
+            offset = start;
+            end = end.max(start + length);
+        }
+    }
+    while let Some((&start, &length)) = extents.range(offset..=end).next() {
+        extents.remove(&start);
+        end = end.max(start + length);
+    }
+    extents.insert(offset, end - offset);
+}
+
+/// Removes `offset..offset + len` from `extents`, splitting the extents it cuts into.
+fn remove_extent(extents: &mut BTreeMap<u64, u64>, offset: u64, len: u64) {
+    let end = offset + len;
+    let overlapping: Vec<(u64, u64)> = extents
+        .range(..end)
+        .rev()
+        .map(|(&start, &length)| (start, length))
+        .take_while(|&(start, length)| start + length > offset)
+        .collect();
+    for (start, length) in overlapping {
+        extents.remove(&start);
+        if start < offset {
+            extents.insert(start, offset - start);
+        }
+        if start + length > end {
+            extents.insert(end, start + length - end);
+        }
+    }
+}
+
+/// True if any byte of `offset..offset + len` lies in one of `extents`.
+fn overlaps(extents: &BTreeMap<u64, u64>, offset: u64, len: u64) -> bool {
+    extents
+        .range(..offset + len)
+        .next_back()
+        .is_some_and(|(&start, &length)| start + length > offset)
+}
+
+/// Extents of the data area the entries reference: every file's block and chunks, each
+/// shared chunk once.
+pub(crate) fn referenced_extents(metadata: &MetadataMap) -> HashSet<(u64, u64)> {
+    let mut extents = HashSet::new();
+    for entry in metadata.values() {
+        extents.insert((entry.data_offset, entry.data_length));
+        for chunk in &entry.chunks {
+            extents.insert((chunk.offset, chunk.stored_len()));
+        }
+    }
+    extents.retain(|&(offset, len)| len > 0 && offset >= DATA_AREA_START_OFFSET);
+    extents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first block boundary in the data area.
+    const BASE: u64 = DATA_AREA_START_OFFSET.next_multiple_of(64);
+
+    #[test]
+    fn test_released_extents_merge_and_are_handed_out_first_fit() {
+        let mut space = FreeSpace::default();
+        space.release(BASE, 100);
+        space.release(BASE + 200, 100);
+        space.release(BASE + 100, 100);
+        space.release(0, 4096); // Never below the data area
+        assert_eq!(space.free, BTreeMap::from([(BASE, 300)]));
+
+        assert_eq!(space.allocate(50, 1), Some(BASE));
+        assert_eq!(space.allocate(64, 64), Some(BASE + 64));
+        assert_eq!(space.free_bytes(), 300 - 50 - 64);
+        assert!(space.overlaps(BASE + 50, 1));
+        assert!(!space.overlaps(BASE + 64, 64));
+        assert_eq!(space.allocate(1000, 1), None);
+        assert_eq!(space.allocate_below(100, 1, BASE + 200), None);
+        assert_eq!(space.allocate_below(10, 1, BASE + 200), Some(BASE + 50));
+        space.release(BASE + 50, 10);
+
+        space.reserve(BASE + 150, 10);
+        assert_eq!(
+            space.free,
+            BTreeMap::from([(BASE + 50, 14), (BASE + 128, 22), (BASE + 160, 140)])
+        );
+        space.fit_to(BASE + 200);
+        assert_eq!(space.last_extent(), Some((BASE + 160, 40)));
+    }
+
+    #[test]
+    fn test_preallocated_volumes_keep_to_their_regions() {
+        let end = BASE + 1024;
+        let region = (BASE + 512, 512);
+        let mut standard = FreeSpace::preallocated(end, region, false);
+        let mut hidden = FreeSpace::preallocated(end, region, true);
+        assert_eq!(standard.free_bytes(), BASE + 512 - DATA_AREA_START_OFFSET);
+        assert_eq!(hidden.free_bytes(), 512);
+        assert_eq!(hidden.hidden_region(), Some(region));
+
+        let start = DATA_AREA_START_OFFSET;
+        assert_eq!(standard.allocate(100, 1), Some(start));
+        assert_eq!(hidden.allocate(100, 64), Some(end - 128));
+        assert_eq!(hidden.allocate(10, 1), Some(end - 10));
+        // Holes the volume freed come first
+        standard.release(start, 50);
+        assert_eq!(standard.allocate(40, 1), Some(start));
+        assert_eq!(standard.allocate(40, 1), Some(start + 100));
+        // Neither volume reaches into the other's region
+        assert_eq!(standard.allocate(BASE + 512 - start - 140 + 1, 1), None);
+        assert_eq!(hidden.allocate(400, 1), None);
+        assert_eq!(hidden.allocate(384, 1), Some(BASE + 512));
+        // Unused space isn't owned yet
+        assert_eq!(
+            standard.extents().collect::<Vec<_>>(),
+            vec![(start + 40, 10)]
+        );
+
+        // Space added past the capacity goes to the standard volume
+        hidden.fit_to(end + 500);
+        assert_eq!(hidden.free_bytes(), 18);
+        standard.fit_to(end + 500);
+        assert_eq!(standard.allocate(500, 1), Some(end));
+        assert!(!FreeSpace::default().is_preallocated());
+        assert!(hidden
+            .next(&MetadataMap::new(), &MetadataMap::new())
+            .is_preallocated());
+    }
+
+    #[test]
+    fn test_writes_touching_protected_extents_are_refused() {
+        let mut protected = Protected::default();
+        protected.insert(1000, 100);
+        protected.insert(1100, 50);
+        protected.insert(64, 8); // Header regions are protected too
+        assert_eq!(protected.extents, BTreeMap::from([(64, 8), (1000, 150)]));
+
+        assert!(protected.check(0, 64).is_ok());
+        assert!(protected.check(72, 928).is_ok());
+        assert!(protected.check(1150, 1 << 20).is_ok());
+        for (offset, len) in [(71, 1), (990, 11), (1149, 1), (900, 400)] {
+            assert!(matches!(
+                protected.check(offset, len),
+                Err(BlobError::HiddenVolumeProtected)
+            ));
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use crate::blob::{
+    get_cipher, read_file_data, ChunkRef, DataFormat, FileMetadata, DATA_AREA_START_OFFSET,
+    XNONCE_LEN,
+};
+use crate::compress::Compression;
+use crate::dedup::{open_chunk, MAX_CHUNK_SIZE};
+use crate::error::{BlobError, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// --- Constants ---
+/// Plaintext bytes per chunk of `Chunked` and `ChunkedWithHeader` blocks.
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+/// Per-file random nonce prefix; the remaining 5 nonce bytes hold the chunk index and last flag.
+pub(crate) const NONCE_PREFIX_LEN: usize = XNONCE_LEN - 5;
+/// Poly1305 authentication tag appended to every chunk.
+pub(crate) const TAG_LEN: usize = 16;
+/// Blocks with a header start at a multiple of this offset, so a scan for them only has to try
+/// one offset in every `BLOCK_ALIGN` bytes.
+pub(crate) const BLOCK_ALIGN: u64 = 64;
+/// Prefix of a decrypted block header preamble.
+const BLOCK_MAGIC: &[u8] = b"KPBLOCK\x01";
+/// Prefix of a decrypted chunk manifest preamble. Same length as `BLOCK_MAGIC`.
+const MANIFEST_MAGIC: &[u8] = b"KPBLOCK\x02";
+/// Plaintext of the fixed-size part of a block header: magic, body nonce and body length.
+const PREAMBLE_LEN: usize = BLOCK_MAGIC.len() + XNONCE_LEN + 4;
+/// On-disk length of the preamble: nonce, ciphertext and tag.
+pub(crate) const SEALED_PREAMBLE_LEN: usize = XNONCE_LEN + PREAMBLE_LEN + TAG_LEN;
+/// Upper bound for the header body (path and MIME type), so a damaged preamble can't ask for
+/// an absurd read.
+const MAX_HEADER_BODY_LEN: u32 = 64 * 1024;
+/// Upper bound for a manifest body, which also lists the file's chunks (about 44 bytes per
+/// 64 KiB of content).
+const MAX_MANIFEST_BODY_LEN: u32 = 64 * 1024 * 1024;
+
+// --- Chunk Geometry ---
+
+/// Number of chunks a file of `size` bytes is split into. Empty files still get one (empty) chunk
+/// so the last-chunk flag is always authenticated.
+pub(crate) fn chunk_count(size: u64, chunk_size: u32) -> u64 {
+    if size == 0 {
+        1
+    } else {
+        size.div_ceil(chunk_size as u64)
+    }
+}
+
+/// Total on-disk length of a chunked block (nonce prefix + every chunk's ciphertext and tag).
+pub(crate) fn encrypted_len(size: u64, chunk_size: u32) -> u64 {
+    NONCE_PREFIX_LEN as u64 + size + chunk_count(size, chunk_size) * TAG_LEN as u64
+}
+
+/// Builds the nonce for one chunk: `prefix || index (u32 BE) || last flag`.
+/// Binding the index and the last flag prevents chunks from being reordered, dropped or truncated.
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], index: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; XNONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..XNONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[XNONCE_LEN - 1] = last as u8;
+    *XNonce::from_slice(&nonce)
+}
+
+fn chunk_index(index: u64) -> Result<u32> {
+    u32::try_from(index).map_err(|_| BlobError::invalid("File exceeds the maximum chunk count"))
+}
+
+// --- Block Header ---
+
+/// The kinds of sealed header a block can start with, told apart by the preamble magic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HeaderKind {
+    /// A `BlockHeader`, followed by the block's chunked content.
+    Block,
+    /// A `ChunkManifest`, which is the whole block.
+    Manifest,
+}
+
+impl HeaderKind {
+    const ALL: [HeaderKind; 2] = [HeaderKind::Block, HeaderKind::Manifest];
+
+    fn magic(self) -> &'static [u8] {
+        match self {
+            HeaderKind::Block => BLOCK_MAGIC,
+            HeaderKind::Manifest => MANIFEST_MAGIC,
+        }
+    }
+
+    fn max_body_len(self) -> u32 {
+        match self {
+            HeaderKind::Block => MAX_HEADER_BODY_LEN,
+            HeaderKind::Manifest => MAX_MANIFEST_BODY_LEN,
+        }
+    }
+}
+
+/// Self-describing header in front of a `DataFormat::ChunkedWithHeader` block, encrypted under
+/// the volume key. It lets `salvage_blob` rebuild the file's metadata entry from the block
+/// alone. `path` is the path the file was written under; renames don't update it.
+///
+/// On disk the header is a fixed-size preamble (nonce, then the encrypted magic, body nonce
+/// and body length) followed by the encrypted body, a bincode `BlockHeader`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BlockHeader {
+    pub(crate) path: String,
+    pub(crate) mime_type: String,
+    pub(crate) size: u64,
+    pub(crate) chunk_size: u32,
+}
+
+impl BlockHeader {
+    /// Reads and decrypts the header of the block starting at `offset`.
+    ///
+    /// # Errors
+    /// `CorruptBlock` if there is no header written with this key at `offset`, or `Io`.
+    pub(crate) fn read(file: &mut File, key: &[u8; 32], offset: u64) -> Result<BlockHeader> {
+        let cipher = get_cipher(key);
+        let (kind, body_nonce, sealed_body) = read_sealed_header(file, &cipher, offset)?;
+        (kind == HeaderKind::Block)
+            .then(|| open_header_body(&cipher, &body_nonce, &sealed_body))
+            .flatten()
+            .ok_or(BlobError::CorruptBlock { path: None })
+    }
+}
+
+/// The whole block of a `DataFormat::Deduplicated` file: its path, MIME type, size and chunk
+/// list, sealed like a `BlockHeader` so `salvage_blob` can find it. The chunk list repeats
+/// the one in the metadata.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ChunkManifest {
+    pub(crate) path: String,
+    pub(crate) mime_type: String,
+    pub(crate) size: u64,
+    pub(crate) chunks: Vec<ChunkRef>,
+    pub(crate) compression: Compression,
+}
+
+impl ChunkManifest {
+    /// Encrypts the manifest under fresh nonces.
+    pub(crate) fn seal(&self, key: &[u8; 32]) -> Result<Vec<u8>> {
+        seal_header(key, HeaderKind::Manifest, self)
+    }
+
+    /// Reads and decrypts the manifest starting at `offset`.
+    ///
+    /// # Errors
+    /// `CorruptBlock` if there is no manifest written with this key at `offset`, or `Io`.
+    pub(crate) fn read(file: &mut File, key: &[u8; 32], offset: u64) -> Result<ChunkManifest> {
+        let cipher = get_cipher(key);
+        let (kind, body_nonce, sealed_body) = read_sealed_header(file, &cipher, offset)?;
+        Self::open(&cipher, kind, &body_nonce, &sealed_body)
+            .ok_or(BlobError::CorruptBlock { path: None })
+    }
+
+    /// Decrypts the body of a manifest header (see `open_header_body`).
+    pub(crate) fn open(
+        cipher: &XChaCha20Poly1305,
+        kind: HeaderKind,
+        body_nonce: &[u8; XNONCE_LEN],
+        sealed_body: &[u8],
+    ) -> Option<ChunkManifest> {
+        match kind {
+            HeaderKind::Block => None,
+            HeaderKind::Manifest => open_header_body(cipher, body_nonce, sealed_body),
+        }
+    }
+}
+
+fn body_len<T: Serialize>(body: &T, kind: HeaderKind) -> Result<u32> {
+    bincode::serialized_size(body)
+        .ok()
+        .and_then(|len| u32::try_from(len).ok())
+        .filter(|len| *len <= kind.max_body_len())
+        .ok_or_else(|| BlobError::invalid("File path, MIME type or chunk list too long"))
+}
+
+/// Encrypts a header body behind a preamble of the given kind, under fresh nonces.
+fn seal_header<T: Serialize>(key: &[u8; 32], kind: HeaderKind, body: &T) -> Result<Vec<u8>> {
+    let body_len = body_len(body, kind)?;
+    let body = bincode::serialize(body).map_err(|_| BlobError::Crypto("block header encoding"))?;
+    let cipher = get_cipher(key);
+    let body_nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut preamble = Vec::with_capacity(PREAMBLE_LEN);
+    preamble.extend_from_slice(kind.magic());
+    preamble.extend_from_slice(body_nonce.as_slice());
+    preamble.extend_from_slice(&body_len.to_le_bytes());
+
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        cipher
+            .encrypt(&nonce, preamble.as_ref())
+            .map_err(|_| BlobError::Crypto("block header encryption"))?,
+    );
+    sealed.extend(
+        cipher
+            .encrypt(&body_nonce, body.as_ref())
+            .map_err(|_| BlobError::Crypto("block header encryption"))?,
+    );
+    Ok(sealed)
+}
+
+/// Decrypts a sealed preamble. Cheap enough to try at every candidate offset of a scan.
+///
+/// # Returns
+/// The kind of header, the body nonce and the on-disk length of the body, or `None` if
+/// `sealed` isn't the preamble of a header written with this key.
+pub(crate) fn open_preamble(
+    cipher: &XChaCha20Poly1305,
+    sealed: &[u8; SEALED_PREAMBLE_LEN],
+) -> Option<(HeaderKind, [u8; XNONCE_LEN], u64)> {
+    let (nonce, ciphertext) = sealed.split_at(XNONCE_LEN);
+    let preamble = cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()?;
+    let (kind, rest) = HeaderKind::ALL
+        .into_iter()
+        .find_map(|kind| Some((kind, preamble.strip_prefix(kind.magic())?)))?;
+    let body_nonce: [u8; XNONCE_LEN] = rest[..XNONCE_LEN].try_into().ok()?;
+    let body_len = u32::from_le_bytes(rest[XNONCE_LEN..].try_into().ok()?);
+    (body_len <= kind.max_body_len()).then_some((
+        kind,
+        body_nonce,
+        body_len as u64 + TAG_LEN as u64,
+    ))
+}
+
+/// Decrypts the header body that follows a preamble opened with `open_preamble`.
+pub(crate) fn open_header_body<T: DeserializeOwned>(
+    cipher: &XChaCha20Poly1305,
+    body_nonce: &[u8; XNONCE_LEN],
+    sealed_body: &[u8],
+) -> Option<T> {
+    let body = cipher
+        .decrypt(XNonce::from_slice(body_nonce), sealed_body)
+        .ok()?;
+    bincode::deserialize(&body).ok()
+}
+
+/// Reads the header starting at `offset` and decrypts its preamble.
+///
+/// # Returns
+/// The kind of header, its body nonce and the still sealed body.
+fn read_sealed_header(
+    file: &mut File,
+    cipher: &XChaCha20Poly1305,
+    offset: u64,
+) -> Result<(HeaderKind, [u8; XNONCE_LEN], Vec<u8>)> {
+    let mut sealed = [0u8; SEALED_PREAMBLE_LEN];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut sealed)?;
+    let (kind, body_nonce, body_len) =
+        open_preamble(cipher, &sealed).ok_or(BlobError::CorruptBlock { path: None })?;
+    let mut sealed_body = vec![0u8; body_len as usize];
+    file.read_exact(&mut sealed_body)?;
+    Ok((kind, body_nonce, sealed_body))
+}
+
+/// Random bytes that move a block ending the blob at `end` to the next `BLOCK_ALIGN`
+/// boundary at or after the start of the data area.
+pub(crate) fn block_padding(end: u64) -> Vec<u8> {
+    let start = end
+        .max(DATA_AREA_START_OFFSET)
+        .next_multiple_of(BLOCK_ALIGN);
+    let mut padding = vec![0u8; (start - end) as usize];
+    OsRng.fill_bytes(&mut padding);
+    padding
+}
+
+/// Where the manifest of a `DataFormat::Deduplicated` file starts. The file's block begins
+/// with the padding, if any, that put the manifest on a `BLOCK_ALIGN` boundary.
+pub(crate) fn manifest_offset(metadata: &FileMetadata) -> u64 {
+    metadata.data_offset.next_multiple_of(BLOCK_ALIGN)
+}
+
+// --- Decryption ---
+
+/// Streaming, seekable reader over a file stored in the blob.
+/// Chunked and deduplicated files are decrypted one chunk at a time, so memory use is bounded
+/// by the chunk size. Legacy single-message blocks (v3) are decrypted in full on first read.
+pub struct FileReader {
+    file: File,
+    key: [u8; 32],
+    metadata: FileMetadata,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    /// Plaintext offset of each chunk of a deduplicated file
+    chunk_starts: Vec<u64>,
+    position: u64,
+    // Index and plaintext of the currently buffered chunk
+    buffered: Option<(u64, Vec<u8>)>,
+}
+
+impl FileReader {
+    pub(crate) fn new(mut file: File, key: &[u8; 32], metadata: &FileMetadata) -> Result<Self> {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        let mut chunk_starts = Vec::new();
+        if metadata.is_dir() {
+            return Err(BlobError::invalid("folders have no content"));
+        }
+        if metadata.format == DataFormat::Deduplicated {
+            // The chunks must add up to the file
+            let mut start = 0u64;
+            for chunk in &metadata.chunks {
+                chunk_starts.push(start);
+                start += chunk.size as u64;
+            }
+            if start != metadata.size
+                || metadata
+                    .chunks
+                    .iter()
+                    .any(|chunk| chunk.size == 0 || chunk.size as usize > MAX_CHUNK_SIZE)
+            {
+                return Err(BlobError::CorruptBlock { path: None });
+            }
+        } else if let Some(chunk_size) = metadata.format.chunk_size() {
+            // Metadata that doesn't describe a valid chunked block can't be read
+            let header_len = metadata.format.header_len();
+            if chunk_size == 0
+                || metadata.data_length != header_len + encrypted_len(metadata.size, chunk_size)
+            {
+                return Err(BlobError::CorruptBlock { path: None });
+            }
+            file.seek(SeekFrom::Start(metadata.data_offset + header_len))?;
+            file.read_exact(&mut prefix)?;
+        }
+        Ok(Self {
+            file,
+            key: *key,
+            metadata: metadata.clone(),
+            prefix,
+            chunk_starts,
+            position: 0,
+            buffered: None,
+        })
+    }
+
+    /// Original (plaintext) size of the file.
+    pub fn len(&self) -> u64 {
+        self.metadata.size
+    }
+
+    /// Returns true if the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.metadata.size == 0
+    }
+
+    /// Index and plaintext offset of the chunk holding the byte at `position`, which must be
+    /// within the file.
+    fn locate(&self, position: u64) -> (u64, u64) {
+        if self.metadata.format == DataFormat::Deduplicated {
+            let index = self
+                .chunk_starts
+                .partition_point(|start| *start <= position)
+                - 1;
+            return (index as u64, self.chunk_starts[index]);
+        }
+        // Legacy blocks are a single "chunk" covering the whole file
+        let chunk_size = match self.metadata.format.chunk_size() {
+            None => self.metadata.size,
+            Some(chunk_size) => chunk_size as u64,
+        };
+        let index = position / chunk_size;
+        (index, index * chunk_size)
+    }
+
+    /// Decrypts the chunk with the given index into the buffer, unless it is already there.
+    fn load_chunk(&mut self, index: u64) -> Result<()> {
+        if matches!(self.buffered, Some((i, _)) if i == index) {
+            return Ok(());
+        }
+        let plaintext = match self.metadata.format {
+            DataFormat::Single => read_file_data(&mut self.file, &self.key, &self.metadata)?,
+            DataFormat::Chunked { chunk_size }
+            | DataFormat::ChunkedWithHeader { chunk_size, .. } => {
+                let count = chunk_count(self.metadata.size, chunk_size);
+                let chunk_start = index * chunk_size as u64;
+                let plain_len = (self.metadata.size - chunk_start).min(chunk_size as u64);
+                let offset = self.metadata.data_offset
+                    + self.metadata.format.header_len()
+                    + NONCE_PREFIX_LEN as u64
+                    + index * (chunk_size as u64 + TAG_LEN as u64);
+                let mut ciphertext = vec![0u8; plain_len as usize + TAG_LEN];
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut ciphertext)?;
+                let nonce = chunk_nonce(&self.prefix, chunk_index(index)?, index + 1 == count);
+                get_cipher(&self.key)
+                    .decrypt(&nonce, ciphertext.as_ref())
+                    .map_err(|_| BlobError::CorruptBlock { path: None })?
+            }
+            DataFormat::Deduplicated => {
+                let chunk = self.metadata.chunks[index as usize];
+                let mut sealed = vec![0u8; chunk.stored_len() as usize];
+                self.file.seek(SeekFrom::Start(chunk.offset))?;
+                self.file.read_exact(&mut sealed)?;
+                open_chunk(&get_cipher(&self.key), &chunk, &sealed)?
+            }
+            DataFormat::Directory => unreachable!("rejected by FileReader::new"),
+        };
+        self.buffered = Some((index, plaintext));
+        Ok(())
+    }
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.metadata.size {
+            return Ok(0);
+        }
+        let (index, chunk_start) = self.locate(self.position);
+        // Blob errors pass through `io::Error` and are unwrapped again by `BlobError::from`
+        self.load_chunk(index).map_err(io::Error::from)?;
+        let chunk = &self.buffered.as_ref().expect("chunk loaded above").1;
+        let within = (self.position - chunk_start) as usize;
+        let n = buf.len().min(chunk.len() - within);
+        buf[..n].copy_from_slice(&chunk[within..within + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for FileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.metadata.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Opens a streaming reader for a file stored in the blob.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `key` - Context: The derived key for the volume containing the file.
+/// * `metadata` - The `FileMetadata` entry for the file (obtained from the unlocked `MetadataMap`).
+///
+/// # Returns
+/// A `FileReader` implementing `Read` and `Seek` over the decrypted content.
+/// Authentication failures surface as `io::ErrorKind::InvalidData` while reading, wrapping a
+/// `BlobError::CorruptBlock` (`BlobError::from` recovers it).
+pub fn open_file(path: &Path, key: &[u8; 32], metadata: &FileMetadata) -> Result<FileReader> {
+    let file = File::open(path)?;
+    FileReader::new(file, key, metadata)
+}
+
+/// Longest range `read_file_range` returns. Longer reads go through `open_file`, which doesn't
+/// buffer the range.
+pub const MAX_RANGE_LEN: u64 = 16 * 1024 * 1024;
+
+/// Reads and decrypts a byte range of a file stored in the blob.
+/// Only the chunks covering `offset..offset + len` are read and authenticated, so the cost is
+/// proportional to the range rather than the file. Legacy single-message blocks have to be
+/// decrypted in full.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `key` - Context: The derived key for the volume containing the file.
+/// * `metadata` - The `FileMetadata` entry for the file.
+/// * `offset` - Offset of the first plaintext byte to return.
+/// * `len` - Number of plaintext bytes to return, at most `MAX_RANGE_LEN`.
+///
+/// # Errors
+/// Returns an error if `len` exceeds `MAX_RANGE_LEN` or the range extends past the end of the
+/// file, or on I/O or authentication failure of any chunk in the range.
+pub fn read_file_range(
+    path: &Path,
+    key: &[u8; 32],
+    metadata: &FileMetadata,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    if len > MAX_RANGE_LEN {
+        return Err(BlobError::invalid(format!(
+            "Range of {} bytes exceeds the limit of {} bytes",
+            len, MAX_RANGE_LEN
+        )));
+    }
+    let end = offset
+        .checked_add(len)
+        .filter(|end| *end <= metadata.size)
+        .ok_or_else(|| {
+            BlobError::invalid(format!(
+                "Range {}+{} out of bounds for file of {} bytes",
+                offset, len, metadata.size
+            ))
+        })?;
+    let mut reader = open_file(path, key, metadata)?;
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut content = vec![0u8; (end - offset) as usize];
+    reader.read_exact(&mut content)?;
+    Ok(content)
+}
@@ -0,0 +1,317 @@
+use crate::blob::{
+    check_volume_metadata, read_blob_version, read_keyslots, DataFormat, FileMetadata, VolumeType,
+    DATA_AREA_START_OFFSET, VERSION_V3, XNONCE_LEN,
+};
+use crate::error::{BlobError, Result};
+use crate::stream::{self, encrypted_len, BlockHeader, ChunkManifest, TAG_LEN};
+use crate::volume::UnlockedVolume;
+use log::{info, warn};
+use serde::Serialize;
+use std::{collections::HashSet, fs::File, io::Read, path::Path};
+
+/// Result of `verify_blob`: what was checked and everything found wrong.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    /// Format version of the blob.
+    pub format_version: u8,
+    /// Size of the blob file in bytes.
+    pub blob_size: u64,
+    /// Number of files whose data block was authenticated in full.
+    pub files_checked: usize,
+    /// Plaintext bytes authenticated across those files.
+    pub bytes_checked: u64,
+    /// Problems found, in the order they were found. Empty for a healthy volume.
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// True if no problems were found.
+    pub fn is_healthy(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// One problem found by `verify_blob`. `path` is the file's path inside the volume.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VerifyIssue {
+    /// Part of the volume header is damaged, but the volume still opens (e.g. through the
+    /// other superblock slot, or with the remaining keyslots).
+    Header { detail: String },
+    /// The volume's metadata doesn't authenticate, so none of its files could be checked.
+    Metadata { detail: String },
+    /// The data block (or, for a deduplicated file, one of its chunks) starts inside the
+    /// header region or ends past the end of the blob.
+    OutOfBounds {
+        path: String,
+        data_offset: u64,
+        data_length: u64,
+    },
+    /// The block's length doesn't match the file size and encryption layout.
+    BadLayout { path: String },
+    /// The block shares bytes with another file's block, or with the metadata (`other` is
+    /// `None`). Chunks shared by deduplicated files only count when they are listed
+    /// differently.
+    Overlap { path: String, other: Option<String> },
+    /// The content fails authentication; the first bad byte is at `offset` in the file.
+    Corrupt { path: String, offset: u64 },
+    /// Reading the block failed (e.g. a bad sector).
+    Unreadable { path: String, detail: String },
+}
+
+/// Checks an unlocked volume for damage without changing anything: the volume header, that
+/// the metadata authenticates, and for every file that its data block (and chunks) lie within
+/// the data area, match the file's size, don't overlap other blocks and authenticate in full.
+/// Blocks of the other volume are invisible and never checked.
+///
+/// # Arguments
+/// * `path` - Path to the blob file.
+/// * `volume_type` - Context: Which volume (`Standard` or `Hidden`) is unlocked.
+/// * `key` - Context: The data key of the unlocked volume.
+///
+/// # Returns
+/// A report listing every problem found. Damage is reported, not returned as an error.
+///
+/// # Errors
+/// `NotABlob` / `UnsupportedVersion` if the file can't be read as a blob, `WrongVolumeKey`
+/// if `key` doesn't open the volume, or `Io` if the blob can't be read at all.
+pub fn verify_blob(path: &Path, volume_type: VolumeType, key: &[u8; 32]) -> Result<VerifyReport> {
+    let mut file = File::open(path)?;
+    let format_version = read_blob_version(&mut file)?;
+    let mut report = VerifyReport {
+        format_version,
+        blob_size: file.metadata()?.len(),
+        files_checked: 0,
+        bytes_checked: 0,
+        issues: Vec::new(),
+    };
+
+    // 1. Header: the key must open one of the volume's keyslots (v3 has none)
+    if format_version != VERSION_V3 {
+        let keyslots = read_keyslots(&mut file, volume_type)?;
+        if !keyslots.iter().flatten().any(|keyslot| keyslot.wraps(key)) {
+            return Err(BlobError::WrongVolumeKey(match volume_type {
+                VolumeType::Standard => "standard",
+                VolumeType::Hidden => "hidden",
+            }));
+        }
+        let unreadable = keyslots.iter().filter(|keyslot| keyslot.is_none()).count();
+        if unreadable > 0 {
+            report.issues.push(VerifyIssue::Header {
+                detail: format!("{} keyslots are unreadable", unreadable),
+            });
+        }
+    }
+
+    // 2. Metadata
+    let check = match check_volume_metadata(&mut file, format_version, volume_type, key) {
+        Ok(check) => check,
+        Err(BlobError::Io(e)) => return Err(BlobError::Io(e)),
+        Err(e) => {
+            report.issues.push(VerifyIssue::Metadata {
+                detail: e.to_string(),
+            });
+            return Ok(report);
+        }
+    };
+    for detail in check.warnings {
+        report.issues.push(VerifyIssue::Header { detail });
+    }
+
+    // 3. Bounds and layout of every block, collecting the extents worth checking further.
+    //    A chunk is one extent however many files list it. Folders have no block.
+    let mut files: Vec<(&String, &FileMetadata)> = check
+        .metadata
+        .iter()
+        .filter(|(_, file_metadata)| !file_metadata.is_dir())
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(b.0));
+    let mut extents: Vec<(u64, u64, Option<&String>)> = Vec::with_capacity(files.len() + 1);
+    let (meta_offset, meta_size) = check.block;
+    if meta_offset >= DATA_AREA_START_OFFSET {
+        extents.push((meta_offset, meta_offset + meta_size, None)); // Spilled metadata
+    }
+    let mut chunk_extents = HashSet::new();
+    let mut intact = Vec::with_capacity(files.len());
+    for (file_path, file_metadata) in files {
+        let in_bounds = |offset: u64, length: u64| {
+            offset >= DATA_AREA_START_OFFSET
+                && offset
+                    .checked_add(length)
+                    .is_some_and(|end| end <= report.blob_size)
+        };
+        let chunk_out_of_bounds = file_metadata
+            .chunks
+            .iter()
+            .find(|chunk| !in_bounds(chunk.offset, chunk.stored_len()));
+        if !in_bounds(file_metadata.data_offset, file_metadata.data_length) {
+            report.issues.push(VerifyIssue::OutOfBounds {
+                path: file_path.clone(),
+                data_offset: file_metadata.data_offset,
+                data_length: file_metadata.data_length,
+            });
+        } else if let Some(chunk) = chunk_out_of_bounds {
+            report.issues.push(VerifyIssue::OutOfBounds {
+                path: file_path.clone(),
+                data_offset: chunk.offset,
+                data_length: chunk.stored_len(),
+            });
+        } else if !layout_matches(file_metadata) {
+            report.issues.push(VerifyIssue::BadLayout {
+                path: file_path.clone(),
+            });
+        } else {
+            extents.push((
+                file_metadata.data_offset,
+                file_metadata.data_offset + file_metadata.data_length,
+                Some(file_path),
+            ));
+            for chunk in &file_metadata.chunks {
+                if chunk_extents.insert(*chunk) {
+                    extents.push((
+                        chunk.offset,
+                        chunk.offset + chunk.stored_len(),
+                        Some(file_path),
+                    ));
+                }
+            }
+            intact.push((file_path, file_metadata));
+        }
+    }
+
+    // 4. Overlapping extents: after sorting by start, each extent may only begin once the
+    //    furthest-reaching extent before it has ended
+    extents.sort();
+    let mut furthest: Option<(u64, Option<&String>)> = None;
+    for (start, end, owner) in extents {
+        if let Some((reach, other)) = furthest {
+            if start < reach {
+                // Report against a file; metadata overlapping a file is reported on the file
+                let (path, other) = match owner {
+                    Some(path) => (path, other),
+                    None => (other.expect("only one metadata extent"), None),
+                };
+                report.issues.push(VerifyIssue::Overlap {
+                    path: path.clone(),
+                    other: other.cloned(),
+                });
+            }
+            if end <= reach {
+                continue;
+            }
+        }
+        furthest = Some((end, owner));
+    }
+
+    // 5. Authenticate every block that looked sane
+    for (file_path, file_metadata) in intact {
+        match authenticate(path, key, file_metadata) {
+            Ok(()) => {
+                report.files_checked += 1;
+                report.bytes_checked += file_metadata.size;
+            }
+            Err(issue) => {
+                warn!("Verification of '{}' failed: {:?}", file_path, issue);
+                report.issues.push(match issue {
+                    Failure::Corrupt(offset) => VerifyIssue::Corrupt {
+                        path: file_path.clone(),
+                        offset,
+                    },
+                    Failure::Unreadable(detail) => VerifyIssue::Unreadable {
+                        path: file_path.clone(),
+                        detail,
+                    },
+                });
+            }
+        }
+    }
+
+    info!(
+        "Verified {:?} volume of {}: {} files checked, {} issues",
+        volume_type,
+        path.display(),
+        report.files_checked,
+        report.issues.len()
+    );
+    Ok(report)
+}
+
+/// True if the block length is what the file size and format produce.
+fn layout_matches(metadata: &FileMetadata) -> bool {
+    if metadata.format == DataFormat::Deduplicated {
+        // The manifest's length depends on the path it was written under, so only the
+        // chunk list can be checked
+        return metadata
+            .chunks
+            .iter()
+            .map(|chunk| chunk.size as u64)
+            .sum::<u64>()
+            == metadata.size;
+    }
+    if !metadata.chunks.is_empty() {
+        return false;
+    }
+    match metadata.format.chunk_size() {
+        None => metadata.data_length == metadata.size + (XNONCE_LEN + TAG_LEN) as u64,
+        Some(chunk_size) => {
+            chunk_size != 0
+                && metadata.data_length
+                    == metadata.format.header_len() + encrypted_len(metadata.size, chunk_size)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Failure {
+    Corrupt(u64),
+    Unreadable(String),
+}
+
+/// Decrypts a whole block, including its header or manifest, discarding the plaintext.
+pub(crate) fn authenticate(
+    path: &Path,
+    key: &[u8; 32],
+    metadata: &FileMetadata,
+) -> Result<(), Failure> {
+    let classify = |e: BlobError, offset: u64| match e {
+        BlobError::CorruptBlock { .. } => Failure::Corrupt(offset),
+        e => Failure::Unreadable(e.to_string()),
+    };
+    if let DataFormat::ChunkedWithHeader { chunk_size, .. } = metadata.format {
+        let mut file = File::open(path).map_err(|e| classify(e.into(), 0))?;
+        let header =
+            BlockHeader::read(&mut file, key, metadata.data_offset).map_err(|e| classify(e, 0))?;
+        // The header describes the block it was written with
+        if header.size != metadata.size || header.chunk_size != chunk_size {
+            return Err(Failure::Corrupt(0));
+        }
+    }
+    if metadata.format == DataFormat::Deduplicated {
+        let mut file = File::open(path).map_err(|e| classify(e.into(), 0))?;
+        let manifest = ChunkManifest::read(&mut file, key, stream::manifest_offset(metadata))
+            .map_err(|e| classify(e, 0))?;
+        if manifest.size != metadata.size || manifest.chunks != metadata.chunks {
+            return Err(Failure::Corrupt(0));
+        }
+    }
+    let mut reader = stream::open_file(path, key, metadata).map_err(|e| classify(e, 0))?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut offset = 0u64;
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => offset += n as u64,
+            Err(e) => return Err(classify(e.into(), offset)),
+        }
+    }
+}
+
+impl UnlockedVolume {
+    /// Checks this volume for damage. See `verify_blob`.
+    ///
+    /// # Errors
+    /// As for `verify_blob`.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        verify_blob(self.path(), self.volume_type(), self.key())
+    }
+}
@@ -0,0 +1,789 @@
+use crate::blob::{
+    append_file_data, commit_metadata, credential_in_use, open_volume_key, read_blob_version,
+    read_free_space, read_keyslots, read_padding_policy, read_volume_kdf, spilled_metadata_blocks,
+    unix_now, unlock_file, volume_extents, write_keyslot, FileMetadata, MetadataMap, VolumeType,
+    VERSION_V3,
+};
+use crate::compact::{move_blocks, CompactionReport, METADATA_SLACK};
+use crate::compress::Compression;
+use crate::credential::{generate_recovery_key, split_recovery_key, Credential};
+use crate::dedup::ChunkIndex;
+use crate::error::{BlobError, Result};
+use crate::kdf::KdfParams;
+use crate::keyslot::Keyslot;
+use crate::space::Protected;
+use crate::stream::{self, FileReader};
+use log::info;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// An unlocked volume of a blob. Owns the open blob file together with the volume's type,
+/// data key and decrypted metadata, so every operation runs against the same state instead
+/// of the caller passing it along on each call.
+///
+/// The in-memory metadata always matches what is committed on disk: an operation that fails
+/// leaves both unchanged. `compact_blob` and `migrate_blob` replace the blob file, so unlock
+/// again after running them.
+pub struct UnlockedVolume {
+    path: PathBuf,
+    file: File,
+    volume_type: VolumeType,
+    key: [u8; 32],
+    metadata: MetadataMap,
+    /// Built on the first write, with the volume's free space. Keeps the chunks of removed
+    /// files, which stay on disk and can be referenced again until their space is reused.
+    chunks: Option<ChunkIndex>,
+    /// Extents of the hidden volume that writes must leave alone. Empty unless the volume
+    /// was unlocked with `unlock_protected`.
+    protected: Protected,
+}
+
+impl fmt::Debug for UnlockedVolume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the key
+        f.debug_struct("UnlockedVolume")
+            .field("path", &self.path)
+            .field("volume_type", &self.volume_type)
+            .field("files", &self.metadata.len())
+            .field("protected", &self.is_protected())
+            .finish_non_exhaustive()
+    }
+}
+
+impl UnlockedVolume {
+    // --- Opening ---
+
+    /// Unlocks whichever volume of the blob at `path` the password belongs to.
+    ///
+    /// # Errors
+    /// As for `unlock_blob`.
+    pub fn unlock(path: &Path, password: &str) -> Result<Self> {
+        Self::unlock_with_credential(path, &Credential::Password(password))
+    }
+
+    /// Unlocks a volume with a password, a password plus keyfile, a recovery key or
+    /// recovery shares.
+    ///
+    /// # Errors
+    /// As for `unlock_blob_with_credential`.
+    pub fn unlock_with_credential(path: &Path, credential: &Credential) -> Result<Self> {
+        info!("Unlock attempt for path: {}", path.display());
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let (volume_type, key, metadata) = unlock_file(&mut file, credential)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            volume_type,
+            key,
+            metadata,
+            chunks: None,
+            protected: Protected::default(),
+        })
+    }
+
+    /// Unlocks the standard volume in protected mode: the hidden volume's password is only
+    /// used to find out which parts of the blob the hidden volume owns, and every write of
+    /// the standard volume that would land on one of them is refused with
+    /// `HiddenVolumeProtected` instead of destroying hidden data.
+    ///
+    /// # Errors
+    /// As for `unlock`; `WrongPassword` as well if `hidden_password` doesn't open the hidden
+    /// volume, and `InvalidInput` if `password` opens the hidden volume itself.
+    pub fn unlock_protected(path: &Path, password: &str, hidden_password: &str) -> Result<Self> {
+        Self::unlock_protected_with_credential(
+            path,
+            &Credential::Password(password),
+            &Credential::Password(hidden_password),
+        )
+    }
+
+    /// Like `unlock_protected`, with any kind of credential for either volume.
+    ///
+    /// # Errors
+    /// As for `unlock_protected`.
+    pub fn unlock_protected_with_credential(
+        path: &Path,
+        credential: &Credential,
+        hidden_credential: &Credential,
+    ) -> Result<Self> {
+        let mut volume = Self::unlock_with_credential(path, credential)?;
+        if volume.volume_type != VolumeType::Standard {
+            return Err(BlobError::invalid(
+                "protected mode unlocks the standard volume",
+            ));
+        }
+        let version = read_blob_version(&mut volume.file)?;
+        let hidden_key = open_volume_key(
+            &mut volume.file,
+            version,
+            VolumeType::Hidden,
+            hidden_credential,
+        )?;
+        volume.protected = volume_extents(&mut volume.file, VolumeType::Hidden, &hidden_key)?;
+        info!("Standard volume unlocked with the hidden volume protected");
+        Ok(volume)
+    }
+
+    /// Builds a volume from context the caller already holds, taking over `metadata_map`
+    /// (give it back with `into_metadata`). The key isn't checked against the blob.
+    pub(crate) fn from_parts(
+        path: &Path,
+        volume_type: VolumeType,
+        key: &[u8; 32],
+        metadata_map: &mut MetadataMap,
+    ) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            volume_type,
+            key: *key,
+            metadata: std::mem::take(metadata_map),
+            chunks: None,
+            protected: Protected::default(),
+        })
+    }
+
+    /// Closes the volume, returning its metadata.
+    pub fn into_metadata(self) -> MetadataMap {
+        self.metadata
+    }
+
+    // --- Accessors ---
+
+    /// Path of the blob file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Which volume is unlocked.
+    pub fn volume_type(&self) -> VolumeType {
+        self.volume_type
+    }
+
+    /// The volume's data key.
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    /// True if the volume was unlocked with `unlock_protected`.
+    pub fn is_protected(&self) -> bool {
+        !self.protected.is_empty()
+    }
+
+    /// Metadata of every file and folder entry in the volume, keyed by path.
+    pub fn metadata(&self) -> &MetadataMap {
+        &self.metadata
+    }
+
+    /// Metadata of one file, or `None` if the volume has no file at `file_path`.
+    pub fn file_metadata(&self, file_path: &str) -> Option<&FileMetadata> {
+        self.metadata.get(file_path)
+    }
+
+    /// Blob path, key and chunk index, for writes through another handle on the blob.
+    #[cfg(feature = "async")]
+    pub(crate) fn write_context(&mut self) -> Result<(&Path, &[u8; 32], &mut ChunkIndex)> {
+        self.chunk_index()?;
+        let chunks = self.chunks.as_mut().expect("built above");
+        Ok((&self.path, &self.key, chunks))
+    }
+
+    /// The chunk index writes go through, built on first use.
+    fn chunk_index(&mut self) -> Result<&mut ChunkIndex> {
+        if self.chunks.is_none() {
+            let mut chunks = ChunkIndex::new(&self.metadata);
+            chunks.protect(self.protected.clone());
+            chunks.set_free_space(read_free_space(
+                &mut self.file,
+                self.volume_type,
+                &self.key,
+            )?);
+            chunks.set_padding(read_padding_policy(
+                &mut self.file,
+                self.volume_type,
+                &self.key,
+            )?);
+            self.chunks = Some(chunks);
+        }
+        Ok(self.chunks.as_mut().expect("built above"))
+    }
+
+    fn existing(&self, file_path: &str) -> Result<&FileMetadata> {
+        self.metadata
+            .get(file_path)
+            .filter(|entry| !entry.is_dir())
+            .ok_or_else(|| BlobError::FileNotFound(file_path.to_string()))
+    }
+
+    fn is_file(&self, file_path: &str) -> bool {
+        self.metadata
+            .get(file_path)
+            .is_some_and(|entry| !entry.is_dir())
+    }
+
+    /// Fails if there is a folder entry at `file_path`, which a file can't replace.
+    fn not_a_folder(&self, file_path: &str) -> Result<()> {
+        match self.metadata.get(file_path) {
+            Some(entry) if entry.is_dir() => {
+                Err(BlobError::invalid(format!("'{}' is a folder", file_path)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // --- Files ---
+
+    /// Adds or updates a file. See `add_file_from_reader`.
+    ///
+    /// # Errors
+    /// Returns an error on file I/O or crypto failures.
+    pub fn add_file(&mut self, file_path: &str, content: &[u8], mime_type: &str) -> Result<()> {
+        self.add_file_from_reader(file_path, &mut &content[..], mime_type)
+    }
+
+    /// Adds or updates a file, encrypting its content while reading it from `reader`. Memory
+    /// use is bounded by the chunk size, not the file size. The metadata entry is committed
+    /// only after the last chunk has been synced to disk, so an interrupted write leaves at
+    /// most an orphaned (unreferenced) data block behind.
+    ///
+    /// # Errors
+    /// Returns an error on read, file I/O or crypto failures.
+    pub fn add_file_from_reader<R: Read>(
+        &mut self,
+        file_path: &str,
+        reader: &mut R,
+        mime_type: &str,
+    ) -> Result<()> {
+        let file_metadata = self.write_file_data(file_path, reader, mime_type)?;
+        self.add_file_entries(vec![(file_path.to_string(), file_metadata)])
+    }
+
+    /// Encrypts and writes a file's content to the data area *without* referencing it from
+    /// the metadata. Space freed by deleted files is filled before the blob grows. Commit the
+    /// returned `FileMetadata` with `add_file_entries` to make the file visible, e.g. once the
+    /// destination path is known or to commit many files with a single metadata write. Chunks
+    /// the volume already stores, including those of earlier uncommitted writes, are
+    /// referenced rather than stored again. New chunks are compressed as
+    /// `Compression::for_file` picks for this volume and `mime_type`.
+    ///
+    /// `file_path` is recorded in the block header for `salvage_blob`; it need not match
+    /// the path the entry is committed under.
+    ///
+    /// # Errors
+    /// Returns an error on read, file I/O or crypto failures.
+    pub fn write_file_data<R: Read>(
+        &mut self,
+        file_path: &str,
+        reader: &mut R,
+        mime_type: &str,
+    ) -> Result<FileMetadata> {
+        let compression = Compression::for_file(self.volume_type, mime_type);
+        self.chunk_index()?;
+        let chunks = self.chunks.as_mut().expect("built above");
+        append_file_data(
+            &mut self.file,
+            &self.key,
+            chunks,
+            file_path,
+            reader,
+            mime_type,
+            compression,
+        )
+    }
+
+    /// Commits previously written data blocks (see `write_file_data`) in a single metadata
+    /// write. Existing entries with the same paths are replaced, keeping their `created_at`.
+    /// Set `original_mode`, `original_mtime` or `attributes` on the entries before committing
+    /// them.
+    ///
+    /// # Errors
+    /// `InvalidInput` if one of the paths is a folder, otherwise an error on file I/O or
+    /// crypto failures.
+    pub fn add_file_entries(&mut self, entries: Vec<(String, FileMetadata)>) -> Result<()> {
+        for (file_path, _) in &entries {
+            self.not_a_folder(file_path)?;
+        }
+        let mut previous = Vec::with_capacity(entries.len());
+        for (file_path, mut file_metadata) in entries {
+            if let Some(created_at) = self
+                .metadata
+                .get(&file_path)
+                .and_then(|existing| existing.created_at)
+            {
+                file_metadata.created_at = Some(created_at);
+            }
+            let replaced = self.metadata.insert(file_path.clone(), file_metadata);
+            previous.push((file_path, replaced));
+        }
+        self.commit(previous)
+    }
+
+    /// Decrypts a whole file into memory. Use `open_file` for large files.
+    ///
+    /// # Errors
+    /// `FileNotFound` if the volume has no such file, `CorruptBlock` if its data fails
+    /// authentication, or `Io`.
+    pub fn get_file(&self, file_path: &str) -> Result<Vec<u8>> {
+        let file_metadata = self.existing(file_path)?;
+        let mut content = Vec::with_capacity(file_metadata.size as usize);
+        stream::open_file(&self.path, &self.key, file_metadata)
+            .and_then(|mut reader| Ok(reader.read_to_end(&mut content)?))
+            .map_err(|e| e.for_file(file_path))?;
+        Ok(content)
+    }
+
+    /// Opens a streaming, seekable reader over a file. The reader has its own handle on the
+    /// blob, so it stays usable while the volume is modified.
+    ///
+    /// # Errors
+    /// `FileNotFound` if the volume has no such file, otherwise as for `open_file`.
+    pub fn open_file(&self, file_path: &str) -> Result<FileReader> {
+        stream::open_file(&self.path, &self.key, self.existing(file_path)?)
+            .map_err(|e| e.for_file(file_path))
+    }
+
+    /// Reads and decrypts a byte range of a file. See `read_file_range`.
+    ///
+    /// # Errors
+    /// `FileNotFound` if the volume has no such file, otherwise as for `read_file_range`.
+    pub fn read_file_range(&self, file_path: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        stream::read_file_range(
+            &self.path,
+            &self.key,
+            self.existing(file_path)?,
+            offset,
+            len,
+        )
+        .map_err(|e| e.for_file(file_path))
+    }
+
+    /// Removes a file from the metadata. The space of its blocks (minus chunks other files
+    /// still share) is freed: after one more commit, later writes fill it before the blob
+    /// grows. The folder holding it remains, even if it is now empty.
+    ///
+    /// # Returns
+    /// `Ok(true)` if the file was removed, `Ok(false)` if there was no such file.
+    ///
+    /// # Errors
+    /// Returns an error on file I/O or crypto failures during the metadata update.
+    pub fn remove_file(&mut self, file_path: &str) -> Result<bool> {
+        if !self.is_file(file_path) {
+            return Ok(false);
+        }
+        let removed = self.metadata.remove(file_path);
+        let mut previous = vec![(file_path.to_string(), removed)];
+        self.keep_parent(file_path, &mut previous);
+        self.commit(previous)?;
+        Ok(true)
+    }
+
+    /// Moves a file to a new path. Only the metadata changes; an existing file at `new_path`
+    /// is replaced. The folder it is moved out of remains, even if it is now empty.
+    ///
+    /// # Returns
+    /// `Ok(true)` if the file was renamed, `Ok(false)` if `old_path` doesn't exist.
+    ///
+    /// # Errors
+    /// `InvalidInput` if `new_path` is a folder, otherwise an error on file I/O or crypto
+    /// failures during the metadata update.
+    pub fn rename_file(&mut self, old_path: &str, new_path: &str) -> Result<bool> {
+        if !self.is_file(old_path) {
+            return Ok(false);
+        }
+        self.not_a_folder(new_path)?;
+        let file_metadata = self.metadata.remove(old_path).expect("checked above");
+        let renamed = FileMetadata {
+            modified_at: Some(unix_now()),
+            ..file_metadata.clone()
+        };
+        let replaced = self.metadata.insert(new_path.to_string(), renamed);
+        let mut previous = vec![
+            (old_path.to_string(), Some(file_metadata)),
+            (new_path.to_string(), replaced),
+        ];
+        self.keep_parent(old_path, &mut previous);
+        self.commit(previous)?;
+        Ok(true)
+    }
+
+    // --- Folders ---
+
+    /// Creates a folder, and any missing parent folders, so it exists even while it is empty.
+    /// Leading and trailing slashes are ignored.
+    ///
+    /// # Returns
+    /// `Ok(true)` if the folder was created, `Ok(false)` if it already had an entry.
+    ///
+    /// # Errors
+    /// `InvalidInput` if the path is empty or the folder or one of its parents is a file,
+    /// otherwise an error on file I/O or crypto failures during the metadata update.
+    pub fn create_folder(&mut self, folder_path: &str) -> Result<bool> {
+        let folder_path = folder_path.trim_matches('/');
+        if folder_path.is_empty() || folder_path.split('/').any(str::is_empty) {
+            return Err(BlobError::invalid(format!(
+                "invalid folder path '{}'",
+                folder_path
+            )));
+        }
+        let mut missing = Vec::new();
+        let ancestors = folder_path
+            .match_indices('/')
+            .map(|(end, _)| &folder_path[..end])
+            .chain([folder_path]);
+        for path in ancestors {
+            match self.metadata.get(path) {
+                Some(entry) if entry.is_dir() => {}
+                Some(_) => return Err(BlobError::invalid(format!("'{}' is a file", path))),
+                None => missing.push(path.to_string()),
+            }
+        }
+        if missing.is_empty() {
+            return Ok(false);
+        }
+
+        let previous = missing
+            .into_iter()
+            .map(|path| {
+                self.metadata
+                    .insert(path.clone(), FileMetadata::directory());
+                (path, None)
+            })
+            .collect();
+        self.commit(previous)?;
+        Ok(true)
+    }
+
+    /// Removes a folder, its entry and everything below it, matching on the path prefix. Like
+    /// `remove_file`, this frees the space of their blocks for later writes and leaves the
+    /// parent folder in place.
+    ///
+    /// # Arguments
+    /// * `folder_path` - The folder to remove (e.g., "documents/work"). Trailing slash is
+    ///   optional.
+    ///
+    /// # Returns
+    /// `Ok(true)` if at least one entry was removed, `Ok(false)` if nothing matched.
+    ///
+    /// # Errors
+    /// Returns an error on file I/O or crypto failures during the metadata update.
+    pub fn remove_folder(&mut self, folder_path: &str) -> Result<bool> {
+        // An empty path only matches an entry named "", never the whole volume
+        let prefix = if folder_path.is_empty() || folder_path.ends_with('/') {
+            folder_path.to_string()
+        } else {
+            format!("{}/", folder_path)
+        };
+        let path_itself = folder_path.trim_end_matches('/');
+        let matching: Vec<String> = self
+            .metadata
+            .keys()
+            .filter(|k| *k == path_itself || (!prefix.is_empty() && k.starts_with(&prefix)))
+            .cloned()
+            .collect();
+        if matching.is_empty() {
+            return Ok(false);
+        }
+
+        let mut previous = matching
+            .into_iter()
+            .map(|file_path| {
+                let removed = self.metadata.remove(&file_path);
+                (file_path, removed)
+            })
+            .collect();
+        self.keep_parent(path_itself, &mut previous);
+        self.commit(previous)?;
+        Ok(true)
+    }
+
+    /// Gives the folder holding `path` an entry of its own if removing `path` left nothing
+    /// else below it, so that emptying a folder doesn't make it disappear.
+    fn keep_parent(&mut self, path: &str, previous: &mut Vec<(String, Option<FileMetadata>)>) {
+        let Some((parent, _)) = path.rsplit_once('/') else {
+            return;
+        };
+        let prefix = format!("{}/", parent);
+        if parent.is_empty()
+            || self.metadata.contains_key(parent)
+            || self.metadata.keys().any(|k| k.starts_with(&prefix))
+        {
+            return;
+        }
+        self.metadata
+            .insert(parent.to_string(), FileMetadata::directory());
+        previous.push((parent.to_string(), None));
+    }
+
+    /// Writes the metadata to disk after an in-memory change. `previous` holds each changed
+    /// path with its entry before the change, which is restored if the write fails.
+    fn commit(&mut self, previous: Vec<(String, Option<FileMetadata>)>) -> Result<()> {
+        let result = commit_metadata(
+            &self.path,
+            &mut self.file,
+            self.volume_type,
+            &self.key,
+            &self.metadata,
+            &self.protected,
+        );
+        match result {
+            Ok(free) => {
+                if let Some(chunks) = &mut self.chunks {
+                    chunks.committed(&self.metadata, free);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for (file_path, entry) in previous.into_iter().rev() {
+                    match entry {
+                        Some(entry) => self.metadata.insert(file_path, entry),
+                        None => self.metadata.remove(&file_path),
+                    };
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // --- Compaction ---
+
+    /// Compacts this volume in place, without the other volume's password: its blocks move
+    /// down into the space its deleted files freed, and free space left at the end of the
+    /// blob is cut off. Only extents the volume itself owns are written or cut off, so the
+    /// other volume's blocks and any bytes the volume can't account for stay exactly where
+    /// they are; unlike `compact_blob`, this can't reclaim space the other volume freed.
+    ///
+    /// Runs `compact_step` until it is done. Each step commits, so after an interruption
+    /// compaction picks up where it left off when run again.
+    ///
+    /// A preallocated blob keeps its length, and each volume keeps to its own region of it, so
+    /// for its volumes this does nothing.
+    ///
+    /// # Errors
+    /// As for `compact_step`. The steps before the failing one stay committed.
+    pub fn compact_in_place(&mut self) -> Result<CompactionReport> {
+        let mut report = CompactionReport::default();
+        while !report.done {
+            report.add(self.compact_step()?);
+        }
+        info!(
+            "Compacted {:?} volume in {} steps: {} bytes moved, {} bytes reclaimed",
+            self.volume_type, report.steps, report.moved_bytes, report.reclaimed_bytes
+        );
+        Ok(report)
+    }
+
+    /// Runs one step of `compact_in_place` and commits it. In order of preference, a step
+    /// moves a batch of blocks down, cuts free space off the end of the blob, or commits
+    /// unchanged metadata so that space released by the last step becomes reusable and
+    /// spilled metadata moves down.
+    ///
+    /// # Returns
+    /// What the step did; `done` once there is nothing left to do, in which case nothing
+    /// was written.
+    ///
+    /// # Errors
+    /// `HiddenVolumeProtected` in protected mode if a block would land on the hidden volume,
+    /// or an error reading or writing the blob. The step is then not committed.
+    pub fn compact_step(&mut self) -> Result<CompactionReport> {
+        let step = CompactionReport {
+            steps: 1,
+            ..CompactionReport::default()
+        };
+        self.chunk_index()?;
+        let chunks = self.chunks.as_mut().expect("built above");
+        if chunks.free_space().is_preallocated() {
+            return Ok(CompactionReport {
+                steps: 0,
+                done: true,
+                ..step
+            });
+        }
+        let (moved_bytes, previous) =
+            move_blocks(&mut self.file, &self.key, &mut self.metadata, chunks)?;
+        if moved_bytes > 0 {
+            self.commit(previous)?;
+            return Ok(CompactionReport {
+                moved_bytes,
+                ..step
+            });
+        }
+
+        let free = chunks.free_space().clone();
+        let end = self.file.seek(SeekFrom::End(0))?;
+        if let Some((offset, len)) = free.last_extent().filter(|(o, l)| o + l == end) {
+            // The commit forgets the space that was cut off
+            self.file.set_len(offset)?;
+            self.file.sync_all()?;
+            self.commit(Vec::new())?;
+            return Ok(CompactionReport {
+                reclaimed_bytes: len,
+                ..step
+            });
+        }
+        let metadata_can_move =
+            spilled_metadata_blocks(&mut self.file, self.volume_type, &self.key)?
+                .into_iter()
+                .any(|(offset, size)| {
+                    free.clone()
+                        .allocate_below(size + METADATA_SLACK, 1, offset)
+                        .is_some()
+                });
+        if free.has_pending() || metadata_can_move {
+            self.commit(Vec::new())?;
+            return Ok(step);
+        }
+        Ok(CompactionReport {
+            steps: 0,
+            done: true,
+            ..step
+        })
+    }
+
+    // --- Keyslots ---
+
+    /// Reads the volume's keyslots for a keyslot operation. Fails for v3 blobs and unless the
+    /// key is the volume's data key.
+    fn keyslots(&mut self) -> Result<Vec<Option<Keyslot>>> {
+        if read_blob_version(&mut self.file)? == VERSION_V3 {
+            return Err(BlobError::RequiresMigration(
+                "v3 blobs have a single password",
+            ));
+        }
+        let keyslots = read_keyslots(&mut self.file, self.volume_type)?;
+        if !keyslots
+            .iter()
+            .flatten()
+            .any(|keyslot| keyslot.wraps(&self.key))
+        {
+            return Err(BlobError::WrongVolumeKey(match self.volume_type {
+                VolumeType::Standard => "standard",
+                VolumeType::Hidden => "hidden",
+            }));
+        }
+        Ok(keyslots)
+    }
+
+    /// Adds a password, keyfile or recovery key to the volume. See `add_keyslot`.
+    ///
+    /// # Returns
+    /// The index of the slot that was filled.
+    ///
+    /// # Errors
+    /// As for `add_keyslot`.
+    pub fn add_keyslot(&mut self, credential: &Credential) -> Result<usize> {
+        self.fill_keyslot(credential, true)
+    }
+
+    /// Seals `credential` into the first slot this volume doesn't use, with the KDF parameters
+    /// of the volume's first slot, which its decoys share too. Checking that the credential
+    /// isn't in use already costs a key derivation per slot of the other volume, so it is
+    /// skipped for freshly generated recovery keys, which can't collide.
+    fn fill_keyslot(&mut self, credential: &Credential, check_in_use: bool) -> Result<usize> {
+        let kdf = self.kdf()?;
+        let key = self.key;
+        let index = self
+            .keyslots()?
+            .iter()
+            .position(|keyslot| !matches!(keyslot, Some(keyslot) if keyslot.wraps(&key)))
+            .ok_or(BlobError::KeyslotsFull)?;
+        if check_in_use && credential_in_use(&mut self.file, credential, self.volume_type, &key)? {
+            return Err(BlobError::CredentialInUse);
+        }
+
+        let keyslot = Keyslot::seal(credential, &key, &kdf)?;
+        write_keyslot(&mut self.file, self.volume_type, index, &keyslot)?;
+        self.file.sync_all()?;
+        info!("Added keyslot {} to {:?} volume", index, self.volume_type);
+        Ok(index)
+    }
+
+    /// Generates a new recovery key and adds it in a free keyslot. See `add_recovery_key`.
+    ///
+    /// # Returns
+    /// The new recovery key and the index of its slot.
+    ///
+    /// # Errors
+    /// As for `add_keyslot`.
+    pub fn add_recovery_key(&mut self) -> Result<(String, usize)> {
+        let recovery_key = generate_recovery_key();
+        let index = self.fill_keyslot(&Credential::RecoveryKey(&recovery_key), false)?;
+        Ok((recovery_key, index))
+    }
+
+    /// Adds a recovery key that is only handed out as `count` mnemonic shares, any
+    /// `threshold` of which unlock the volume. See `add_recovery_shares`.
+    ///
+    /// # Returns
+    /// The shares and the index of their slot.
+    ///
+    /// # Errors
+    /// Returns an error if the threshold is out of range, otherwise as for `add_keyslot`.
+    pub fn add_recovery_shares(
+        &mut self,
+        threshold: u8,
+        count: u8,
+    ) -> Result<(Vec<String>, usize)> {
+        let recovery_key = generate_recovery_key();
+        // Split before touching the blob so a bad threshold leaves it unchanged
+        let shares = split_recovery_key(&recovery_key, threshold, count)?;
+        let index = self.fill_keyslot(&Credential::RecoveryKey(&recovery_key), false)?;
+        Ok((shares, index))
+    }
+
+    /// KDF parameters of the volume's first slot, used for every slot added later.
+    fn kdf(&mut self) -> Result<KdfParams> {
+        let version = read_blob_version(&mut self.file)?;
+        read_volume_kdf(&mut self.file, version, self.volume_type)
+    }
+
+    /// Lists the indices of the keyslots in use by this volume. See `list_keyslots`.
+    ///
+    /// # Errors
+    /// As for `list_keyslots`.
+    pub fn list_keyslots(&mut self) -> Result<Vec<usize>> {
+        let key = self.key;
+        Ok(self
+            .keyslots()?
+            .iter()
+            .enumerate()
+            .filter(|(_, keyslot)| matches!(keyslot, Some(keyslot) if keyslot.wraps(&key)))
+            .map(|(index, _)| index)
+            .collect())
+    }
+
+    /// Revokes a keyslot of this volume by overwriting it with a decoy. See `revoke_keyslot`.
+    ///
+    /// # Errors
+    /// As for `revoke_keyslot`.
+    pub fn revoke_keyslot(&mut self, index: usize) -> Result<()> {
+        let key = self.key;
+        let keyslots = self.keyslots()?;
+        let in_use = keyslots
+            .iter()
+            .flatten()
+            .filter(|keyslot| keyslot.wraps(&key))
+            .count();
+        let keyslot = match keyslots.get(index) {
+            Some(Some(keyslot)) if keyslot.wraps(&key) => keyslot,
+            _ => return Err(BlobError::KeyslotNotInUse(index)),
+        };
+        if in_use == 1 {
+            return Err(BlobError::LastKeyslot);
+        }
+
+        write_keyslot(
+            &mut self.file,
+            self.volume_type,
+            index,
+            &Keyslot::decoy(keyslot.kdf()),
+        )?;
+        self.file.sync_all()?;
+        info!("Revoked keyslot {} of {:?} volume", index, self.volume_type);
+        Ok(())
+    }
+}
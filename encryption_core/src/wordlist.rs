@@ -0,0 +1,31 @@
+/// Mnemonic words, one per byte value. Short, concrete, distinct nouns that are hard to
+/// mishear; the list is part of the share format and must never be reordered.
+pub(crate) const WORDS: [&str; 256] = [
+    "acid", "acorn", "actor", "adult", "agent", "alarm", "album", "alert", "alley", "amber",
+    "angle", "ankle", "apple", "april", "apron", "arena", "armor", "arrow", "atlas", "attic",
+    "audio", "autumn", "avenue", "award", "bacon", "badge", "bagel", "baker", "bamboo", "banana",
+    "banjo", "barrel", "basil", "basket", "beach", "beacon", "beaver", "bench", "berry", "bicycle",
+    "blade", "blanket", "blossom", "board", "bonus", "boots", "bottle", "bracket", "branch",
+    "bread", "brick", "bridge", "bronze", "broom", "bubble", "bucket", "buffalo", "butter",
+    "button", "cabin", "cable", "cactus", "camel", "camera", "canal", "candle", "canoe", "canyon",
+    "carbon", "carpet", "carrot", "castle", "cattle", "cedar", "cello", "cement", "chalk",
+    "cherry", "chess", "chimney", "circus", "citrus", "clock", "cloud", "clover", "coast",
+    "cobalt", "coconut", "coffee", "comet", "copper", "coral", "cotton", "cougar", "crane",
+    "crater", "crayon", "cricket", "crystal", "curtain", "cushion", "daisy", "dancer", "delta",
+    "denim", "desert", "diamond", "dolphin", "donkey", "dragon", "drum", "eagle", "easel", "echo",
+    "eclipse", "elbow", "elder", "ember", "engine", "falcon", "feather", "fence", "fern", "ferry",
+    "fiddle", "finch", "fossil", "fountain", "fox", "galaxy", "garden", "garlic", "gecko",
+    "geyser", "ginger", "giraffe", "glacier", "globe", "glove", "goat", "gorilla", "granite",
+    "grape", "gravel", "guitar", "hammer", "harbor", "harvest", "hazel", "helmet", "honey",
+    "horizon", "hornet", "igloo", "insect", "iris", "island", "ivory", "jacket", "jaguar",
+    "jasmine", "jelly", "jewel", "jigsaw", "jungle", "kayak", "kettle", "kiwi", "koala", "ladder",
+    "lagoon", "lantern", "lemon", "lens", "leopard", "lettuce", "lilac", "lily", "linen", "lizard",
+    "lobster", "locket", "lotus", "magnet", "mango", "maple", "marble", "meadow", "melon",
+    "mirror", "mitten", "monkey", "mosaic", "moss", "mountain", "muffin", "nectar", "needle",
+    "nickel", "noodle", "nutmeg", "oasis", "ocean", "olive", "onion", "orange", "orbit", "orchid",
+    "otter", "oven", "owl", "oyster", "paddle", "palace", "panda", "paper", "parrot", "pasta",
+    "peach", "peanut", "pearl", "pebble", "pelican", "pencil", "pepper", "piano", "pigeon",
+    "pillow", "pine", "planet", "plum", "pocket", "pony", "poppy", "potato", "prism", "pumpkin",
+    "puzzle", "quartz", "quilt", "rabbit", "radar", "radish", "raven", "ribbon", "river", "robin",
+    "rocket", "saddle", "salmon", "sandal", "satin", "scarf", "shadow", "shell", "silver",
+];
@@ -0,0 +1,40 @@
+//! Fixtures shared by the integration tests. Each test crate uses only some of them.
+#![allow(dead_code)]
+
+use encryption_core::{InitOptions, KdfParams};
+use std::path::Path;
+
+/// A KDF cheap enough to run many times per test.
+pub fn light_kdf() -> KdfParams {
+    KdfParams {
+        memory_kib: 8192,
+        iterations: 1,
+        ..KdfParams::default()
+    }
+}
+
+/// Default init options with `light_kdf`. Tests set the options they exercise on top.
+pub fn light_options() -> InitOptions {
+    InitOptions {
+        kdf: light_kdf(),
+        ..InitOptions::default()
+    }
+}
+
+/// Pseudo-random bytes (xorshift), which don't compress and split into chunks where real
+/// content would.
+pub fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+pub fn blob_size(path: &Path) -> u64 {
+    std::fs::metadata(path).unwrap().len()
+}
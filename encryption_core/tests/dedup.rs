@@ -0,0 +1,318 @@
+mod common;
+
+use common::{blob_size, noise};
+use encryption_core::*;
+use tempfile::tempdir;
+
+#[test]
+fn repeated_content_is_stored_once() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let mut volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    let photo = noise(1024 * 1024, 3);
+
+    volume
+        .add_file("photos/a.jpg", &photo, "image/jpeg")
+        .unwrap();
+    let after_first = blob_size(&blob_path);
+    volume
+        .add_file("backup/a.jpg", &photo, "image/jpeg")
+        .unwrap();
+    let after_copy = blob_size(&blob_path);
+    // Only a manifest and the metadata are written for the copy
+    assert!(
+        after_copy - after_first < 16 * 1024,
+        "{}",
+        after_copy - after_first
+    );
+
+    // An edited copy shares all but the chunks around the edit
+    let mut edited = b"EXIF".to_vec();
+    edited.extend_from_slice(&photo);
+    volume
+        .add_file("photos/a-edited.jpg", &edited, "image/jpeg")
+        .unwrap();
+    let after_edit = blob_size(&blob_path);
+    assert!(after_edit - after_copy < photo.len() as u64 / 2);
+
+    let first = volume.file_metadata("photos/a.jpg").unwrap();
+    let copy = volume.file_metadata("backup/a.jpg").unwrap();
+    assert_eq!(first.format, DataFormat::Deduplicated);
+    assert_eq!(first.chunks, copy.chunks);
+    let index = ChunkIndex::new(volume.metadata());
+    assert!(first
+        .chunks
+        .iter()
+        .all(|chunk| index.references(&chunk.hash) >= 2));
+
+    assert_eq!(volume.get_file("backup/a.jpg").unwrap(), photo);
+    assert_eq!(volume.get_file("photos/a-edited.jpg").unwrap(), edited);
+    assert_eq!(
+        volume
+            .read_file_range("photos/a-edited.jpg", 300_000, 100)
+            .unwrap(),
+        edited[300_000..300_100]
+    );
+    let report = volume.verify().unwrap();
+    assert!(report.is_healthy(), "{:?}", report.issues);
+}
+
+#[test]
+fn volumes_never_share_chunks() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let content = noise(512 * 1024, 5);
+
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    standard
+        .add_file("a.bin", &content, "application/octet-stream")
+        .unwrap();
+    let before = blob_size(&blob_path);
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    hidden
+        .add_file("a.bin", &content, "application/octet-stream")
+        .unwrap();
+    assert!(blob_size(&blob_path) - before > content.len() as u64);
+
+    // Equal content has unrelated hashes in the two volumes
+    let standard_hashes = ChunkIndex::new(standard.metadata());
+    assert!(hidden
+        .file_metadata("a.bin")
+        .unwrap()
+        .chunks
+        .iter()
+        .all(|chunk| { standard_hashes.references(&chunk.hash) == 0 }));
+}
+
+#[test]
+fn compaction_keeps_shared_chunks_and_drops_unreferenced_ones() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let installer = noise(768 * 1024, 9);
+    let other = noise(768 * 1024, 13);
+
+    let mut volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    volume
+        .add_file("v1/setup.exe", &installer, "application/octet-stream")
+        .unwrap();
+    volume
+        .add_file("v2/setup.exe", &installer, "application/octet-stream")
+        .unwrap();
+    volume
+        .add_file("other.bin", &other, "application/octet-stream")
+        .unwrap();
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    hidden
+        .add_file("secret.bin", &other, "application/octet-stream")
+        .unwrap();
+
+    // One of the two references to the installer's chunks goes, the other stays
+    assert!(volume.remove_file("v1/setup.exe").unwrap());
+    assert!(volume.remove_file("other.bin").unwrap());
+    let before = blob_size(&blob_path);
+    compact_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let after = blob_size(&blob_path);
+    // The standard volume's copy of `other` is gone, the installer is kept once
+    assert!(
+        before - after >= other.len() as u64,
+        "{} -> {}",
+        before,
+        after
+    );
+
+    let volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    // The emptied v1 folder and the remaining installer
+    assert_eq!(volume.metadata().len(), 2);
+    assert!(volume.file_metadata("v1").unwrap().is_dir());
+    assert_eq!(volume.get_file("v2/setup.exe").unwrap(), installer);
+    assert!(volume.verify().unwrap().is_healthy());
+    let hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(hidden.get_file("secret.bin").unwrap(), other);
+}
+
+#[test]
+fn text_is_compressed_except_in_the_hidden_volume() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let log: Vec<u8> = (0..20_000)
+        .flat_map(|i| {
+            format!("2026-10-17T12:00:{:02} INFO served request {}\n", i % 60, i).into_bytes()
+        })
+        .collect();
+
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    let before = blob_size(&blob_path);
+    standard.add_file("server.log", &log, "text/plain").unwrap();
+    let growth = blob_size(&blob_path) - before;
+    assert!(growth < log.len() as u64 / 4, "{} of {}", growth, log.len());
+    let entry = standard.file_metadata("server.log").unwrap();
+    assert_eq!(entry.compression, Compression::Zstd);
+    assert!(entry
+        .chunks
+        .iter()
+        .all(|chunk| chunk.compressed_size.is_some()));
+    assert_eq!(standard.get_file("server.log").unwrap(), log);
+    assert_eq!(
+        standard.read_file_range("server.log", 500_000, 64).unwrap(),
+        log[500_000..500_064]
+    );
+    assert!(standard.verify().unwrap().is_healthy());
+
+    // Already compressed formats are stored as they are
+    let photo = noise(100_000, 17);
+    standard.add_file("a.jpg", &photo, "image/jpeg").unwrap();
+    let entry = standard.file_metadata("a.jpg").unwrap();
+    assert_eq!(entry.compression, Compression::None);
+    assert!(entry
+        .chunks
+        .iter()
+        .all(|chunk| chunk.compressed_size.is_none()));
+
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    let before = blob_size(&blob_path);
+    hidden.add_file("server.log", &log, "text/plain").unwrap();
+    assert!(blob_size(&blob_path) - before > log.len() as u64);
+    assert_eq!(
+        hidden.file_metadata("server.log").unwrap().compression,
+        Compression::None
+    );
+
+    // Compaction keeps both encodings
+    compact_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    assert_eq!(
+        standard.file_metadata("server.log").unwrap().compression,
+        Compression::Zstd
+    );
+    assert_eq!(standard.get_file("server.log").unwrap(), log);
+    let hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(hidden.get_file("server.log").unwrap(), log);
+}
+
+#[test]
+fn space_of_replaced_files_is_reused() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let secret = noise(256 * 1024, 21);
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    hidden
+        .add_file("secret.bin", &secret, "application/octet-stream")
+        .unwrap();
+
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    let before = blob_size(&blob_path);
+    let size = 256 * 1024;
+    for seed in 0..16 {
+        standard
+            .add_file(
+                "draft.bin",
+                &noise(size, 100 + seed),
+                "application/octet-stream",
+            )
+            .unwrap();
+        standard
+            .add_file(
+                "scratch.bin",
+                &noise(size / 4, 200 + seed),
+                "application/octet-stream",
+            )
+            .unwrap();
+        assert!(standard.remove_file("scratch.bin").unwrap());
+    }
+    // A replaced version is reusable two commits later, so only a few versions are on disk
+    let growth = blob_size(&blob_path) - before;
+    assert!(growth < 4 * size as u64, "{}", growth);
+
+    let last = noise(size, 115);
+    assert_eq!(standard.get_file("draft.bin").unwrap(), last);
+    let report = standard.verify().unwrap();
+    assert!(report.is_healthy(), "{:?}", report.issues);
+    let standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    assert_eq!(standard.get_file("draft.bin").unwrap(), last);
+    let hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(hidden.get_file("secret.bin").unwrap(), secret);
+    assert!(hidden.verify().unwrap().is_healthy());
+}
+
+#[test]
+fn in_place_compaction_moves_only_the_volumes_own_blocks() {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    standard
+        .add_file("old.bin", &noise(600 * 1024, 1), "application/octet-stream")
+        .unwrap();
+    let keep = noise(300 * 1024, 2);
+    standard
+        .add_file("keep.bin", &keep, "application/octet-stream")
+        .unwrap();
+    let secret = noise(300 * 1024, 3);
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    hidden
+        .add_file("secret.bin", &secret, "application/octet-stream")
+        .unwrap();
+    // Bytes neither volume knows about
+    let junk = noise(64 * 1024, 4);
+    let junk_offset = blob_size(&blob_path);
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&blob_path)
+        .unwrap();
+    file.write_all(&junk).unwrap();
+    drop(file);
+    let late = noise(500 * 1024, 5);
+    standard
+        .add_file("late.bin", &late, "application/octet-stream")
+        .unwrap();
+    standard
+        .add_file("copy/late.bin", &late, "application/octet-stream")
+        .unwrap();
+    assert!(standard.remove_file("old.bin").unwrap());
+
+    let before = blob_size(&blob_path);
+    let report = standard.compact_in_place().unwrap();
+    assert!(report.done);
+    assert!(report.moved_bytes >= late.len() as u64, "{:?}", report);
+    assert_eq!(before - blob_size(&blob_path), report.reclaimed_bytes);
+    assert!(report.reclaimed_bytes >= late.len() as u64, "{:?}", report);
+
+    // The late file moved below the unknown bytes, which are untouched
+    for path in ["late.bin", "copy/late.bin"] {
+        let entry = standard.file_metadata(path).unwrap();
+        assert!(entry.data_offset < junk_offset);
+        assert!(entry.chunks.iter().all(|chunk| chunk.offset < junk_offset));
+    }
+    let mut file = std::fs::File::open(&blob_path).unwrap();
+    let mut found = vec![0u8; junk.len()];
+    file.seek(SeekFrom::Start(junk_offset)).unwrap();
+    file.read_exact(&mut found).unwrap();
+    assert_eq!(found, junk);
+
+    let report = standard.verify().unwrap();
+    assert!(report.is_healthy(), "{:?}", report.issues);
+    let (volume_type, key, mut metadata) = unlock_blob(&blob_path, "standard_pw").unwrap();
+    assert_eq!(metadata.len(), standard.metadata().len());
+    let again = compact_volume_step(&blob_path, volume_type, &key, &mut metadata).unwrap();
+    assert_eq!(
+        again,
+        CompactionReport {
+            done: true,
+            ..CompactionReport::default()
+        }
+    );
+    let standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    assert_eq!(standard.get_file("late.bin").unwrap(), late);
+    assert_eq!(standard.get_file("copy/late.bin").unwrap(), late);
+    assert_eq!(standard.get_file("keep.bin").unwrap(), keep);
+    let hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(hidden.get_file("secret.bin").unwrap(), secret);
+    assert!(hidden.verify().unwrap().is_healthy());
+}
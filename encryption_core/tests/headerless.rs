@@ -0,0 +1,104 @@
+mod common;
+
+use encryption_core::*;
+use rand::RngCore;
+use tempfile::tempdir;
+
+fn headerless_options() -> InitOptions {
+    InitOptions {
+        headerless: true,
+        ..InitOptions::default()
+    }
+}
+
+fn random_file(path: &std::path::Path, len: usize) {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    std::fs::write(path, bytes).unwrap();
+}
+
+#[test]
+fn headerless_blobs_have_no_plaintext_header() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("photos.bin");
+    init_blob_with_options(
+        &blob_path,
+        "standard_pw",
+        "hidden_pw",
+        &headerless_options(),
+    )
+    .unwrap();
+    let bytes = std::fs::read(&blob_path).unwrap();
+    assert!(!bytes.starts_with(b"ENC_BLOB"));
+    // Only a password tells it apart from random data
+    assert_eq!(blob_kind(&blob_path).unwrap(), BlobKind::Headerless);
+    assert!(matches!(blob_version(&blob_path), Err(BlobError::NotABlob)));
+
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    assert_eq!(standard.volume_type(), VolumeType::Standard);
+    standard
+        .add_file("notes.txt", b"decoy notes", "text/plain")
+        .unwrap();
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(hidden.volume_type(), VolumeType::Hidden);
+    hidden
+        .add_file("secret.txt", b"hidden notes", "text/plain")
+        .unwrap();
+    assert!(matches!(
+        UnlockedVolume::unlock(&blob_path, "other_pw"),
+        Err(BlobError::WrongPassword)
+    ));
+
+    // New keyslots get the default KDF parameters of the others
+    standard
+        .add_keyslot(&Credential::Password("second_pw"))
+        .unwrap();
+    let (recovery_key, _) = standard.add_recovery_key().unwrap();
+    assert!(standard.verify().unwrap().is_healthy());
+    assert!(hidden.verify().unwrap().is_healthy());
+    drop((standard, hidden));
+
+    // Compaction keeps the format
+    compact_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    assert!(!std::fs::read(&blob_path).unwrap().starts_with(b"ENC_BLOB"));
+    let standard =
+        UnlockedVolume::unlock_with_credential(&blob_path, &Credential::RecoveryKey(&recovery_key))
+            .unwrap();
+    assert_eq!(standard.get_file("notes.txt").unwrap(), b"decoy notes");
+    let hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(hidden.get_file("secret.txt").unwrap(), b"hidden notes");
+}
+
+#[test]
+fn headerless_blobs_use_the_default_kdf() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("photos.bin");
+    let options = InitOptions {
+        kdf: KdfParams {
+            iterations: 1,
+            ..KdfParams::default()
+        },
+        ..headerless_options()
+    };
+    assert!(matches!(
+        init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &options),
+        Err(BlobError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn random_files_are_not_unlocked() {
+    let dir = tempdir().unwrap();
+    let noise_path = dir.path().join("noise.bin");
+    random_file(&noise_path, 2 * 1024 * 1024);
+    assert_eq!(blob_kind(&noise_path).unwrap(), BlobKind::Headerless);
+    assert!(matches!(
+        UnlockedVolume::unlock(&noise_path, "standard_pw"),
+        Err(BlobError::WrongPassword)
+    ));
+
+    // Too short to hold a header region
+    let short_path = dir.path().join("short.bin");
+    random_file(&short_path, 4096);
+    assert!(matches!(blob_kind(&short_path), Err(BlobError::NotABlob)));
+}
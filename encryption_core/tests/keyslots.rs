@@ -0,0 +1,237 @@
+mod common;
+
+use common::light_options;
+use encryption_core::*;
+use tempfile::tempdir;
+
+#[test]
+fn shared_volume_with_several_passwords() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("shared.blob");
+    let options = light_options();
+    init_blob_with_options(&blob_path, "alice_pw", "hidden_pw", &options).unwrap();
+    let (volume, key, mut meta) = unlock_blob(&blob_path, "alice_pw").unwrap();
+    add_file(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        "notes.txt",
+        b"shared notes",
+        "text/plain",
+    )
+    .unwrap();
+    // Slot 0 holds the password, slot 1 the recovery key
+    assert_eq!(list_keyslots(&blob_path, volume, &key).unwrap(), vec![0, 1]);
+
+    // Each person gets their own slot
+    let bob = add_keyslot(&blob_path, volume, &key, &Credential::Password("bob_pw")).unwrap();
+    let carol = add_keyslot(&blob_path, volume, &key, &Credential::Password("carol_pw")).unwrap();
+    assert_eq!(
+        list_keyslots(&blob_path, volume, &key).unwrap(),
+        vec![0, 1, bob, carol]
+    );
+
+    // Passwords already in use, in either volume, are rejected
+    for taken in ["bob_pw", "hidden_pw"] {
+        let credential = Credential::Password(taken);
+        assert!(matches!(
+            add_keyslot(&blob_path, volume, &key, &credential),
+            Err(BlobError::CredentialInUse)
+        ));
+    }
+
+    for password in ["alice_pw", "bob_pw", "carol_pw"] {
+        let (unlocked, same_key, meta) = unlock_blob(&blob_path, password).unwrap();
+        assert_eq!(unlocked, VolumeType::Standard);
+        assert_eq!(same_key, key);
+        assert_eq!(
+            get_file(&blob_path, &same_key, &meta["notes.txt"]).unwrap(),
+            b"shared notes"
+        );
+    }
+
+    // Revoking Bob doesn't touch the data or the other passwords
+    revoke_keyslot(&blob_path, volume, &key, bob).unwrap();
+    assert!(matches!(
+        unlock_blob(&blob_path, "bob_pw"),
+        Err(BlobError::WrongPassword)
+    ));
+    assert!(matches!(
+        revoke_keyslot(&blob_path, volume, &key, bob),
+        Err(BlobError::KeyslotNotInUse(_))
+    ));
+    let (_, key, meta) = unlock_blob(&blob_path, "carol_pw").unwrap();
+    assert_eq!(
+        get_file(&blob_path, &key, &meta["notes.txt"]).unwrap(),
+        b"shared notes"
+    );
+
+    // Changing a password only replaces that person's slot
+    change_password(&blob_path, "carol_pw", "carol_new_pw").unwrap();
+    assert!(unlock_blob(&blob_path, "carol_pw").is_err());
+    assert!(unlock_blob(&blob_path, "carol_new_pw").is_ok());
+    assert!(unlock_blob(&blob_path, "alice_pw").is_ok());
+
+    // Compaction keeps every slot
+    compact_blob(&blob_path, "alice_pw", "hidden_pw").unwrap();
+    let (_, key, meta) = unlock_blob(&blob_path, "carol_new_pw").unwrap();
+    assert_eq!(
+        get_file(&blob_path, &key, &meta["notes.txt"]).unwrap(),
+        b"shared notes"
+    );
+    assert_eq!(
+        list_keyslots(&blob_path, volume, &key).unwrap(),
+        vec![0, 1, carol]
+    );
+
+    // The last remaining slot can't be revoked
+    revoke_keyslot(&blob_path, volume, &key, carol).unwrap();
+    revoke_keyslot(&blob_path, volume, &key, 1).unwrap();
+    assert!(matches!(
+        revoke_keyslot(&blob_path, volume, &key, 0),
+        Err(BlobError::LastKeyslot)
+    ));
+}
+
+#[test]
+fn keyslots_are_scoped_to_the_unlocked_volume() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    let options = light_options();
+    init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &options).unwrap();
+    let (_, key_s, _) = unlock_blob(&blob_path, "standard_pw").unwrap();
+    let (_, key_h, _) = unlock_blob(&blob_path, "hidden_pw").unwrap();
+
+    // Fill every standard slot; the hidden volume's slots are unaffected
+    for i in 2..KEYSLOTS_PER_VOLUME {
+        add_keyslot(
+            &blob_path,
+            VolumeType::Standard,
+            &key_s,
+            &Credential::Password(&format!("member_{}", i)),
+        )
+        .unwrap();
+    }
+    assert!(matches!(
+        add_keyslot(
+            &blob_path,
+            VolumeType::Standard,
+            &key_s,
+            &Credential::Password("one_too_many")
+        ),
+        Err(BlobError::KeyslotsFull)
+    ));
+    assert_eq!(
+        list_keyslots(&blob_path, VolumeType::Standard, &key_s)
+            .unwrap()
+            .len(),
+        KEYSLOTS_PER_VOLUME
+    );
+    assert_eq!(
+        list_keyslots(&blob_path, VolumeType::Hidden, &key_h).unwrap(),
+        vec![0, 1]
+    );
+
+    // A key only manages its own volume's slots
+    assert!(matches!(
+        list_keyslots(&blob_path, VolumeType::Hidden, &key_s),
+        Err(BlobError::WrongVolumeKey(_))
+    ));
+    assert!(add_keyslot(
+        &blob_path,
+        VolumeType::Hidden,
+        &key_s,
+        &Credential::Password("sneaky_pw")
+    )
+    .is_err());
+    assert!(revoke_keyslot(&blob_path, VolumeType::Standard, &key_h, 1).is_err());
+}
+
+#[test]
+fn keyfile_and_recovery_key_unlock() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("archive.blob");
+    let options = light_options();
+    let recovery =
+        init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &options).unwrap();
+    assert_ne!(recovery.standard, recovery.hidden);
+
+    // Each recovery key opens its own volume, however it is typed back in
+    let (volume, key_s, _) =
+        unlock_blob_with_credential(&blob_path, &Credential::RecoveryKey(&recovery.standard))
+            .unwrap();
+    assert_eq!(volume, VolumeType::Standard);
+    let sloppy = recovery.hidden.replace('-', " ").to_lowercase();
+    let (volume, _, _) =
+        unlock_blob_with_credential(&blob_path, &Credential::RecoveryKey(&sloppy)).unwrap();
+    assert_eq!(volume, VolumeType::Hidden);
+    assert!(unlock_blob(&blob_path, &recovery.standard).is_err());
+
+    // A keyfile slot needs both the keyfile and its password
+    let keyfile = b"-----BEGIN SOME RANDOM KEYFILE----- 0123456789".to_vec();
+    let with_keyfile = Credential::PasswordAndKeyfile {
+        password: "kf_pw",
+        keyfile: &keyfile,
+    };
+    add_keyslot(&blob_path, VolumeType::Standard, &key_s, &with_keyfile).unwrap();
+    let (_, key, _) = unlock_blob_with_credential(&blob_path, &with_keyfile).unwrap();
+    assert_eq!(key, key_s);
+    assert!(unlock_blob(&blob_path, "kf_pw").is_err());
+    let mut other_keyfile = keyfile.clone();
+    other_keyfile[0] ^= 1;
+    assert!(unlock_blob_with_credential(
+        &blob_path,
+        &Credential::PasswordAndKeyfile {
+            password: "kf_pw",
+            keyfile: &other_keyfile,
+        }
+    )
+    .is_err());
+
+    // A lost recovery key can be replaced by revoking its slot and generating a new one
+    revoke_keyslot(&blob_path, VolumeType::Standard, &key_s, 1).unwrap();
+    assert!(
+        unlock_blob_with_credential(&blob_path, &Credential::RecoveryKey(&recovery.standard))
+            .is_err()
+    );
+    let (new_recovery, slot) = add_recovery_key(&blob_path, VolumeType::Standard, &key_s).unwrap();
+    assert_eq!(slot, 1);
+    assert!(
+        unlock_blob_with_credential(&blob_path, &Credential::RecoveryKey(&new_recovery)).is_ok()
+    );
+}
+
+#[test]
+fn recovery_shares_unlock_with_threshold() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("family.blob");
+    let options = light_options();
+    let recovery =
+        init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &options).unwrap();
+    let (_, key_s, _) = unlock_blob(&blob_path, "standard_pw").unwrap();
+
+    // A fresh secret split 3-of-5 into its own slot
+    assert!(add_recovery_shares(&blob_path, VolumeType::Standard, &key_s, 6, 5).is_err());
+    let (shares, slot) =
+        add_recovery_shares(&blob_path, VolumeType::Standard, &key_s, 3, 5).unwrap();
+    assert_eq!(shares.len(), 5);
+    assert_eq!(slot, 2);
+
+    let three = [shares[4].clone(), shares[1].clone(), shares[2].clone()];
+    let (volume, key, _) =
+        unlock_blob_with_credential(&blob_path, &Credential::RecoveryShares(&three)).unwrap();
+    assert_eq!(volume, VolumeType::Standard);
+    assert_eq!(key, key_s);
+    assert!(
+        unlock_blob_with_credential(&blob_path, &Credential::RecoveryShares(&shares[..2])).is_err()
+    );
+
+    // The recovery key from init can be split offline, and reassembled from its shares
+    let hidden_shares = split_recovery_key(&recovery.hidden, 2, 3).unwrap();
+    let pair = [hidden_shares[0].clone(), hidden_shares[2].clone()];
+    let (volume, _, _) =
+        unlock_blob_with_credential(&blob_path, &Credential::RecoveryShares(&pair)).unwrap();
+    assert_eq!(volume, VolumeType::Hidden);
+    assert_eq!(combine_recovery_shares(&pair).unwrap(), recovery.hidden);
+}
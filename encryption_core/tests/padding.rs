@@ -0,0 +1,62 @@
+mod common;
+
+use common::{blob_size, light_options, noise};
+use encryption_core::*;
+use tempfile::tempdir;
+
+#[test]
+fn uploads_grow_the_blob_by_whole_buckets() {
+    for padding in [PaddingPolicy::PowerOfTwo, PaddingPolicy::Padme] {
+        let dir = tempdir().unwrap();
+        let blob_path = dir.path().join("padded.blob");
+        init_blob_with_options(
+            &blob_path,
+            "standard_pw",
+            "hidden_pw",
+            &InitOptions {
+                padding,
+                ..light_options()
+            },
+        )
+        .unwrap();
+        let mut volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+
+        let mut contents = Vec::new();
+        for (i, size) in [0, 1000, 50_000, 300_000, 1_234_567]
+            .into_iter()
+            .enumerate()
+        {
+            let content = noise(size, 2 * i as u64 + 1);
+            let path = format!("file-{}.bin", i);
+            let before = blob_size(&blob_path);
+            volume
+                .add_file(&path, &content, "application/octet-stream")
+                .unwrap();
+            // The metadata stays inline, so only the file's blocks were appended
+            let growth = blob_size(&blob_path) - before;
+            assert!(growth > size as u64);
+            assert_eq!(padding.padded_len(growth), growth, "{:?} {}", padding, size);
+            contents.push((path, content));
+        }
+
+        // The space of a padded file is reused as a whole
+        let (path, _) = contents.pop().unwrap();
+        assert!(volume.remove_file(&path).unwrap());
+        volume.add_file("marker.txt", b"x", "text/plain").unwrap();
+        let before = blob_size(&blob_path);
+        let content = noise(700_000, 21);
+        volume
+            .add_file("refill.bin", &content, "application/octet-stream")
+            .unwrap();
+        assert_eq!(blob_size(&blob_path), before);
+        contents.push(("refill.bin".to_string(), content));
+
+        assert!(volume.verify().unwrap().is_healthy());
+        volume.compact_in_place().unwrap();
+        assert!(volume.verify().unwrap().is_healthy());
+        let volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+        for (path, content) in &contents {
+            assert_eq!(&volume.get_file(path).unwrap(), content);
+        }
+    }
+}
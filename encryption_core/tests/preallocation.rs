@@ -0,0 +1,178 @@
+mod common;
+
+use common::{blob_size, light_options, noise};
+use encryption_core::*;
+use tempfile::tempdir;
+
+const CAPACITY: u64 = 8 * 1024 * 1024;
+
+#[test]
+fn preallocated_blobs_never_grow_by_themselves() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("fixed.blob");
+    init_blob_with_options(
+        &blob_path,
+        "standard_pw",
+        "hidden_pw",
+        &InitOptions {
+            capacity: Some(CAPACITY),
+            ..light_options()
+        },
+    )
+    .unwrap();
+    assert_eq!(blob_size(&blob_path), CAPACITY);
+
+    let decoy = noise(1_000_000, 1);
+    let secret = noise(2_000_000, 3);
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    hidden
+        .add_file("secret.bin", &secret, "application/octet-stream")
+        .unwrap();
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    standard
+        .add_file("decoy.bin", &decoy, "application/octet-stream")
+        .unwrap();
+    assert_eq!(blob_size(&blob_path), CAPACITY);
+
+    // The hidden volume keeps to its region at the end of the blob
+    let decoy_end = {
+        let entry = standard.file_metadata("decoy.bin").unwrap();
+        entry.data_offset + entry.data_length
+    };
+    let secret_start = hidden.file_metadata("secret.bin").unwrap().chunks[0].offset;
+    assert!(decoy_end < secret_start, "{} {}", decoy_end, secret_start);
+
+    // A write that doesn't fit in the standard volume's region fails without growing the blob
+    let big = noise(6_000_000, 5);
+    assert!(matches!(
+        standard.add_file("big.bin", &big, "application/octet-stream"),
+        Err(BlobError::BlobFull)
+    ));
+    assert!(standard.file_metadata("big.bin").is_none());
+    assert_eq!(blob_size(&blob_path), CAPACITY);
+    assert!(matches!(
+        grow_blob(&blob_path, CAPACITY - 1),
+        Err(BlobError::InvalidInput(_))
+    ));
+
+    // Once grown, the standard volume writes into the new space
+    grow_blob(&blob_path, 2 * CAPACITY).unwrap();
+    assert_eq!(blob_size(&blob_path), 2 * CAPACITY);
+    standard
+        .add_file("big.bin", &big, "application/octet-stream")
+        .unwrap();
+    assert!(standard.remove_file("decoy.bin").unwrap());
+    assert_eq!(blob_size(&blob_path), 2 * CAPACITY);
+    assert!(standard.verify().unwrap().is_healthy());
+    assert!(hidden.verify().unwrap().is_healthy());
+
+    // In-place compaction leaves a preallocated blob alone
+    let report = standard.compact_in_place().unwrap();
+    assert_eq!(report.steps, 0);
+    assert!(report.done);
+    assert_eq!(blob_size(&blob_path), 2 * CAPACITY);
+
+    // Rebuilding keeps the size and the blob stays preallocated
+    drop((standard, hidden));
+    compact_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    assert_eq!(blob_size(&blob_path), 2 * CAPACITY);
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    assert_eq!(standard.get_file("big.bin").unwrap(), big);
+    standard
+        .add_file("decoy.bin", &decoy, "application/octet-stream")
+        .unwrap();
+    assert_eq!(blob_size(&blob_path), 2 * CAPACITY);
+    let hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(hidden.get_file("secret.bin").unwrap(), secret);
+}
+
+#[test]
+fn capacity_must_cover_the_header_region() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("tiny.blob");
+    assert!(matches!(
+        init_blob_with_options(
+            &blob_path,
+            "standard_pw",
+            "hidden_pw",
+            &InitOptions {
+                capacity: Some(4096),
+                ..light_options()
+            },
+        ),
+        Err(BlobError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn volumes_filled_until_they_meet_stay_intact() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("full.blob");
+    init_blob_with_options(
+        &blob_path,
+        "standard_pw",
+        "hidden_pw",
+        &InitOptions {
+            capacity: Some(CAPACITY),
+            hidden_capacity: Some(3_000_000),
+            ..light_options()
+        },
+    )
+    .unwrap();
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+
+    // Take turns until neither volume has room left
+    let mut files = [Vec::new(), Vec::new()];
+    let mut full = [false, false];
+    let mut seed = 1;
+    while full != [true, true] {
+        for (i, volume) in [&mut standard, &mut hidden].into_iter().enumerate() {
+            if full[i] {
+                continue;
+            }
+            let name = format!("file{}.bin", seed);
+            let content = noise(300_000, seed);
+            seed += 2;
+            match volume.add_file(&name, &content, "application/octet-stream") {
+                Ok(()) => files[i].push((name, content)),
+                Err(BlobError::BlobFull) => full[i] = true,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+    assert_eq!(blob_size(&blob_path), CAPACITY);
+    assert!(files[0].len() > files[1].len());
+    assert!(!files[1].is_empty());
+
+    // Reopened, every file of both volumes reads back unchanged
+    drop((standard, hidden));
+    for (password, files) in [("standard_pw", &files[0]), ("hidden_pw", &files[1])] {
+        let volume = UnlockedVolume::unlock(&blob_path, password).unwrap();
+        assert!(volume.verify().unwrap().is_healthy());
+        for (name, content) in files {
+            assert_eq!(&volume.get_file(name).unwrap(), content);
+        }
+    }
+}
+
+#[test]
+fn hidden_capacity_must_fit_the_data_area() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("split.blob");
+    for (capacity, hidden_capacity) in [(None, Some(1024)), (Some(CAPACITY), Some(CAPACITY))] {
+        assert!(matches!(
+            init_blob_with_options(
+                &blob_path,
+                "standard_pw",
+                "hidden_pw",
+                &InitOptions {
+                    capacity,
+                    hidden_capacity,
+                    ..light_options()
+                },
+            ),
+            Err(BlobError::InvalidInput(_))
+        ));
+    }
+}
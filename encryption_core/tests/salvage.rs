@@ -0,0 +1,123 @@
+mod common;
+
+use common::light_options;
+use encryption_core::*;
+use std::io::{Seek, SeekFrom, Write};
+use tempfile::tempdir;
+
+/// Overwrites `len` bytes of the blob file at `offset`.
+fn clobber(path: &std::path::Path, offset: u64, len: usize) {
+    let mut file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&vec![0xAA; len]).unwrap();
+}
+
+#[test]
+fn salvage_rebuilds_lost_metadata_from_block_headers() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("damaged.blob");
+    let output = dir.path().join("salvaged.blob");
+    let keys =
+        init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &light_options()).unwrap();
+
+    let big: Vec<u8> = (0..150_000).map(|i| (i % 251) as u8).collect();
+    let mut standard = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    standard
+        .add_file("docs/a.txt", b"first draft", "text/plain")
+        .unwrap();
+    standard
+        .add_file("big.bin", &big, "application/octet-stream")
+        .unwrap();
+    standard
+        .add_file("docs/a.txt", b"final draft", "text/plain")
+        .unwrap();
+    standard
+        .add_file("c.txt", b"charlie", "text/plain")
+        .unwrap();
+    assert!(standard.rename_file("c.txt", "renamed.txt").unwrap());
+    standard.add_file("e.txt", b"echo", "text/plain").unwrap();
+    assert!(standard.remove_file("e.txt").unwrap());
+    let unnamed = standard
+        .write_file_data("", &mut &b"no name"[..], "text/plain")
+        .unwrap();
+    // Salvage finds the manifest after the padding that aligned it
+    let unnamed_offset = unnamed.data_offset.next_multiple_of(64);
+    standard
+        .add_file_entries(vec![("x.txt".to_string(), unnamed)])
+        .unwrap();
+
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    hidden.add_file("h1.txt", b"hotel", "text/plain").unwrap();
+    hidden.add_file("h2.txt", b"india", "text/plain").unwrap();
+    let h2 = hidden.file_metadata("h2.txt").unwrap().clone();
+    drop((standard, hidden));
+
+    // Wreck everything of the standard volume after its keyslots (8 slots of 117 bytes after
+    // the 9-byte magic and version) up to the hidden header, and one hidden data block
+    let keyslots_end = 9 + 8 * 117;
+    clobber(&blob_path, keyslots_end, 65536 - keyslots_end as usize);
+    clobber(&blob_path, h2.data_offset + h2.data_length - 8, 8);
+    assert!(UnlockedVolume::unlock(&blob_path, "standard_pw").is_err());
+
+    let report = salvage_blob(&blob_path, "standard_pw", "hidden_pw", &output).unwrap();
+
+    // Standard volume: scanned. Renames are lost and deleted files come back, unless their
+    // space was reused: e.txt was written where the first draft of docs/a.txt used to be
+    assert!(!report.standard.metadata_intact);
+    assert_eq!(report.standard.placeholders.len(), 1);
+    let unnamed_path = format!("lost+found/block-{}", unnamed_offset);
+    assert!(report.standard.placeholders.contains(&unnamed_path));
+    let mut expected = vec![
+        "big.bin".to_string(),
+        "c.txt".to_string(),
+        "docs/a.txt".to_string(),
+        "e.txt".to_string(),
+        unnamed_path.clone(),
+    ];
+    expected.sort();
+    assert_eq!(report.standard.recovered, expected);
+    assert!(report.standard.lost.is_empty());
+
+    // Hidden volume: metadata intact, the damaged file is left behind
+    assert!(report.hidden.metadata_intact);
+    assert_eq!(report.hidden.recovered, ["h1.txt"]);
+    assert_eq!(report.hidden.lost, ["h2.txt"]);
+
+    // The salvaged blob opens with the same credentials and holds the content
+    let standard = UnlockedVolume::unlock(&output, "standard_pw").unwrap();
+    assert_eq!(standard.get_file("docs/a.txt").unwrap(), b"final draft");
+    assert_eq!(standard.get_file("big.bin").unwrap(), big);
+    assert_eq!(standard.get_file("c.txt").unwrap(), b"charlie");
+    assert_eq!(standard.get_file(&unnamed_path).unwrap(), b"no name");
+    assert_eq!(
+        standard.file_metadata("big.bin").unwrap().mime_type,
+        "application/octet-stream"
+    );
+    assert!(standard.verify().unwrap().is_healthy());
+    let hidden =
+        UnlockedVolume::unlock_with_credential(&output, &Credential::RecoveryKey(&keys.hidden))
+            .unwrap();
+    assert_eq!(hidden.volume_type(), VolumeType::Hidden);
+    assert_eq!(hidden.get_file("h1.txt").unwrap(), b"hotel");
+}
+
+#[test]
+fn salvage_checks_passwords_and_output() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &light_options()).unwrap();
+
+    assert!(matches!(
+        salvage_blob(
+            &blob_path,
+            "standard_pw",
+            "wrong",
+            &dir.path().join("out.blob")
+        ),
+        Err(BlobError::WrongPassword)
+    ));
+    assert!(matches!(
+        salvage_blob(&blob_path, "standard_pw", "hidden_pw", &blob_path),
+        Err(BlobError::InvalidInput(_))
+    ));
+}
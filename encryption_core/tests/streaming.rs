@@ -0,0 +1,307 @@
+mod common;
+
+use common::noise;
+use encryption_core::*;
+use std::io::{Read, Seek, SeekFrom, Write};
+use tempfile::tempdir;
+
+/// Plaintext offset of each of a deduplicated file's chunks.
+fn chunk_starts(entry: &FileMetadata) -> Vec<u64> {
+    let mut start = 0;
+    entry
+        .chunks
+        .iter()
+        .map(|chunk| {
+            start += chunk.size as u64;
+            start - chunk.size as u64
+        })
+        .collect()
+}
+
+#[test]
+fn chunked_roundtrip_and_seek() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let (volume, key, mut meta) = unlock_blob(&blob_path, "standard_pw").unwrap();
+
+    // Spans several chunks
+    let content = noise(5 * DEFAULT_CHUNK_SIZE as usize + 1234, 1);
+    add_file(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        "video.bin",
+        &content,
+        "application/octet-stream",
+    )
+    .unwrap();
+    add_file(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        "empty.txt",
+        b"",
+        "text/plain",
+    )
+    .unwrap();
+
+    // Metadata survives a fresh unlock
+    let (_, key, meta) = unlock_blob(&blob_path, "standard_pw").unwrap();
+    let entry = &meta["video.bin"];
+    assert_eq!(entry.format, DataFormat::Deduplicated);
+    assert!(entry.chunks.len() > 1);
+    assert_eq!(get_file(&blob_path, &key, entry).unwrap(), content);
+    assert!(get_file(&blob_path, &key, &meta["empty.txt"])
+        .unwrap()
+        .is_empty());
+
+    // Seek across a chunk boundary and read a slice
+    let mut reader = open_file(&blob_path, &key, entry).unwrap();
+    assert_eq!(reader.len(), content.len() as u64);
+    let start = entry.chunks[0].size as usize - 10;
+    reader.seek(SeekFrom::Start(start as u64)).unwrap();
+    let mut buf = vec![0u8; 100];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(buf, content[start..start + 100]);
+
+    // Reading from the end returns the tail
+    reader.seek(SeekFrom::End(-5)).unwrap();
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail, content[content.len() - 5..]);
+}
+
+#[test]
+fn tampered_chunk_fails_authentication() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let (volume, key, mut meta) = unlock_blob(&blob_path, "standard_pw").unwrap();
+
+    let content = noise(5 * DEFAULT_CHUNK_SIZE as usize + 10, 1);
+    add_file(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        "f.bin",
+        &content,
+        "application/octet-stream",
+    )
+    .unwrap();
+    let entry = meta["f.bin"].clone();
+
+    // Flip one byte inside the last chunk
+    let last = entry.chunks.last().unwrap();
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&blob_path)
+        .unwrap();
+    let offset = last.offset + last.stored_len() - 30;
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    let mut byte = [0u8; 1];
+    file.read_exact(&mut byte).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[byte[0] ^ 0xff]).unwrap();
+    drop(file);
+
+    // The untouched first chunk still decrypts, the damaged one does not
+    let mut reader = open_file(&blob_path, &key, &entry).unwrap();
+    let mut first = vec![0u8; entry.chunks[0].size as usize];
+    reader.read_exact(&mut first).unwrap();
+    assert_eq!(first, content[..first.len()]);
+    assert!(matches!(
+        get_file(&blob_path, &key, &entry),
+        Err(BlobError::CorruptBlock { .. })
+    ));
+}
+
+#[test]
+fn range_reads_only_touch_covering_chunks() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let (volume, key, mut meta) = unlock_blob(&blob_path, "standard_pw").unwrap();
+
+    let content = noise(6 * DEFAULT_CHUNK_SIZE as usize, 1);
+    add_file(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        "movie.mp4",
+        &content,
+        "video/mp4",
+    )
+    .unwrap();
+    let entry = meta["movie.mp4"].clone();
+    let starts = chunk_starts(&entry);
+    assert!(starts.len() >= 3);
+
+    // Range straddling the boundary between the second and third chunk
+    let offset = starts[2] - 50;
+    let range = read_file_range(&blob_path, &key, &entry, offset, 100).unwrap();
+    assert_eq!(range, content[offset as usize..offset as usize + 100]);
+
+    // Corrupt the first chunk: ranges that don't cover it still decrypt
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&blob_path)
+        .unwrap();
+    file.seek(SeekFrom::Start(entry.chunks[0].offset + 100))
+        .unwrap();
+    file.write_all(&[0u8; 8]).unwrap();
+    drop(file);
+    assert!(matches!(
+        read_file_range(&blob_path, &key, &entry, 0, 10),
+        Err(BlobError::CorruptBlock { .. })
+    ));
+    assert_eq!(
+        read_file_range(&blob_path, &key, &entry, starts[2], 10).unwrap(),
+        content[starts[2] as usize..starts[2] as usize + 10]
+    );
+
+    // Out-of-bounds ranges are rejected
+    assert!(matches!(
+        read_file_range(&blob_path, &key, &entry, content.len() as u64 - 5, 10),
+        Err(BlobError::InvalidInput(_))
+    ));
+    assert!(matches!(
+        read_file_range(&blob_path, &key, &entry, 0, MAX_RANGE_LEN + 1),
+        Err(BlobError::InvalidInput(_))
+    ));
+}
+
+/// Yields `limit` bytes of content, then fails like a dropped connection.
+struct FailingReader {
+    inner: std::io::Cursor<Vec<u8>>,
+    limit: u64,
+}
+
+impl Read for FailingReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.inner.position() >= self.limit {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "client went away",
+            ));
+        }
+        let remaining = (self.limit - self.inner.position()) as usize;
+        let n = buf.len().min(remaining);
+        self.inner.read(&mut buf[..n])
+    }
+}
+
+#[test]
+fn add_from_reader_commits_only_complete_files() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let (volume, key, mut meta) = unlock_blob(&blob_path, "standard_pw").unwrap();
+
+    let content = noise(5 * DEFAULT_CHUNK_SIZE as usize + 7, 1);
+    add_file_from_reader(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        "big.bin",
+        &mut std::io::Cursor::new(content.clone()),
+        "application/octet-stream",
+    )
+    .unwrap();
+
+    // A reader failing half way through leaves the metadata untouched
+    let mut failing = FailingReader {
+        inner: std::io::Cursor::new(content.clone()),
+        limit: 2 * DEFAULT_CHUNK_SIZE as u64 + 3,
+    };
+    assert!(add_file_from_reader(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        "partial.bin",
+        &mut failing,
+        "application/octet-stream",
+    )
+    .is_err());
+    assert!(!meta.contains_key("partial.bin"));
+
+    // Staged blocks become visible only once committed, all in one metadata write
+    let mut chunks = ChunkIndex::new(&meta);
+    let staged_a = write_file_data(
+        &blob_path,
+        &key,
+        &mut chunks,
+        "a.txt",
+        &mut &b"alpha"[..],
+        "text/plain",
+        Compression::Zstd,
+    )
+    .unwrap();
+    let staged_b = write_file_data(
+        &blob_path,
+        &key,
+        &mut chunks,
+        "b.txt",
+        &mut &b"beta"[..],
+        "text/plain",
+        Compression::None,
+    )
+    .unwrap();
+    let (_, _, on_disk) = unlock_blob(&blob_path, "standard_pw").unwrap();
+    assert_eq!(on_disk.len(), 1);
+    add_file_entries(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        vec![("a.txt".into(), staged_a), ("b.txt".into(), staged_b)],
+    )
+    .unwrap();
+
+    let (_, key, meta) = unlock_blob(&blob_path, "standard_pw").unwrap();
+    assert!(!meta.contains_key("partial.bin"));
+    assert_eq!(
+        get_file(&blob_path, &key, &meta["big.bin"]).unwrap(),
+        content
+    );
+    assert_eq!(
+        get_file(&blob_path, &key, &meta["a.txt"]).unwrap(),
+        b"alpha"
+    );
+    assert_eq!(get_file(&blob_path, &key, &meta["b.txt"]).unwrap(), b"beta");
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn add_from_async_reader_roundtrip() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let (volume, key, mut meta) = unlock_blob(&blob_path, "standard_pw").unwrap();
+
+    let content = noise(2 * DEFAULT_CHUNK_SIZE as usize, 1);
+    add_file_from_async_reader(
+        &blob_path,
+        volume,
+        &key,
+        &mut meta,
+        "exact.bin",
+        &mut &content[..],
+        "application/octet-stream",
+    )
+    .await
+    .unwrap();
+
+    let (_, key, meta) = unlock_blob(&blob_path, "standard_pw").unwrap();
+    assert_eq!(
+        get_file(&blob_path, &key, &meta["exact.bin"]).unwrap(),
+        content
+    );
+}
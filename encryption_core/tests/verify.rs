@@ -0,0 +1,112 @@
+mod common;
+
+use common::noise;
+use encryption_core::*;
+use std::io::{Read, Seek, SeekFrom, Write};
+use tempfile::tempdir;
+
+/// Flips one byte of the blob file.
+fn flip_byte(path: &std::path::Path, offset: u64) {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let mut byte = [0u8; 1];
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.read_exact(&mut byte).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    file.write_all(&[byte[0] ^ 0xff]).unwrap();
+}
+
+#[test]
+fn healthy_volume_verifies_clean() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let mut volume = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    volume.add_file("a.txt", b"alpha", "text/plain").unwrap();
+    volume
+        .add_file("big.bin", &noise(100_000, 1), "application/octet-stream")
+        .unwrap();
+    volume.add_file("empty.txt", b"", "text/plain").unwrap();
+
+    let report = volume.verify().unwrap();
+    assert!(report.is_healthy(), "{:?}", report.issues);
+    assert_eq!(report.format_version, 4);
+    assert_eq!(report.files_checked, 3);
+    assert_eq!(report.bytes_checked, 100_005);
+
+    // The key must belong to the volume it is checked against
+    let (_, standard_key, _) = unlock_blob(&blob_path, "standard_pw").unwrap();
+    assert!(matches!(
+        verify_blob(&blob_path, VolumeType::Hidden, &standard_key),
+        Err(BlobError::WrongVolumeKey(_))
+    ));
+}
+
+#[test]
+fn verify_reports_damaged_blocks() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let mut volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    let content = noise(5 * DEFAULT_CHUNK_SIZE as usize + 10, 1);
+    volume
+        .add_file("rotten.bin", &content, "application/octet-stream")
+        .unwrap();
+    volume.add_file("good.txt", b"fine", "text/plain").unwrap();
+
+    // Bit rot in the last chunk of one file
+    let rotten = volume.file_metadata("rotten.bin").unwrap().clone();
+    let (last, rest) = rotten.chunks.split_last().unwrap();
+    flip_byte(&blob_path, last.offset + last.stored_len() - 20);
+
+    // Broken metadata entries: a copy of another file's extent, a block past the end of the
+    // blob and a length that doesn't fit the file size
+    let good = volume.file_metadata("good.txt").unwrap().clone();
+    let blob_size = std::fs::metadata(&blob_path).unwrap().len();
+    let past_end = FileMetadata {
+        data_offset: blob_size,
+        ..good.clone()
+    };
+    let wrong_size = FileMetadata {
+        size: good.size + 1,
+        ..good.clone()
+    };
+    volume
+        .add_file_entries(vec![
+            ("copy.txt".to_string(), good.clone()),
+            ("past_end.txt".to_string(), past_end.clone()),
+            ("wrong_size.txt".to_string(), wrong_size),
+        ])
+        .unwrap();
+
+    let report = volume.verify().unwrap();
+    assert!(!report.is_healthy());
+    let mut issues = report.issues.clone();
+    issues.sort_by_key(|issue| format!("{:?}", issue));
+    assert_eq!(
+        issues,
+        vec![
+            VerifyIssue::BadLayout {
+                path: "wrong_size.txt".to_string()
+            },
+            VerifyIssue::Corrupt {
+                path: "rotten.bin".to_string(),
+                offset: rest.iter().map(|chunk| chunk.size as u64).sum(),
+            },
+            VerifyIssue::OutOfBounds {
+                path: "past_end.txt".to_string(),
+                data_offset: past_end.data_offset,
+                data_length: past_end.data_length,
+            },
+            VerifyIssue::Overlap {
+                path: "good.txt".to_string(),
+                other: Some("copy.txt".to_string()),
+            },
+        ]
+    );
+    // The intact blocks (including the duplicated one) still authenticate
+    assert_eq!(report.files_checked, 2);
+}
@@ -0,0 +1,285 @@
+mod common;
+
+use common::light_options;
+use encryption_core::*;
+use std::io::{Read, Seek, SeekFrom};
+use tempfile::tempdir;
+
+#[test]
+fn unlocked_volume_file_operations() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("volume.blob");
+    init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &light_options()).unwrap();
+
+    let mut volume = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(volume.volume_type(), VolumeType::Hidden);
+    assert!(volume.metadata().is_empty());
+
+    volume
+        .add_file("docs/a.txt", b"alpha", "text/plain")
+        .unwrap();
+    volume
+        .add_file("docs/sub/b.txt", b"bravo", "text/plain")
+        .unwrap();
+    volume.add_file("c.txt", b"charlie", "text/plain").unwrap();
+    // Staged data only shows up once committed
+    let staged = volume
+        .write_file_data("d.txt", &mut &b"delta"[..], "text/plain")
+        .unwrap();
+    assert!(volume.file_metadata("d.txt").is_none());
+    volume
+        .add_file_entries(vec![("d.txt".to_string(), staged)])
+        .unwrap();
+
+    assert_eq!(volume.get_file("docs/a.txt").unwrap(), b"alpha");
+    assert_eq!(volume.read_file_range("c.txt", 2, 3).unwrap(), b"arl");
+    let mut reader = volume.open_file("d.txt").unwrap();
+    reader.seek(SeekFrom::Start(1)).unwrap();
+    let mut rest = String::new();
+    reader.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "elta");
+    assert!(matches!(
+        volume.get_file("missing.txt"),
+        Err(BlobError::FileNotFound(_))
+    ));
+
+    assert!(volume.rename_file("c.txt", "docs/c.txt").unwrap());
+    assert!(!volume.rename_file("c.txt", "x.txt").unwrap());
+    assert!(volume.remove_file("d.txt").unwrap());
+    assert!(!volume.remove_file("d.txt").unwrap());
+    assert!(volume.remove_folder("docs/sub").unwrap());
+    let mut paths: Vec<&String> = volume.metadata().keys().collect();
+    paths.sort();
+    assert_eq!(paths, ["docs/a.txt", "docs/c.txt"]);
+
+    // Every change was committed: a fresh unlock sees the same state
+    let reopened = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    let mut reopened_paths: Vec<&String> = reopened.metadata().keys().collect();
+    reopened_paths.sort();
+    assert_eq!(reopened_paths, paths);
+    assert_eq!(reopened.get_file("docs/c.txt").unwrap(), b"charlie");
+
+    // The standard volume is untouched, and the free functions still see the same blob
+    let (volume_type, _, metadata) = unlock_blob(&blob_path, "standard_pw").unwrap();
+    assert_eq!(volume_type, VolumeType::Standard);
+    assert!(metadata.is_empty());
+}
+
+#[test]
+fn unlocked_volume_keyslots() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("volume.blob");
+    init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &light_options()).unwrap();
+
+    let mut volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    assert_eq!(volume.list_keyslots().unwrap(), vec![0, 1]);
+    let slot = volume
+        .add_keyslot(&Credential::Password("second_pw"))
+        .unwrap();
+    let (recovery_key, recovery_slot) = volume.add_recovery_key().unwrap();
+    assert_eq!(
+        volume.list_keyslots().unwrap(),
+        vec![0, 1, slot, recovery_slot]
+    );
+
+    let second = UnlockedVolume::unlock(&blob_path, "second_pw").unwrap();
+    assert_eq!(second.volume_type(), VolumeType::Standard);
+    assert_eq!(second.key(), volume.key());
+    UnlockedVolume::unlock_with_credential(&blob_path, &Credential::RecoveryKey(&recovery_key))
+        .unwrap();
+
+    volume.revoke_keyslot(slot).unwrap();
+    assert!(matches!(
+        UnlockedVolume::unlock(&blob_path, "second_pw"),
+        Err(BlobError::WrongPassword)
+    ));
+    assert!(matches!(
+        volume.revoke_keyslot(slot),
+        Err(BlobError::KeyslotNotInUse(_))
+    ));
+}
+
+#[test]
+fn file_metadata_records_timestamps_hash_and_attributes() {
+    use sha2::{Digest, Sha256};
+
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("volume.blob");
+    init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &light_options()).unwrap();
+    let mut volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+
+    // Uploader details are set on the staged entry before it is committed
+    let mut staged = volume
+        .write_file_data("notes.txt", &mut &b"first draft"[..], "text/plain")
+        .unwrap();
+    staged.original_mode = Some(0o644);
+    staged.original_mtime = Some(1_700_000_000);
+    staged.created_at = Some(1_000);
+    staged
+        .attributes
+        .insert("tag".to_string(), "work".to_string());
+    volume
+        .add_file_entries(vec![("notes.txt".to_string(), staged)])
+        .unwrap();
+    let entry = volume.file_metadata("notes.txt").unwrap();
+    assert_eq!(
+        entry.sha256,
+        Some(<[u8; 32]>::from(Sha256::digest(b"first draft")))
+    );
+    assert!(entry.uploaded_at.is_some());
+    assert_eq!(entry.modified_at, entry.uploaded_at);
+
+    // Overwriting keeps the creation time, renaming updates the modification time
+    volume
+        .add_file("notes.txt", b"second draft", "text/plain")
+        .unwrap();
+    assert!(volume
+        .rename_file("notes.txt", "archive/notes.txt")
+        .unwrap());
+    let entry = volume.file_metadata("archive/notes.txt").unwrap().clone();
+    assert_eq!(entry.created_at, Some(1_000));
+    assert_eq!(
+        entry.sha256,
+        Some(<[u8; 32]>::from(Sha256::digest(b"second draft")))
+    );
+    assert!(entry.modified_at >= entry.uploaded_at);
+    assert!(entry.attributes.is_empty());
+
+    // Everything survives a reopen and a compaction
+    volume.add_file("keep.txt", b"kept", "text/plain").unwrap();
+    let mut kept = volume.file_metadata("keep.txt").unwrap().clone();
+    kept.attributes
+        .insert("colour".to_string(), "blue".to_string());
+    kept.original_mtime = Some(1_600_000_000);
+    volume
+        .add_file_entries(vec![("keep.txt".to_string(), kept.clone())])
+        .unwrap();
+    drop(volume);
+    compact_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    let compacted = volume.file_metadata("keep.txt").unwrap();
+    assert_eq!(compacted.attributes, kept.attributes);
+    assert_eq!(compacted.original_mtime, Some(1_600_000_000));
+    assert_eq!(compacted.created_at, kept.created_at);
+    assert_eq!(compacted.uploaded_at, kept.uploaded_at);
+    assert_eq!(compacted.sha256, kept.sha256);
+    assert_eq!(
+        volume
+            .file_metadata("archive/notes.txt")
+            .unwrap()
+            .created_at,
+        Some(1_000)
+    );
+}
+
+#[test]
+fn folders_are_entries_of_their_own() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &light_options()).unwrap();
+    let mut volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    let is_dir = |volume: &UnlockedVolume, path| {
+        volume
+            .file_metadata(path)
+            .is_some_and(|entry: &FileMetadata| entry.is_dir())
+    };
+
+    // Parents are created along with the folder
+    assert!(volume.create_folder("/docs/empty/").unwrap());
+    assert!(!volume.create_folder("docs/empty").unwrap());
+    assert!(is_dir(&volume, "docs") && is_dir(&volume, "docs/empty"));
+    assert!(volume
+        .file_metadata("docs/empty")
+        .unwrap()
+        .created_at
+        .is_some());
+    assert!(matches!(
+        volume.get_file("docs/empty"),
+        Err(BlobError::FileNotFound(_))
+    ));
+    assert!(volume
+        .add_file("docs/empty", b"not a folder", "text/plain")
+        .is_err());
+    assert!(!volume.remove_file("docs/empty").unwrap());
+    assert!(volume.create_folder("").is_err());
+
+    // Emptying a folder, explicit or implicit, keeps it
+    volume
+        .add_file("photos/a.jpg", b"jpeg", "image/jpeg")
+        .unwrap();
+    assert!(volume.create_folder("photos/a.jpg/thumbs").is_err());
+    assert!(volume.remove_file("photos/a.jpg").unwrap());
+    assert!(is_dir(&volume, "photos"));
+    volume
+        .add_file("docs/empty/draft.txt", b"draft", "text/plain")
+        .unwrap();
+    assert!(volume
+        .rename_file("docs/empty/draft.txt", "draft.txt")
+        .unwrap());
+    assert!(is_dir(&volume, "docs/empty"));
+    assert_eq!(volume.metadata().len(), 4);
+
+    // Folders survive a reopen and a compaction, and are checked like any other entry
+    drop(volume);
+    compact_blob(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    let mut volume = UnlockedVolume::unlock(&blob_path, "standard_pw").unwrap();
+    assert!(is_dir(&volume, "docs/empty") && is_dir(&volume, "photos"));
+    assert!(volume.verify().unwrap().is_healthy());
+
+    assert!(volume.remove_folder("docs").unwrap());
+    assert_eq!(volume.metadata().len(), 2);
+    assert!(!volume.remove_folder("docs").unwrap());
+}
+
+#[test]
+fn protected_mode_keeps_standard_writes_off_the_hidden_volume() {
+    let dir = tempdir().unwrap();
+    let blob_path = dir.path().join("test.blob");
+    init_blob_with_options(&blob_path, "standard_pw", "hidden_pw", &light_options()).unwrap();
+    let secret: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    hidden
+        .add_file("secret.bin", &secret, "application/octet-stream")
+        .unwrap();
+    hidden.add_file("gone.txt", b"freed", "text/plain").unwrap();
+    assert!(hidden.remove_file("gone.txt").unwrap());
+    drop(hidden);
+
+    // The guard password must open the hidden volume, the main one the standard volume
+    assert!(matches!(
+        UnlockedVolume::unlock_protected(&blob_path, "standard_pw", "wrong_pw"),
+        Err(BlobError::WrongPassword)
+    ));
+    assert!(matches!(
+        UnlockedVolume::unlock_protected(&blob_path, "hidden_pw", "hidden_pw"),
+        Err(BlobError::InvalidInput(_))
+    ));
+    assert!(!UnlockedVolume::unlock(&blob_path, "standard_pw")
+        .unwrap()
+        .is_protected());
+
+    let mut standard =
+        UnlockedVolume::unlock_protected(&blob_path, "standard_pw", "hidden_pw").unwrap();
+    assert!(standard.is_protected());
+    for round in 0..6u8 {
+        let draft = vec![round; 100_000];
+        standard
+            .add_file("draft.bin", &draft, "application/octet-stream")
+            .unwrap();
+        standard
+            .add_file(&format!("notes/{}.txt", round), b"note", "text/plain")
+            .unwrap();
+    }
+    assert!(standard.remove_folder("notes").unwrap());
+    assert_eq!(standard.get_file("draft.bin").unwrap(), vec![5u8; 100_000]);
+    assert!(standard.verify().unwrap().is_healthy());
+
+    // Compaction never guesses at the hidden volume either
+    assert!(matches!(
+        compact_blob(&blob_path, "standard_pw", "random_pw"),
+        Err(BlobError::WrongPassword)
+    ));
+    let hidden = UnlockedVolume::unlock(&blob_path, "hidden_pw").unwrap();
+    assert_eq!(hidden.get_file("secret.bin").unwrap(), secret);
+    assert!(hidden.verify().unwrap().is_healthy());
+}
@@ -244,46 +244,42 @@ function Dashboard() {
         setPreviewFile(file);
     }, []);
 
-    // Handle storage compaction
+    // Handle storage compaction: moves the unlocked volume's files into the space freed by
+    // deleted ones, in a background job, without asking for the other volume's password
     const handleCompaction = useCallback(async () => {
         if (!confirm('Compaction will optimize storage by removing deleted file space. This may take a while. Continue?')) {
             return;
         }
 
-        // Get passwords for both volumes
-        const standardPassword = prompt('Enter standard volume password:');
-        if (!standardPassword) return;
-
-        const hiddenPassword = prompt('Enter hidden volume password (or leave empty if none):');
-
         try {
-            const payload = {
-                password_s: standardPassword,
-                password_h: hiddenPassword || null
-            };
-
             const response = await apiCall('/api/storage/compact', {
                 method: 'POST',
-                body: JSON.stringify(payload)
+                body: JSON.stringify({})
             });
+            let data = await response.json();
+            if (!data.success) {
+                toast.error(`Compaction process failed: ${data.message || 'Unknown error'}`);
+                return;
+            }
 
-            const data = await response.json();
-            if (data.success) {
-                toast.success('Storage compaction completed successfully');
-            } else {
-                // Improved error handling to distinguish password vs process errors
-                if (data.message && (
-                    data.message.includes('password') || 
-                    data.message.includes('authentication') ||
-                    data.message.includes('invalid') ||
-                    data.message.includes('incorrect')
-                )) {
-                    toast.error('Incorrect password provided. Please check your passwords and try again.');
-                } else if (data.message && data.message.includes('permission')) {
-                    toast.error('Permission denied. Check your authentication status.');
-                } else {
+            let job = data.data;
+            toast.info('Compaction started');
+            while (job.state === 'running') {
+                await new Promise(resolve => setTimeout(resolve, 1000));
+                const poll = await apiCall(`/api/jobs/${encodeURIComponent(job.id)}`);
+                data = await poll.json();
+                if (!data.success) {
                     toast.error(`Compaction process failed: ${data.message || 'Unknown error'}`);
+                    return;
                 }
+                job = data.data;
+            }
+
+            if (job.state === 'finished') {
+                const reclaimed = job.result?.reclaimed_bytes || 0;
+                toast.success(`Storage compaction completed, ${(reclaimed / (1024 * 1024)).toFixed(1)} MB reclaimed`);
+            } else {
+                toast.error(`Compaction process failed: ${job.message || 'Unknown error'}`);
             }
         } catch (error) {
             if (error.message === 'Authentication required') {
@@ -78,7 +78,14 @@ const FileExplorer = ({
             
             const segments = relativePath.split('/').filter(Boolean);
             
-            if (segments.length === 1) {
+            if (segments.length === 1 && file.is_dir) {
+                // An explicit folder, possibly empty
+                items.set(file.path, {
+                    ...file,
+                    type: 'folder',
+                    displayName: segments[0]
+                });
+            } else if (segments.length === 1) {
                 // It's a file in current directory
                 items.set(file.path, {
                     ...file,
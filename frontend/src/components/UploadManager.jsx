@@ -131,6 +131,7 @@ const UploadManager = ({
                 formData.append('file', fileItem.file);
                 formData.append('file_path', fileItem.path);
             }
+            formData.append('last_modified', String(fileItem.file.lastModified ?? ''));
             // Update status to uploading
             setFiles(prevFiles => 
                 prevFiles.map(f => 
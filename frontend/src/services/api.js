@@ -62,6 +62,21 @@ class ApiService {
     return response;
   }
 
+  // Unlock with a keyfile (File or Blob) and optional password
+  async unlockWithKeyfile(keyfile, password = '', blobName) {
+    const formData = new FormData();
+    formData.append('password', password);
+    formData.append('keyfile', keyfile);
+    if (blobName) {
+      formData.append('blob_name', blobName);
+    }
+    const response = await fetch('/api/unlock', {
+      method: 'POST',
+      body: formData,
+    });
+    return response;
+  }
+
   async getInfo() {
     const response = await fetch('/api/info');
     return response.json();
@@ -114,13 +129,65 @@ class ApiService {
     return this.apiCall('/api/storage/stats');
   }
 
-  async compactStorage(payload) {
+  // Without both passwords in the payload, starts compacting the unlocked volume in the
+  // background; poll the returned job with getJob
+  async compactStorage(payload = {}) {
     return this.apiCall('/api/storage/compact', {
       method: 'POST',
       body: JSON.stringify(payload),
     });
   }
 
+  async migrateStorage(payload) {
+    return this.apiCall('/api/storage/migrate', {
+      method: 'POST',
+      body: JSON.stringify(payload),
+    });
+  }
+
+  // Starts a background check of the unlocked volume; poll the returned job with getJob
+  async verifyStorage() {
+    return this.apiCall('/api/storage/verify', {
+      method: 'POST',
+    });
+  }
+
+  async getJob(jobId) {
+    return this.apiCall(`/api/jobs/${encodeURIComponent(jobId)}`);
+  }
+
+  async changePassword(oldPassword, newPassword) {
+    return this.apiCall('/api/password', {
+      method: 'POST',
+      body: JSON.stringify({ old_password: oldPassword, new_password: newPassword }),
+    });
+  }
+
+  async getKeyslots() {
+    return this.apiCall('/api/keyslots');
+  }
+
+  async addKeyslot(password, kdfSettings = {}) {
+    return this.apiCall('/api/keyslots', {
+      method: 'POST',
+      body: JSON.stringify({ password, ...kdfSettings }),
+    });
+  }
+
+  async revokeKeyslot(slot) {
+    return this.apiCall(`/api/keyslots/${slot}`, {
+      method: 'DELETE',
+    });
+  }
+
+  // Unlock later with unlock({ recovery_shares: [...] })
+  async createRecoveryShares(threshold, shares) {
+    return this.apiCall('/api/recovery-shares', {
+      method: 'POST',
+      body: JSON.stringify({ threshold, shares }),
+    });
+  }
+
   // Legacy endpoints for backward compatibility
   async getTree() {
     return this.getFiles();
@@ -154,6 +221,13 @@ class ApiService {
     });
   }
 
+  async createFolder(path) {
+    return this.apiCall('/api/folders', {
+      method: 'POST',
+      body: JSON.stringify({ path }),
+    });
+  }
+
   async deleteFolder(path) {
     return this.apiCall(`/api/delete-folder?path=${encodeURIComponent(path)}`, {
       method: 'DELETE',
@@ -0,0 +1,163 @@
+use crate::session::SessionId;
+use rand::{rngs::OsRng, RngCore};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub type JobId = String;
+
+/// Progress of a background job
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Finished {
+        result: serde_json::Value,
+    },
+    Failed {
+        message: String,
+        error_code: &'static str,
+    },
+}
+
+/// Status of a background job as returned by the API
+#[derive(Clone, Debug, Serialize)]
+pub struct JobStatus {
+    pub id: JobId,
+    pub kind: &'static str,
+    /// Unix timestamps in seconds
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    #[serde(flatten)]
+    pub state: JobState,
+}
+
+struct Job {
+    session_id: SessionId,
+    status: JobStatus,
+}
+
+/// Background jobs started by sessions (e.g. blob verification). A job is only visible to
+/// the session that started it, and each session runs at most one job of each kind.
+#[derive(Clone, Default)]
+pub struct JobRegistry {
+    jobs: Arc<Mutex<HashMap<JobId, Job>>>,
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new running job, unless the session already runs one of this kind.
+    ///
+    /// # Returns
+    /// `Ok` with the new job, or `Err` with the job that is already running.
+    pub fn start(&self, session_id: &str, kind: &'static str) -> Result<JobStatus, JobStatus> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(running) = jobs.values().find(|job| {
+            job.session_id == session_id
+                && job.status.kind == kind
+                && matches!(job.status.state, JobState::Running)
+        }) {
+            return Err(running.status.clone());
+        }
+
+        // Forget earlier jobs of this kind; only the latest result is kept
+        jobs.retain(|_, job| !(job.session_id == session_id && job.status.kind == kind));
+
+        let mut id_bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut id_bytes);
+        let status = JobStatus {
+            id: hex::encode(id_bytes),
+            kind,
+            started_at: unix_now(),
+            finished_at: None,
+            state: JobState::Running,
+        };
+        jobs.insert(
+            status.id.clone(),
+            Job {
+                session_id: session_id.to_string(),
+                status: status.clone(),
+            },
+        );
+        Ok(status)
+    }
+
+    /// Records the result of a finished job.
+    pub fn finish(&self, job_id: &str, result: serde_json::Value) {
+        self.complete(job_id, JobState::Finished { result });
+    }
+
+    /// Records that a job failed.
+    pub fn fail(&self, job_id: &str, message: String, error_code: &'static str) {
+        self.complete(
+            job_id,
+            JobState::Failed {
+                message,
+                error_code,
+            },
+        );
+    }
+
+    fn complete(&self, job_id: &str, state: JobState) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(job_id) {
+            job.status.finished_at = Some(unix_now());
+            job.status.state = state;
+        }
+    }
+
+    /// Returns the job's status if it belongs to the session.
+    pub fn get(&self, session_id: &str, job_id: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(job_id)
+            .filter(|job| job.session_id == session_id)
+            .map(|job| job.status.clone())
+    }
+
+    /// Drops every job of a session (e.g. on logout).
+    pub fn remove_session(&self, session_id: &str) {
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|_, job| job.session_id != session_id);
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_running_job_per_session_and_kind() {
+        let jobs = JobRegistry::new();
+        let first = jobs.start("session_a", "verify").unwrap();
+        let running = jobs.start("session_a", "verify").unwrap_err();
+        assert_eq!(running.id, first.id);
+        // Other sessions don't see or block the job
+        assert!(jobs.get("session_b", &first.id).is_none());
+        let other = jobs.start("session_b", "verify").unwrap();
+
+        jobs.finish(&first.id, serde_json::json!({ "ok": true }));
+        let status = jobs.get("session_a", &first.id).unwrap();
+        assert!(matches!(status.state, JobState::Finished { .. }));
+        assert!(status.finished_at.is_some());
+
+        // A new run replaces the finished one
+        let second = jobs.start("session_a", "verify").unwrap();
+        assert!(jobs.get("session_a", &first.id).is_none());
+        assert!(jobs.get("session_a", &second.id).is_some());
+
+        jobs.remove_session("session_b");
+        assert!(jobs.get("session_b", &other.id).is_none());
+    }
+}
@@ -3,13 +3,19 @@
 #![allow(clippy::type_complexity)]
 #![allow(clippy::useless_format)]
 #![allow(clippy::unwrap_or_default)]
+#![allow(clippy::result_large_err)]
 
 mod auth;
+mod jobs;
+mod recovery;
 mod session;
 mod state;
 
-use crate::{auth::AuthContext, state::AppState};
-use axum::extract::{ConnectInfo, Extension};
+use crate::{
+    auth::AuthContext, jobs::JobStatus, recovery::RecoveryCommand, session::Session,
+    state::AppState,
+};
+use axum::extract::{ConnectInfo, Extension, FromRequest, Request};
 use axum::{
     extract::{DefaultBodyLimit, Path, Query},
     http::{
@@ -21,20 +27,30 @@ use axum::{
     Json,
 };
 use axum_extra::extract::Multipart;
-use clap::Parser;
+use base64::Engine;
+use clap::{Parser, Subcommand};
 use encryption_core::{
-    add_file, compact_blob, get_file, init_blob, remove_file, remove_folder, rename_file,
-    unlock_blob,
+    add_file_entries, add_keyslot, add_recovery_key, add_recovery_shares, blob_kind,
+    change_password, compact_blob, compact_volume_step, create_folder, get_file, grow_blob,
+    init_blob_with_options, list_keyslots, migrate_blob, open_file, recover_migration, remove_file,
+    remove_folder, rename_file, revoke_keyslot, unlock_blob, unlock_blob_with_credential,
+    verify_blob, write_file_data_async, BlobError, BlobKind, ChunkIndex, CompactionReport,
+    Compression, Credential, FileMetadata, FileReader, InitOptions, KdfParams, PaddingPolicy,
+    VolumeType, KEYSLOTS_PER_VOLUME, MAX_KEYFILE_LEN,
 };
+use futures_util::TryStreamExt;
 use local_ip_address::local_ip;
 use log;
 use mime_guess::{from_path, mime};
 use rand::{rngs::OsRng, RngCore};
 use rust_embed::RustEmbed;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::{Read, Seek, SeekFrom};
 use std::{net::SocketAddr, path::PathBuf};
 use tokio::net::TcpListener;
+use tokio_util::io::StreamReader;
 use tower::ServiceBuilder;
 
 /// Command-line arguments
@@ -56,6 +72,33 @@ struct Args {
     /// Path to a directory that will hold (or already holds) blob files – enables directory mode
     #[arg(short = 'd', long = "dir", value_name = "DIR", group = "storage")]
     dir: Option<PathBuf>,
+
+    /// Highest Argon2 memory cost (KiB) a client may choose for a new password
+    #[arg(long, default_value_t = 256 * 1024)]
+    max_kdf_memory_kib: u32,
+
+    /// Highest Argon2 iteration count a client may choose for a new password
+    #[arg(long, default_value_t = 10)]
+    max_kdf_iterations: u32,
+
+    /// Longest unlock time (ms) a client may ask the KDF to be calibrated to
+    #[arg(long, default_value_t = 3000)]
+    max_kdf_target_ms: u64,
+
+    /// Largest size (bytes) a client may preallocate or grow a blob to
+    #[arg(long, default_value_t = 16 * 1024 * 1024 * 1024)]
+    max_capacity: u64,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Offline subcommands, run instead of the server
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Recovery key shares and password reset
+    #[command(subcommand)]
+    Recovery(RecoveryCommand),
 }
 
 // Server mode for blob handling
@@ -70,6 +113,18 @@ enum ServerMode {
 struct AppContext {
     mode: ServerMode,
     app_state: AppState,
+    limits: RequestLimits,
+}
+
+/// Upper bounds on the work clients can make the server take on. `/api/init` needs no login,
+/// the KDF cost chosen there is paid again on every unlock attempt, and a preallocated blob
+/// is written out in full.
+#[derive(Clone, Debug)]
+struct RequestLimits {
+    kdf_memory_kib: u32,
+    kdf_iterations: u32,
+    kdf_target_ms: u64,
+    capacity: u64,
 }
 
 /// API response
@@ -80,6 +135,74 @@ struct ApiResponse<T> {
     message: Option<String>,
 }
 
+/// Error response for failed blob operations. Same shape as `ApiResponse`, plus a stable
+/// `error_code` (see `BlobError::code`) clients can match on instead of the message.
+#[derive(Serialize)]
+struct ApiErrorResponse {
+    success: bool,
+    data: Option<()>,
+    message: Option<String>,
+    error_code: &'static str,
+}
+
+/// HTTP status for each kind of blob error.
+fn blob_error_status(e: &BlobError) -> StatusCode {
+    match e {
+        BlobError::WrongPassword => StatusCode::UNAUTHORIZED,
+        BlobError::WrongVolumeKey(_) => StatusCode::FORBIDDEN,
+        BlobError::KeyslotNotInUse(_) | BlobError::FileNotFound(_) => StatusCode::NOT_FOUND,
+        BlobError::Io(io) if io.kind() == std::io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        BlobError::RequiresMigration(_)
+        | BlobError::KeyslotsFull
+        | BlobError::CredentialInUse
+        | BlobError::LastKeyslot
+        | BlobError::HiddenVolumeProtected => StatusCode::CONFLICT,
+        BlobError::NotABlob | BlobError::UnsupportedVersion(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        BlobError::MetadataTooLarge { .. } | BlobError::BlobFull => {
+            StatusCode::INSUFFICIENT_STORAGE
+        }
+        BlobError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+        // Corrupt data, crypto and other I/O failures
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Builds the response for a failed blob operation, prefixing the error with `context`.
+fn blob_error_response(context: &str, e: &BlobError) -> Response {
+    let resp = ApiErrorResponse {
+        success: false,
+        data: None,
+        message: Some(format!("{}: {}", context, e)),
+        error_code: e.code(),
+    };
+    (blob_error_status(e), Json(resp)).into_response()
+}
+
+/// Waits for the write lock of the session's blob (see `AppState::blob_write_lock`) and
+/// returns it with the session as it is once the lock is held, so a commit starts from the
+/// metadata of every commit before it. `None` if the session is gone.
+async fn lock_session_blob(
+    app_context: &AppContext,
+    session_id: &str,
+) -> Option<(tokio::sync::OwnedMutexGuard<()>, Session)> {
+    let session_manager = &app_context.app_state.session_manager;
+    let blob_path = session_manager.get_session(session_id)?.blob_path;
+    let guard = app_context
+        .app_state
+        .blob_write_lock(&blob_path)
+        .lock_owned()
+        .await;
+    Some((guard, session_manager.get_session(session_id)?))
+}
+
+/// Error for a blocking blob task that panicked or was cancelled.
+fn blob_task_failed(e: tokio::task::JoinError) -> BlobError {
+    BlobError::Io(std::io::Error::other(format!(
+        "background task failed: {}",
+        e
+    )))
+}
+
 /// File list
 #[derive(Serialize)]
 struct FileList {
@@ -91,6 +214,52 @@ struct FileList {
 struct FileInfo {
     path: String,
     size: usize,
+    /// Folder entry rather than a file
+    is_dir: bool,
+    mime_type: String,
+    created_at: Option<u64>,
+    modified_at: Option<u64>,
+    uploaded_at: Option<u64>,
+    /// SHA-256 of the plaintext, hex-encoded
+    sha256: Option<String>,
+    original_mode: Option<u32>,
+    original_mtime: Option<u64>,
+    attributes: BTreeMap<String, String>,
+}
+
+impl FileInfo {
+    fn new(path: &str, meta: &FileMetadata) -> Self {
+        FileInfo {
+            path: path.to_string(),
+            size: meta.size as usize,
+            is_dir: meta.is_dir(),
+            mime_type: meta.mime_type.clone(),
+            created_at: meta.created_at,
+            modified_at: meta.modified_at,
+            uploaded_at: meta.uploaded_at,
+            sha256: meta.sha256.map(hex::encode),
+            original_mode: meta.original_mode,
+            original_mtime: meta.original_mtime,
+            attributes: meta.attributes.clone(),
+        }
+    }
+
+    /// A folder that has no entry of its own but holds other entries.
+    fn implicit_folder(path: &str) -> Self {
+        FileInfo {
+            path: path.to_string(),
+            size: 0,
+            is_dir: true,
+            mime_type: "inode/directory".to_string(),
+            created_at: None,
+            modified_at: None,
+            uploaded_at: None,
+            sha256: None,
+            original_mode: None,
+            original_mtime: None,
+            attributes: BTreeMap::new(),
+        }
+    }
 }
 
 /// Init response
@@ -99,6 +268,8 @@ struct InitResponse {
     token: String,
     files: Vec<FileInfo>,
     volume_type: String,
+    recovery_key: String, // Shown once: the blob only stores it wrapped
+    hidden_recovery_key: Option<String>, // Only when the hidden password was chosen by the user
 }
 
 /// Init payload - updated
@@ -109,15 +280,100 @@ struct InitPayload {
     #[allow(dead_code)]
     blob_path: Option<String>, // Optional blob path override (single mode only)
     blob_name: Option<String>,  // Optional blob name (directory mode only)
+    #[serde(flatten)]
+    kdf: KdfSettings,
+    padding: Option<PaddingPolicy>, // Optional size padding: "none", "power_of_two" or "padme"
+    capacity: Option<u64>,          // Optional preallocated blob size in bytes
+    headerless: Option<bool>,       // Optional: no plaintext magic, version or KDF parameters
+}
+
+/// Optional KDF settings of an init request
+#[derive(Deserialize)]
+struct KdfSettings {
+    kdf_memory_kib: Option<u32>, // Optional Argon2 memory cost override (KiB)
+    kdf_iterations: Option<u32>, // Optional Argon2 iteration count override
+    kdf_parallelism: Option<u32>, // Optional Argon2 lane count override
+    kdf_target_ms: Option<u64>,  // Auto-calibrate the KDF cost to this unlock time instead
+}
+
+/// Builds KDF parameters from the optional settings of a request.
+/// An explicit calibration target wins; otherwise individual overrides apply to the defaults.
+/// Settings above the server's limits are rejected.
+fn kdf_params_from_settings(
+    settings: &KdfSettings,
+    limits: &RequestLimits,
+) -> Result<KdfParams, BlobError> {
+    if settings
+        .kdf_target_ms
+        .is_some_and(|target_ms| target_ms > limits.kdf_target_ms)
+    {
+        return Err(BlobError::InvalidInput(format!(
+            "kdf_target_ms may be at most {}",
+            limits.kdf_target_ms
+        )));
+    }
+    let kdf = match settings.kdf_target_ms {
+        Some(target_ms) => KdfParams::calibrate(std::time::Duration::from_millis(target_ms))?,
+        None => {
+            let defaults = KdfParams::default();
+            KdfParams {
+                memory_kib: settings.kdf_memory_kib.unwrap_or(defaults.memory_kib),
+                iterations: settings.kdf_iterations.unwrap_or(defaults.iterations),
+                parallelism: settings.kdf_parallelism.unwrap_or(defaults.parallelism),
+                ..defaults
+            }
+        }
+    };
+    kdf.validate()?;
+    if kdf.memory_kib > limits.kdf_memory_kib || kdf.iterations > limits.kdf_iterations {
+        return Err(BlobError::InvalidInput(format!(
+            "KDF cost may be at most {} KiB and {} iterations",
+            limits.kdf_memory_kib, limits.kdf_iterations
+        )));
+    }
+    Ok(kdf)
+}
+
+/// Refuses a blob size above the server's limit.
+fn check_capacity(capacity: u64, limits: &RequestLimits) -> Result<u64, BlobError> {
+    if capacity > limits.capacity {
+        return Err(BlobError::InvalidInput(format!(
+            "Capacity may be at most {} bytes",
+            limits.capacity
+        )));
+    }
+    Ok(capacity)
+}
+
+/// Builds the blob init options from the optional KDF and padding settings of an init request.
+fn init_options_from_payload(
+    payload: &InitPayload,
+    limits: &RequestLimits,
+) -> Result<InitOptions, BlobError> {
+    Ok(InitOptions {
+        kdf: kdf_params_from_settings(&payload.kdf, limits)?,
+        padding: payload.padding.unwrap_or_default(),
+        capacity: payload
+            .capacity
+            .map(|capacity| check_capacity(capacity, limits))
+            .transpose()?,
+        hidden_capacity: None,
+        headerless: payload.headerless.unwrap_or(false),
+    })
 }
 
-/// Unlock payload
+/// Unlock payload. Sent as JSON, or as multipart form data to upload a keyfile.
 #[derive(Deserialize)]
 struct UnlockPayload {
+    #[serde(default)]
     password: String,
+    recovery_key: Option<String>, // Unlock with a recovery key instead of the password
+    recovery_shares: Option<Vec<String>>, // Or with the threshold number of its shares
+    #[serde(skip)]
+    keyfile: Option<Vec<u8>>, // Keyfile contents (multipart field `keyfile` only)
     #[allow(dead_code)]
     blob_path: Option<String>, // Optional blob path override (single mode only)
-    blob_name: Option<String>, // Optional blob name (directory mode only)
+    blob_name: Option<String>,    // Optional blob name (directory mode only)
 }
 
 /// Rename payload
@@ -127,6 +383,12 @@ struct RenamePayload {
     new_path: String,
 }
 
+/// Create folder payload
+#[derive(Deserialize)]
+struct CreateFolderPayload {
+    path: String,
+}
+
 /// Delete params
 #[derive(Deserialize)]
 struct DeleteParams {
@@ -160,6 +422,46 @@ struct CompactPayload {
     password_h: String,
 }
 
+/// Storage compaction payload. Without both passwords only the unlocked volume is compacted,
+/// in place.
+#[derive(Deserialize, Default)]
+struct StorageCompactPayload {
+    #[serde(default)]
+    password_s: Option<String>,
+    #[serde(default)]
+    password_h: Option<String>,
+}
+
+/// Payload to grow a preallocated blob
+#[derive(Deserialize)]
+struct GrowPayload {
+    capacity: u64, // New blob size in bytes
+}
+
+/// Password change payload
+#[derive(Deserialize)]
+struct PasswordPayload {
+    old_password: String,
+    new_password: String,
+}
+
+/// Add keyslot payload
+#[derive(Deserialize)]
+struct AddKeyslotPayload {
+    #[serde(default)]
+    password: String,
+    keyfile: Option<String>, // Base64 keyfile contents, used together with the password
+    #[serde(default)]
+    recovery_key: bool, // Generate a new recovery key instead
+}
+
+/// Recovery shares payload
+#[derive(Deserialize)]
+struct RecoverySharesPayload {
+    threshold: u8, // Shares needed to unlock
+    shares: u8,    // Shares to create
+}
+
 // Helper function to validate or create directory
 fn validate_or_create_directory(dir_path: &PathBuf) {
     if dir_path.exists() {
@@ -227,6 +529,38 @@ fn get_available_blobs(dir_path: &PathBuf) -> Vec<String> {
     blobs
 }
 
+/// Size of each body frame when streaming decrypted file content to the client
+const STREAM_FRAME_SIZE: u64 = 64 * 1024;
+
+// Helper to stream `len` bytes of a stored file, starting at `offset`, as a response body.
+// Decryption runs on a blocking thread one chunk at a time, so memory use stays bounded
+// regardless of the file size.
+fn file_body(mut reader: FileReader, offset: u64, len: u64) -> axum::body::Body {
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<Vec<u8>>>(4);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = reader.seek(SeekFrom::Start(offset)) {
+            let _ = tx.blocking_send(Err(e));
+            return;
+        }
+        let mut remaining = len;
+        while remaining > 0 {
+            let mut frame = vec![0u8; remaining.min(STREAM_FRAME_SIZE) as usize];
+            if let Err(e) = reader.read_exact(&mut frame) {
+                log::error!("Error streaming file content: {}", e);
+                let _ = tx.blocking_send(Err(e));
+                return;
+            }
+            remaining -= frame.len() as u64;
+            if tx.blocking_send(Ok(frame)).is_err() {
+                return; // Client went away
+            }
+        }
+    });
+    axum::body::Body::from_stream(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|frame| (frame, rx))
+    }))
+}
+
 #[derive(RustEmbed)]
 #[folder = "../frontend/dist/"]
 struct Assets;
@@ -271,6 +605,10 @@ async fn main() {
 
     let args = Args::parse();
 
+    if let Some(Command::Recovery(command)) = args.command {
+        std::process::exit(recovery::run(command));
+    }
+
     // Determine server mode from args and environment variables
     let mode = match (
         args.single,
@@ -357,6 +695,12 @@ async fn main() {
     let app_context = AppContext {
         mode: mode.clone(),
         app_state: app_state.clone(),
+        limits: RequestLimits {
+            kdf_memory_kib: args.max_kdf_memory_kib,
+            kdf_iterations: args.max_kdf_iterations,
+            kdf_target_ms: args.max_kdf_target_ms,
+            capacity: args.max_capacity,
+        },
     };
 
     let app = axum::Router::new()
@@ -375,8 +719,18 @@ async fn main() {
         .route("/api/files/*filepath", delete(file_delete_handler))
         .route("/api/storage/stats", get(storage_stats_handler))
         .route("/api/storage/compact", post(compact_handler))
+        .route("/api/storage/migrate", post(migrate_handler))
+        .route("/api/storage/grow", post(grow_handler))
+        .route("/api/storage/verify", post(verify_handler))
+        .route("/api/jobs/:job_id", get(job_status_handler))
+        .route("/api/password", post(password_handler))
+        .route("/api/keyslots", get(keyslots_handler))
+        .route("/api/keyslots", post(add_keyslot_handler))
+        .route("/api/keyslots/:slot", delete(revoke_keyslot_handler))
+        .route("/api/recovery-shares", post(recovery_shares_handler))
         // Legacy routes updated for session authentication
         .route("/api/tree", get(tree_handler))
+        .route("/api/folders", post(create_folder_handler))
         .route("/api/rename", post(rename_handler))
         .route("/api/delete", delete(delete_query_handler))
         .route("/api/delete-folder", delete(delete_folder_handler))
@@ -484,6 +838,23 @@ async fn init_handler(
     let client_ip = Some(addr.ip().to_string());
     let user_agent = extract_user_agent(&headers);
 
+    // Resolve KDF settings before touching the filesystem. Calibrating takes up to
+    // `max_kdf_target_ms`, so it runs off the runtime.
+    let limits = app_context.limits.clone();
+    let (options, payload) = match tokio::task::spawn_blocking(move || {
+        (init_options_from_payload(&payload, &limits), payload)
+    })
+    .await
+    {
+        Ok(resolved) => resolved,
+        Err(e) => return blob_error_response("Init error", &blob_task_failed(e)),
+    };
+    let options = match options {
+        Ok(options) => options,
+        Err(e) => return blob_error_response("Invalid KDF settings", &e),
+    };
+    log::debug!("Using KDF parameters: {:?}", options.kdf);
+
     // Allow multiple sessions to access the same blob - this is important for privacy
     // Each browser session should be able to independently unlock the blob
 
@@ -541,6 +912,7 @@ async fn init_handler(
 
     let password_s = payload.password_s;
     let password_h_final: String; // Will hold the final hidden password
+    let hidden_password_given;
 
     // Validate and determine the hidden password
     match payload.password_h {
@@ -556,6 +928,7 @@ async fn init_handler(
             }
             println!("Using provided password for hidden volume.");
             password_h_final = ph;
+            hidden_password_given = true;
         }
         _ => {
             // User did not provide a hidden password, or it was empty - generate a random one
@@ -564,16 +937,25 @@ async fn init_handler(
             OsRng.fill_bytes(&mut password_h_bytes);
             // Prefix ensures it's unlikely to match user's standard password by chance
             password_h_final = format!("hidden_{}", hex::encode(password_h_bytes));
+            hidden_password_given = false;
         }
     }
 
     println!("Using provided password for standard volume.");
 
-    // Initialize new blob with both passwords
-    match init_blob(&blob_path, &password_s, &password_h_final) {
-        Ok(()) => {
-            // Unlock immediately using the standard password to get initial state
-            match unlock_blob(&blob_path, &password_s) {
+    // Initialize new blob with both passwords, deriving a key for each
+    let init_path = blob_path.clone();
+    let init = tokio::task::spawn_blocking(move || {
+        let recovery_keys =
+            init_blob_with_options(&init_path, &password_s, &password_h_final, &options)?;
+        // Unlock immediately using the standard password to get initial state
+        Ok((recovery_keys, unlock_blob(&init_path, &password_s)))
+    })
+    .await
+    .unwrap_or_else(|e| Err(blob_task_failed(e)));
+    match init {
+        Ok((recovery_keys, unlocked)) => {
+            match unlocked {
                 Ok((volume_type, key, metadata)) => {
                     // Create session instead of storing in global state
                     match app_context.app_state.session_manager.create_session(
@@ -587,10 +969,7 @@ async fn init_handler(
                         Ok(token) => {
                             let files = metadata
                                 .iter()
-                                .map(|(path, meta)| FileInfo {
-                                    path: path.clone(),
-                                    size: meta.size as usize,
-                                })
+                                .map(|(path, meta)| FileInfo::new(path, meta))
                                 .collect();
 
                             let resp: ApiResponse<InitResponse> = ApiResponse {
@@ -599,6 +978,9 @@ async fn init_handler(
                                     token,
                                     files,
                                     volume_type: format!("{:?}", volume_type),
+                                    recovery_key: recovery_keys.standard,
+                                    hidden_recovery_key: hidden_password_given
+                                        .then_some(recovery_keys.hidden),
                                 }),
                                 message: Some("Blob initialized and session created".into()),
                             };
@@ -614,27 +996,83 @@ async fn init_handler(
                         }
                     }
                 }
-                Err(e) => {
-                    // Error Case 3: Failed unlock after init
-                    let resp: ApiResponse<String> = ApiResponse {
-                        success: false,
-                        data: None,
-                        message: Some(format!("Failed to unlock after init: {}", e)),
-                    };
-                    (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-                }
+                Err(e) => blob_error_response("Failed to unlock after init", &e),
             }
         }
-        Err(e) => {
-            // Error Case 4: Failed init_blob
-            let resp: ApiResponse<String> = ApiResponse {
-                success: false,
-                data: None,
-                message: Some(format!("Init error: {}", e)),
-            };
-            (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
+        Err(e) => blob_error_response("Init error", &e),
+    }
+}
+
+/// Reads an unlock request: JSON, or multipart form data with `password`, `recovery_key`,
+/// `recovery_share` (repeated), `blob_name` and `keyfile` fields when a keyfile is uploaded.
+async fn read_unlock_payload(request: Request) -> Result<UnlockPayload, Response> {
+    let is_multipart = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    if !is_multipart {
+        return Json::<UnlockPayload>::from_request(request, &())
+            .await
+            .map(|Json(payload)| payload)
+            .map_err(IntoResponse::into_response);
+    }
+
+    let bad_request = |message: String| {
+        let resp: ApiResponse<String> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some(message),
+        };
+        (StatusCode::BAD_REQUEST, Json(resp)).into_response()
+    };
+    let mut multipart = Multipart::from_request(request, &())
+        .await
+        .map_err(IntoResponse::into_response)?;
+    let mut payload = UnlockPayload {
+        password: String::new(),
+        recovery_key: None,
+        recovery_shares: None,
+        keyfile: None,
+        blob_path: None,
+        blob_name: None,
+    };
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| bad_request(format!("Invalid unlock form: {}", e)))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "keyfile" {
+            let keyfile = field
+                .bytes()
+                .await
+                .map_err(|e| bad_request(format!("Error reading keyfile: {}", e)))?;
+            if keyfile.len() > MAX_KEYFILE_LEN {
+                return Err(bad_request(format!(
+                    "Keyfile exceeds the maximum size of {} bytes",
+                    MAX_KEYFILE_LEN
+                )));
+            }
+            payload.keyfile = Some(keyfile.to_vec());
+            continue;
+        }
+        let value = field
+            .text()
+            .await
+            .map_err(|e| bad_request(format!("Invalid unlock form: {}", e)))?;
+        match name.as_str() {
+            "password" => payload.password = value,
+            "recovery_key" => payload.recovery_key = Some(value),
+            "recovery_share" => payload
+                .recovery_shares
+                .get_or_insert_with(Vec::new)
+                .push(value),
+            "blob_name" => payload.blob_name = Some(value),
+            _ => {}
         }
     }
+    Ok(payload)
 }
 
 /// Unlock response
@@ -649,11 +1087,15 @@ async fn unlock_handler(
     Extension(app_context): Extension<AppContext>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: axum::http::HeaderMap,
-    Json(payload): Json<UnlockPayload>,
+    request: Request,
 ) -> Response {
     // Extract client info
     let client_ip = Some(addr.ip().to_string());
     let user_agent = extract_user_agent(&headers);
+    let payload = match read_unlock_payload(request).await {
+        Ok(payload) => payload,
+        Err(resp) => return resp,
+    };
 
     // Get blob path based on server mode
     let blob_path: PathBuf = match &app_context.mode {
@@ -707,8 +1149,33 @@ async fn unlock_handler(
 
     println!("Unlocking blob at: {}", blob_path.display());
 
-    // Unlock blob and get metadata
-    match unlock_blob(&blob_path, &payload.password) {
+    // Every keyslot tried costs a key derivation, so a wrong password takes seconds
+    let unlock_path = blob_path.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        // Finish cleaning up after an interrupted format migration, if any
+        if let Err(e) = recover_migration(&unlock_path) {
+            println!("Failed to recover interrupted migration: {}", e);
+        }
+
+        // Unlock blob and get metadata
+        let credential = match (
+            &payload.recovery_key,
+            &payload.recovery_shares,
+            &payload.keyfile,
+        ) {
+            (Some(recovery_key), _, _) => Credential::RecoveryKey(recovery_key),
+            (None, Some(shares), _) => Credential::RecoveryShares(shares),
+            (None, None, Some(keyfile)) => Credential::PasswordAndKeyfile {
+                password: &payload.password,
+                keyfile,
+            },
+            (None, None, None) => Credential::Password(&payload.password),
+        };
+        unlock_blob_with_credential(&unlock_path, &credential)
+    })
+    .await
+    .unwrap_or_else(|e| Err(blob_task_failed(e)));
+    match result {
         Ok((volume_type, key, metadata)) => {
             // Create session
             match app_context.app_state.session_manager.create_session(
@@ -722,10 +1189,7 @@ async fn unlock_handler(
                 Ok(token) => {
                     let files = metadata
                         .iter()
-                        .map(|(path, meta)| FileInfo {
-                            path: path.clone(),
-                            size: meta.size as usize,
-                        })
+                        .map(|(path, meta)| FileInfo::new(path, meta))
                         .collect();
 
                     let resp: ApiResponse<UnlockResponse> = ApiResponse {
@@ -751,12 +1215,7 @@ async fn unlock_handler(
         }
         Err(e) => {
             log::error!("Unlock failed: {}", e);
-            let resp: ApiResponse<String> = ApiResponse {
-                success: false,
-                data: None,
-                message: Some("Invalid password or corrupt blob".into()),
-            };
-            (StatusCode::UNAUTHORIZED, Json(resp)).into_response()
+            blob_error_response("Unlock failed", &e)
         }
     }
 }
@@ -766,6 +1225,7 @@ async fn logout_handler(
     Extension(app_context): Extension<AppContext>,
 ) -> Response {
     log::info!("Logout request received for session: {}", auth.session_id);
+    app_context.app_state.jobs.remove_session(&auth.session_id);
 
     if app_context
         .app_state
@@ -865,10 +1325,7 @@ async fn files_handler(
         let files = session
             .metadata
             .iter()
-            .map(|(path, meta)| FileInfo {
-                path: path.clone(),
-                size: meta.size as usize,
-            })
+            .map(|(path, meta)| FileInfo::new(path, meta))
             .collect();
         let resp: ApiResponse<FileList> = ApiResponse {
             success: true,
@@ -886,12 +1343,94 @@ async fn files_handler(
     }
 }
 
+/// Lists every file and folder, sorted by path. Folders that only exist implicitly, as the
+/// prefix of other paths, are listed like folders with an entry of their own.
 async fn tree_handler(
     auth: AuthContext,
     Extension(app_context): Extension<AppContext>,
 ) -> Response {
-    // Legacy handler that redirects to files_handler
-    files_handler(auth, Extension(app_context)).await
+    if let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    {
+        let mut entries: BTreeMap<&str, FileInfo> = session
+            .metadata
+            .iter()
+            .map(|(path, meta)| (path.as_str(), FileInfo::new(path, meta)))
+            .collect();
+        for path in session.metadata.keys() {
+            for (end, _) in path.match_indices('/') {
+                let folder = &path[..end];
+                if !folder.is_empty() && !entries.contains_key(folder) {
+                    entries.insert(folder, FileInfo::implicit_folder(folder));
+                }
+            }
+        }
+        let resp: ApiResponse<FileList> = ApiResponse {
+            success: true,
+            data: Some(FileList {
+                files: entries.into_values().collect(),
+            }),
+            message: None,
+        };
+        (StatusCode::OK, Json(resp)).into_response()
+    } else {
+        let resp: ApiResponse<String> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        (StatusCode::NOT_FOUND, Json(resp)).into_response()
+    }
+}
+
+/// Creates an (empty) folder and any missing parents.
+async fn create_folder_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    Json(payload): Json<CreateFolderPayload>,
+) -> Response {
+    if let Some((_guard, session)) = lock_session_blob(&app_context, &auth.session_id).await {
+        let mut metadata = session.metadata.clone();
+        match create_folder(
+            &session.blob_path,
+            session.volume_type,
+            &auth.derived_key,
+            &mut metadata,
+            &payload.path,
+        ) {
+            Ok(created) => {
+                log::info!(
+                    "Updating session metadata after creating folder: {}",
+                    payload.path
+                );
+                app_context
+                    .app_state
+                    .session_manager
+                    .update_session_metadata(&auth.session_id, metadata);
+                let (status, message) = if created {
+                    (StatusCode::CREATED, None)
+                } else {
+                    (StatusCode::OK, Some("Folder already exists".into()))
+                };
+                let resp: ApiResponse<()> = ApiResponse {
+                    success: true,
+                    data: None,
+                    message,
+                };
+                (status, Json(resp)).into_response()
+            }
+            Err(e) => blob_error_response("Create folder error", &e),
+        }
+    } else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        (StatusCode::NOT_FOUND, Json(resp)).into_response()
+    }
 }
 
 // Unified file GET handler that supports download, stream, and thumbnail operations
@@ -920,7 +1459,37 @@ async fn file_get_handler(
     }
 }
 
-// Stream handler implementation for video/audio with HTTP range support
+// Helper to parse a single HTTP byte range ("bytes=0-1023", "bytes=1024-" or "bytes=-1024")
+// against a file of `size` bytes. Returns the inclusive (start, end) pair, or None if the header
+// is malformed or unsatisfiable, in which case the whole file is served.
+fn parse_byte_range(range_str: &str, size: u64) -> Option<(u64, u64)> {
+    let range_part = range_str.strip_prefix("bytes=")?;
+    let (start_str, end_str) = range_part.split_once('-')?;
+    if size == 0 {
+        return None;
+    }
+    let (start, end) = if start_str.is_empty() {
+        // Suffix range like "-1024"
+        let suffix_len: u64 = end_str.parse().ok()?;
+        (size.saturating_sub(suffix_len), size - 1)
+    } else {
+        let start: u64 = start_str.parse().unwrap_or(0);
+        let end = if end_str.is_empty() {
+            size - 1
+        } else {
+            end_str.parse().unwrap_or(size - 1).min(size - 1)
+        };
+        (start, end)
+    };
+    if start < size && start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+// Stream handler implementation for video/audio with HTTP range support.
+// Only the encrypted chunks covering the requested range are read and decrypted.
 async fn stream_handler_impl(
     auth: AuthContext,
     app_context: Extension<AppContext>,
@@ -934,63 +1503,31 @@ async fn stream_handler_impl(
     {
         match session.metadata.get(&file_id) {
             Some(metadata) => {
-                match get_file(&session.blob_path, &auth.derived_key, metadata) {
-                    Ok(content) => {
-                        let content_length = content.len();
+                match open_file(&session.blob_path, &auth.derived_key, metadata) {
+                    Ok(reader) => {
+                        let content_length = metadata.size;
                         let mime = from_path(&file_id).first_or_octet_stream();
 
                         // Check for Range header
-                        if let Some(range_header) = headers.get("range") {
-                            if let Ok(range_str) = range_header.to_str() {
-                                if range_str.starts_with("bytes=") {
-                                    let range_part = &range_str[6..];
-
-                                    // Parse range (e.g., "0-1023" or "1024-" or "-1024")
-                                    if let Some((start_str, end_str)) = range_part.split_once('-') {
-                                        let start = if start_str.is_empty() {
-                                            // Suffix range like "-1024"
-                                            if let Ok(suffix_len) = end_str.parse::<usize>() {
-                                                content_length.saturating_sub(suffix_len)
-                                            } else {
-                                                0
-                                            }
-                                        } else {
-                                            start_str.parse().unwrap_or(0)
-                                        };
-
-                                        let end = if end_str.is_empty() {
-                                            content_length - 1
-                                        } else if start_str.is_empty() {
-                                            // Suffix range, end is already calculated above
-                                            content_length - 1
-                                        } else {
-                                            end_str
-                                                .parse()
-                                                .unwrap_or(content_length - 1)
-                                                .min(content_length - 1)
-                                        };
-
-                                        if start < content_length && start <= end {
-                                            let chunk = &content[start..=end];
-                                            return Response::builder()
-                                                .status(StatusCode::PARTIAL_CONTENT)
-                                                .header(CONTENT_TYPE, mime.as_ref())
-                                                .header(
-                                                    "Content-Range",
-                                                    format!(
-                                                        "bytes {}-{}/{}",
-                                                        start, end, content_length
-                                                    ),
-                                                )
-                                                .header("Content-Length", chunk.len().to_string())
-                                                .header("Accept-Ranges", "bytes")
-                                                .body(axum::body::Body::from(chunk.to_vec()))
-                                                .unwrap()
-                                                .into_response();
-                                        }
-                                    }
-                                }
-                            }
+                        let range = headers
+                            .get("range")
+                            .and_then(|h| h.to_str().ok())
+                            .and_then(|range_str| parse_byte_range(range_str, content_length));
+
+                        if let Some((start, end)) = range {
+                            let len = end - start + 1;
+                            return Response::builder()
+                                .status(StatusCode::PARTIAL_CONTENT)
+                                .header(CONTENT_TYPE, mime.as_ref())
+                                .header(
+                                    "Content-Range",
+                                    format!("bytes {}-{}/{}", start, end, content_length),
+                                )
+                                .header("Content-Length", len.to_string())
+                                .header("Accept-Ranges", "bytes")
+                                .body(file_body(reader, start, len))
+                                .unwrap()
+                                .into_response();
                         }
 
                         // Return full content if no valid range requested
@@ -1003,18 +1540,11 @@ async fn stream_handler_impl(
                                 CONTENT_DISPOSITION,
                                 format!("inline; filename=\"{}\"", file_id),
                             )
-                            .body(axum::body::Body::from(content))
+                            .body(file_body(reader, 0, content_length))
                             .unwrap()
                             .into_response()
                     }
-                    Err(e) => {
-                        let resp: ApiResponse<()> = ApiResponse {
-                            success: false,
-                            data: None,
-                            message: Some(format!("Error reading file: {}", e)),
-                        };
-                        (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-                    }
+                    Err(e) => blob_error_response("Error reading file", &e),
                 }
             }
             None => {
@@ -1089,14 +1619,7 @@ async fn thumbnail_handler_impl(
                                 .into_response()
                         }
                     }
-                    Err(e) => {
-                        let resp: ApiResponse<()> = ApiResponse {
-                            success: false,
-                            data: None,
-                            message: Some(format!("Error reading file: {}", e)),
-                        };
-                        (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-                    }
+                    Err(e) => blob_error_response("Error reading file", &e),
                 }
             }
             None => {
@@ -1122,10 +1645,16 @@ async fn thumbnail_handler_impl(
 #[derive(Serialize)]
 struct StorageStatsResponse {
     total_files: usize,
+    /// Logical (uncompressed) size of all files
     total_size: u64,
+    /// Bytes the volume's files take up in the blob, after deduplication and compression
+    stored_size: u64,
     blob_file_size: u64,
     volume_type: String,
     blob_path: String,
+    format_version: Option<u8>,
+    /// The blob has no plaintext header (see `InitOptions::headerless`)
+    headerless: bool,
 }
 
 async fn storage_stats_handler(
@@ -1139,6 +1668,13 @@ async fn storage_stats_handler(
     {
         // Calculate total size of all files in metadata
         let total_size: u64 = session.metadata.values().map(|meta| meta.size).sum();
+        // Shared chunks count once, on top of each file's own block
+        let stored_size = ChunkIndex::new(&session.metadata).stored_bytes()
+            + session
+                .metadata
+                .values()
+                .map(|meta| meta.data_length)
+                .sum::<u64>();
 
         // Get blob file size from filesystem
         let blob_file_size = match fs::metadata(&session.blob_path) {
@@ -1146,12 +1682,21 @@ async fn storage_stats_handler(
             Err(_) => 0,
         };
 
+        // The session's password opened the blob, so a headerless file is a v4 blob
+        let kind = blob_kind(&session.blob_path).ok();
         let stats = StorageStatsResponse {
-            total_files: session.metadata.len(),
+            total_files: session
+                .metadata
+                .values()
+                .filter(|meta| !meta.is_dir())
+                .count(),
             total_size,
+            stored_size,
             blob_file_size,
             volume_type: format!("{:?}", session.volume_type),
             blob_path: session.blob_path.to_string_lossy().to_string(),
+            format_version: kind.map(BlobKind::unlocked_version),
+            headerless: kind == Some(BlobKind::Headerless),
         };
 
         let resp: ApiResponse<StorageStatsResponse> = ApiResponse {
@@ -1176,11 +1721,7 @@ async fn rename_handler(
     Extension(app_context): Extension<AppContext>,
     Json(payload): Json<RenamePayload>,
 ) -> Response {
-    if let Some(session) = app_context
-        .app_state
-        .session_manager
-        .get_session(&auth.session_id)
-    {
+    if let Some((_guard, session)) = lock_session_blob(&app_context, &auth.session_id).await {
         // Note: For now, we'll work around the mutable session issue
         // In production, session metadata should be updated through the session manager
         let mut metadata = session.metadata.clone();
@@ -1218,14 +1759,7 @@ async fn rename_handler(
                 };
                 (StatusCode::NOT_FOUND, Json(resp)).into_response()
             }
-            Err(e) => {
-                let resp: ApiResponse<()> = ApiResponse {
-                    success: false,
-                    data: None,
-                    message: Some(format!("Rename error: {}", e)),
-                };
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-            }
+            Err(e) => blob_error_response("Rename error", &e),
         }
     } else {
         let resp: ApiResponse<()> = ApiResponse {
@@ -1243,11 +1777,7 @@ async fn delete_query_handler(
     Extension(app_context): Extension<AppContext>,
     Query(params): Query<DeleteParams>,
 ) -> Response {
-    if let Some(session) = app_context
-        .app_state
-        .session_manager
-        .get_session(&auth.session_id)
-    {
+    if let Some((_guard, session)) = lock_session_blob(&app_context, &auth.session_id).await {
         let mut metadata = session.metadata.clone();
         match remove_file(
             &session.blob_path,
@@ -1281,14 +1811,7 @@ async fn delete_query_handler(
                 };
                 (StatusCode::NOT_FOUND, Json(resp)).into_response()
             }
-            Err(e) => {
-                let resp: ApiResponse<()> = ApiResponse {
-                    success: false,
-                    data: None,
-                    message: Some(format!("Delete error: {}", e)),
-                };
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-            }
+            Err(e) => blob_error_response("Delete error", &e),
         }
     } else {
         let resp: ApiResponse<()> = ApiResponse {
@@ -1324,11 +1847,7 @@ async fn delete_handler_impl(
     app_context: Extension<AppContext>,
     file_id: String,
 ) -> Response {
-    if let Some(session) = app_context
-        .app_state
-        .session_manager
-        .get_session(&auth.session_id)
-    {
+    if let Some((_guard, session)) = lock_session_blob(&app_context, &auth.session_id).await {
         let mut metadata = session.metadata.clone();
         match remove_file(
             &session.blob_path,
@@ -1359,14 +1878,7 @@ async fn delete_handler_impl(
                 };
                 (StatusCode::NOT_FOUND, Json(resp)).into_response()
             }
-            Err(e) => {
-                let resp: ApiResponse<()> = ApiResponse {
-                    success: false,
-                    data: None,
-                    message: Some(format!("Delete error: {}", e)),
-                };
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-            }
+            Err(e) => blob_error_response("Delete error", &e),
         }
     } else {
         let resp: ApiResponse<()> = ApiResponse {
@@ -1383,11 +1895,7 @@ async fn delete_folder_handler(
     Extension(app_context): Extension<AppContext>,
     Query(params): Query<DeleteParams>,
 ) -> Response {
-    if let Some(session) = app_context
-        .app_state
-        .session_manager
-        .get_session(&auth.session_id)
-    {
+    if let Some((_guard, session)) = lock_session_blob(&app_context, &auth.session_id).await {
         let mut metadata = session.metadata.clone();
         match remove_folder(
             &session.blob_path,
@@ -1421,14 +1929,7 @@ async fn delete_folder_handler(
                 };
                 (StatusCode::NOT_FOUND, Json(resp)).into_response()
             }
-            Err(e) => {
-                let resp: ApiResponse<()> = ApiResponse {
-                    success: false,
-                    data: None,
-                    message: Some(format!("Delete error: {}", e)),
-                };
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-            }
+            Err(e) => blob_error_response("Delete error", &e),
         }
     } else {
         let resp: ApiResponse<()> = ApiResponse {
@@ -1452,28 +1953,22 @@ async fn download_query_handler(
         .get_session(&auth.session_id)
     {
         match session.metadata.get(&params.path) {
-            Some(metadata) => match get_file(&session.blob_path, &auth.derived_key, metadata) {
-                Ok(content) => {
+            Some(metadata) => match open_file(&session.blob_path, &auth.derived_key, metadata) {
+                Ok(reader) => {
                     let mime = from_path(&params.path).first_or_octet_stream();
                     Response::builder()
                         .status(StatusCode::OK)
                         .header(CONTENT_TYPE, mime.as_ref())
+                        .header("Content-Length", metadata.size.to_string())
                         .header(
                             CONTENT_DISPOSITION,
                             format!("inline; filename=\"{}\"", params.path),
                         )
-                        .body(axum::body::Body::from(content))
+                        .body(file_body(reader, 0, metadata.size))
                         .unwrap()
                         .into_response()
                 }
-                Err(e) => {
-                    let resp: ApiResponse<()> = ApiResponse {
-                        success: false,
-                        data: None,
-                        message: Some(format!("Error reading file: {}", e)),
-                    };
-                    (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-                }
+                Err(e) => blob_error_response("Error reading file", &e),
             },
             None => {
                 let resp: ApiResponse<()> = ApiResponse {
@@ -1506,28 +2001,22 @@ async fn download_handler_impl(
         .get_session(&auth.session_id)
     {
         match session.metadata.get(&file_id) {
-            Some(metadata) => match get_file(&session.blob_path, &auth.derived_key, metadata) {
-                Ok(content) => {
+            Some(metadata) => match open_file(&session.blob_path, &auth.derived_key, metadata) {
+                Ok(reader) => {
                     let mime = from_path(&file_id).first_or_octet_stream();
                     Response::builder()
                         .status(StatusCode::OK)
                         .header(CONTENT_TYPE, mime.as_ref())
+                        .header("Content-Length", metadata.size.to_string())
                         .header(
                             CONTENT_DISPOSITION,
                             format!("inline; filename=\"{}\"", file_id),
                         )
-                        .body(axum::body::Body::from(content))
+                        .body(file_body(reader, 0, metadata.size))
                         .unwrap()
                         .into_response()
                 }
-                Err(e) => {
-                    let resp: ApiResponse<()> = ApiResponse {
-                        success: false,
-                        data: None,
-                        message: Some(format!("Error reading file: {}", e)),
-                    };
-                    (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-                }
+                Err(e) => blob_error_response("Error reading file", &e),
             },
             None => {
                 let resp: ApiResponse<()> = ApiResponse {
@@ -1548,218 +2037,270 @@ async fn download_handler_impl(
     }
 }
 
-async fn upload_handler(
-    auth: AuthContext,
-    Extension(app_context): Extension<AppContext>,
-    Query(current_folder_query): Query<std::collections::HashMap<String, String>>,
-    mut multipart: Multipart,
-) -> Response {
-    if let Some(_session) = app_context
-        .app_state
-        .session_manager
-        .get_session(&auth.session_id)
-    {
-        println!("Upload started, processing multipart data");
-        println!("Current folder query: {:?}", current_folder_query);
-        let mut file_count = 0;
-        let mut total_size = 0;
-        let mut uploaded_files: Vec<(String, Vec<u8>)> = Vec::new();
-        let mut file_paths: Vec<String> = Vec::new();
-
-        // Process multipart without holding any locks
-        loop {
-            match multipart.next_field().await {
-                Ok(Some(field)) => {
-                    if let Some(name) = field.name() {
-                        if name == "file" || name == "files" {
-                            match field.file_name() {
-                                Some(fname) => {
-                                    let fname_owned = fname.to_string();
-                                    println!("Processing file: {}", fname_owned);
-
-                                    match field.bytes().await {
-                                        Ok(bytes) => {
-                                            let size = bytes.len();
-                                            total_size += size;
-                                            println!(
-                                                "Received file: {} ({} bytes)",
-                                                fname_owned, size
-                                            );
-
-                                            uploaded_files.push((fname_owned, bytes.to_vec()));
-                                            file_count += 1;
-                                        }
-                                        Err(e) => {
-                                            println!("Error reading file data: {}", e);
-                                            let resp: ApiResponse<FileList> = ApiResponse {
-                                                success: false,
-                                                data: None,
-                                                message: Some(format!(
-                                                    "Error reading file data: {}",
-                                                    e
-                                                )),
-                                            };
-                                            return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp))
-                                                .into_response();
-                                        }
-                                    }
-                                }
-                                None => {
-                                    println!("File field without filename");
-                                }
-                            }
-                        } else if name == "file_path" || name == "file_paths" {
-                            match field.text().await {
-                                Ok(path) => {
-                                    file_paths.push(path);
-                                    println!("Received file path: {}", file_paths.last().unwrap());
-                                }
-                                Err(e) => {
-                                    println!("Error reading file path: {}", e);
-                                }
-                            }
-                        }
-                    }
-                }
-                Ok(None) => break,
-                Err(e) => {
-                    println!("Error parsing multipart/form-data request: {}", e);
-                    let resp: ApiResponse<FileList> = ApiResponse {
-                        success: false,
-                        data: None,
-                        message: Some(format!("Invalid multipart payload: {}", e)),
-                    };
-                    return (StatusCode::BAD_REQUEST, Json(resp)).into_response();
-                }
-            }
-        }
-
-        println!(
-            "Processed {} files, total size: {} bytes",
-            file_count, total_size
-        );
-
-        if !uploaded_files.is_empty() {
-            // Process uploads
-            let mut successful_uploads = Vec::new();
-            let mut failed_uploads = Vec::new();
+/// Files whose data has been streamed into the blob but not yet committed to the metadata.
+struct StagedUpload {
+    /// Client file name and the durable data block for each file field, in upload order
+    files: Vec<(String, FileMetadata)>,
+    /// Relative paths sent alongside the files (matched to `files` by position)
+    file_paths: Vec<String>,
+    /// Modification times reported by the client in Unix milliseconds (matched by position)
+    last_modified: Vec<Option<u64>>,
+    total_size: u64,
+}
 
-            for (index, (filename, content)) in uploaded_files.iter().enumerate() {
-                // Get a fresh session reference for each upload
-                if let Some(session) = app_context
-                    .app_state
-                    .session_manager
-                    .get_session(&auth.session_id)
-                {
-                    let mut metadata = session.metadata.clone();
-
-                    // Use the full path if available, otherwise use filename
-                    let relative_path = if index < file_paths.len() {
-                        &file_paths[index]
-                    } else {
-                        filename
-                    };
+/// Streams every file field of a multipart upload straight into the blob, one encrypted chunk
+/// at a time, so uploads never have to fit in memory. Nothing is committed here: the caller
+/// commits all staged files with a single metadata write once the whole payload has been read.
+/// Chunks already in `chunks` are referenced instead of stored again, including those of
+/// earlier files in the same upload. New chunks are compressed as `Compression::for_file`
+/// picks for the volume and the file's MIME type. Data of a failed upload stays unreferenced and is
+/// reclaimed by compaction.
+async fn stage_multipart_upload(
+    multipart: &mut Multipart,
+    blob_path: &std::path::Path,
+    volume_type: VolumeType,
+    key: &[u8; 32],
+    chunks: &mut ChunkIndex,
+    current_folder: Option<&str>,
+) -> Result<StagedUpload, Response> {
+    let mut staged = StagedUpload {
+        files: Vec::new(),
+        file_paths: Vec::new(),
+        last_modified: Vec::new(),
+        total_size: 0,
+    };
 
-                    // Construct the full file path based on current folder
-                    let file_path = if let Some(current_folder) =
-                        current_folder_query.get("current_folder")
-                    {
-                        if current_folder.is_empty() {
-                            relative_path.clone()
-                        } else {
-                            format!("{}/{}", current_folder.trim_end_matches('/'), relative_path)
-                        }
-                    } else {
-                        relative_path.clone()
+    loop {
+        match multipart.next_field().await {
+            Ok(Some(field)) => {
+                let name = field.name().unwrap_or_default().to_string();
+                if name == "file" || name == "files" {
+                    let Some(fname) = field.file_name().map(str::to_string) else {
+                        println!("File field without filename");
+                        continue;
                     };
-
-                    println!(
-                        "Regular upload: Constructing file path: '{}' + '{}' = '{}'",
-                        current_folder_query
-                            .get("current_folder")
-                            .unwrap_or(&"".to_string()),
-                        relative_path,
-                        file_path
-                    );
-
-                    let mime_type = from_path(&filename).first_or_octet_stream();
-                    match add_file(
-                        &session.blob_path,
-                        session.volume_type,
-                        &auth.derived_key,
-                        &mut metadata,
+                    println!("Processing file: {}", fname);
+
+                    let mime_type = from_path(&fname).first_or_octet_stream();
+                    // Recorded in the block header; the relative path may only arrive later
+                    let relative_path = staged.file_paths.get(staged.files.len()).unwrap_or(&fname);
+                    let file_path = upload_path(current_folder, relative_path);
+                    let mut reader =
+                        StreamReader::new(field.map_err(|e| std::io::Error::other(e.to_string())));
+                    match write_file_data_async(
+                        blob_path,
+                        key,
+                        chunks,
                         &file_path,
-                        &content,
+                        &mut reader,
                         mime_type.as_ref(),
-                    ) {
-                        Ok(_) => {
-                            successful_uploads.push(file_path.clone());
-                            println!("Successfully uploaded: {}", file_path);
-                            // Update session metadata in session manager
-                            app_context
-                                .app_state
-                                .session_manager
-                                .update_session_metadata(&auth.session_id, metadata.clone());
+                        Compression::for_file(volume_type, mime_type.as_ref()),
+                    )
+                    .await
+                    {
+                        Ok(file_metadata) => {
+                            println!("Received file: {} ({} bytes)", fname, file_metadata.size);
+                            staged.total_size += file_metadata.size;
+                            staged.files.push((fname, file_metadata));
                         }
                         Err(e) => {
-                            failed_uploads.push((file_path.clone(), e.to_string()));
-                            println!("Failed to upload {}: {}", file_path, e);
+                            println!("Error reading file data: {}", e);
+                            return Err(blob_error_response("Error reading file data", &e));
                         }
                     }
-                } else {
-                    failed_uploads.push((filename.clone(), "Session not found".to_string()));
+                } else if name == "file_path" || name == "file_paths" {
+                    match field.text().await {
+                        Ok(path) => {
+                            println!("Received file path: {}", path);
+                            staged.file_paths.push(path);
+                        }
+                        Err(e) => {
+                            println!("Error reading file path: {}", e);
+                        }
+                    }
+                } else if name == "last_modified" {
+                    // Unparseable values still take their slot so later files stay matched
+                    let millis = field.text().await.ok().and_then(|t| t.trim().parse().ok());
+                    staged.last_modified.push(millis);
                 }
             }
-
-            if !failed_uploads.is_empty() {
-                let error_msg = failed_uploads
-                    .iter()
-                    .map(|(name, err)| format!("{}: {}", name, err))
-                    .collect::<Vec<_>>()
-                    .join(", ");
-
+            Ok(None) => break,
+            Err(e) => {
+                println!("Error parsing multipart/form-data request: {}", e);
                 let resp: ApiResponse<FileList> = ApiResponse {
                     success: false,
                     data: None,
-                    message: Some(format!("Upload errors: {}", error_msg)),
+                    message: Some(format!("Invalid multipart payload: {}", e)),
                 };
-                return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response();
+                return Err((StatusCode::BAD_REQUEST, Json(resp)).into_response());
             }
+        }
+    }
 
-            println!("All uploads successful: {:?}", successful_uploads);
-        } else {
-            println!("No files were uploaded");
+    println!(
+        "Processed {} files, total size: {} bytes",
+        staged.files.len(),
+        staged.total_size
+    );
+    Ok(staged)
+}
+
+/// Path inside the volume for an uploaded file: `relative_path` below `current_folder`.
+fn upload_path(current_folder: Option<&str>, relative_path: &str) -> String {
+    match current_folder {
+        Some(folder) if !folder.is_empty() => {
+            format!("{}/{}", folder.trim_end_matches('/'), relative_path)
         }
+        _ => relative_path.to_string(),
+    }
+}
 
-        // Return current file list from session
-        if let Some(session) = app_context
-            .app_state
-            .session_manager
-            .get_session(&auth.session_id)
+/// Commits staged uploads under `current_folder` with a single metadata write and refreshes the
+/// session's metadata. Returns the committed paths, or an error response.
+fn commit_staged_upload(
+    app_context: &AppContext,
+    auth: &AuthContext,
+    staged: StagedUpload,
+    current_folder: Option<&str>,
+) -> Result<Vec<String>, Response> {
+    let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    else {
+        let resp: ApiResponse<FileList> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        return Err((StatusCode::NOT_FOUND, Json(resp)).into_response());
+    };
+
+    let entries = staged
+        .files
+        .into_iter()
+        .enumerate()
+        .map(|(index, (filename, mut file_metadata))| {
+            // Use the full path if available, otherwise use filename
+            let relative_path = staged.file_paths.get(index).unwrap_or(&filename);
+
+            // Construct the full file path based on current folder
+            let file_path = upload_path(current_folder, relative_path);
+            println!(
+                "Constructing file path: '{}' + '{}' = '{}'",
+                current_folder.unwrap_or(""),
+                relative_path,
+                file_path
+            );
+            if let Some(&Some(millis)) = staged.last_modified.get(index) {
+                file_metadata.original_mtime = Some(millis / 1000);
+            }
+            (file_path, file_metadata)
+        })
+        .collect::<Vec<_>>();
+    let committed = entries.iter().map(|(path, _)| path.clone()).collect();
+
+    let mut metadata = session.metadata.clone();
+    if let Err(e) = add_file_entries(
+        &session.blob_path,
+        session.volume_type,
+        &auth.derived_key,
+        &mut metadata,
+        entries,
+    ) {
+        println!("Failed to commit upload: {}", e);
+        return Err(blob_error_response("Upload errors", &e));
+    }
+
+    // Update session metadata in session manager
+    app_context
+        .app_state
+        .session_manager
+        .update_session_metadata(&auth.session_id, metadata);
+    Ok(committed)
+}
+
+/// Builds the response listing the session's current files after an upload.
+fn uploaded_file_list_response(
+    app_context: &AppContext,
+    auth: &AuthContext,
+    message: Option<String>,
+) -> Response {
+    if let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    {
+        let files = session
+            .metadata
+            .iter()
+            .map(|(path, meta)| FileInfo::new(path, meta))
+            .collect::<Vec<_>>();
+
+        let resp: ApiResponse<FileList> = ApiResponse {
+            success: true,
+            data: Some(FileList { files }),
+            message,
+        };
+        (StatusCode::OK, Json(resp)).into_response()
+    } else {
+        let resp: ApiResponse<FileList> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        (StatusCode::NOT_FOUND, Json(resp)).into_response()
+    }
+}
+
+async fn upload_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    Query(current_folder_query): Query<std::collections::HashMap<String, String>>,
+    mut multipart: Multipart,
+) -> Response {
+    // Holds the blob's write lock for the whole upload
+    if let Some((_guard, session)) = lock_session_blob(&app_context, &auth.session_id).await {
+        println!("Upload started, processing multipart data");
+        println!("Current folder query: {:?}", current_folder_query);
+
+        let current_folder = current_folder_query
+            .get("current_folder")
+            .map(String::as_str);
+        let mut chunks = match ChunkIndex::open(
+            &session.blob_path,
+            session.volume_type,
+            &auth.derived_key,
+            &session.metadata,
+        ) {
+            Ok(chunks) => chunks,
+            Err(e) => return blob_error_response("Upload error", &e),
+        };
+        let staged = match stage_multipart_upload(
+            &mut multipart,
+            &session.blob_path,
+            session.volume_type,
+            &auth.derived_key,
+            &mut chunks,
+            current_folder,
+        )
+        .await
         {
-            let files = session
-                .metadata
-                .iter()
-                .map(|(path, meta)| FileInfo {
-                    path: path.clone(),
-                    size: meta.size as usize,
-                })
-                .collect::<Vec<_>>();
-
-            let resp: ApiResponse<FileList> = ApiResponse {
-                success: true,
-                data: Some(FileList { files }),
-                message: None,
-            };
-            (StatusCode::OK, Json(resp)).into_response()
+            Ok(staged) => staged,
+            Err(resp) => return resp,
+        };
+
+        if staged.files.is_empty() {
+            println!("No files were uploaded");
         } else {
-            let resp: ApiResponse<FileList> = ApiResponse {
-                success: false,
-                data: None,
-                message: Some("Session not found".into()),
-            };
-            (StatusCode::NOT_FOUND, Json(resp)).into_response()
+            match commit_staged_upload(&app_context, &auth, staged, current_folder) {
+                Ok(uploaded) => println!("All uploads successful: {:?}", uploaded),
+                Err(resp) => return resp,
+            }
         }
+
+        // Return current file list from session
+        uploaded_file_list_response(&app_context, &auth, None)
     } else {
         let resp: ApiResponse<FileList> = ApiResponse {
             success: false,
@@ -1783,210 +2324,52 @@ async fn batch_upload_handler(
         batch_info.batch_id, batch_info.is_final_batch, batch_info.current_folder
     );
 
-    if let Some(_session) = app_context
-        .app_state
-        .session_manager
-        .get_session(&auth.session_id)
-    {
+    // Holds the blob's write lock for the whole batch
+    if let Some((_guard, session)) = lock_session_blob(&app_context, &auth.session_id).await {
         println!("Batch upload started, processing multipart data");
-        let mut file_count = 0;
-        let mut total_size = 0;
-        let mut uploaded_files: Vec<(String, Vec<u8>)> = Vec::new();
-        let mut file_paths: Vec<String> = Vec::new();
-
-        // Process multipart without holding any locks
-        loop {
-            match multipart.next_field().await {
-                Ok(Some(field)) => {
-                    if let Some(name) = field.name() {
-                        if name == "files" || name == "file" {
-                            match field.file_name() {
-                                Some(fname) => {
-                                    let fname_owned = fname.to_string();
-                                    println!("Processing batch file: {}", fname_owned);
-
-                                    match field.bytes().await {
-                                        Ok(bytes) => {
-                                            let size = bytes.len();
-                                            total_size += size;
-                                            println!(
-                                                "Received batch file: {} ({} bytes)",
-                                                fname_owned, size
-                                            );
-
-                                            uploaded_files.push((fname_owned, bytes.to_vec()));
-                                            file_count += 1;
-                                        }
-                                        Err(e) => {
-                                            println!("Error reading batch file data: {}", e);
-                                            let resp: ApiResponse<FileList> = ApiResponse {
-                                                success: false,
-                                                data: None,
-                                                message: Some(format!(
-                                                    "Error reading file data: {}",
-                                                    e
-                                                )),
-                                            };
-                                            return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp))
-                                                .into_response();
-                                        }
-                                    }
-                                }
-                                None => {
-                                    println!("File field without filename in batch");
-                                }
-                            }
-                        } else if name == "file_paths" || name == "file_path" {
-                            match field.text().await {
-                                Ok(path) => {
-                                    file_paths.push(path);
-                                    println!("Received file path: {}", file_paths.last().unwrap());
-                                }
-                                Err(e) => {
-                                    println!("Error reading file path: {}", e);
-                                }
-                            }
-                        }
-                    }
-                }
-                Ok(None) => break,
-                Err(e) => {
-                    println!("Error parsing batch multipart/form-data request: {}", e);
-                    let resp: ApiResponse<FileList> = ApiResponse {
-                        success: false,
-                        data: None,
-                        message: Some(format!("Invalid multipart payload: {}", e)),
-                    };
-                    return (StatusCode::BAD_REQUEST, Json(resp)).into_response();
-                }
-            }
-        }
 
-        println!(
-            "Processed {} batch files, total size: {} bytes",
-            file_count, total_size
-        );
-
-        if !uploaded_files.is_empty() {
-            // Process uploads
-            let mut successful_uploads = Vec::new();
-            let mut failed_uploads = Vec::new();
-
-            for (index, (filename, content)) in uploaded_files.iter().enumerate() {
-                // Get a fresh session reference for each upload
-                if let Some(session) = app_context
-                    .app_state
-                    .session_manager
-                    .get_session(&auth.session_id)
-                {
-                    let mut metadata = session.metadata.clone();
-
-                    // Use the full path if available, otherwise use filename
-                    let relative_path = if index < file_paths.len() {
-                        &file_paths[index]
-                    } else {
-                        filename
-                    };
-
-                    // Construct the full file path based on current folder
-                    let file_path = if let Some(ref folder) = batch_info.current_folder {
-                        if folder.is_empty() {
-                            relative_path.clone()
-                        } else {
-                            format!("{}/{}", folder.trim_end_matches('/'), relative_path)
-                        }
-                    } else {
-                        relative_path.clone()
-                    };
-
-                    println!(
-                        "Constructing file path: '{}' + '{}' = '{}'",
-                        batch_info.current_folder.as_deref().unwrap_or(""),
-                        relative_path,
-                        file_path
-                    );
-
-                    let mime_type = from_path(&filename).first_or_octet_stream();
-                    match add_file(
-                        &session.blob_path,
-                        session.volume_type,
-                        &auth.derived_key,
-                        &mut metadata,
-                        &file_path,
-                        &content,
-                        mime_type.as_ref(),
-                    ) {
-                        Ok(_) => {
-                            successful_uploads.push(file_path.clone());
-                            println!("Successfully uploaded batch file: {}", file_path);
-                            // Update session metadata in session manager
-                            app_context
-                                .app_state
-                                .session_manager
-                                .update_session_metadata(&auth.session_id, metadata.clone());
-                        }
-                        Err(e) => {
-                            failed_uploads.push((file_path.clone(), e.to_string()));
-                            println!("Failed to upload batch file {}: {}", file_path, e);
-                        }
-                    }
-                } else {
-                    failed_uploads.push((filename.clone(), "Session not found".to_string()));
-                }
-            }
-
-            if !failed_uploads.is_empty() {
-                let error_msg = failed_uploads
-                    .iter()
-                    .map(|(name, err)| format!("{}: {}", name, err))
-                    .collect::<Vec<_>>()
-                    .join(", ");
-
-                let resp: ApiResponse<FileList> = ApiResponse {
-                    success: false,
-                    data: None,
-                    message: Some(format!("Batch upload errors: {}", error_msg)),
-                };
-                return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response();
-            }
+        let current_folder = batch_info.current_folder.as_deref();
+        let mut chunks = match ChunkIndex::open(
+            &session.blob_path,
+            session.volume_type,
+            &auth.derived_key,
+            &session.metadata,
+        ) {
+            Ok(chunks) => chunks,
+            Err(e) => return blob_error_response("Upload error", &e),
+        };
+        let staged = match stage_multipart_upload(
+            &mut multipart,
+            &session.blob_path,
+            session.volume_type,
+            &auth.derived_key,
+            &mut chunks,
+            current_folder,
+        )
+        .await
+        {
+            Ok(staged) => staged,
+            Err(resp) => return resp,
+        };
 
-            println!("All batch uploads successful: {:?}", successful_uploads);
-        } else {
+        if staged.files.is_empty() {
             println!("No files were uploaded in batch");
+        } else {
+            match commit_staged_upload(&app_context, &auth, staged, current_folder) {
+                Ok(uploaded) => println!("All batch uploads successful: {:?}", uploaded),
+                Err(resp) => return resp,
+            }
         }
 
         // Return current file list from session
-        if let Some(session) = app_context
-            .app_state
-            .session_manager
-            .get_session(&auth.session_id)
-        {
-            let files = session
-                .metadata
-                .iter()
-                .map(|(path, meta)| FileInfo {
-                    path: path.clone(),
-                    size: meta.size as usize,
-                })
-                .collect::<Vec<_>>();
-
-            let resp: ApiResponse<FileList> = ApiResponse {
-                success: true,
-                data: Some(FileList { files }),
-                message: Some(format!(
-                    "Batch {} uploaded successfully",
-                    batch_info.batch_id
-                )),
-            };
-            (StatusCode::OK, Json(resp)).into_response()
-        } else {
-            let resp: ApiResponse<FileList> = ApiResponse {
-                success: false,
-                data: None,
-                message: Some("Session not found".into()),
-            };
-            (StatusCode::NOT_FOUND, Json(resp)).into_response()
-        }
+        uploaded_file_list_response(
+            &app_context,
+            &auth,
+            Some(format!(
+                "Batch {} uploaded successfully",
+                batch_info.batch_id
+            )),
+        )
     } else {
         let resp: ApiResponse<FileList> = ApiResponse {
             success: false,
@@ -2034,7 +2417,122 @@ async fn delete_blob_handler(
     }
 }
 
+/// Compacts the blob. With both passwords the whole blob is rewritten (see `compact_blob`);
+/// otherwise the unlocked volume is compacted in place by a background job (see
+/// `compact_volume_step`), returned to poll via `/api/jobs/:job_id`. Every step of the job
+/// commits, so a job that stops early is picked up where it left by the next one.
 async fn compact_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    payload: Option<Json<StorageCompactPayload>>,
+) -> Response {
+    let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        return (StatusCode::NOT_FOUND, Json(resp)).into_response();
+    };
+
+    let Json(payload) = payload.unwrap_or_default();
+    if let (Some(password_s), Some(password_h)) = (payload.password_s, payload.password_h) {
+        if !password_s.is_empty() && !password_h.is_empty() {
+            return compact_session_blob(&app_context, &auth, password_s, password_h).await;
+        }
+    }
+
+    let job = match app_context
+        .app_state
+        .jobs
+        .start(&auth.session_id, "compact")
+    {
+        Ok(job) => job,
+        Err(running) => {
+            let resp: ApiResponse<JobStatus> = ApiResponse {
+                success: true,
+                data: Some(running),
+                message: Some("Compaction already running".into()),
+            };
+            return (StatusCode::ACCEPTED, Json(resp)).into_response();
+        }
+    };
+    println!("Starting compaction job {}", job.id);
+
+    let jobs = app_context.app_state.jobs.clone();
+    let session_manager = app_context.app_state.session_manager.clone();
+    let write_lock = app_context.app_state.blob_write_lock(&session.blob_path);
+    let job_id = job.id.clone();
+    let session_id = auth.session_id.clone();
+    let key = auth.derived_key;
+    tokio::spawn(async move {
+        let _guard = write_lock.lock_owned().await;
+        // Start from the metadata as the commits made while waiting for the lock left it
+        let Some(session) = session_manager.get_session(&session_id) else {
+            jobs.fail(&job_id, "Session not found".into(), "not_found");
+            return;
+        };
+        let mut total = CompactionReport::default();
+        let mut metadata = session.metadata.clone();
+        let result = loop {
+            let blob_path = session.blob_path.clone();
+            let volume_type = session.volume_type;
+            let step = tokio::task::spawn_blocking(move || {
+                compact_volume_step(&blob_path, volume_type, &key, &mut metadata)
+                    .map(|report| (report, metadata))
+            })
+            .await
+            .unwrap_or_else(|e| Err(blob_task_failed(e)));
+            match step {
+                Ok((report, committed)) => {
+                    total.add(report);
+                    session_manager.update_session_metadata(&session_id, committed.clone());
+                    metadata = committed;
+                    if report.done {
+                        break Ok(total);
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        match result {
+            Ok(report) => {
+                println!(
+                    "Compaction job {} finished: {} bytes moved, {} bytes reclaimed",
+                    job_id, report.moved_bytes, report.reclaimed_bytes
+                );
+                jobs.finish(
+                    &job_id,
+                    serde_json::to_value(report).unwrap_or(serde_json::Value::Null),
+                );
+            }
+            Err(e) => {
+                println!("Compaction job {} failed: {}", job_id, e);
+                jobs.fail(&job_id, format!("Compaction failed: {}", e), e.code());
+            }
+        }
+    });
+
+    let resp: ApiResponse<JobStatus> = ApiResponse {
+        success: true,
+        data: Some(job),
+        message: None,
+    };
+    (StatusCode::ACCEPTED, Json(resp)).into_response()
+}
+
+/// Migration response
+#[derive(Serialize)]
+struct MigrateResponse {
+    migrated: bool,
+    format_version: u8,
+}
+
+async fn migrate_handler(
     auth: AuthContext,
     Extension(app_context): Extension<AppContext>,
     Json(payload): Json<CompactPayload>,
@@ -2044,23 +2542,31 @@ async fn compact_handler(
         .session_manager
         .get_session(&auth.session_id)
     {
-        match compact_blob(&session.blob_path, &payload.password_s, &payload.password_h) {
-            Ok(_) => {
-                let resp: ApiResponse<()> = ApiResponse {
+        // No uploads may append to the blob while its header region is rewritten
+        let write_lock = app_context.app_state.blob_write_lock(&session.blob_path);
+        let _guard = write_lock.lock().await;
+
+        let blob_path = session.blob_path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let migrated = migrate_blob(&blob_path, &payload.password_s, &payload.password_h)?;
+            Ok::<_, BlobError>((migrated, blob_kind(&blob_path)?.unlocked_version()))
+        })
+        .await
+        .unwrap_or_else(|e| Err(blob_task_failed(e)));
+
+        match result {
+            Ok((migrated, format_version)) => {
+                let resp: ApiResponse<MigrateResponse> = ApiResponse {
                     success: true,
-                    data: None,
+                    data: Some(MigrateResponse {
+                        migrated,
+                        format_version,
+                    }),
                     message: None,
                 };
                 (StatusCode::OK, Json(resp)).into_response()
             }
-            Err(e) => {
-                let resp: ApiResponse<()> = ApiResponse {
-                    success: false,
-                    data: None,
-                    message: Some(format!("Compaction failed: {}", e)),
-                };
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
-            }
+            Err(e) => blob_error_response("Migration failed", &e),
         }
     } else {
         let resp: ApiResponse<()> = ApiResponse {
@@ -2072,34 +2578,521 @@ async fn compact_handler(
     }
 }
 
+/// Grow response
+#[derive(Serialize)]
+struct GrowResponse {
+    capacity: u64,
+}
+
+/// Grows the session's preallocated blob with random data (see `grow_blob`).
+async fn grow_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    Json(payload): Json<GrowPayload>,
+) -> Response {
+    let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        return (StatusCode::NOT_FOUND, Json(resp)).into_response();
+    };
+    // Writes in flight may not see the blob change length under them
+    let write_lock = app_context.app_state.blob_write_lock(&session.blob_path);
+    let _guard = write_lock.lock().await;
+
+    let capacity = match check_capacity(payload.capacity, &app_context.limits) {
+        Ok(capacity) => capacity,
+        Err(e) => return blob_error_response("Growing the blob failed", &e),
+    };
+    let blob_path = session.blob_path.clone();
+    let result = tokio::task::spawn_blocking(move || grow_blob(&blob_path, capacity))
+        .await
+        .unwrap_or_else(|e| Err(blob_task_failed(e)));
+    match result {
+        Ok(()) => {
+            let resp: ApiResponse<GrowResponse> = ApiResponse {
+                success: true,
+                data: Some(GrowResponse { capacity }),
+                message: None,
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => blob_error_response("Growing the blob failed", &e),
+    }
+}
+
+/// Starts verifying the unlocked volume in the background (see `verify_blob`). Returns the
+/// job to poll via `/api/jobs/:job_id`; if a verification is already running for this
+/// session, that job is returned instead of starting another.
+async fn verify_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+) -> Response {
+    let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        return (StatusCode::NOT_FOUND, Json(resp)).into_response();
+    };
+
+    let job = match app_context.app_state.jobs.start(&auth.session_id, "verify") {
+        Ok(job) => job,
+        Err(running) => {
+            let resp: ApiResponse<JobStatus> = ApiResponse {
+                success: true,
+                data: Some(running),
+                message: Some("Verification already running".into()),
+            };
+            return (StatusCode::ACCEPTED, Json(resp)).into_response();
+        }
+    };
+    println!("Starting verification job {}", job.id);
+
+    let jobs = app_context.app_state.jobs.clone();
+    // Blocks must not move (compaction) or be half-written while they are checked
+    let write_lock = app_context.app_state.blob_write_lock(&session.blob_path);
+    let job_id = job.id.clone();
+    let blob_path = session.blob_path.clone();
+    let volume_type = session.volume_type;
+    let key = auth.derived_key;
+    tokio::spawn(async move {
+        let _guard = write_lock.lock_owned().await;
+        let result =
+            tokio::task::spawn_blocking(move || verify_blob(&blob_path, volume_type, &key))
+                .await
+                .unwrap_or_else(|e| Err(blob_task_failed(e)));
+        match result {
+            Ok(report) => {
+                println!(
+                    "Verification job {} finished: {} files checked, {} issues",
+                    job_id,
+                    report.files_checked,
+                    report.issues.len()
+                );
+                jobs.finish(
+                    &job_id,
+                    serde_json::to_value(&report).unwrap_or(serde_json::Value::Null),
+                );
+            }
+            Err(e) => {
+                println!("Verification job {} failed: {}", job_id, e);
+                jobs.fail(&job_id, format!("Verification failed: {}", e), e.code());
+            }
+        }
+    });
+
+    let resp: ApiResponse<JobStatus> = ApiResponse {
+        success: true,
+        data: Some(job),
+        message: None,
+    };
+    (StatusCode::ACCEPTED, Json(resp)).into_response()
+}
+
+/// Returns the status of a background job started by this session.
+async fn job_status_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    Path(job_id): Path<String>,
+) -> Response {
+    match app_context.app_state.jobs.get(&auth.session_id, &job_id) {
+        Some(job) => {
+            let resp: ApiResponse<JobStatus> = ApiResponse {
+                success: true,
+                data: Some(job),
+                message: None,
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        None => {
+            let resp: ApiResponse<()> = ApiResponse {
+                success: false,
+                data: None,
+                message: Some("Job not found".into()),
+            };
+            (StatusCode::NOT_FOUND, Json(resp)).into_response()
+        }
+    }
+}
+
+/// Rebuilds the session's blob with both passwords (see `compact_blob`) under its write lock,
+/// then reads the session's metadata again, as every block has moved.
+async fn compact_session_blob(
+    app_context: &AppContext,
+    auth: &AuthContext,
+    password_s: String,
+    password_h: String,
+) -> Response {
+    let Some((_guard, session)) = lock_session_blob(app_context, &auth.session_id).await else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        return (StatusCode::NOT_FOUND, Json(resp)).into_response();
+    };
+    let blob_path = session.blob_path.clone();
+    let volume_type = session.volume_type;
+    let result = tokio::task::spawn_blocking(move || {
+        compact_blob(&blob_path, &password_s, &password_h)?;
+        let password = match volume_type {
+            VolumeType::Standard => &password_s,
+            VolumeType::Hidden => &password_h,
+        };
+        unlock_blob(&blob_path, password).map(|(_, _, metadata)| metadata)
+    })
+    .await
+    .unwrap_or_else(|e| Err(blob_task_failed(e)));
+    match result {
+        Ok(metadata) => {
+            app_context
+                .app_state
+                .session_manager
+                .update_session_metadata(&auth.session_id, metadata);
+            let resp: ApiResponse<()> = ApiResponse {
+                success: true,
+                data: None,
+                message: None,
+            };
+            (StatusCode::OK, Json(resp)).into_response()
+        }
+        Err(e) => blob_error_response("Compaction failed", &e),
+    }
+}
+
 // Session-based compact handler for legacy route
 async fn compact_legacy_handler(
     auth: AuthContext,
     Extension(app_context): Extension<AppContext>,
     Json(payload): Json<CompactPayload>,
 ) -> Response {
+    compact_session_blob(&app_context, &auth, payload.password_s, payload.password_h).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_byte_range() {
+        assert_eq!(parse_byte_range("bytes=0-1023", 4096), Some((0, 1023)));
+        assert_eq!(parse_byte_range("bytes=1024-", 4096), Some((1024, 4095)));
+        assert_eq!(parse_byte_range("bytes=-100", 4096), Some((3996, 4095)));
+        // End is clamped to the file size
+        assert_eq!(
+            parse_byte_range("bytes=4000-9999", 4096),
+            Some((4000, 4095))
+        );
+        // Unsatisfiable or malformed ranges fall back to the full file
+        assert_eq!(parse_byte_range("bytes=5000-", 4096), None);
+        assert_eq!(parse_byte_range("bytes=10-5", 4096), None);
+        assert_eq!(parse_byte_range("items=0-10", 4096), None);
+        assert_eq!(parse_byte_range("bytes=0-", 0), None);
+    }
+
+    #[test]
+    fn test_check_capacity() {
+        let limits = RequestLimits {
+            kdf_memory_kib: 8192,
+            kdf_iterations: 1,
+            kdf_target_ms: 100,
+            capacity: 1 << 30,
+        };
+        assert_eq!(check_capacity(1 << 30, &limits).unwrap(), 1 << 30);
+        assert!(matches!(
+            check_capacity((1 << 30) + 1, &limits),
+            Err(BlobError::InvalidInput(_))
+        ));
+    }
+}
+
+async fn password_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    Json(payload): Json<PasswordPayload>,
+) -> Response {
+    if payload.new_password.is_empty() {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("New password must not be empty".into()),
+        };
+        return (StatusCode::BAD_REQUEST, Json(resp)).into_response();
+    }
+
     if let Some(session) = app_context
         .app_state
         .session_manager
         .get_session(&auth.session_id)
     {
-        match compact_blob(&session.blob_path, &payload.password_s, &payload.password_h) {
+        // Keyslots live in the header region, which compaction and migration rewrite
+        let write_lock = app_context.app_state.blob_write_lock(&session.blob_path);
+        let _guard = write_lock.lock().await;
+
+        let blob_path = session.blob_path.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            change_password(&blob_path, &payload.old_password, &payload.new_password)
+        })
+        .await
+        .unwrap_or_else(|e| Err(blob_task_failed(e)));
+
+        match result {
             Ok(_) => {
+                println!("Password changed for session {}", auth.session_id);
                 let resp: ApiResponse<()> = ApiResponse {
                     success: true,
                     data: None,
+                    message: Some("Password changed".into()),
+                };
+                (StatusCode::OK, Json(resp)).into_response()
+            }
+            Err(e) => blob_error_response("Password change failed", &e),
+        }
+    } else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        (StatusCode::NOT_FOUND, Json(resp)).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct KeyslotsResponse {
+    slots: Vec<usize>,
+    max_slots: usize,
+}
+
+#[derive(Serialize)]
+struct AddKeyslotResponse {
+    slot: usize,
+    recovery_key: Option<String>, // The generated recovery key, shown once
+}
+
+async fn keyslots_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+) -> Response {
+    if let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    {
+        // Only the unlocked volume's slots are listed; the other volume's are never inspected
+        match list_keyslots(&session.blob_path, session.volume_type, &auth.derived_key) {
+            Ok(slots) => {
+                let resp: ApiResponse<KeyslotsResponse> = ApiResponse {
+                    success: true,
+                    data: Some(KeyslotsResponse {
+                        slots,
+                        max_slots: KEYSLOTS_PER_VOLUME,
+                    }),
                     message: None,
                 };
                 (StatusCode::OK, Json(resp)).into_response()
             }
-            Err(e) => {
+            Err(e) => blob_error_response("Failed to list keyslots", &e),
+        }
+    } else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        (StatusCode::NOT_FOUND, Json(resp)).into_response()
+    }
+}
+
+async fn add_keyslot_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    Json(payload): Json<AddKeyslotPayload>,
+) -> Response {
+    let keyfile = match payload
+        .keyfile
+        .as_deref()
+        .map(|k| base64::prelude::BASE64_STANDARD.decode(k))
+    {
+        Some(Ok(keyfile)) => Some(keyfile),
+        Some(Err(_)) => {
+            let resp: ApiResponse<()> = ApiResponse {
+                success: false,
+                data: None,
+                message: Some("Keyfile must be base64 encoded".into()),
+            };
+            return (StatusCode::BAD_REQUEST, Json(resp)).into_response();
+        }
+        None => None,
+    };
+    if payload.password.is_empty() && keyfile.is_none() && !payload.recovery_key {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("A password, keyfile or recovery key is required".into()),
+        };
+        return (StatusCode::BAD_REQUEST, Json(resp)).into_response();
+    }
+
+    if let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    {
+        let write_lock = app_context.app_state.blob_write_lock(&session.blob_path);
+        let _guard = write_lock.lock().await;
+
+        let blob_path = session.blob_path.clone();
+        let volume_type = session.volume_type;
+        let key = auth.derived_key;
+        let result = tokio::task::spawn_blocking(move || {
+            if payload.recovery_key {
+                let (recovery_key, slot) = add_recovery_key(&blob_path, volume_type, &key)?;
+                return Ok((slot, Some(recovery_key)));
+            }
+            let credential = match &keyfile {
+                Some(keyfile) => Credential::PasswordAndKeyfile {
+                    password: &payload.password,
+                    keyfile,
+                },
+                None => Credential::Password(&payload.password),
+            };
+            let slot = add_keyslot(&blob_path, volume_type, &key, &credential)?;
+            Ok((slot, None))
+        })
+        .await
+        .unwrap_or_else(|e| Err(blob_task_failed(e)));
+
+        match result {
+            Ok((slot, recovery_key)) => {
+                println!("Added keyslot {} for session {}", slot, auth.session_id);
+                let resp: ApiResponse<AddKeyslotResponse> = ApiResponse {
+                    success: true,
+                    data: Some(AddKeyslotResponse { slot, recovery_key }),
+                    message: None,
+                };
+                (StatusCode::OK, Json(resp)).into_response()
+            }
+            Err(e) => blob_error_response("Failed to add keyslot", &e),
+        }
+    } else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        (StatusCode::NOT_FOUND, Json(resp)).into_response()
+    }
+}
+
+async fn revoke_keyslot_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    Path(slot): Path<usize>,
+) -> Response {
+    if let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    {
+        let write_lock = app_context.app_state.blob_write_lock(&session.blob_path);
+        let _guard = write_lock.lock().await;
+
+        match revoke_keyslot(
+            &session.blob_path,
+            session.volume_type,
+            &auth.derived_key,
+            slot,
+        ) {
+            Ok(()) => {
+                println!("Revoked keyslot {} for session {}", slot, auth.session_id);
                 let resp: ApiResponse<()> = ApiResponse {
-                    success: false,
+                    success: true,
                     data: None,
-                    message: Some(format!("Compaction failed: {}", e)),
+                    message: Some(format!("Keyslot {} revoked", slot)),
                 };
-                (StatusCode::INTERNAL_SERVER_ERROR, Json(resp)).into_response()
+                (StatusCode::OK, Json(resp)).into_response()
+            }
+            Err(e) => blob_error_response("Failed to revoke keyslot", &e),
+        }
+    } else {
+        let resp: ApiResponse<()> = ApiResponse {
+            success: false,
+            data: None,
+            message: Some("Session not found".into()),
+        };
+        (StatusCode::NOT_FOUND, Json(resp)).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct RecoverySharesResponse {
+    slot: usize,
+    threshold: u8,
+    shares: Vec<String>, // Mnemonic shares, shown once
+}
+
+async fn recovery_shares_handler(
+    auth: AuthContext,
+    Extension(app_context): Extension<AppContext>,
+    Json(payload): Json<RecoverySharesPayload>,
+) -> Response {
+    if let Some(session) = app_context
+        .app_state
+        .session_manager
+        .get_session(&auth.session_id)
+    {
+        let write_lock = app_context.app_state.blob_write_lock(&session.blob_path);
+        let _guard = write_lock.lock().await;
+
+        let blob_path = session.blob_path.clone();
+        let volume_type = session.volume_type;
+        let key = auth.derived_key;
+        let result = tokio::task::spawn_blocking(move || {
+            add_recovery_shares(
+                &blob_path,
+                volume_type,
+                &key,
+                payload.threshold,
+                payload.shares,
+            )
+        })
+        .await
+        .unwrap_or_else(|e| Err(blob_task_failed(e)));
+
+        match result {
+            Ok((shares, slot)) => {
+                println!(
+                    "Added {}-of-{} recovery shares in keyslot {} for session {}",
+                    payload.threshold,
+                    shares.len(),
+                    slot,
+                    auth.session_id
+                );
+                let resp: ApiResponse<RecoverySharesResponse> = ApiResponse {
+                    success: true,
+                    data: Some(RecoverySharesResponse {
+                        slot,
+                        threshold: payload.threshold,
+                        shares,
+                    }),
+                    message: None,
+                };
+                (StatusCode::OK, Json(resp)).into_response()
             }
+            Err(e) => blob_error_response("Failed to create recovery shares", &e),
         }
     } else {
         let resp: ApiResponse<()> = ApiResponse {
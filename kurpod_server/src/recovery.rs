@@ -0,0 +1,149 @@
+//! Offline recovery tooling (`kurpod_server recovery ...`). Runs against the blob file
+//! directly, without starting the server. Secrets are read from stdin so they don't end up
+//! in shell history or the process list.
+
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use encryption_core::{
+    add_keyslot, combine_recovery_shares, salvage_blob, split_recovery_key,
+    unlock_blob_with_credential, Credential, VolumeSalvage,
+};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand, Debug)]
+pub enum RecoveryCommand {
+    /// Split a recovery key (read from stdin) into mnemonic shares
+    Split {
+        /// Number of shares needed to recover the key
+        #[arg(short = 'k', long)]
+        threshold: u8,
+        /// Number of shares to create
+        #[arg(short = 'n', long)]
+        shares: u8,
+    },
+    /// Reassemble a recovery key from shares read from stdin, one per line
+    Combine,
+    /// Unlock a blob with shares read from stdin (one per line, ending with an empty line),
+    /// then add the password on the following line as a new keyslot
+    ResetPassword {
+        /// Path to the blob file
+        #[arg(short, long, value_name = "FILE")]
+        blob: PathBuf,
+    },
+    /// Copy everything still readable from a damaged blob into a new blob. Reads the standard
+    /// password, then the hidden password from stdin, one per line
+    Salvage {
+        /// Path to the damaged blob file (left untouched)
+        #[arg(short, long, value_name = "FILE")]
+        blob: PathBuf,
+        /// Path of the new blob to write
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+}
+
+/// Runs a recovery subcommand and returns the process exit code.
+pub fn run(command: RecoveryCommand) -> i32 {
+    let stdin = std::io::stdin();
+    let result = match command {
+        RecoveryCommand::Split { threshold, shares } => split(stdin.lock(), threshold, shares),
+        RecoveryCommand::Combine => combine(stdin.lock()),
+        RecoveryCommand::ResetPassword { blob } => reset_password(stdin.lock(), &blob),
+        RecoveryCommand::Salvage { blob, output } => salvage(stdin.lock(), &blob, &output),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn split(mut input: impl BufRead, threshold: u8, count: u8) -> Result<()> {
+    let mut recovery_key = String::new();
+    input.read_line(&mut recovery_key)?;
+    let shares = split_recovery_key(recovery_key.trim(), threshold, count)?;
+    // One share per line, so any `threshold` of the lines can be fed back to `combine`
+    for share in shares {
+        println!("{}", share);
+    }
+    Ok(())
+}
+
+fn combine(input: impl BufRead) -> Result<()> {
+    let shares = read_shares(input)?;
+    println!("{}", combine_recovery_shares(&shares)?);
+    Ok(())
+}
+
+fn reset_password(mut input: impl BufRead, blob: &Path) -> Result<()> {
+    let shares = read_shares(&mut input)?;
+    let mut password = String::new();
+    input.read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(anyhow!("No new password given after the shares"));
+    }
+
+    let (volume_type, key, _) =
+        unlock_blob_with_credential(blob, &Credential::RecoveryShares(&shares))?;
+    let slot = add_keyslot(blob, volume_type, &key, &Credential::Password(password))?;
+    println!("Added the new password to keyslot {}", slot);
+    Ok(())
+}
+
+fn salvage(mut input: impl BufRead, blob: &Path, output: &Path) -> Result<()> {
+    if output.exists() {
+        return Err(anyhow!("{} already exists", output.display()));
+    }
+    let mut passwords = [String::new(), String::new()];
+    for password in passwords.iter_mut() {
+        input.read_line(password)?;
+        password.truncate(password.trim_end_matches(['\r', '\n']).len());
+    }
+
+    let report = salvage_blob(blob, &passwords[0], &passwords[1], output)?;
+    print_volume_salvage("Standard", &report.standard);
+    print_volume_salvage("Hidden", &report.hidden);
+    println!("Salvaged blob written to {}", output.display());
+    Ok(())
+}
+
+fn print_volume_salvage(name: &str, salvage: &VolumeSalvage) {
+    let source = if salvage.metadata_intact {
+        "metadata intact"
+    } else {
+        "metadata lost, rebuilt from data blocks"
+    };
+    println!(
+        "{} volume ({}): {} files recovered, {} lost",
+        name,
+        source,
+        salvage.recovered.len(),
+        salvage.lost.len()
+    );
+    for path in &salvage.placeholders {
+        println!("  recovered without its original path: {}", path);
+    }
+    for path in &salvage.lost {
+        println!("  lost: {}", path);
+    }
+}
+
+/// Reads non-empty lines up to an empty line or end of input.
+fn read_shares(input: impl BufRead) -> Result<Vec<String>> {
+    let mut shares = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            break;
+        }
+        shares.push(line.trim().to_string());
+    }
+    if shares.is_empty() {
+        return Err(anyhow!("No shares given"));
+    }
+    Ok(shares)
+}
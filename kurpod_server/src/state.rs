@@ -1,11 +1,21 @@
+use crate::jobs::JobRegistry;
 use crate::session::SessionManager;
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 /// Application state for the server
 /// This now uses session-based authentication instead of global state
 #[derive(Clone)]
 pub struct AppState {
     pub session_manager: Arc<SessionManager>,
+    /// Background jobs (e.g. blob verification) started by sessions.
+    pub jobs: JobRegistry,
+    /// One lock per blob file, held while appending data blocks and committing metadata so
+    /// concurrent uploads never interleave their writes at the end of the blob.
+    blob_write_locks: Arc<Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>>,
 }
 
 impl AppState {
@@ -15,7 +25,17 @@ impl AppState {
         // Start the background cleanup task
         session_manager.start_cleanup_task();
 
-        Self { session_manager }
+        Self {
+            session_manager,
+            jobs: JobRegistry::new(),
+            blob_write_locks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the write lock for the given blob file, creating it on first use.
+    pub fn blob_write_lock(&self, blob_path: &Path) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.blob_write_locks.lock().unwrap();
+        locks.entry(blob_path.to_path_buf()).or_default().clone()
     }
 }
 